@@ -0,0 +1,33 @@
+#ifndef WSTUNNEL_H
+#define WSTUNNEL_H
+
+/* Generated by cbindgen from src/ffi.rs with `just ffi`, do not edit */
+
+#include <stddef.h>
+
+// Client started by `wstunnel_client_start`. Its tunnels run on its own runtime, until `wstunnel_client_stop`
+typedef struct WstunnelClient WstunnelClient;
+
+// Start a client with the arguments of `wstunnel client`, i.e: {"wss://wstunnel.example.com", "--http-upgrade-path-prefix", "secret"}
+// Tunnels are then added with `wstunnel_client_add_tunnel`. On success, `*client` is set to the started client
+//
+// # Safety
+// `argv` must point to `argc` nul terminated strings, and `client` must be a valid pointer to write to.
+// Not to be called from a thread running a tokio runtime
+int wstunnel_client_start(const char *const *argv, size_t argc, WstunnelClient **client);
+
+// Add a tunnel to the client, with the syntax of `wstunnel client -L`, i.e: "tcp://1212:google.com:443"
+// The local port is listening once it returns. Stdio tunnels are not supported
+//
+// # Safety
+// `client` must be a client started by `wstunnel_client_start` and not stopped, `tunnel` a nul terminated string.
+// Not to be called from a thread running a tokio runtime
+int wstunnel_client_add_tunnel(const WstunnelClient *client, const char *tunnel);
+
+// Stop the client, closing its listeners and its tunnels, without waiting for them to be closed.
+//
+// # Safety
+// `client` must be a client started by `wstunnel_client_start`, or null. It must not be used afterward
+void wstunnel_client_stop(WstunnelClient *client);
+
+#endif  /* WSTUNNEL_H */
@@ -0,0 +1,185 @@
+use anyhow::Context;
+use base64::Engine;
+use hyper::header::{AUTHORIZATION, REFERER, USER_AGENT};
+use hyper::Request;
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+static ACCESS_LOG: OnceCell<Mutex<File>> = OnceCell::new();
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Start writing one line per tunnel and per rejected request to the file at `path`,
+/// in the Combined Log Format used by apache/nginx
+pub fn init(path: &Path) -> anyhow::Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Cannot open access log file {}", path.display()))?;
+    if ACCESS_LOG.set(Mutex::new(file)).is_err() {
+        return Err(anyhow::anyhow!("access log is already initialized"));
+    }
+
+    info!("Writing access log to {}", path.display());
+    Ok(())
+}
+
+#[derive(Clone)]
+pub struct AccessLogEntry {
+    client_ip: IpAddr,
+    user: Option<String>,
+    started_at: SystemTime,
+    request: String,
+    status: u16,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl AccessLogEntry {
+    /// Return None if the access log is not enabled, to not pay for it
+    pub fn new<B>(client_ip: IpAddr, req: &Request<B>, target: impl Display, status: u16) -> Option<Self> {
+        ACCESS_LOG.get()?;
+
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|h| h.to_str().ok())
+                .map(str::to_string)
+        };
+        Some(Self {
+            client_ip,
+            user: basic_auth_user(req),
+            started_at: SystemTime::now(),
+            request: format!("{} {} {:?}", req.method(), target, req.version()),
+            status,
+            referer: header(REFERER),
+            user_agent: header(USER_AGENT),
+        })
+    }
+
+    pub fn set_status(&mut self, status: u16) {
+        self.status = status;
+    }
+
+    /// Same request started now, for the next connections relayed over a reused websocket
+    pub fn restarted(&self) -> Self {
+        Self {
+            started_at: SystemTime::now(),
+            ..self.clone()
+        }
+    }
+
+    pub fn write(&self, bytes_sent: u64) {
+        let Some(file) = ACCESS_LOG.get() else {
+            return;
+        };
+
+        let line = self.format(bytes_sent);
+        if let Err(err) = file.lock().write_all(line.as_bytes()) {
+            warn!("Cannot write to access log: {}", err);
+        }
+    }
+
+    fn format(&self, bytes_sent: u64) -> String {
+        let quoted = |val: &Option<String>| match val {
+            Some(val) => val.replace('\\', "\\\\").replace('"', "\\\""),
+            None => "-".to_string(),
+        };
+        format!(
+            "{} - {} [{}] \"{}\" {} {} \"{}\" \"{}\"\n",
+            self.client_ip,
+            self.user.as_deref().unwrap_or("-"),
+            format_date(self.started_at),
+            self.request,
+            self.status,
+            bytes_sent,
+            quoted(&self.referer),
+            quoted(&self.user_agent),
+        )
+    }
+}
+
+fn basic_auth_user<B>(req: &Request<B>) -> Option<String> {
+    let auth = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    let encoded = auth.strip_prefix("Basic ")?;
+    let decoded = base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, _) = decoded.split_once(':')?;
+    // Usernames must not break the parsing of the line
+    Some(user.replace(|c: char| c.is_whitespace() || c == '"', "_"))
+}
+
+// i.e: 10/Oct/2000:13:55:36 +0000
+fn format_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // Convert days since epoch to a civil date, from http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!(format_date(UNIX_EPOCH), "01/Jan/1970:00:00:00 +0000");
+        assert_eq!(
+            format_date(UNIX_EPOCH + Duration::from_secs(971_186_136)),
+            "10/Oct/2000:13:55:36 +0000"
+        );
+        assert_eq!(
+            format_date(UNIX_EPOCH + Duration::from_secs(1_709_210_096)),
+            "29/Feb/2024:12:34:56 +0000"
+        );
+    }
+
+    #[test]
+    fn test_format_line() {
+        let entry = AccessLogEntry {
+            client_ip: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            user: Some("frank".to_string()),
+            started_at: UNIX_EPOCH + Duration::from_secs(971_186_136),
+            request: "GET google.com:443 HTTP/1.1".to_string(),
+            status: 101,
+            referer: None,
+            user_agent: Some("my \"agent\"".to_string()),
+        };
+
+        assert_eq!(
+            entry.format(2326),
+            "127.0.0.1 - frank [10/Oct/2000:13:55:36 +0000] \"GET google.com:443 HTTP/1.1\" 101 2326 \"-\" \"my \\\"agent\\\"\"\n"
+        );
+    }
+}
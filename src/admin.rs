@@ -0,0 +1,204 @@
+use crate::tunnel;
+use crate::tunnel::LongPollingFallback;
+#[cfg(unix)]
+use crate::WsClientConfig;
+use ahash::{HashMap, HashMapExt};
+#[cfg(unix)]
+use anyhow::Context;
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::Mutex;
+use std::fmt::Write;
+use std::future::Future;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(unix)]
+use std::sync::Arc;
+use std::time::Instant;
+#[cfg(unix)]
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::select;
+use tokio::sync::oneshot;
+#[cfg(unix)]
+use tokio_stream::StreamExt;
+use tracing::info;
+#[cfg(unix)]
+use tracing::warn;
+use uuid::Uuid;
+
+// Tunnels are only tracked when the admin socket is enabled, to not pay for it otherwise
+static ADMIN_ENABLED: OnceCell<()> = OnceCell::new();
+static TUNNELS: Lazy<Mutex<HashMap<Uuid, TunnelEntry>>> = Lazy::new(|| Mutex::new(HashMap::with_capacity(0)));
+
+const USAGE: &str = "error: unknown command, expected one of: status, stats, reload, close-tunnel ID\n";
+
+struct TunnelEntry {
+    remote: String,
+    name: Option<String>,
+    started_at: Instant,
+    close_tx: oneshot::Sender<()>,
+}
+
+/// Run the tunnel until it finishes, or until it is closed with the close-tunnel command
+pub async fn track_tunnel(id: Uuid, remote: String, name: Option<String>, tunnel: impl Future<Output = ()>) {
+    if ADMIN_ENABLED.get().is_none() {
+        return tunnel.await;
+    }
+
+    let (close_tx, close_rx) = oneshot::channel::<()>();
+    TUNNELS.lock().insert(
+        id,
+        TunnelEntry {
+            remote,
+            name,
+            started_at: Instant::now(),
+            close_tx,
+        },
+    );
+    let _guard = scopeguard::guard((), |_| {
+        TUNNELS.lock().remove(&id);
+    });
+
+    select! {
+        _ = tunnel => {},
+        _ = close_rx => info!("Tunnel {} closed from the admin socket", id),
+    }
+}
+
+/// Execute one command line, and return its response. Responses always end with an empty line
+fn handle_command(cmd: &str, long_polling_fallback: &LongPollingFallback) -> String {
+    let mut args = cmd.split_whitespace();
+    let mut response = match (args.next(), args.next(), args.next()) {
+        (Some("status"), None, _) => {
+            let mut response = format!(
+                "version {}\ntransport {}\n",
+                env!("CARGO_PKG_VERSION"),
+                if long_polling_fallback.is_active() {
+                    "long-polling"
+                } else {
+                    "default"
+                }
+            );
+            for (id, tunnel) in TUNNELS.lock().iter() {
+                let _ = write!(
+                    response,
+                    "tunnel {} {} {}s",
+                    id,
+                    tunnel.remote,
+                    tunnel.started_at.elapsed().as_secs()
+                );
+                match &tunnel.name {
+                    Some(name) => {
+                        let _ = writeln!(response, " {}", name);
+                    }
+                    None => response.push('\n'),
+                }
+            }
+            response
+        }
+        (Some("stats"), None, _) => {
+            let (opened, bytes_tx, bytes_rx) = tunnel::totals();
+            let mut response = format!(
+                "tunnels.active {}\ntunnels.opened {}\nbytes.tx {}\nbytes.rx {}\n",
+                tunnel::active_tunnels(),
+                opened,
+                bytes_tx,
+                bytes_rx
+            );
+            if let Some(rtt) = tunnel::last_rtt() {
+                let _ = writeln!(response, "rtt.ms {:.1}", rtt.as_secs_f64() * 1000.0);
+            }
+            response
+        }
+        (Some("reload"), None, _) => {
+            // Headers file is already read again for each tunnel, only the transport can be stuck on a fallback
+            long_polling_fallback.reset();
+            "ok\n".to_string()
+        }
+        (Some("close-tunnel"), Some(id), None) => {
+            let tunnel = Uuid::parse_str(id).ok().and_then(|id| TUNNELS.lock().remove(&id));
+            match tunnel {
+                Some(tunnel) => {
+                    let _ = tunnel.close_tx.send(());
+                    "ok\n".to_string()
+                }
+                None => format!("error: unknown tunnel {}\n", id),
+            }
+        }
+        _ => USAGE.to_string(),
+    };
+
+    response.push('\n');
+    response
+}
+
+/// Listen for commands on a unix socket, one command per line
+#[cfg(unix)]
+pub async fn run_server(socket_path: &Path, client_config: Arc<WsClientConfig>) -> anyhow::Result<()> {
+    let mut listener = crate::unix_socket::run_server(socket_path).await?;
+    // The socket allows to close tunnels, only the owner of the process should be able to use it
+    std::fs::set_permissions(socket_path, std::fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("Cannot restrict permissions of admin socket {:?}", socket_path))?;
+    let _ = ADMIN_ENABLED.set(());
+
+    info!("Admin interface listening on {:?}", socket_path);
+    tokio::spawn(async move {
+        while let Some(stream) = listener.next().await {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("Error while accepting admin connection {:?}", err);
+                    continue;
+                }
+            };
+
+            let client_config = client_config.clone();
+            tokio::spawn(async move {
+                let (rx, mut tx) = stream.into_split();
+                let mut lines = BufReader::new(rx).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let response = handle_command(&line, &client_config.long_polling_fallback);
+                    if tx.write_all(response.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_close_tunnel_command() {
+        let _ = ADMIN_ENABLED.set(());
+        let fallback = LongPollingFallback::default();
+        fallback.activate();
+        let id = Uuid::now_v7();
+        let tunnel = tokio::spawn(track_tunnel(
+            id,
+            "localhost:22".to_string(),
+            Some("ssh".to_string()),
+            std::future::pending(),
+        ));
+        while !TUNNELS.lock().contains_key(&id) {
+            tokio::task::yield_now().await;
+        }
+
+        let status = handle_command("status", &fallback);
+        assert!(status.contains(&format!("tunnel {} localhost:22 ", id)));
+        assert!(status.contains("s ssh\n"));
+        assert_eq!(handle_command(&format!("close-tunnel {}", id), &fallback), "ok\n\n");
+        tunnel.await.unwrap();
+        assert!(handle_command(&format!("close-tunnel {}", id), &fallback).starts_with("error: unknown tunnel"));
+
+        assert_eq!(handle_command("reload", &fallback), "ok\n\n");
+        assert!(!fallback.is_active());
+        assert_eq!(handle_command("unknown", &fallback), format!("{}\n", USAGE));
+    }
+}
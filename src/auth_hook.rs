@@ -0,0 +1,291 @@
+use crate::dns::DnsResolver;
+use crate::http_client;
+use crate::{Destination, LocalProtocol};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bytes::Bytes;
+use hyper::http::{HeaderMap, HeaderName};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tracing::warn;
+use url::{Host, Url};
+
+const AUTH_HOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Upgrade request of a tunnel, once the built-in authentications accepted it
+pub struct AuthRequest<'a> {
+    pub headers: &'a HeaderMap,
+    pub path: &'a str,
+    pub peer_addr: SocketAddr,
+    pub protocol: &'a LocalProtocol,
+    pub host: &'a str,
+    pub port: u16,
+    // User authenticated with --auth-htpasswd, if any
+    pub user: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthDecision {
+    Allow,
+    // Refuse the tunnel as unauthorized, the message is sent back to the client
+    Deny(String),
+    // Allow the tunnel, but toward this destination instead of the requested one
+    Route(Destination),
+}
+
+/// Decide whether a tunnel is allowed, to plug an external authentication and authorization system (--auth-hook-url).
+/// It runs after --auth-* checks. --force-destination, --restrict-to and --restrict-config still apply to the
+/// destination it picks
+#[async_trait]
+pub trait AuthHook: Send + Sync {
+    async fn authorize(&self, request: AuthRequest<'_>) -> AuthDecision;
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "decision", rename_all = "lowercase")]
+enum HookResponse {
+    Allow,
+    Deny {
+        #[serde(default)]
+        reason: String,
+    },
+    Route {
+        host: String,
+        port: u16,
+    },
+}
+
+impl HookResponse {
+    fn into_decision(self) -> anyhow::Result<AuthDecision> {
+        Ok(match self {
+            HookResponse::Allow => AuthDecision::Allow,
+            HookResponse::Deny { reason } => AuthDecision::Deny(reason),
+            HookResponse::Route { host, port } => {
+                let host = Host::parse(&host).map_err(|err| anyhow!("invalid host {} to route to: {}", host, err))?;
+                AuthDecision::Route((host, port))
+            }
+        })
+    }
+}
+
+/// Hook of --auth-hook-url, POST the upgrade request as a JSON document and read the decision from the response.
+/// The tunnel is denied if the url cannot be reached or answers something else, to never let a tunnel through by mistake.
+/// Only the headers of --auth-hook-header are sent, the others carry the tunnel jwt or credentials the hook has no use of
+pub struct HttpAuthHook {
+    url: Url,
+    headers: Vec<HeaderName>,
+    dns_resolver: DnsResolver,
+}
+
+impl HttpAuthHook {
+    pub fn new(url: Url, headers: Vec<HeaderName>, dns_resolver: DnsResolver) -> anyhow::Result<Self> {
+        match url.scheme() {
+            "https" => {}
+            // The request carries the credentials of the client, only a hook on the same host can get them in clear
+            "http" if is_loopback(&url) => {}
+            "http" => return Err(anyhow!("auth hook url must be https unless on a loopback address, got {}", url)),
+            _ => return Err(anyhow!("auth hook url must be http(s), got {}", url)),
+        }
+
+        Ok(Self {
+            url,
+            headers,
+            dns_resolver,
+        })
+    }
+
+    fn body(&self, request: &AuthRequest<'_>) -> Value {
+        let mut headers = Map::new();
+        for name in &self.headers {
+            let values: Vec<&str> = request
+                .headers
+                .get_all(name)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .collect();
+            if !values.is_empty() {
+                headers.insert(name.to_string(), json!(values.join(", ")));
+            }
+        }
+
+        json!({
+            "headers": headers,
+            "path": request.path,
+            "peer_addr": request.peer_addr.to_string(),
+            "protocol": request.protocol,
+            "host": request.host,
+            "port": request.port,
+            "user": request.user,
+        })
+    }
+}
+
+fn is_loopback(url: &Url) -> bool {
+    match url.host() {
+        Some(Host::Domain(domain)) => domain.eq_ignore_ascii_case("localhost"),
+        Some(Host::Ipv4(ip)) => IpAddr::V4(ip).is_loopback(),
+        Some(Host::Ipv6(ip)) => IpAddr::V6(ip).is_loopback(),
+        None => false,
+    }
+}
+
+#[async_trait]
+impl AuthHook for HttpAuthHook {
+    async fn authorize(&self, request: AuthRequest<'_>) -> AuthDecision {
+        let body = Bytes::from(self.body(&request).to_string());
+        let decision = http_client::post_json(&self.url, body, &self.dns_resolver, AUTH_HOOK_TIMEOUT)
+            .await
+            .and_then(|response| serde_json::from_slice::<HookResponse>(&response).map_err(anyhow::Error::from))
+            .and_then(HookResponse::into_decision);
+
+        match decision {
+            Ok(decision) => decision,
+            Err(err) => {
+                warn!("Cannot get a decision from the auth hook: {:?}", err);
+                AuthDecision::Deny("auth hook unavailable".to_string())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::LocalServer;
+    use crate::tunnel::{self, RemoteAddr};
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    struct RouteToBackend(u16);
+
+    #[async_trait]
+    impl AuthHook for RouteToBackend {
+        async fn authorize(&self, request: AuthRequest<'_>) -> AuthDecision {
+            match request.headers.get("x-team").and_then(|h| h.to_str().ok()) {
+                Some("backend") if request.port == 1 => AuthDecision::Route((Host::Ipv4(Ipv4Addr::LOCALHOST), self.0)),
+                Some("backend") => AuthDecision::Allow,
+                _ => AuthDecision::Deny("unknown team".to_string()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_http_auth_hook_response() {
+        let decision = |response: &str| {
+            serde_json::from_str::<HookResponse>(response)
+                .map_err(anyhow::Error::from)
+                .and_then(HookResponse::into_decision)
+                .ok()
+        };
+
+        assert_eq!(decision(r#"{"decision":"allow"}"#), Some(AuthDecision::Allow));
+        assert_eq!(
+            decision(r#"{"decision":"deny","reason":"unknown team"}"#),
+            Some(AuthDecision::Deny("unknown team".to_string()))
+        );
+        assert_eq!(
+            decision(r#"{"decision":"route","host":"127.0.0.1","port":8080}"#),
+            Some(AuthDecision::Route((Host::Ipv4(Ipv4Addr::LOCALHOST), 8080)))
+        );
+        assert_eq!(decision(r#"{"decision":"maybe"}"#), None);
+        assert_eq!(decision(r#"{"decision":"route","host":"","port":8080}"#), None);
+        assert_eq!(decision("allow"), None);
+    }
+
+    #[test]
+    fn test_http_auth_hook_body() {
+        let url = Url::parse("http://127.0.0.1:8080/authorize").unwrap();
+        let hook = HttpAuthHook::new(url, vec![HeaderName::from_static("x-team")], DnsResolver::system()).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-team", "backend".parse().unwrap());
+        headers.insert("authorization", "Basic dXNlcjpwYXNz".parse().unwrap());
+        headers.insert("sec-websocket-protocol", "v1, authorization.bearer.jwt".parse().unwrap());
+        let request = AuthRequest {
+            headers: &headers,
+            path: "/v1/events",
+            peer_addr: "127.0.0.1:1234".parse().unwrap(),
+            protocol: &LocalProtocol::Tcp { proxy_protocol: false },
+            host: "localhost",
+            port: 22,
+            user: None,
+        };
+
+        let body = hook.body(&request);
+        assert_eq!(body["headers"], json!({"x-team": "backend"}));
+    }
+
+    #[test]
+    fn test_http_auth_hook_url() {
+        let hook = |url: &str| HttpAuthHook::new(Url::parse(url).unwrap(), vec![], DnsResolver::system()).is_ok();
+
+        assert!(hook("https://auth.example.com/authorize"));
+        assert!(hook("http://127.0.0.1:8080/authorize"));
+        assert!(hook("http://[::1]:8080/authorize"));
+        assert!(hook("http://localhost:8080/authorize"));
+        assert!(!hook("http://auth.example.com/authorize"));
+        assert!(!hook("http://10.0.0.1/authorize"));
+        assert!(!hook("ftp://localhost/authorize"));
+    }
+
+    #[tokio::test]
+    async fn test_auth_hook() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_port = backend.local_addr().unwrap().port();
+        let server = LocalServer::start_with("ws", [], |config| {
+            config.auth_hook = Some(Arc::new(RouteToBackend(backend_port)));
+        })
+        .await
+        .unwrap();
+        let destination = RemoteAddr {
+            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            host: Host::Ipv4(Ipv4Addr::LOCALHOST),
+            port: 1,
+            fallbacks: vec![],
+        };
+
+        let client = server.client([]).await.unwrap();
+        assert!(tunnel::client::healthcheck(&client, &destination, Duration::from_secs(5))
+            .await
+            .is_err());
+
+        // Nothing listens on port 1, the tunnel only opens if the hook routed it to the backend
+        let client = server
+            .client(["--http-headers=x-team: backend".to_string()])
+            .await
+            .unwrap();
+        assert!(tunnel::client::healthcheck(&client, &destination, Duration::from_secs(5))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_auth_hook_route_keeps_forced_destination() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_port = backend.local_addr().unwrap().port();
+        // The hook routes to port 2 where nothing listens, the tunnel only opens if it is forced back to the backend
+        let server =
+            LocalServer::start_with("ws", [format!("--force-destination=127.0.0.1:{}", backend_port)], |config| {
+                config.auth_hook = Some(Arc::new(RouteToBackend(2)));
+            })
+            .await
+            .unwrap();
+        let destination = RemoteAddr {
+            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            host: Host::Ipv4(Ipv4Addr::LOCALHOST),
+            port: 1,
+            fallbacks: vec![],
+        };
+
+        let client = server
+            .client(["--http-headers=x-team: backend".to_string()])
+            .await
+            .unwrap();
+        assert!(tunnel::client::healthcheck(&client, &destination, Duration::from_secs(5))
+            .await
+            .is_ok());
+    }
+}
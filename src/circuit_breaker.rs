@@ -0,0 +1,122 @@
+use parking_lot::Mutex;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+use tracing::{error, info};
+
+/// Error returned while the breaker is open, the server is not even tried
+#[derive(Debug)]
+pub struct CircuitOpen {
+    remaining: Duration,
+}
+
+impl Display for CircuitOpen {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "server is considered down, not trying to connect for {}s",
+            self.remaining.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+#[derive(Default)]
+struct State {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+    // Attempts that failed fast while the breaker was open, logged once it closes
+    rejected: u64,
+}
+
+/// Stop trying to connect to the server for a cool-down period, after too many consecutive failures.
+/// It prevents retry storms (i.e: of reverse tunnels or many local connections) from hammering a down server,
+/// and from spamming the logs with the same error
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Return an error if the breaker is open. Once the cool-down is over, attempts are allowed again,
+    /// and a single failure opens it again
+    pub fn check(&self) -> Result<(), CircuitOpen> {
+        let mut state = self.state.lock();
+        match state.open_until {
+            Some(open_until) if open_until > Instant::now() => {
+                state.rejected += 1;
+                Err(CircuitOpen {
+                    remaining: open_until - Instant::now(),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock();
+        if state.open_until.take().is_some() {
+            info!(
+                "Server is reachable again, closing circuit breaker. {} connection attempts failed fast while it was open",
+                state.rejected
+            );
+        }
+        state.consecutive_failures = 0;
+        state.rejected = 0;
+    }
+
+    pub fn record_failure(&self, err: &anyhow::Error) {
+        let mut state = self.state.lock();
+        state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+        // Attempts started before the breaker opened can still fail, it is only logged once
+        let now = Instant::now();
+        if state.consecutive_failures < self.threshold || state.open_until.is_some_and(|open_until| open_until > now) {
+            return;
+        }
+
+        state.open_until = Some(now + self.cooldown);
+        error!(
+            "{} consecutive failures to connect to the server, failing fast for the next {}s. Last error: {:?}",
+            state.consecutive_failures,
+            self.cooldown.as_secs(),
+            err
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(2, Duration::from_millis(50));
+        let err = anyhow::anyhow!("connection refused");
+
+        breaker.record_failure(&err);
+        assert!(breaker.check().is_ok());
+        breaker.record_failure(&err);
+        assert!(breaker.check().is_err());
+
+        // Half open after the cool-down, one failure is enough to open it again
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.check().is_ok());
+        breaker.record_failure(&err);
+        assert!(breaker.check().is_err());
+
+        std::thread::sleep(Duration::from_millis(60));
+        breaker.record_success();
+        assert_eq!(breaker.state.lock().rejected, 0);
+        breaker.record_failure(&err);
+        assert!(breaker.check().is_ok());
+    }
+}
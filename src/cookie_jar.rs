@@ -0,0 +1,117 @@
+use hyper::header::{COOKIE, SET_COOKIE};
+use hyper::http::HeaderValue;
+use hyper::HeaderMap;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+#[derive(Debug)]
+struct Cookie {
+    value: String,
+    expires: Option<SystemTime>,
+}
+
+/// Cookies set by the server, or by a CDN/load balancer in front of it, in the responses to the upgrade requests.
+/// They are sent back with the next upgrade requests, for session affinity and bot mitigation cookies to keep working.
+/// There is one jar per server, so Domain and Path attributes are ignored
+#[derive(Debug, Default)]
+pub struct CookieJar {
+    cookies: Mutex<BTreeMap<String, Cookie>>,
+}
+
+impl CookieJar {
+    pub fn store(&self, headers: &HeaderMap) {
+        let now = SystemTime::now();
+        let mut cookies = self.cookies.lock();
+        for set_cookie in headers.get_all(SET_COOKIE).iter().filter_map(|h| h.to_str().ok()) {
+            let Some((name, cookie)) = parse_set_cookie(set_cookie, now) else {
+                continue;
+            };
+            if cookie.expires.is_some_and(|expires| expires <= now) {
+                cookies.remove(&name);
+            } else {
+                cookies.insert(name, cookie);
+            }
+        }
+    }
+
+    pub fn add_cookie_header(&self, headers: &mut HeaderMap) {
+        let now = SystemTime::now();
+        let mut cookies = self.cookies.lock();
+        cookies.retain(|_, cookie| cookie.expires.is_none_or(|expires| expires > now));
+        if cookies.is_empty() {
+            return;
+        }
+
+        let cookie = cookies
+            .iter()
+            .map(|(name, cookie)| format!("{}={}", name, cookie.value))
+            .collect::<Vec<_>>()
+            .join("; ");
+        if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+            headers.append(COOKIE, cookie);
+        }
+    }
+}
+
+// NAME=VALUE; Max-Age=SECONDS; Expires=DATE; ... Max-Age takes precedence over Expires
+fn parse_set_cookie(set_cookie: &str, now: SystemTime) -> Option<(String, Cookie)> {
+    let mut attributes = set_cookie.split(';').map(str::trim);
+    let (name, value) = attributes.next()?.split_once('=')?;
+    if name.trim().is_empty() {
+        return None;
+    }
+
+    let mut max_age = None;
+    let mut expires = None;
+    for attribute in attributes {
+        let (key, val) = attribute.split_once('=').unwrap_or((attribute, ""));
+        if key.eq_ignore_ascii_case("max-age") {
+            max_age = val.parse::<i64>().ok().map(|secs| match u64::try_from(secs) {
+                Ok(secs) if secs > 0 => now + Duration::from_secs(secs),
+                _ => SystemTime::UNIX_EPOCH,
+            });
+        } else if key.eq_ignore_ascii_case("expires") {
+            expires = httpdate::parse_http_date(val).ok();
+        }
+    }
+
+    Some((
+        name.trim().to_string(),
+        Cookie {
+            value: value.trim().to_string(),
+            expires: max_age.or(expires),
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cookie_jar() {
+        let jar = CookieJar::default();
+        let mut headers = HeaderMap::new();
+        jar.add_cookie_header(&mut headers);
+        assert!(headers.get(COOKIE).is_none());
+
+        let mut response = HeaderMap::new();
+        response.append(SET_COOKIE, HeaderValue::from_static("lb=node-2; Path=/; HttpOnly"));
+        response.append(SET_COOKIE, HeaderValue::from_static("__cf_bm=abc=; Max-Age=1800; Secure"));
+        response.append(
+            SET_COOKIE,
+            HeaderValue::from_static("old=1; Expires=Thu, 01 Jan 1970 00:00:00 GMT"),
+        );
+        jar.store(&response);
+        jar.add_cookie_header(&mut headers);
+        assert_eq!(headers.get(COOKIE).unwrap(), "__cf_bm=abc=; lb=node-2");
+
+        let mut response = HeaderMap::new();
+        response.append(SET_COOKIE, HeaderValue::from_static("lb=; Max-Age=0"));
+        jar.store(&response);
+        let mut headers = HeaderMap::new();
+        jar.add_cookie_header(&mut headers);
+        assert_eq!(headers.get(COOKIE).unwrap(), "__cf_bm=abc=");
+    }
+}
@@ -0,0 +1,127 @@
+use std::io;
+use std::io::ErrorKind;
+use url::Host;
+
+// HOST:PORT where * matches any part of the host, or any port
+#[derive(Clone, Debug)]
+struct Pattern {
+    host: String,
+    port: Option<u16>,
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Result<Self, io::Error> {
+        let invalid = || {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "invalid destination pattern {}, expected HOST:PORT i.e: *.corp.example:*",
+                    pattern
+                ),
+            )
+        };
+        let (host, port) = pattern.trim().rsplit_once(':').ok_or_else(invalid)?;
+        let port = match port {
+            "*" => None,
+            port => Some(port.parse::<u16>().map_err(|_| invalid())?),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            host: host.to_ascii_lowercase(),
+            port,
+        })
+    }
+
+    fn matches(&self, host: &str, port: u16) -> bool {
+        self.port.is_none_or(|p| p == port) && glob_match(self.host.as_bytes(), host.as_bytes())
+    }
+}
+
+fn glob_match(pattern: &[u8], value: &[u8]) -> bool {
+    let (mut p, mut v) = (0, 0);
+    // Position of the last * in the pattern, and of the value when we met it, to backtrack to
+    let mut backtrack = None;
+    while v < value.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            backtrack = Some((p, v));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == value[v] {
+            p += 1;
+            v += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            v = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+/// Destinations a dynamic listener (socks5) accepts to tunnel, for other software on the machine
+/// not to be able to use it as a general purpose proxy
+#[derive(Clone, Debug, Default)]
+pub struct DestinationFilter {
+    allow: Vec<Pattern>,
+    deny: Vec<Pattern>,
+}
+
+impl DestinationFilter {
+    /// Comma separated lists of patterns. None if there is nothing to filter
+    pub fn parse(allow: Option<&str>, deny: Option<&str>) -> Result<Option<Self>, io::Error> {
+        let parse_patterns = |patterns: Option<&str>| {
+            patterns
+                .unwrap_or_default()
+                .split(',')
+                .filter(|pattern| !pattern.trim().is_empty())
+                .map(Pattern::parse)
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let filter = Self {
+            allow: parse_patterns(allow)?,
+            deny: parse_patterns(deny)?,
+        };
+
+        Ok((!filter.allow.is_empty() || !filter.deny.is_empty()).then_some(filter))
+    }
+
+    /// Deny patterns win over the allow ones. Without allow patterns, everything not denied is allowed
+    pub fn is_allowed(&self, host: &Host, port: u16) -> bool {
+        let host = host.to_string().to_ascii_lowercase();
+        !self.deny.iter().any(|p| p.matches(&host, port))
+            && (self.allow.is_empty() || self.allow.iter().any(|p| p.matches(&host, port)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_destination_filter() {
+        let host = |host: &str| Host::parse(host).unwrap();
+        let filter = DestinationFilter::parse(Some("*.corp.example:*, 10.0.0.1:22"), Some("secret.corp.example:*"))
+            .unwrap()
+            .unwrap();
+
+        assert!(filter.is_allowed(&host("git.corp.example"), 443));
+        assert!(filter.is_allowed(&host("a.b.CORP.example"), 22));
+        assert!(filter.is_allowed(&host("10.0.0.1"), 22));
+        assert!(!filter.is_allowed(&host("10.0.0.1"), 80));
+        assert!(!filter.is_allowed(&host("corp.example"), 443));
+        assert!(!filter.is_allowed(&host("evil.com"), 443));
+        assert!(!filter.is_allowed(&host("secret.corp.example"), 443));
+
+        let filter = DestinationFilter::parse(None, Some("[::1]:*")).unwrap().unwrap();
+        assert!(!filter.is_allowed(&host("[::1]"), 8080));
+        assert!(filter.is_allowed(&host("example.com"), 443));
+
+        assert!(DestinationFilter::parse(None, None).unwrap().is_none());
+        assert!(DestinationFilter::parse(Some("example.com"), None).is_err());
+        assert!(DestinationFilter::parse(Some("example.com:http"), None).is_err());
+    }
+}
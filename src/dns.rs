@@ -1,17 +1,236 @@
+use anyhow::{anyhow, Context};
+use hickory_resolver::config::{LookupIpStrategy, ResolverOpts};
+use hickory_resolver::proto::rr::rdata::svcb::SvcParamValue;
+use hickory_resolver::proto::rr::{RData, RecordType};
 use hickory_resolver::TokioAsyncResolver;
+use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::sync::Arc;
+use std::time::Duration;
+
+fn normalize_host(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn parse_hosts_file(content: &str, overrides: &mut HashMap<String, Vec<IpAddr>>) -> anyhow::Result<()> {
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(ip) = fields.next() else {
+            continue;
+        };
+        let ip: IpAddr = ip
+            .parse()
+            .with_context(|| format!("invalid ip in hosts line: {}", line))?;
+        for host in fields {
+            overrides.entry(normalize_host(host)).or_default().push(ip);
+        }
+    }
+    Ok(())
+}
+
+/// Parse `host=ip` overrides, or `@path` to a hosts-format file. A host can be given several times to get multiple ips
+pub fn parse_overrides(args: &[String]) -> anyhow::Result<HashMap<String, Vec<IpAddr>>> {
+    let mut overrides: HashMap<String, Vec<IpAddr>> = HashMap::new();
+    for arg in args {
+        if let Some(path) = arg.strip_prefix('@') {
+            let content =
+                std::fs::read_to_string(path).with_context(|| format!("cannot read dns overrides from {}", path))?;
+            parse_hosts_file(&content, &mut overrides)?;
+            continue;
+        }
+
+        let Some((host, ip)) = arg.split_once('=') else {
+            return Err(anyhow!("invalid dns override {}, expected host=ip or @/path/to/hosts", arg));
+        };
+        let ip: IpAddr = ip
+            .trim()
+            .parse()
+            .with_context(|| format!("invalid ip in dns override {}", arg))?;
+        overrides.entry(normalize_host(host.trim())).or_default().push(ip);
+    }
+    Ok(overrides)
+}
+
+/// Only address family to connect with, for hosts whose other family is broken
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    pub fn from_flags(ipv4_only: bool, ipv6_only: bool) -> Option<Self> {
+        match (ipv4_only, ipv6_only) {
+            (true, _) => Some(IpFamily::V4),
+            (_, true) => Some(IpFamily::V6),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            IpFamily::V4 => "IPv4",
+            IpFamily::V6 => "IPv6",
+        }
+    }
+
+    fn matches(self, ip: &IpAddr) -> bool {
+        match self {
+            IpFamily::V4 => ip.is_ipv4(),
+            IpFamily::V6 => ip.is_ipv6(),
+        }
+    }
+}
+
+/// Do not even query the records of the other family, as their lookup can stall too
+pub fn configure_ip_family(opts: &mut ResolverOpts, family: Option<IpFamily>) {
+    match family {
+        Some(IpFamily::V4) => opts.ip_strategy = LookupIpStrategy::Ipv4Only,
+        Some(IpFamily::V6) => opts.ip_strategy = LookupIpStrategy::Ipv6Only,
+        None => {}
+    }
+}
+
+/// Keep up to `cache_size` answers, for their TTL capped to `max_ttl`.
+/// It avoids a resolver round trip for each tunnel toward the same popular hosts
+pub fn configure_cache(opts: &mut ResolverOpts, cache_size: usize, max_ttl: Option<Duration>) {
+    opts.cache_size = cache_size;
+    if let Some(max_ttl) = max_ttl {
+        opts.positive_max_ttl = Some(max_ttl);
+        opts.negative_max_ttl = Some(max_ttl);
+    }
+}
+
+/// Give up on a query to a name server after `timeout`, retry it up to `attempts` times,
+/// and send it to `concurrency` name servers at once, so one slow name server does not stall the lookup.
+/// Options not given keep the values of resolv.conf, or the defaults of hickory
+pub fn configure_lookup(
+    opts: &mut ResolverOpts,
+    timeout: Option<Duration>,
+    attempts: Option<usize>,
+    concurrency: Option<usize>,
+) {
+    if let Some(timeout) = timeout {
+        opts.timeout = timeout;
+    }
+    if let Some(attempts) = attempts {
+        opts.attempts = attempts;
+    }
+    if let Some(concurrency) = concurrency {
+        opts.num_concurrent_reqs = concurrency;
+    }
+}
+
+/// Server advertised by a SRV record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvTarget {
+    pub priority: u16,
+    pub weight: u16,
+    pub host: String,
+    pub port: u16,
+}
+
+/// Pick one of the targets with the lowest priority, randomly according to their weight as described in rfc2782
+pub fn select_srv_target(targets: &[SrvTarget], rand: u64) -> Option<&SrvTarget> {
+    let priority = targets.iter().map(|t| t.priority).min()?;
+    let candidates: Vec<&SrvTarget> = targets.iter().filter(|t| t.priority == priority).collect();
+    let total_weight: u64 = candidates.iter().map(|t| t.weight as u64).sum();
+    if total_weight == 0 {
+        return Some(candidates[(rand % candidates.len() as u64) as usize]);
+    }
+
+    let mut pick = rand % total_weight;
+    for target in candidates {
+        if pick < target.weight as u64 {
+            return Some(target);
+        }
+        pick -= target.weight as u64;
+    }
+    None
+}
 
 #[derive(Clone)]
-pub enum DnsResolver {
+enum Backend {
     System,
     TrustDns(TokioAsyncResolver),
 }
 
+#[derive(Clone)]
+pub struct DnsResolver {
+    backend: Backend,
+    // Static host -> ips, consulted before asking the resolver
+    overrides: Arc<HashMap<String, Vec<IpAddr>>>,
+    ip_family: Option<IpFamily>,
+    // Time after which a lookup of the system resolver is abandoned, as libc does not allow to cancel it
+    system_lookup_timeout: Option<Duration>,
+}
+
 impl DnsResolver {
+    /// Resolve with getaddrinfo, i.e: to follow nsswitch.conf and /etc/hosts
+    pub fn system() -> Self {
+        Self::new(Backend::System)
+    }
+
+    pub fn trust_dns(resolver: TokioAsyncResolver) -> Self {
+        Self::new(Backend::TrustDns(resolver))
+    }
+
+    fn new(backend: Backend) -> Self {
+        Self {
+            backend,
+            overrides: Arc::new(HashMap::new()),
+            ip_family: None,
+            system_lookup_timeout: None,
+        }
+    }
+
+    pub fn with_overrides(mut self, overrides: HashMap<String, Vec<IpAddr>>) -> Self {
+        self.overrides = Arc::new(overrides);
+        self
+    }
+
+    /// Only return the addresses of this family. The options of a trust-dns resolver must be set with [configure_ip_family] too
+    pub fn with_ip_family(mut self, ip_family: Option<IpFamily>) -> Self {
+        self.ip_family = ip_family;
+        self
+    }
+
+    pub fn with_system_lookup_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.system_lookup_timeout = timeout;
+        self
+    }
+
     pub async fn lookup_host(&self, domain: &str, port: u16) -> anyhow::Result<Vec<SocketAddr>> {
-        let addrs: Vec<SocketAddr> = match self {
-            DnsResolver::System => tokio::net::lookup_host(format!("{}:{}", domain, port)).await?.collect(),
-            DnsResolver::TrustDns(dns_resolver) => dns_resolver
+        let addrs = self.lookup_all(domain, port).await?;
+        let Some(family) = self.ip_family else {
+            return Ok(addrs);
+        };
+
+        let addrs: Vec<SocketAddr> = addrs.into_iter().filter(|addr| family.matches(&addr.ip())).collect();
+        if addrs.is_empty() {
+            return Err(anyhow!("no {} address found for {}", family.as_str(), domain));
+        }
+        Ok(addrs)
+    }
+
+    async fn lookup_all(&self, domain: &str, port: u16) -> anyhow::Result<Vec<SocketAddr>> {
+        if let Some(ips) = self.overrides.get(&normalize_host(domain)) {
+            return Ok(ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect());
+        }
+
+        let addrs: Vec<SocketAddr> = match &self.backend {
+            Backend::System => {
+                let lookup = tokio::net::lookup_host(format!("{}:{}", domain, port));
+                match self.system_lookup_timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, lookup)
+                        .await
+                        .map_err(|_| anyhow!("dns lookup of {} timed out after {:?}", domain, timeout))??
+                        .collect(),
+                    None => lookup.await?.collect(),
+                }
+            }
+            Backend::TrustDns(dns_resolver) => dns_resolver
                 .lookup_ip(domain)
                 .await?
                 .into_iter()
@@ -24,4 +243,136 @@ impl DnsResolver {
 
         Ok(addrs)
     }
+
+    /// Forget the cached answers, for the next lookups to follow a DNS failover
+    pub fn clear_cache(&self) {
+        if let Backend::TrustDns(dns_resolver) = &self.backend {
+            dns_resolver.clear_cache();
+        }
+    }
+
+    /// Targets of the SRV record, i.e: _wstunnel._tcp.example.com. A target of "." means the service is not available
+    pub async fn lookup_srv(&self, name: &str) -> anyhow::Result<Vec<SrvTarget>> {
+        let Backend::TrustDns(dns_resolver) = &self.backend else {
+            return Err(anyhow!("cannot resolve SRV record {} with the system resolver", name));
+        };
+
+        let targets = dns_resolver
+            .srv_lookup(name)
+            .await?
+            .iter()
+            .map(|srv| SrvTarget {
+                priority: srv.priority(),
+                weight: srv.weight(),
+                host: srv.target().to_utf8().trim_end_matches('.').to_string(),
+                port: srv.port(),
+            })
+            .filter(|target| !target.host.is_empty())
+            .collect();
+
+        Ok(targets)
+    }
+
+    /// ECHConfigList published in the HTTPS record of the host, i.e: _8443._https.example.com for another port than 443
+    pub async fn lookup_ech_config(&self, host: &str, port: u16) -> anyhow::Result<Option<Vec<u8>>> {
+        let Backend::TrustDns(dns_resolver) = &self.backend else {
+            return Err(anyhow!("cannot resolve HTTPS record of {} with the system resolver", host));
+        };
+
+        let name = match port {
+            443 => host.to_string(),
+            port => format!("_{}._https.{}", port, host),
+        };
+        let ech_config = dns_resolver
+            .lookup(name, RecordType::HTTPS)
+            .await?
+            .iter()
+            .filter_map(|rdata| match rdata {
+                RData::HTTPS(https) => https.svc_params().iter().find_map(|(_, value)| match value {
+                    SvcParamValue::EchConfig(ech_config) => Some(ech_config.0.clone()),
+                    _ => None,
+                }),
+                _ => None,
+            })
+            .next();
+
+        // hickory strips the length prefix of the ECHConfigList, that rustls expects
+        Ok(ech_config.map(|config| {
+            let mut config_list = (config.len() as u16).to_be_bytes().to_vec();
+            config_list.extend(config);
+            config_list
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_overrides() {
+        let mut overrides = parse_overrides(&[
+            "api.example.com=10.0.0.1".to_string(),
+            "API.example.com.=::1".to_string(),
+        ])
+        .unwrap();
+        parse_hosts_file(
+            "# staging\n192.168.1.10 db.internal db   # primary\n\n192.168.1.11\tdb.internal\n",
+            &mut overrides,
+        )
+        .unwrap();
+
+        assert_eq!(
+            overrides["api.example.com"],
+            vec!["10.0.0.1".parse::<IpAddr>().unwrap(), "::1".parse().unwrap()]
+        );
+        assert_eq!(overrides["db"], vec!["192.168.1.10".parse::<IpAddr>().unwrap()]);
+        assert_eq!(overrides["db.internal"].len(), 2);
+        assert!(parse_overrides(&["example.com".to_string()]).is_err());
+        assert!(parse_overrides(&["example.com=not_an_ip".to_string()]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolver_options() {
+        let overrides = parse_overrides(&[
+            "api.example.com=10.0.0.1".to_string(),
+            "api.example.com=::1".to_string(),
+        ]);
+        let resolver = DnsResolver::system().with_overrides(overrides.unwrap());
+        let ipv6_resolver = resolver.clone().with_ip_family(Some(IpFamily::V6));
+
+        // Each resolver keeps its own options
+        assert_eq!(resolver.lookup_host("api.example.com", 443).await.unwrap().len(), 2);
+        assert_eq!(
+            ipv6_resolver.lookup_host("API.example.com.", 443).await.unwrap(),
+            vec!["[::1]:443".parse::<SocketAddr>().unwrap()]
+        );
+        assert!(DnsResolver::system()
+            .with_overrides(parse_overrides(&["v4.example.com=10.0.0.1".to_string()]).unwrap())
+            .with_ip_family(Some(IpFamily::V6))
+            .lookup_host("v4.example.com", 443)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_select_srv_target() {
+        let target = |priority, weight, host: &str| SrvTarget {
+            priority,
+            weight,
+            host: host.to_string(),
+            port: 443,
+        };
+        let targets = vec![target(20, 100, "backup"), target(10, 1, "a"), target(10, 3, "b")];
+
+        // Backup is never picked while a server with a lower priority exists
+        assert_eq!(select_srv_target(&targets, 0).unwrap().host, "a");
+        assert_eq!(select_srv_target(&targets, 1).unwrap().host, "b");
+        assert_eq!(select_srv_target(&targets, 3).unwrap().host, "b");
+        assert_eq!(select_srv_target(&targets, 4).unwrap().host, "a");
+
+        let targets = vec![target(0, 0, "a"), target(0, 0, "b")];
+        assert_eq!(select_srv_target(&targets, 1).unwrap().host, "b");
+        assert!(select_srv_target(&[], 0).is_none());
+    }
 }
@@ -0,0 +1,106 @@
+use ipnet::IpNet;
+use std::net::IpAddr;
+use std::str::FromStr;
+use url::{Host, Url};
+
+/// Http proxy to reach the server with, from the environment variables as curl and git do.
+/// HTTPS_PROXY for a server with tls, then HTTP_PROXY that was used for every server before.
+/// Lowercase variables win over uppercase ones. None if the server is listed in NO_PROXY
+pub fn env_proxy(server: &Url, env: impl Fn(&str) -> Option<String>) -> Option<String> {
+    let var = |name: &str| {
+        env(&name.to_ascii_lowercase())
+            .or_else(|| env(name))
+            .filter(|value| !value.trim().is_empty())
+    };
+
+    let tls = matches!(server.scheme(), "wss" | "https");
+    let proxy = tls
+        .then(|| var("HTTPS_PROXY"))
+        .flatten()
+        .or_else(|| var("HTTP_PROXY"))?;
+    let host = server.host()?;
+    let port = server.port_or_known_default().unwrap_or(80);
+    if var("NO_PROXY").is_some_and(|no_proxy| is_no_proxy(&no_proxy, &host, port)) {
+        return None;
+    }
+
+    Some(proxy.trim().to_string())
+}
+
+// Comma separated list of *, domains that also match their sub-domains, ips or cidrs, with an optional :port
+fn is_no_proxy(no_proxy: &str, host: &Host<&str>, port: u16) -> bool {
+    no_proxy
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| {
+            if entry == "*" {
+                return true;
+            }
+
+            let (pattern, entry_port) = match entry.rsplit_once(':') {
+                Some((pattern, p)) if !pattern.contains(':') || pattern.ends_with(']') => match p.parse::<u16>() {
+                    Ok(p) => (pattern, Some(p)),
+                    Err(_) => (entry, None),
+                },
+                _ => (entry, None),
+            };
+            if entry_port.is_some_and(|p| p != port) {
+                return false;
+            }
+
+            let pattern = pattern.trim_start_matches('[').trim_end_matches(']');
+            let net = IpNet::from_str(pattern)
+                .ok()
+                .or_else(|| IpAddr::from_str(pattern).ok().map(IpNet::from));
+            match (host, net) {
+                (Host::Ipv4(ip), Some(net)) => net.contains(&IpAddr::V4(*ip)),
+                (Host::Ipv6(ip), Some(net)) => net.contains(&IpAddr::V6(*ip)),
+                (Host::Domain(domain), None) => {
+                    let domain = domain.to_ascii_lowercase();
+                    let pattern = pattern
+                        .trim_start_matches('*')
+                        .trim_start_matches('.')
+                        .to_ascii_lowercase();
+                    domain == pattern || domain.ends_with(&format!(".{}", pattern))
+                }
+                _ => false,
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_env_proxy() {
+        let vars = HashMap::from([
+            ("HTTP_PROXY", "http://proxy:3128"),
+            ("https_proxy", "http://secure-proxy:3128"),
+            ("HTTPS_PROXY", "http://ignored:3128"),
+            ("no_proxy", "localhost, .corp.example, 10.0.0.0/8, [::1], mirror.example:8443"),
+        ]);
+        let env = |name: &str| vars.get(name).map(|value| value.to_string());
+        let proxy = |server: &str| env_proxy(&Url::parse(server).unwrap(), env);
+
+        assert_eq!(proxy("ws://server.example").as_deref(), Some("http://proxy:3128"));
+        assert_eq!(proxy("wss://server.example").as_deref(), Some("http://secure-proxy:3128"));
+        assert_eq!(proxy("https://server.example").as_deref(), Some("http://secure-proxy:3128"));
+        assert_eq!(proxy("wss://localhost:8080"), None);
+        assert_eq!(proxy("wss://corp.example"), None);
+        assert_eq!(proxy("wss://git.CORP.example"), None);
+        assert_eq!(proxy("wss://notcorp.example").as_deref(), Some("http://secure-proxy:3128"));
+        assert_eq!(proxy("ws://10.1.2.3"), None);
+        assert_eq!(proxy("ws://11.1.2.3").as_deref(), Some("http://proxy:3128"));
+        assert_eq!(proxy("ws://[::1]:8080"), None);
+        assert_eq!(proxy("wss://mirror.example:8443"), None);
+        assert_eq!(proxy("wss://mirror.example").as_deref(), Some("http://secure-proxy:3128"));
+
+        let vars = HashMap::from([("HTTP_PROXY", "proxy:3128"), ("NO_PROXY", "*")]);
+        let env = |name: &str| vars.get(name).map(|value| value.to_string());
+        assert_eq!(env_proxy(&Url::parse("wss://server.example").unwrap(), env), None);
+        assert_eq!(env_proxy(&Url::parse("wss://server.example").unwrap(), |_| None), None);
+    }
+}
@@ -0,0 +1,187 @@
+use crate::tunnel::CloseReason;
+use std::fmt::{Debug, Display};
+use tracing::error;
+
+pub const EXIT_CODES_HELP: &str = "Exit codes:
+  1  unexpected failure
+  2  invalid configuration
+  3  cannot bind a listening socket
+  4  cannot load TLS material
+  5  cannot resolve an address with DNS
+  6  authentication rejected by the server
+Fatal errors are also printed on stderr as a JSON line, i.e: {\"fatal\":\"bind_failed\",\"exit_code\":3,\"message\":\"...\"}";
+
+/// Failures that stop the process, each kind with its own exit code for supervisors and scripts to react to it
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Fatal {
+    Other,
+    // Same exit code as clap when the arguments cannot be parsed
+    InvalidConfig,
+    BindFailed,
+    TlsFailed,
+    DnsFailed,
+    AuthRejected,
+}
+
+impl Fatal {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Fatal::Other => 1,
+            Fatal::InvalidConfig => 2,
+            Fatal::BindFailed => 3,
+            Fatal::TlsFailed => 4,
+            Fatal::DnsFailed => 5,
+            Fatal::AuthRejected => 6,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Fatal::Other => "other",
+            Fatal::InvalidConfig => "invalid_config",
+            Fatal::BindFailed => "bind_failed",
+            Fatal::TlsFailed => "tls_failed",
+            Fatal::DnsFailed => "dns_failed",
+            Fatal::AuthRejected => "auth_rejected",
+        }
+    }
+
+    /// Find the kind of failure from the errors of the chain, when it is not known by the caller
+    pub fn from_error(err: &anyhow::Error) -> Self {
+        for cause in err.chain() {
+            if let Some(failure) = cause.downcast_ref::<Failure>() {
+                return failure.kind;
+            }
+            if cause.downcast_ref::<CloseReason>() == Some(&CloseReason::Unauthorized) {
+                return Fatal::AuthRejected;
+            }
+            if cause.downcast_ref::<hickory_resolver::error::ResolveError>().is_some() {
+                return Fatal::DnsFailed;
+            }
+            if cause.downcast_ref::<tokio_rustls::rustls::Error>().is_some() {
+                return Fatal::TlsFailed;
+            }
+        }
+
+        Fatal::Other
+    }
+
+    /// Log the error, print it as a JSON line on stderr, and exit with the code of this kind of failure
+    pub fn exit(self, msg: impl Display) -> ! {
+        let msg = msg.to_string();
+        error!("{}", msg);
+        eprintln!("{}", self.json_line(&msg));
+        std::process::exit(self.exit_code())
+    }
+
+    fn json_line(self, msg: &str) -> String {
+        serde_json::json!({ "fatal": self.as_str(), "exit_code": self.exit_code(), "message": msg }).to_string()
+    }
+}
+
+/// Error of a known kind of failure, for the caller to either exit with its code or to handle it, i.e: in tests
+#[derive(Debug)]
+pub struct Failure {
+    pub kind: Fatal,
+    message: String,
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Failure {}
+
+impl Failure {
+    pub fn new(kind: Fatal, message: impl Display) -> anyhow::Error {
+        anyhow::Error::new(Failure {
+            kind,
+            message: message.to_string(),
+        })
+    }
+}
+
+pub trait OrFail<T> {
+    /// Same as `or_exit`, with the failure returned as an error instead of exiting
+    fn or_fail(self, kind: Fatal, context: &str) -> anyhow::Result<T>;
+}
+
+impl<T, E: Debug> OrFail<T> for Result<T, E> {
+    fn or_fail(self, kind: Fatal, context: &str) -> anyhow::Result<T> {
+        self.map_err(|err| Failure::new(kind, format_args!("{}: {:?}", context, err)))
+    }
+}
+
+pub trait OrExit<T> {
+    /// Unwrap the value, or exit the process with the given kind of failure
+    fn or_exit(self, kind: Fatal, context: &str) -> T;
+}
+
+impl<T, E: Debug> OrExit<T> for Result<T, E> {
+    fn or_exit(self, kind: Fatal, context: &str) -> T {
+        match self {
+            Ok(value) => value,
+            Err(err) => kind.exit(format_args!("{}: {:?}", context, err)),
+        }
+    }
+}
+
+/// Unwrap the value, or exit the process with the kind of failure found in the error
+pub fn or_exit_on_failure<T>(ret: anyhow::Result<T>) -> T {
+    ret.unwrap_or_else(|err| Fatal::from_error(&err).exit(format_args!("{:#}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fatal_from_error() {
+        let err = anyhow::Error::new(CloseReason::Unauthorized).context("Server rejected the upgrade request");
+        assert_eq!(Fatal::from_error(&err), Fatal::AuthRejected);
+        assert_eq!(Fatal::from_error(&anyhow::anyhow!("boom")), Fatal::Other);
+
+        let err = Err::<(), _>("address in use")
+            .or_fail(Fatal::BindFailed, "Cannot bind")
+            .unwrap_err();
+        assert_eq!(Fatal::from_error(&err), Fatal::BindFailed);
+        assert_eq!(err.to_string(), "Cannot bind: \"address in use\"");
+    }
+
+    #[test]
+    fn test_fatal_json_line() {
+        let line: serde_json::Value = serde_json::from_str(&Fatal::DnsFailed.json_line("no \"record\"")).unwrap();
+        assert_eq!(
+            line,
+            serde_json::json!({ "fatal": "dns_failed", "exit_code": 5, "message": "no \"record\"" })
+        );
+    }
+
+    // Run by test_or_exit in a child process, as it exits
+    #[test]
+    fn test_or_exit_child() {
+        if std::env::var_os("WSTUNNEL_TEST_OR_EXIT").is_none() {
+            return;
+        }
+        Err::<(), _>("address in use").or_exit(Fatal::BindFailed, "Cannot bind");
+    }
+
+    #[test]
+    fn test_or_exit() {
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "fatal::tests::test_or_exit_child", "--nocapture"])
+            .env("WSTUNNEL_TEST_OR_EXIT", "1")
+            .output()
+            .unwrap();
+
+        assert_eq!(output.status.code(), Some(3));
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        let line = stderr.lines().find(|line| line.starts_with('{')).unwrap();
+        let line: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert_eq!(line["fatal"], "bind_failed");
+        assert_eq!(line["exit_code"], 3);
+        assert_eq!(line["message"], "Cannot bind: \"address in use\"");
+    }
+}
@@ -0,0 +1,164 @@
+//! C API to embed the wstunnel client in applications not written in Rust, i.e: mobile or desktop apps.
+//! Built as a static and a dynamic library with `just ffi`, its header is include/wstunnel.h
+//! Functions return 0 on success, or the exit code the binary would exit with, as listed by `wstunnel --help`
+
+use crate::fatal::{Failure, Fatal, OrFail};
+use crate::tls::{self, TlsCryptoProvider};
+use crate::{
+    create_client_config, expand_dual_stack, parse_args, parse_tunnel_arg, start_local_tunnel, Client, LocalProtocol,
+    WsClientConfig,
+};
+use std::ffi::{c_char, c_int, CStr};
+use std::iter;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+use tracing::error;
+
+/// Client started by `wstunnel_client_start`. Its tunnels run on its own runtime, until `wstunnel_client_stop`
+pub struct WstunnelClient {
+    runtime: Runtime,
+    args: Client,
+    client_config: Arc<WsClientConfig>,
+}
+
+/// Run `f`, and turn its failure or its panic into an exit code, as panics must not unwind into C
+fn exit_code(f: impl FnOnce() -> anyhow::Result<()>) -> c_int {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => 0,
+        Ok(Err(err)) => {
+            error!("{:?}", err);
+            Fatal::from_error(&err).exit_code()
+        }
+        Err(_) => Fatal::Other.exit_code(),
+    }
+}
+
+unsafe fn c_string(ptr: *const c_char) -> anyhow::Result<String> {
+    if ptr.is_null() {
+        return Err(Failure::new(Fatal::InvalidConfig, "Unexpected null string"));
+    }
+    CStr::from_ptr(ptr)
+        .to_str()
+        .map(str::to_string)
+        .or_fail(Fatal::InvalidConfig, "Invalid UTF-8 string")
+}
+
+/// Start a client with the arguments of `wstunnel client`, i.e: {"wss://wstunnel.example.com", "--http-upgrade-path-prefix", "secret"}
+/// Tunnels are then added with `wstunnel_client_add_tunnel`. On success, `*client` is set to the started client
+///
+/// # Safety
+/// `argv` must point to `argc` nul terminated strings, and `client` must be a valid pointer to write to.
+/// Not to be called from a thread running a tokio runtime
+#[no_mangle]
+pub unsafe extern "C" fn wstunnel_client_start(
+    argv: *const *const c_char,
+    argc: usize,
+    client: *mut *mut WstunnelClient,
+) -> c_int {
+    exit_code(|| {
+        if client.is_null() || (argv.is_null() && argc > 0) {
+            return Err(Failure::new(Fatal::InvalidConfig, "Unexpected null pointer"));
+        }
+        let args = (0..argc)
+            .map(|i| c_string(*argv.add(i)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let args: Client = parse_args(iter::once("wstunnel".to_string()).chain(args))?;
+
+        let runtime = Runtime::new().or_fail(Fatal::Other, "Cannot start tokio runtime")?;
+        // Already installed if the application started another client
+        let _ = tls::install_crypto_provider(TlsCryptoProvider::default());
+        let client_config = runtime.block_on(create_client_config(&args))?;
+
+        *client = Box::into_raw(Box::new(WstunnelClient {
+            runtime,
+            args,
+            client_config,
+        }));
+        Ok(())
+    })
+}
+
+/// Add a tunnel to the client, with the syntax of `wstunnel client -L`, i.e: "tcp://1212:google.com:443"
+/// The local port is listening once it returns. Stdio tunnels are not supported
+///
+/// # Safety
+/// `client` must be a client started by `wstunnel_client_start` and not stopped, `tunnel` a nul terminated string.
+/// Not to be called from a thread running a tokio runtime
+#[no_mangle]
+pub unsafe extern "C" fn wstunnel_client_add_tunnel(client: *const WstunnelClient, tunnel: *const c_char) -> c_int {
+    exit_code(|| {
+        let Some(client) = client.as_ref() else {
+            return Err(Failure::new(Fatal::InvalidConfig, "Unexpected null client"));
+        };
+        let tunnel = parse_tunnel_arg(&c_string(tunnel)?).or_fail(Fatal::InvalidConfig, "Invalid tunnel")?;
+        if tunnel.local_protocol == LocalProtocol::Stdio {
+            return Err(Failure::new(Fatal::InvalidConfig, "Stdio tunnels are not supported"));
+        }
+
+        client.runtime.block_on(async {
+            for tunnel in expand_dual_stack(tunnel) {
+                start_local_tunnel(&client.args, &client.client_config, tunnel).await?;
+            }
+            Ok(())
+        })
+    })
+}
+
+/// Stop the client, closing its listeners and its tunnels, without waiting for them to be closed.
+///
+/// # Safety
+/// `client` must be a client started by `wstunnel_client_start`, or null. It must not be used afterward
+#[no_mangle]
+pub unsafe extern "C" fn wstunnel_client_stop(client: *mut WstunnelClient) {
+    if client.is_null() {
+        return;
+    }
+    let client = Box::from_raw(client);
+    let _ = catch_unwind(AssertUnwindSafe(move || {
+        let WstunnelClient {
+            runtime,
+            args,
+            client_config,
+        } = *client;
+        drop((args, client_config));
+        runtime.shutdown_background();
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::LocalServer;
+    use std::ffi::CString;
+    use std::ptr;
+
+    fn start(args: &[&str]) -> (c_int, *mut WstunnelClient) {
+        let args = args.iter().map(|arg| CString::new(*arg).unwrap()).collect::<Vec<_>>();
+        let argv = args.iter().map(|arg| arg.as_ptr()).collect::<Vec<_>>();
+        let mut client = ptr::null_mut();
+        let ret = unsafe { wstunnel_client_start(argv.as_ptr(), argv.len(), &mut client) };
+        (ret, client)
+    }
+
+    #[test]
+    fn test_client_lifecycle() {
+        let server_runtime = Runtime::new().unwrap();
+        let server = server_runtime.block_on(LocalServer::start("ws", [])).unwrap();
+
+        let (ret, client) = start(&[server.url.as_str(), "--no-such-option"]);
+        assert_eq!(ret, Fatal::InvalidConfig.exit_code());
+        assert!(client.is_null());
+
+        let (ret, client) = start(&[server.url.as_str()]);
+        assert_eq!(ret, 0);
+        let add_tunnel = |tunnel: &str| {
+            let tunnel = CString::new(tunnel).unwrap();
+            unsafe { wstunnel_client_add_tunnel(client, tunnel.as_ptr()) }
+        };
+        assert_eq!(add_tunnel("tcp://127.0.0.1:0:127.0.0.1:9"), 0);
+        assert_eq!(add_tunnel("stdio://127.0.0.1:9"), Fatal::InvalidConfig.exit_code());
+        assert_eq!(add_tunnel("tcp://noport:127.0.0.1:9"), Fatal::InvalidConfig.exit_code());
+        unsafe { wstunnel_client_stop(client) };
+    }
+}
@@ -0,0 +1,141 @@
+use anyhow::anyhow;
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Display;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+// Number of events kept per tunnel, only set when the recorder is enabled
+static CAPACITY: OnceCell<usize> = OnceCell::new();
+// Recorders of the live tunnels, to dump them all on demand
+static LIVE: Lazy<Mutex<HashMap<String, Weak<FlightRecorder>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Keep the last `capacity` events of each tunnel in memory, and log them once a tunnel fails.
+/// With dump_on_signal, the events of all live tunnels are logged on SIGUSR2
+pub fn init(capacity: usize, dump_on_signal: bool) -> anyhow::Result<()> {
+    if capacity == 0 {
+        return Err(anyhow!("flight recorder must keep at least one event per tunnel"));
+    }
+    if CAPACITY.set(capacity).is_err() {
+        return Err(anyhow!("flight recorder is already initialized"));
+    }
+    if dump_on_signal {
+        dump_on_sigusr2()?;
+    }
+
+    info!("Keeping the last {} events of each tunnel, logged if it fails", capacity);
+    Ok(())
+}
+
+#[cfg(unix)]
+fn dump_on_sigusr2() -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigusr2 = signal(SignalKind::user_defined2())?;
+    tokio::spawn(async move {
+        while sigusr2.recv().await.is_some() {
+            dump_all();
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn dump_on_sigusr2() -> anyhow::Result<()> {
+    warn!("Flight recorder can only be dumped on SIGUSR2 on unix");
+    Ok(())
+}
+
+fn dump_all() {
+    let recorders: Vec<_> = LIVE.lock().values().filter_map(Weak::upgrade).collect();
+    info!("Dumping the flight recorder of {} live tunnels", recorders.len());
+    for recorder in recorders {
+        recorder.dump("on SIGUSR2");
+    }
+}
+
+/// Last events of a tunnel: state changes, ping round trip times and errors.
+/// They are only logged when something goes wrong, without running at trace level permanently
+pub struct FlightRecorder {
+    tunnel_id: String,
+    started_at: Instant,
+    events: Mutex<VecDeque<(Duration, String)>>,
+}
+
+impl FlightRecorder {
+    /// Return None if the flight recorder is not enabled, to not pay for it
+    pub fn new(tunnel_id: &str) -> Option<Arc<Self>> {
+        let capacity = *CAPACITY.get()?;
+        let recorder = Arc::new(Self {
+            tunnel_id: tunnel_id.to_string(),
+            started_at: Instant::now(),
+            events: Mutex::new(VecDeque::with_capacity(capacity)),
+        });
+        LIVE.lock().insert(tunnel_id.to_string(), Arc::downgrade(&recorder));
+        Some(recorder)
+    }
+
+    pub fn record(&self, event: impl Display) {
+        let capacity = CAPACITY.get().copied().unwrap_or(1);
+        let mut events = self.events.lock();
+        while events.len() >= capacity {
+            events.pop_front();
+        }
+        events.push_back((self.started_at.elapsed(), event.to_string()));
+    }
+
+    pub fn dump(&self, reason: &str) {
+        let events = self.events.lock();
+        warn!(
+            "Flight recorder of tunnel {} {}, last {} events:",
+            self.tunnel_id,
+            reason,
+            events.len()
+        );
+        for (at, event) in events.iter() {
+            warn!("  {} +{:?} {}", self.tunnel_id, at, event);
+        }
+    }
+}
+
+impl Drop for FlightRecorder {
+    fn drop(&mut self) {
+        // A resumed tunnel registers a new recorder under the same id, keep it
+        let mut live = LIVE.lock();
+        if live
+            .get(&self.tunnel_id)
+            .is_some_and(|recorder| recorder.strong_count() == 0)
+        {
+            live.remove(&self.tunnel_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flight_recorder() {
+        let _ = CAPACITY.set(3);
+        let recorder = FlightRecorder::new("tunnel-1").unwrap();
+        for rtt in 1..=5 {
+            recorder.record(format_args!("ping round trip time {}ms", rtt));
+        }
+        let events: Vec<_> = recorder.events.lock().iter().map(|(_, e)| e.clone()).collect();
+        assert_eq!(
+            events,
+            [
+                "ping round trip time 3ms",
+                "ping round trip time 4ms",
+                "ping round trip time 5ms"
+            ]
+        );
+
+        assert!(LIVE.lock().contains_key("tunnel-1"));
+        drop(recorder);
+        assert!(!LIVE.lock().contains_key("tunnel-1"));
+    }
+}
@@ -0,0 +1,116 @@
+use anyhow::Context;
+use maxminddb::geoip2;
+use std::fmt::{Debug, Formatter};
+use std::net::IpAddr;
+use std::path::Path;
+
+enum Countries {
+    Database(maxminddb::Reader<Vec<u8>>),
+    // Country of each ip, for tests not to need a MaxMind database
+    #[cfg(test)]
+    Static(std::collections::HashMap<IpAddr, String>),
+}
+
+/// Accept or reject clients depending on the country of their ip, looked up in a MaxMind database (i.e: GeoLite2-Country.mmdb)
+pub struct GeoIp {
+    countries: Countries,
+    allow_countries: Vec<String>,
+    deny_countries: Vec<String>,
+}
+
+impl Debug for GeoIp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let database = match &self.countries {
+            Countries::Database(reader) => reader.metadata.database_type.as_str(),
+            #[cfg(test)]
+            Countries::Static(_) => "static",
+        };
+        f.debug_struct("GeoIp")
+            .field("database", &database)
+            .field("allow_countries", &self.allow_countries)
+            .field("deny_countries", &self.deny_countries)
+            .finish()
+    }
+}
+
+impl GeoIp {
+    pub fn new(database: &Path, allow_countries: Vec<String>, deny_countries: Vec<String>) -> anyhow::Result<Self> {
+        let reader = maxminddb::Reader::open_readfile(database)
+            .with_context(|| format!("Cannot load geoip database {}", database.display()))?;
+        Ok(Self::with_countries(
+            Countries::Database(reader),
+            allow_countries,
+            deny_countries,
+        ))
+    }
+
+    #[cfg(test)]
+    pub fn from_countries(
+        countries: impl IntoIterator<Item = (IpAddr, &'static str)>,
+        allow_countries: Vec<String>,
+        deny_countries: Vec<String>,
+    ) -> Self {
+        let countries = countries.into_iter().map(|(ip, c)| (ip, c.to_string())).collect();
+        Self::with_countries(Countries::Static(countries), allow_countries, deny_countries)
+    }
+
+    fn with_countries(countries: Countries, allow_countries: Vec<String>, deny_countries: Vec<String>) -> Self {
+        let normalize = |countries: Vec<String>| countries.into_iter().map(|c| c.trim().to_uppercase()).collect();
+        Self {
+            countries,
+            allow_countries: normalize(allow_countries),
+            deny_countries: normalize(deny_countries),
+        }
+    }
+
+    /// ISO 3166-1 code of the country of the ip, if known
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        match &self.countries {
+            Countries::Database(reader) => {
+                let country: geoip2::Country = reader.lookup(ip).ok()?;
+                country.country?.iso_code.map(str::to_string)
+            }
+            #[cfg(test)]
+            Countries::Static(countries) => countries.get(&ip).cloned(),
+        }
+    }
+
+    /// Ipv4 mapped addresses must be converted back to ipv4 beforehand, they are not found in the database
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        let country = self.country(ip);
+        if let Some(country) = &country {
+            if self.deny_countries.contains(country) {
+                return false;
+            }
+        }
+
+        // Ips without a known country are refused if only some countries are allowed
+        self.allow_countries.is_empty() || matches!(&country, Some(country) if self.allow_countries.contains(country))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_is_allowed() {
+        let (fr, us, unknown) = (
+            IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)),
+            IpAddr::V4(Ipv4Addr::new(2, 2, 2, 2)),
+            IpAddr::V4(Ipv4Addr::new(3, 3, 3, 3)),
+        );
+        let countries = [(fr, "FR"), (us, "US")];
+
+        let geoip = GeoIp::from_countries(countries, vec![], vec!["fr".to_string()]);
+        assert!(!geoip.is_allowed(fr));
+        assert!(geoip.is_allowed(us));
+        assert!(geoip.is_allowed(unknown));
+
+        let geoip = GeoIp::from_countries(countries, vec!["FR".to_string()], vec![]);
+        assert!(geoip.is_allowed(fr));
+        assert!(!geoip.is_allowed(us));
+        assert!(!geoip.is_allowed(unknown));
+    }
+}
@@ -0,0 +1,148 @@
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+
+// Listening socket inherited from the previous server process
+#[cfg(unix)]
+const LISTEN_FD_ENV: &str = "WSTUNNEL_HANDOVER_LISTEN_FD";
+// Socket to tell the previous server process that this one serves
+#[cfg(unix)]
+const READY_FD_ENV: &str = "WSTUNNEL_HANDOVER_READY_FD";
+#[cfg(unix)]
+const READY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Wait for an operator to ask for a hand over to a new server process
+pub struct HandoverSignal {
+    #[cfg(unix)]
+    inner: tokio::signal::unix::Signal,
+}
+
+impl HandoverSignal {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            #[cfg(unix)]
+            inner: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined2())?,
+        })
+    }
+
+    pub async fn recv(&mut self) {
+        #[cfg(unix)]
+        self.inner.recv().await;
+
+        #[cfg(not(unix))]
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Listening socket handed over by the previous server process, if it is bound to `bind`
+#[cfg(unix)]
+pub fn inherited_listener(bind: SocketAddr) -> Option<TcpListener> {
+    use std::os::fd::{FromRawFd, IntoRawFd};
+    use tracing::{info, warn};
+
+    let fd = std::env::var(LISTEN_FD_ENV).ok()?.parse::<i32>().ok()?;
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    match listener.local_addr() {
+        Ok(addr) if addr == bind => {}
+        Ok(addr) => {
+            warn!(
+                "Closing listener on {} handed over by the previous server, not bound to {}",
+                addr, bind
+            );
+            return None;
+        }
+        // Not a socket, the variable does not come from a hand over. Leave the fd alone
+        Err(_) => {
+            let _ = listener.into_raw_fd();
+            return None;
+        }
+    }
+
+    info!("Serving on the listener handed over by the previous server");
+    listener.set_nonblocking(true).ok()?;
+    TcpListener::from_std(listener).ok()
+}
+
+#[cfg(not(unix))]
+pub fn inherited_listener(_bind: SocketAddr) -> Option<TcpListener> {
+    None
+}
+
+/// Let the previous server process know that this one serves, for it to stop accepting connections
+#[cfg(unix)]
+pub fn notify_ready() {
+    use std::io::Write;
+    use std::os::fd::{FromRawFd, IntoRawFd};
+
+    let Some(fd) = std::env::var(READY_FD_ENV).ok().and_then(|fd| fd.parse::<i32>().ok()) else {
+        return;
+    };
+    let mut ready = unsafe { std::os::unix::net::UnixStream::from_raw_fd(fd) };
+    if ready.local_addr().is_err() {
+        let _ = ready.into_raw_fd();
+        return;
+    }
+    let _ = ready.write_all(b"1");
+}
+
+#[cfg(not(unix))]
+pub fn notify_ready() {}
+
+/// Zero downtime upgrade of the server binary: start the binary at the path of this one, with the same arguments,
+/// and hand it the listening socket. Once the new process serves, this one can stop accepting connections and drain
+/// its tunnels, so that no connection is refused and no tunnel is cut
+#[cfg(unix)]
+pub async fn hand_over(listener: &TcpListener) -> anyhow::Result<()> {
+    use anyhow::{anyhow, Context};
+    use nix::libc;
+    use std::os::fd::AsRawFd;
+    use std::os::unix::process::CommandExt;
+    use tokio::io::AsyncReadExt;
+    use tracing::info;
+
+    let exe = std::env::current_exe().context("Cannot find the path of the server binary")?;
+    let (ready_rx, ready_tx) = std::os::unix::net::UnixStream::pair()?;
+    let listen_fd = listener.as_raw_fd();
+    let ready_fd = ready_tx.as_raw_fd();
+
+    info!("Starting {:?} to hand over the listener", exe);
+    let mut command = std::process::Command::new(&exe);
+    command
+        .args(std::env::args_os().skip(1))
+        .env(LISTEN_FD_ENV, listen_fd.to_string())
+        .env(READY_FD_ENV, ready_fd.to_string());
+    // Both sockets are close-on-exec, like every fd opened by the std
+    unsafe {
+        command.pre_exec(move || {
+            for fd in [listen_fd, ready_fd] {
+                if libc::fcntl(fd, libc::F_SETFD, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn().with_context(|| format!("Cannot start {:?}", exe))?;
+    // For the read to end if the new process dies without answering
+    drop(ready_tx);
+
+    ready_rx.set_nonblocking(true)?;
+    let mut ready_rx = tokio::net::UnixStream::from_std(ready_rx)?;
+    let mut ready = [0; 1];
+    let err = match tokio::time::timeout(READY_TIMEOUT, ready_rx.read(&mut ready)).await {
+        Ok(Ok(1)) => return Ok(()),
+        Ok(Ok(_)) => anyhow!("new server process exited: {:?}", child.wait()?),
+        Ok(Err(err)) => anyhow!("cannot wait for the new server process: {}", err),
+        Err(_) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            anyhow!("new server process is not serving after {:?}, killed it", READY_TIMEOUT)
+        }
+    };
+    Err(err)
+}
+
+#[cfg(not(unix))]
+pub async fn hand_over(_listener: &TcpListener) -> anyhow::Result<()> {
+    Err(anyhow::anyhow!("Handing over the listener is only supported on unix"))
+}
@@ -0,0 +1,203 @@
+use crate::fatal::{Fatal, OrFail};
+use crate::tls::{self, TlsCryptoProvider};
+use crate::tunnel::transport::memory::MemoryTransport;
+use crate::{
+    create_client_config, create_server_config, parse_args, tcp, tunnel, Client, Server, WsClientConfig, WsServerConfig,
+};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tracing::error;
+use url::Url;
+
+/// Wstunnel server started in this process on a free loopback port, without going through the binary.
+/// Used by `wstunnel bench` and by tests. The server stops accepting connections once dropped
+pub struct LocalServer {
+    pub url: Url,
+    pub bind: SocketAddr,
+    config: Arc<WsServerConfig>,
+    task: JoinHandle<()>,
+}
+
+impl LocalServer {
+    /// Start a server with the given url scheme, one of ws, wss, http or https, and the extra arguments of `wstunnel server`.
+    /// An invalid configuration is returned as an error, with the kind of failure the binary would exit with
+    pub async fn start(scheme: &str, args: impl IntoIterator<Item = String>) -> anyhow::Result<Self> {
+        Self::start_with(scheme, args, |_| {}).await
+    }
+
+    /// Same as `start`, with `configure` to change what cannot be set from the command line, like an auth hook
+    pub async fn start_with(
+        scheme: &str,
+        args: impl IntoIterator<Item = String>,
+        configure: impl FnOnce(&mut WsServerConfig),
+    ) -> anyhow::Result<Self> {
+        // Already installed when started by the binary, tests have no main to do it
+        let _ = tls::install_crypto_provider(TlsCryptoProvider::default());
+
+        // Bound before the config is built, so no other process can take the port in between
+        let listener = tcp::bind_listener(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)), false)
+            .or_fail(Fatal::BindFailed, "Cannot bind a local port for the server")?;
+        let bind = listener
+            .local_addr()
+            .or_fail(Fatal::BindFailed, "Cannot get the local port of the server")?;
+        let url = Url::parse(&format!("{}://{}", scheme, bind)).or_fail(Fatal::InvalidConfig, "Invalid scheme")?;
+
+        let args: Server = parse_args(["wstunnel".to_string(), url.to_string()].into_iter().chain(args))?;
+        let mut server_config = create_server_config(args).await?;
+        configure(&mut server_config);
+        server_config.bind = bind;
+        let config = Arc::new(server_config);
+        let task = tokio::spawn({
+            let config = config.clone();
+            async move {
+                if let Err(err) = tunnel::server::serve(config, listener).await {
+                    error!("Local server stopped: {:?}", err);
+                }
+            }
+        });
+
+        Ok(Self {
+            url,
+            bind,
+            config,
+            task,
+        })
+    }
+
+    /// Config of a client of this server, with the extra arguments of `wstunnel client`
+    pub async fn client(&self, args: impl IntoIterator<Item = String>) -> anyhow::Result<Arc<WsClientConfig>> {
+        let args: Client = parse_args(["wstunnel".to_string(), self.url.to_string()].into_iter().chain(args))?;
+        create_client_config(&args).await
+    }
+
+    /// Transport reaching this server without the network, to set in WsClientConfig::transport.
+    /// Its tunnels keep being served after `shutdown`, until the transport and all its clones are dropped
+    pub fn memory_transport(&self) -> MemoryTransport {
+        let (transport, connections) = MemoryTransport::new();
+        tokio::spawn(tunnel::server::serve_memory(self.config.clone(), connections));
+        transport
+    }
+
+    /// Stop accepting connections, and wait for the listener to be closed. Tunnels already opened keep running
+    pub async fn shutdown(mut self) {
+        self.task.abort();
+        let _ = (&mut self.task).await;
+    }
+}
+
+impl Drop for LocalServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::transport::io::TunnelPriority;
+    use crate::tunnel::RemoteAddr;
+    use crate::LocalProtocol;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use url::Host;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_local_server() {
+        let server = LocalServer::start("ws", ["--restrict-to=127.0.0.1:9".to_string()])
+            .await
+            .unwrap();
+        let client = server.client([]).await.unwrap();
+
+        let allowed = RemoteAddr {
+            protocol: LocalProtocol::Udp { timeout: None },
+            host: Host::Ipv4(Ipv4Addr::LOCALHOST),
+            port: 9,
+            fallbacks: vec![],
+        };
+        assert!(tunnel::client::healthcheck(&client, &allowed, Duration::from_secs(10))
+            .await
+            .is_ok());
+        let denied = RemoteAddr { port: 10, ..allowed };
+        assert!(tunnel::client::healthcheck(&client, &denied, Duration::from_secs(10))
+            .await
+            .is_err());
+
+        let bind = server.bind;
+        server.shutdown().await;
+        assert!(tokio::net::TcpStream::connect(bind).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_local_server_invalid_config() {
+        let err = LocalServer::start("ws", ["--restrict-config=/nonexistent/restrictions.yaml".to_string()])
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(Fatal::from_error(&err), Fatal::InvalidConfig);
+
+        let err = LocalServer::start("ws", ["--no-such-option".to_string()])
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(Fatal::from_error(&err), Fatal::InvalidConfig);
+    }
+
+    #[tokio::test]
+    async fn test_memory_transport() {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let (mut rx, mut tx) = stream.split();
+            let _ = tokio::io::copy(&mut rx, &mut tx).await;
+        });
+
+        let server = LocalServer::start("ws", [format!("--restrict-to=127.0.0.1:{}", echo_port)])
+            .await
+            .unwrap();
+        let mut client = WsClientConfig::clone(&server.client([]).await.unwrap());
+        client.transport = Some(Arc::new(server.memory_transport()));
+        // Only the in-memory transport can reach the server from now on
+        server.shutdown().await;
+
+        let remote = RemoteAddr {
+            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            host: Host::Ipv4(Ipv4Addr::LOCALHOST),
+            port: echo_port,
+            fallbacks: vec![],
+        };
+        let (local, tunnel_end) = tokio::io::duplex(1024);
+        let tunnel = tokio::spawn(async move {
+            tunnel::client::connect_to_server(
+                Uuid::now_v7(),
+                &client,
+                &remote,
+                TunnelPriority::default(),
+                None,
+                None,
+                None,
+                tokio::io::split(tunnel_end),
+            )
+            .await
+        });
+
+        let (mut rx, mut tx) = tokio::io::split(local);
+        tx.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        tokio::time::timeout(Duration::from_secs(10), rx.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+
+        drop((rx, tx));
+        assert!(tokio::time::timeout(Duration::from_secs(10), tunnel)
+            .await
+            .unwrap()
+            .unwrap()
+            .is_ok());
+    }
+}
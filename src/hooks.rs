@@ -0,0 +1,113 @@
+use once_cell::sync::OnceCell;
+use std::net::IpAddr;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+static HOOKS: OnceCell<Hooks> = OnceCell::new();
+
+struct Hooks {
+    on_connect: Option<String>,
+    on_disconnect: Option<String>,
+}
+
+/// Run these shell commands each time a tunnel is opened/closed, with the metadata of the tunnel in env variables
+pub fn init(on_connect: Option<String>, on_disconnect: Option<String>) -> anyhow::Result<()> {
+    if on_connect.is_none() && on_disconnect.is_none() {
+        return Ok(());
+    }
+
+    if HOOKS
+        .set(Hooks {
+            on_connect,
+            on_disconnect,
+        })
+        .is_err()
+    {
+        return Err(anyhow::anyhow!("hooks are already initialized"));
+    }
+
+    Ok(())
+}
+
+/// Metadata of a tunnel, given to the hook commands
+pub struct TunnelHook {
+    id: String,
+    protocol: &'static str,
+    remote: String,
+    peer: Option<IpAddr>,
+}
+
+impl TunnelHook {
+    /// Return None if no hook is configured, to not pay for it
+    pub fn new(id: &str, protocol: &'static str, remote: String, peer: Option<IpAddr>) -> Option<Self> {
+        HOOKS.get()?;
+        Some(Self {
+            id: id.to_string(),
+            protocol,
+            remote,
+            peer,
+        })
+    }
+
+    fn envs(&self, event: &str) -> Vec<(&'static str, String)> {
+        let mut envs = vec![
+            ("WSTUNNEL_EVENT", event.to_string()),
+            ("WSTUNNEL_TUNNEL_ID", self.id.clone()),
+            ("WSTUNNEL_PROTOCOL", self.protocol.to_string()),
+            ("WSTUNNEL_REMOTE", self.remote.clone()),
+        ];
+        if let Some(peer) = self.peer {
+            envs.push(("WSTUNNEL_PEER_IP", peer.to_string()));
+        }
+        envs
+    }
+
+    pub fn connected(&self) {
+        if let Some(cmd) = HOOKS.get().and_then(|hooks| hooks.on_connect.as_ref()) {
+            run(cmd, self.envs("connect"));
+        }
+    }
+
+    pub fn disconnected(&self, bytes_tx: u64, bytes_rx: u64, duration: Duration) {
+        if let Some(cmd) = HOOKS.get().and_then(|hooks| hooks.on_disconnect.as_ref()) {
+            let mut envs = self.envs("disconnect");
+            envs.push(("WSTUNNEL_BYTES_TX", bytes_tx.to_string()));
+            envs.push(("WSTUNNEL_BYTES_RX", bytes_rx.to_string()));
+            envs.push(("WSTUNNEL_DURATION_MS", duration.as_millis().to_string()));
+            run(cmd, envs);
+        }
+    }
+}
+
+fn run(cmd: &str, envs: Vec<(&'static str, String)>) {
+    let mut command = if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(cmd);
+        command
+    } else {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(cmd);
+        command
+    };
+
+    // stdout is not inherited, as it may be used by a stdio tunnel
+    command.envs(envs).stdin(Stdio::null()).stdout(Stdio::null());
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(err) => {
+            warn!("Cannot run hook command {}: {}", cmd, err);
+            return;
+        }
+    };
+
+    let cmd = cmd.to_string();
+    tokio::spawn(async move {
+        match child.wait().await {
+            Ok(status) if status.success() => debug!("Hook command {} succeeded", cmd),
+            Ok(status) => warn!("Hook command {} failed with {}", cmd, status),
+            Err(err) => warn!("Cannot wait for hook command {}: {}", cmd, err),
+        }
+    });
+}
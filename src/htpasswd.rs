@@ -0,0 +1,243 @@
+use ahash::{HashMap, HashMapExt};
+use anyhow::{anyhow, Context};
+use base64::Engine;
+use md5::{Digest, Md5};
+use parking_lot::Mutex;
+use std::fmt::{Debug, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tracing::{info, warn};
+
+const APR1_MAGIC: &str = "$apr1$";
+const APR1_ITOA64: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+struct HtpasswdState {
+    // user => password hash
+    users: HashMap<String, String>,
+    modified: Option<SystemTime>,
+}
+
+/// Users and password hashes loaded from an Apache htpasswd file.
+/// Only bcrypt ($2y$) and apr1 ($apr1$) hashes are supported, as created by `htpasswd -B` and `htpasswd -m`.
+/// The file is reloaded when it changes, so users can be managed without restarting the server.
+#[derive(Clone)]
+pub struct Htpasswd {
+    path: PathBuf,
+    state: Arc<Mutex<HtpasswdState>>,
+}
+
+impl Debug for Htpasswd {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Htpasswd").field(&self.path).finish()
+    }
+}
+
+impl Htpasswd {
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let (users, modified) = load_htpasswd(path)?;
+        info!("Loaded {} users from htpasswd file {:?}", users.len(), path);
+        Ok(Self {
+            path: path.to_path_buf(),
+            state: Arc::new(Mutex::new(HtpasswdState { users, modified })),
+        })
+    }
+
+    fn reload_if_modified(&self) {
+        let modified = std::fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        let mut state = self.state.lock();
+        if modified.is_none() || modified == state.modified {
+            return;
+        }
+
+        match load_htpasswd(&self.path) {
+            Ok((users, modified)) => {
+                info!("Reloaded {} users from htpasswd file {:?}", users.len(), self.path);
+                *state = HtpasswdState { users, modified };
+            }
+            Err(err) => warn!("Cannot reload htpasswd file {:?}, keeping previous users: {:?}", self.path, err),
+        }
+    }
+
+    /// Verify the value of a basic `Authorization` header, and return the user if the password matches.
+    /// Hashing is slow on purpose, so this should not be called directly from the async runtime
+    pub fn verify(&self, authorization: &str) -> Option<String> {
+        let credentials = authorization.trim().strip_prefix("Basic ")?;
+        let credentials = base64::engine::general_purpose::STANDARD
+            .decode(credentials.trim())
+            .ok()?;
+        let credentials = String::from_utf8(credentials).ok()?;
+        let (user, password) = credentials.split_once(':').unwrap_or((&credentials, ""));
+
+        self.reload_if_modified();
+        let (hash, is_known_user) = {
+            let state = self.state.lock();
+            match state.users.get(user) {
+                Some(hash) => (hash.clone(), true),
+                // Still hash the password of an unknown user, against the hash of another user of the file to have the same
+                // scheme and cost, for the response time not to tell which users exist
+                None => (state.users.values().next()?.clone(), false),
+            }
+        };
+        if verify_password(password, &hash) && is_known_user {
+            Some(user.to_string())
+        } else {
+            None
+        }
+    }
+}
+
+fn load_htpasswd(path: &Path) -> anyhow::Result<(HashMap<String, String>, Option<SystemTime>)> {
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let content = std::fs::read_to_string(path).with_context(|| format!("Cannot read htpasswd file {:?}", path))?;
+
+    let mut users = HashMap::with_capacity(0);
+    for (ix, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let Some((user, hash)) = line.split_once(':') else {
+            return Err(anyhow!("Invalid entry at line {} of htpasswd file {:?}", ix + 1, path));
+        };
+        if !hash.starts_with("$2") && !hash.starts_with(APR1_MAGIC) {
+            warn!(
+                "Ignoring user {} of htpasswd file {:?}, only bcrypt and apr1 hashes are supported",
+                user, path
+            );
+            continue;
+        }
+        users.insert(user.to_string(), hash.to_string());
+    }
+
+    Ok((users, modified))
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    if let Some(salt_and_hash) = hash.strip_prefix(APR1_MAGIC) {
+        let salt = salt_and_hash.split('$').next().unwrap_or_default();
+        return constant_time_eq(apr1_crypt(password.as_bytes(), salt.as_bytes()).as_bytes(), hash.as_bytes());
+    }
+
+    bcrypt::verify(password, hash).unwrap_or(false)
+}
+
+/// Compare secrets without stopping at the first difference, to not leak through timing how much of it matched.
+/// Only the length can leak, which is not secret for hashes or tokens
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Apache variant of the md5 crypt algorithm
+fn apr1_crypt(password: &[u8], salt: &[u8]) -> String {
+    let salt = &salt[..salt.len().min(8)];
+
+    let alternate = Md5::new()
+        .chain_update(password)
+        .chain_update(salt)
+        .chain_update(password)
+        .finalize();
+
+    let mut ctx = Md5::new();
+    ctx.update(password);
+    ctx.update(APR1_MAGIC.as_bytes());
+    ctx.update(salt);
+    for chunk in password.chunks(16) {
+        ctx.update(&alternate[..chunk.len()]);
+    }
+    let mut i = password.len();
+    while i > 0 {
+        if i & 1 == 1 {
+            ctx.update([0u8]);
+        } else {
+            ctx.update(&password[..1]);
+        }
+        i >>= 1;
+    }
+    let mut digest = ctx.finalize();
+
+    // Stretch the hash to make brute forcing slower
+    for i in 0..1000 {
+        let mut ctx = Md5::new();
+        if i & 1 == 1 {
+            ctx.update(password);
+        } else {
+            ctx.update(digest);
+        }
+        if i % 3 != 0 {
+            ctx.update(salt);
+        }
+        if i % 7 != 0 {
+            ctx.update(password);
+        }
+        if i & 1 == 1 {
+            ctx.update(digest);
+        } else {
+            ctx.update(password);
+        }
+        digest = ctx.finalize();
+    }
+
+    let mut out = String::with_capacity(APR1_MAGIC.len() + salt.len() + 23);
+    out.push_str(APR1_MAGIC);
+    out.push_str(&String::from_utf8_lossy(salt));
+    out.push('$');
+    for (a, b, c) in [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+        let v = (digest[a] as u32) << 16 | (digest[b] as u32) << 8 | digest[c] as u32;
+        push_base64(&mut out, v, 4);
+    }
+    push_base64(&mut out, digest[11] as u32, 2);
+
+    out
+}
+
+fn push_base64(out: &mut String, mut v: u32, len: usize) {
+    for _ in 0..len {
+        out.push(APR1_ITOA64[(v & 0x3f) as usize] as char);
+        v >>= 6;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apr1_crypt() {
+        assert_eq!(apr1_crypt(b"myPassword", b"r31....."), "$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/");
+        assert_eq!(apr1_crypt(b"hello world", b"abcdefgh"), "$apr1$abcdefgh$CZx3qOBL3IJw7t4yUwt4J.");
+    }
+
+    #[test]
+    fn test_verify_password() {
+        assert!(verify_password("myPassword", "$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/"));
+        assert!(!verify_password("notMyPassword", "$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/"));
+        assert!(!verify_password("myPassword", "$apr1$r31.....$HqJZimcKQFAMYayBlzkrA"));
+
+        let hash = bcrypt::hash("myPassword", 4).unwrap();
+        assert!(verify_password("myPassword", &hash));
+        assert!(!verify_password("notMyPassword", &hash));
+    }
+
+    #[test]
+    fn test_verify_unknown_user() {
+        let mut users = HashMap::new();
+        users.insert("alice".to_string(), "$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/".to_string());
+        let htpasswd = Htpasswd {
+            path: PathBuf::from("/nonexistent/htpasswd"),
+            state: Arc::new(Mutex::new(HtpasswdState { users, modified: None })),
+        };
+        let basic =
+            |credentials: &str| format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(credentials));
+
+        assert_eq!(htpasswd.verify(&basic("alice:myPassword")), Some("alice".to_string()));
+        // The password of an unknown user is checked against the hash of alice, but never accepted
+        assert_eq!(htpasswd.verify(&basic("bob:myPassword")), None);
+        assert_eq!(htpasswd.verify(&basic("bob:notMyPassword")), None);
+    }
+}
@@ -0,0 +1,104 @@
+use crate::dns::DnsResolver;
+use crate::tls::TlsFingerprint;
+use crate::tunnel::TransportStream;
+use crate::{tcp, tls};
+use anyhow::{anyhow, Context};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::header::{CONTENT_TYPE, HOST, USER_AGENT};
+use hyper::{Method, Request};
+use hyper_util::rt::TokioIo;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tracing::debug;
+use url::{Host, Position, Url};
+
+/// Minimal HTTP/1.1 client used to fetch small documents (i.e: jwks) from http(s) urls
+pub async fn get(url: &Url, dns_resolver: &DnsResolver, timeout: Duration) -> anyhow::Result<Bytes> {
+    request(Method::GET, url, None, dns_resolver, timeout).await
+}
+
+/// Send a JSON document (i.e: webhook event) to an http(s) url, and return the body of the response
+pub async fn post_json(url: &Url, body: Bytes, dns_resolver: &DnsResolver, timeout: Duration) -> anyhow::Result<Bytes> {
+    request(Method::POST, url, Some(("application/json", body)), dns_resolver, timeout).await
+}
+
+async fn request(
+    method: Method,
+    url: &Url,
+    body: Option<(&'static str, Bytes)>,
+    dns_resolver: &DnsResolver,
+    timeout: Duration,
+) -> anyhow::Result<Bytes> {
+    let host = url
+        .host()
+        .with_context(|| format!("missing host in url {}", url))?
+        .to_owned();
+    let port = url
+        .port_or_known_default()
+        .with_context(|| format!("missing port in url {}", url))?;
+
+    let tcp_stream = tcp::connect(&host, port, None, timeout, dns_resolver).await?;
+    let stream = match url.scheme() {
+        "http" => TransportStream::Plain(tcp_stream),
+        "https" => {
+            let tls_connector =
+                tls::tls_connector(true, Some(vec![b"http/1.1".to_vec()]), true, TlsFingerprint::Rustls, None)?;
+            let server_name = match &host {
+                Host::Domain(domain) => ServerName::try_from(domain.clone())
+                    .with_context(|| format!("invalid domain name for tls {}", domain))?,
+                Host::Ipv4(ip) => ServerName::from(IpAddr::V4(*ip)),
+                Host::Ipv6(ip) => ServerName::from(IpAddr::V6(*ip)),
+            };
+            let tls_stream = tls_connector
+                .connect(server_name, tcp_stream)
+                .await
+                .with_context(|| format!("failed to do TLS handshake with {}", url))?;
+            TransportStream::Tls(tls_stream)
+        }
+        scheme => return Err(anyhow!("unsupported scheme {} for url {}", scheme, url)),
+    };
+
+    let (mut request_sender, cnx) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .with_context(|| format!("failed to do http handshake with {}", url))?;
+    tokio::spawn(async move {
+        if let Err(err) = cnx.await {
+            debug!("http client connection closed with error {:?}", err);
+        }
+    });
+
+    let host_header = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    let mut req = Request::builder()
+        .method(method)
+        .uri(&url[Position::BeforePath..])
+        .header(HOST, host_header)
+        .header(USER_AGENT, concat!("wstunnel/", env!("CARGO_PKG_VERSION")));
+    let body = match body {
+        Some((content_type, body)) => {
+            req = req.header(CONTENT_TYPE, content_type);
+            body
+        }
+        None => Bytes::new(),
+    };
+    let req = req
+        .body(Full::new(body))
+        .with_context(|| format!("failed to build http request for {}", url))?;
+
+    let response = tokio::time::timeout(timeout, request_sender.send_request(req))
+        .await
+        .with_context(|| format!("timeout while requesting {}", url))??;
+    if !response.status().is_success() {
+        return Err(anyhow!("{} returned status {}", url, response.status()));
+    }
+
+    let body = tokio::time::timeout(timeout, response.into_body().collect())
+        .await
+        .with_context(|| format!("timeout while reading response of {}", url))??;
+
+    Ok(body.to_bytes())
+}
@@ -0,0 +1,190 @@
+use crate::dns::DnsResolver;
+use crate::http_client;
+use anyhow::{anyhow, Context};
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm};
+use jsonwebtoken::{Algorithm, DecodingKey, TokenData, Validation};
+use parking_lot::{Mutex, RwLock};
+use serde::Deserialize;
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+use url::Url;
+
+// Keys are refreshed periodically to follow key rotations of the identity provider
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(3600);
+// When a token is signed with an unknown key, we refresh the jwks but not more often than this
+const JWKS_MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+const JWKS_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Deserialize)]
+pub struct JwksClaims {
+    pub sub: Option<String>,
+}
+
+pub struct JwksValidator {
+    audience: Option<String>,
+    jwks: Arc<Jwks>,
+}
+
+// Keys of the jwks, shared with the task refreshing them in the background
+struct Jwks {
+    url: Url,
+    dns_resolver: DnsResolver,
+    keys: RwLock<JwkSet>,
+    last_refresh: Mutex<Instant>,
+}
+
+impl Debug for JwksValidator {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JwksValidator")
+            .field("url", &self.jwks.url.as_str())
+            .field("audience", &self.audience)
+            .finish()
+    }
+}
+
+impl Jwks {
+    async fn refresh(&self, min_interval: Duration) {
+        {
+            let mut last_refresh = self.last_refresh.lock();
+            if last_refresh.elapsed() < min_interval {
+                return;
+            }
+            // Set it before fetching to avoid concurrent refreshes
+            *last_refresh = Instant::now();
+        }
+
+        match fetch_jwks(&self.url, &self.dns_resolver).await {
+            Ok(keys) => *self.keys.write() = keys,
+            Err(err) => warn!("Cannot refresh jwks from {}: {:?}", self.url, err),
+        }
+    }
+
+    // Refresh periodically until the validator is dropped
+    async fn refresh_periodically(jwks: Weak<Self>) {
+        loop {
+            tokio::time::sleep(JWKS_REFRESH_INTERVAL).await;
+            let Some(jwks) = jwks.upgrade() else {
+                return;
+            };
+            jwks.refresh(JWKS_REFRESH_INTERVAL).await;
+        }
+    }
+
+    fn decoding_key(&self, kid: Option<&str>) -> Option<(DecodingKey, Vec<Algorithm>)> {
+        let keys = self.keys.read();
+        let jwk = match kid {
+            Some(kid) => keys.find(kid)?,
+            // Without key id, we can only pick the key if there is no ambiguity
+            None if keys.keys.len() == 1 => &keys.keys[0],
+            None => return None,
+        };
+
+        Some((DecodingKey::from_jwk(jwk).ok()?, key_algorithms(jwk)?))
+    }
+}
+
+/// Algorithms a key can verify, from the alg of the jwk or else its key type.
+/// The alg of the token header is chosen by the client, so it must never be trusted to pick how to verify it
+fn key_algorithms(jwk: &Jwk) -> Option<Vec<Algorithm>> {
+    let algorithms = match (jwk.common.key_algorithm, &jwk.algorithm) {
+        (Some(KeyAlgorithm::RS256), AlgorithmParameters::RSA(_)) => vec![Algorithm::RS256],
+        (Some(KeyAlgorithm::RS384), AlgorithmParameters::RSA(_)) => vec![Algorithm::RS384],
+        (Some(KeyAlgorithm::RS512), AlgorithmParameters::RSA(_)) => vec![Algorithm::RS512],
+        (Some(KeyAlgorithm::PS256), AlgorithmParameters::RSA(_)) => vec![Algorithm::PS256],
+        (Some(KeyAlgorithm::PS384), AlgorithmParameters::RSA(_)) => vec![Algorithm::PS384],
+        (Some(KeyAlgorithm::PS512), AlgorithmParameters::RSA(_)) => vec![Algorithm::PS512],
+        (Some(KeyAlgorithm::ES256), AlgorithmParameters::EllipticCurve(_)) => vec![Algorithm::ES256],
+        (Some(KeyAlgorithm::ES384), AlgorithmParameters::EllipticCurve(_)) => vec![Algorithm::ES384],
+        (Some(KeyAlgorithm::EdDSA), AlgorithmParameters::OctetKeyPair(_)) => vec![Algorithm::EdDSA],
+        (None, AlgorithmParameters::RSA(_)) => vec![
+            Algorithm::RS256,
+            Algorithm::RS384,
+            Algorithm::RS512,
+            Algorithm::PS256,
+            Algorithm::PS384,
+            Algorithm::PS512,
+        ],
+        (None, AlgorithmParameters::EllipticCurve(params)) => match params.curve {
+            EllipticCurve::P256 => vec![Algorithm::ES256],
+            EllipticCurve::P384 => vec![Algorithm::ES384],
+            _ => return None,
+        },
+        (None, AlgorithmParameters::OctetKeyPair(_)) => vec![Algorithm::EdDSA],
+        // Symmetric keys of a published jwks are not secret, and the alg must match the key type
+        _ => return None,
+    };
+
+    Some(algorithms)
+}
+
+impl JwksValidator {
+    pub async fn new(url: Url, audience: Option<String>, dns_resolver: DnsResolver) -> anyhow::Result<Self> {
+        let keys = fetch_jwks(&url, &dns_resolver).await?;
+        let jwks = Arc::new(Jwks {
+            url,
+            dns_resolver,
+            keys: RwLock::new(keys),
+            last_refresh: Mutex::new(Instant::now()),
+        });
+        tokio::spawn(Jwks::refresh_periodically(Arc::downgrade(&jwks)));
+
+        Ok(Self { audience, jwks })
+    }
+
+    /// Validate signature, expiration and audience of the token against the keys of the jwks
+    pub async fn validate(&self, token: &str) -> anyhow::Result<TokenData<JwksClaims>> {
+        let header = jsonwebtoken::decode_header(token).context("invalid jwt header")?;
+        let Some((decoding_key, algorithms)) = self.jwks.decoding_key(header.kid.as_deref()) else {
+            // The identity provider may have rotated its keys, refresh them for the next requests
+            // without making this one wait for the jwks endpoint
+            let jwks = self.jwks.clone();
+            tokio::spawn(async move { jwks.refresh(JWKS_MIN_REFRESH_INTERVAL).await });
+            return Err(anyhow!("no usable key found in jwks for kid {:?}", header.kid));
+        };
+
+        let mut validation = Validation::new(algorithms[0]);
+        validation.algorithms = algorithms;
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        let token = jsonwebtoken::decode(token, &decoding_key, &validation).context("invalid jwt")?;
+        Ok(token)
+    }
+}
+
+async fn fetch_jwks(url: &Url, dns_resolver: &DnsResolver) -> anyhow::Result<JwkSet> {
+    info!("Fetching jwks from {}", url);
+    let body = http_client::get(url, dns_resolver, JWKS_FETCH_TIMEOUT).await?;
+    let jwks: JwkSet = serde_json::from_slice(&body).with_context(|| format!("invalid jwks returned by {}", url))?;
+    if jwks.keys.is_empty() {
+        return Err(anyhow!("jwks returned by {} does not contain any key", url));
+    }
+
+    Ok(jwks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_algorithms() {
+        let jwk = |json: &str| serde_json::from_str::<Jwk>(json).unwrap();
+        let rsa = r#""kty": "RSA", "n": "sXchDaQebHnPiGvyDOAT4saGEUetSyo9MKLOoWFsueri23bOdgWp4Dy1WlUzewbgBHod5pcM9H95GQRV3JDXboIRROSBigeC5yjU1hGzHHyXss8UDprecbAYxknTcQkhslANGRUZmdTOQ5qTRsLAt6BTYuyvVRdhS8exSZEy_c4gs_7svlJJQ4H9_NxsiIoLwAEk7-Q3UXERGYw_75IDrGA84-lA_-Ct4eTlXHBIY2EaV7t7LjJaynVJCpkv4LKjTTAumiGUIuQhrNhZLuF_RJLqHpM2kgWFLU7-VTdL1VbC2tejvcI2BlMkEpk1BzBZI0KQB0GaDWFLN-aEAw3vRw", "e": "AQAB""#;
+
+        let algorithms = key_algorithms(&jwk(&format!("{{{}}}", rsa))).unwrap();
+        assert!(algorithms.contains(&Algorithm::RS256));
+        assert!(!algorithms.contains(&Algorithm::HS256));
+        assert_eq!(
+            key_algorithms(&jwk(&format!(r#"{{{}, "alg": "PS256"}}"#, rsa))),
+            Some(vec![Algorithm::PS256])
+        );
+        // An alg not matching the key type, or a symmetric key, cannot be used to verify tokens
+        assert_eq!(key_algorithms(&jwk(&format!(r#"{{{}, "alg": "HS256"}}"#, rsa))), None);
+        assert_eq!(key_algorithms(&jwk(r#"{"kty": "oct", "k": "c2VjcmV0"}"#)), None);
+    }
+}
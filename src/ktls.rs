@@ -0,0 +1,538 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, Interest, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+const TLS_RECORD_HEADER_LEN: usize = 5;
+
+/// Tcp stream used for the TLS handshake, that never reads past the end of the current TLS record when kTLS is wanted.
+/// Like that rustls has nothing left in its buffers once the handshake is done, and the kernel can decrypt the next records
+pub struct HandshakeStream {
+    stream: TcpStream,
+    record_aligned: bool,
+    header: [u8; TLS_RECORD_HEADER_LEN],
+    header_len: usize,
+    record_remaining: usize,
+}
+
+impl HandshakeStream {
+    fn new(stream: TcpStream, record_aligned: bool) -> Self {
+        Self {
+            stream,
+            record_aligned,
+            header: [0; TLS_RECORD_HEADER_LEN],
+            header_len: 0,
+            record_remaining: 0,
+        }
+    }
+}
+
+impl AsyncRead for HandshakeStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.record_aligned {
+            return Pin::new(&mut this.stream).poll_read(cx, buf);
+        }
+
+        let limit = if this.header_len < TLS_RECORD_HEADER_LEN {
+            TLS_RECORD_HEADER_LEN - this.header_len
+        } else {
+            this.record_remaining
+        };
+
+        let mut limited = buf.take(limit);
+        ready!(Pin::new(&mut this.stream).poll_read(cx, &mut limited))?;
+        let read = limited.filled().len();
+        if this.header_len < TLS_RECORD_HEADER_LEN {
+            this.header[this.header_len..this.header_len + read].copy_from_slice(limited.filled());
+            this.header_len += read;
+            if this.header_len == TLS_RECORD_HEADER_LEN {
+                this.record_remaining = u16::from_be_bytes([this.header[3], this.header[4]]) as usize;
+            }
+        } else {
+            this.record_remaining -= read;
+        }
+        if this.header_len == TLS_RECORD_HEADER_LEN && this.record_remaining == 0 {
+            this.header_len = 0;
+        }
+
+        // safety: the bytes have just been written by the read into the unfilled part of buf
+        unsafe { buf.assume_init(read) };
+        buf.advance(read);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for HandshakeStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+}
+
+/// Tcp stream whose TLS records are encrypted and decrypted by the kernel, the application sees only plaintext
+pub struct KtlsStream {
+    stream: TcpStream,
+    alpn_protocol: Option<Vec<u8>>,
+    // The peer sent its close_notify alert, nothing more can be read
+    read_closed: bool,
+    close_notify_sent: bool,
+}
+
+impl AsyncRead for KtlsStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.read_closed {
+            return Poll::Ready(Ok(()));
+        }
+
+        match ready!(Pin::new(&mut this.stream).poll_read(cx, buf)) {
+            // The kernel returns EIO for records that are not application data, they can only be read with a control message.
+            // Only the close_notify alert of the peer is the end of the stream, any other record is an error
+            Err(err) if is_control_record(&err) => match this
+                .stream
+                .try_io(Interest::READABLE, || recv_close_notify(&this.stream))
+            {
+                Ok(()) => {
+                    this.read_closed = true;
+                    Poll::Ready(Ok(()))
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+                Err(err) => Poll::Ready(Err(err)),
+            },
+            ret => Poll::Ready(ret),
+        }
+    }
+}
+
+impl AsyncWrite for KtlsStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        // Tell the peer that the stream ends here, and was not truncated by an attacker
+        while !this.close_notify_sent {
+            ready!(this.stream.poll_write_ready(cx))?;
+            match this
+                .stream
+                .try_io(Interest::WRITABLE, || send_close_notify(&this.stream))
+            {
+                Ok(()) => this.close_notify_sent = true,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(target_os = "linux")]
+use linux::{recv_close_notify, send_close_notify};
+
+#[cfg(target_os = "linux")]
+fn is_control_record(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(nix::libc::EIO)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_control_record(_err: &io::Error) -> bool {
+    false
+}
+
+#[cfg(not(target_os = "linux"))]
+fn recv_close_notify(_stream: &TcpStream) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "kTLS is only supported on linux"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_close_notify(_stream: &TcpStream) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "kTLS is only supported on linux"))
+}
+
+/// TLS stream accepted by the server, with its records handled either by rustls or by the kernel
+pub enum ServerTlsStream {
+    Userspace(Box<TlsStream<HandshakeStream>>),
+    Kernel(KtlsStream),
+}
+
+impl ServerTlsStream {
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        match self {
+            ServerTlsStream::Userspace(stream) => stream.get_ref().1.alpn_protocol(),
+            ServerTlsStream::Kernel(stream) => stream.alpn_protocol.as_deref(),
+        }
+    }
+}
+
+impl AsyncRead for ServerTlsStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerTlsStream::Userspace(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+            ServerTlsStream::Kernel(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ServerTlsStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ServerTlsStream::Userspace(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+            ServerTlsStream::Kernel(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerTlsStream::Userspace(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+            ServerTlsStream::Kernel(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerTlsStream::Userspace(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+            ServerTlsStream::Kernel(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Do the TLS handshake, then hand over the records of the connection to the kernel if `ktls` is set and possible.
+/// Connections that cannot be offloaded stay in userspace, with rustls
+pub async fn accept(tls_acceptor: &TlsAcceptor, stream: TcpStream, ktls: bool) -> io::Result<ServerTlsStream> {
+    let mut tls_stream = tls_acceptor.accept(HandshakeStream::new(stream, ktls)).await?;
+    tls_stream.get_mut().0.record_aligned = false;
+
+    if !ktls {
+        return Ok(ServerTlsStream::Userspace(Box::new(tls_stream)));
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        linux::offload(tls_stream)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        Ok(ServerTlsStream::Userspace(Box::new(tls_stream)))
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::{HandshakeStream, KtlsStream, ServerTlsStream};
+    use nix::libc;
+    use std::io;
+    use std::os::fd::AsRawFd;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tokio::net::TcpStream;
+    use tokio_rustls::rustls::crypto::cipher::{AeadKey, Iv};
+    use tokio_rustls::rustls::{CipherSuite, ConnectionTrafficSecrets, ProtocolVersion};
+    use tokio_rustls::server::TlsStream;
+    use tracing::{debug, warn};
+
+    // From linux/tls.h
+    const TLS_TX: libc::c_int = 1;
+    const TLS_RX: libc::c_int = 2;
+    const TLS_SET_RECORD_TYPE: libc::c_int = 1;
+    const TLS_GET_RECORD_TYPE: libc::c_int = 2;
+    const TLS_1_3_VERSION: u16 = 0x0304;
+    const TLS_CIPHER_AES_GCM_128: u16 = 51;
+    const TLS_CIPHER_AES_GCM_256: u16 = 52;
+    const TLS_CIPHER_CHACHA20_POLY1305: u16 = 54;
+
+    const TLS_RECORD_TYPE_ALERT: u8 = 21;
+    const TLS_ALERT_LEVEL_WARNING: u8 = 1;
+    const TLS_ALERT_CLOSE_NOTIFY: u8 = 0;
+
+    // Once the kernel refused kTLS, don't try again for each connection
+    static KTLS_UNAVAILABLE: AtomicBool = AtomicBool::new(false);
+
+    /// Layout of the tls12_crypto_info_* structs of linux/tls.h
+    #[repr(C)]
+    struct CryptoInfo<const IV: usize, const KEY: usize, const SALT: usize> {
+        version: u16,
+        cipher_type: u16,
+        iv: [u8; IV],
+        key: [u8; KEY],
+        salt: [u8; SALT],
+        rec_seq: [u8; 8],
+    }
+
+    fn setsockopt<T>(stream: &impl AsRawFd, level: libc::c_int, name: libc::c_int, value: &T) -> io::Result<()> {
+        let ret = unsafe {
+            libc::setsockopt(
+                stream.as_raw_fd(),
+                level,
+                name,
+                value as *const T as *const libc::c_void,
+                std::mem::size_of::<T>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn crypto_info<const IV: usize, const KEY: usize, const SALT: usize>(
+        cipher_type: u16,
+        key: &AeadKey,
+        iv: &Iv,
+        seq: u64,
+    ) -> io::Result<CryptoInfo<IV, KEY, SALT>> {
+        let invalid_size = |_| io::Error::new(io::ErrorKind::InvalidData, "unexpected size of TLS secrets");
+        // The kernel splits the nonce of the AEAD into an implicit salt and an iv
+        let nonce = iv.as_ref();
+        Ok(CryptoInfo {
+            version: TLS_1_3_VERSION,
+            cipher_type,
+            iv: nonce.get(SALT..).unwrap_or_default().try_into().map_err(invalid_size)?,
+            key: key.as_ref().try_into().map_err(invalid_size)?,
+            salt: nonce.get(..SALT).unwrap_or_default().try_into().map_err(invalid_size)?,
+            rec_seq: seq.to_be_bytes(),
+        })
+    }
+
+    fn set_crypto_info(
+        stream: &impl AsRawFd,
+        direction: libc::c_int,
+        seq: u64,
+        secrets: ConnectionTrafficSecrets,
+    ) -> io::Result<()> {
+        match secrets {
+            ConnectionTrafficSecrets::Aes128Gcm { key, iv } => {
+                let info: CryptoInfo<8, 16, 4> = crypto_info(TLS_CIPHER_AES_GCM_128, &key, &iv, seq)?;
+                setsockopt(stream, libc::SOL_TLS, direction, &info)
+            }
+            ConnectionTrafficSecrets::Aes256Gcm { key, iv } => {
+                let info: CryptoInfo<8, 32, 4> = crypto_info(TLS_CIPHER_AES_GCM_256, &key, &iv, seq)?;
+                setsockopt(stream, libc::SOL_TLS, direction, &info)
+            }
+            ConnectionTrafficSecrets::Chacha20Poly1305 { key, iv } => {
+                let info: CryptoInfo<12, 32, 0> = crypto_info(TLS_CIPHER_CHACHA20_POLY1305, &key, &iv, seq)?;
+                setsockopt(stream, libc::SOL_TLS, direction, &info)
+            }
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, "cipher not supported by kTLS")),
+        }
+    }
+
+    /// Control message buffer, big enough and aligned for a single cmsg carrying the record type
+    type CmsgBuffer = [u64; 4];
+
+    /// Read the record that is not application data, which made the kernel fail the read with EIO.
+    /// Ok if it is the close_notify alert of the peer, an error for any other alert or record like a KeyUpdate
+    pub fn recv_close_notify(stream: &TcpStream) -> io::Result<()> {
+        let mut data = [0u8; 64];
+        let mut iov = libc::iovec {
+            iov_base: data.as_mut_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        let mut cmsg_buffer: CmsgBuffer = [0; 4];
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buffer.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = std::mem::size_of::<CmsgBuffer>() as _;
+
+        let read = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_DONTWAIT) };
+        if read < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        if cmsg.is_null()
+            || unsafe { (*cmsg).cmsg_level } != libc::SOL_TLS
+            || unsafe { (*cmsg).cmsg_type } != TLS_GET_RECORD_TYPE
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "TLS record received without its type",
+            ));
+        }
+        let record_type = unsafe { *libc::CMSG_DATA(cmsg) };
+
+        match (record_type, &data[..read as usize]) {
+            (TLS_RECORD_TYPE_ALERT, [_, TLS_ALERT_CLOSE_NOTIFY]) => Ok(()),
+            (TLS_RECORD_TYPE_ALERT, [_, description]) => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                format!("TLS alert {} received from the peer", description),
+            )),
+            (record_type, _) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected TLS record of type {} once handled by the kernel", record_type),
+            )),
+        }
+    }
+
+    /// Send the close_notify alert, as a record of type alert set with a control message
+    pub fn send_close_notify(stream: &TcpStream) -> io::Result<()> {
+        let data = [TLS_ALERT_LEVEL_WARNING, TLS_ALERT_CLOSE_NOTIFY];
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        let mut cmsg_buffer: CmsgBuffer = [0; 4];
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buffer.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(1) } as _;
+
+        // safety: the control buffer is big enough for a cmsg of 1 byte, so CMSG_FIRSTHDR is not null
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_TLS;
+            (*cmsg).cmsg_type = TLS_SET_RECORD_TYPE;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(1) as _;
+            *libc::CMSG_DATA(cmsg) = TLS_RECORD_TYPE_ALERT;
+        }
+
+        let ret = unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Why the connection must stay in userspace, if it must
+    fn cannot_offload(tls_stream: &mut TlsStream<HandshakeStream>) -> Option<String> {
+        let (_, conn) = tls_stream.get_mut();
+
+        // With TLS 1.2, the kernel does not build the explicit nonces the same way as rustls,
+        // and they could be reused. Only offload TLS 1.3, where the nonce is derived from the record sequence
+        if conn.protocol_version() != Some(ProtocolVersion::TLSv1_3) {
+            return Some(format!("protocol {:?} is not TLS 1.3", conn.protocol_version()));
+        }
+        let suite = conn.negotiated_cipher_suite().map(|s| s.suite());
+        if !matches!(
+            suite,
+            Some(CipherSuite::TLS13_AES_128_GCM_SHA256)
+                | Some(CipherSuite::TLS13_AES_256_GCM_SHA384)
+                | Some(CipherSuite::TLS13_CHACHA20_POLY1305_SHA256)
+        ) {
+            return Some(format!("cipher suite {:?} is not supported", suite));
+        }
+        if conn.wants_write() {
+            return Some("handshake messages are still pending".to_string());
+        }
+        match conn.process_new_packets() {
+            Ok(state) if state.plaintext_bytes_to_read() == 0 => None,
+            Ok(_) => Some("data is already buffered by rustls".to_string()),
+            Err(err) => Some(format!("{}", err)),
+        }
+    }
+
+    pub fn offload(mut tls_stream: TlsStream<HandshakeStream>) -> io::Result<ServerTlsStream> {
+        if KTLS_UNAVAILABLE.load(Ordering::Relaxed) {
+            return Ok(ServerTlsStream::Userspace(Box::new(tls_stream)));
+        }
+
+        if let Some(reason) = cannot_offload(&mut tls_stream) {
+            debug!("Keeping TLS in userspace: {}", reason);
+            return Ok(ServerTlsStream::Userspace(Box::new(tls_stream)));
+        }
+
+        // The socket is still usable as is if the kernel does not support kTLS, so we can fall back to rustls
+        if let Err(err) = setsockopt(&tls_stream.get_ref().0.stream, libc::SOL_TCP, libc::TCP_ULP, b"tls") {
+            if !KTLS_UNAVAILABLE.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "Kernel TLS is not available, is the tls kernel module loaded ? Using userspace TLS: {}",
+                    err
+                );
+            }
+            return Ok(ServerTlsStream::Userspace(Box::new(tls_stream)));
+        }
+
+        // From here rustls gives up the connection, we cannot go back to it in case of error
+        let alpn_protocol = tls_stream.get_ref().1.alpn_protocol().map(|p| p.to_vec());
+        let (stream, conn) = tls_stream.into_inner();
+        let secrets = conn
+            .dangerous_extract_secrets()
+            .map_err(|err| io::Error::other(format!("cannot extract TLS secrets: {}", err)))?;
+        set_crypto_info(&stream.stream, TLS_TX, secrets.tx.0, secrets.tx.1)?;
+        set_crypto_info(&stream.stream, TLS_RX, secrets.rx.0, secrets.rx.1)?;
+
+        debug!("TLS records are now handled by the kernel");
+        Ok(ServerTlsStream::Kernel(KtlsStream {
+            stream: stream.stream,
+            alpn_protocol,
+            read_closed: false,
+            close_notify_sent: false,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_handshake_stream_stops_at_record_boundary() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        // Two records of 3 and 2 bytes sent at once
+        client
+            .write_all(&[
+                0x16, 0x03, 0x03, 0x00, 0x03, 1, 2, 3, 0x17, 0x03, 0x03, 0x00, 0x02, 4, 5,
+            ])
+            .await
+            .unwrap();
+
+        let mut stream = HandshakeStream::new(server, true);
+        let mut buf = [0u8; 64];
+        let mut reads = vec![];
+        let mut total = 0;
+        while total < 15 {
+            let n = stream.read(&mut buf).await.unwrap();
+            reads.push(buf[..n].to_vec());
+            total += n;
+        }
+
+        // Each read stops at the end of the header or of the payload of a record
+        assert_eq!(
+            reads,
+            vec![
+                vec![0x16, 0x03, 0x03, 0x00, 0x03],
+                vec![1, 2, 3],
+                vec![0x17, 0x03, 0x03, 0x00, 0x02],
+                vec![4, 5]
+            ]
+        );
+    }
+}
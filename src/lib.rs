@@ -0,0 +1,3432 @@
+mod access_log;
+mod admin;
+mod auth_hook;
+mod circuit_breaker;
+mod cookie_jar;
+mod dest_filter;
+mod dns;
+mod embedded_certificate;
+mod env_proxy;
+mod fatal;
+#[cfg(feature = "ffi")]
+mod ffi;
+mod flight_recorder;
+mod geoip;
+mod handover;
+pub mod harness;
+mod hooks;
+mod htpasswd;
+mod http_client;
+mod jwks;
+mod ktls;
+mod metrics;
+mod natpmp;
+mod negotiate;
+mod netns;
+mod pac;
+mod pcap;
+mod pkcs11;
+mod proxy_auth;
+mod redact;
+mod restrictions;
+mod shutdown;
+mod socks5;
+mod socks5_udp;
+mod speed_test;
+mod statsd;
+mod stdio;
+mod tcp;
+mod tls;
+mod tokio_console;
+mod totp;
+mod tunnel;
+mod udp;
+#[cfg(unix)]
+mod unix_socket;
+mod version;
+mod webhook;
+
+/// To serve the tunnels from the routes of an existing hyper server
+pub use tunnel::server::WsServerService;
+
+use anyhow::{anyhow, Context};
+use base64::Engine;
+use bb8::ManageConnection;
+use clap::Parser;
+use futures_util::{future, stream, TryStreamExt};
+use hickory_resolver::config::{NameServerConfig, ResolverConfig, ResolverOpts};
+use hyper::header::HOST;
+use hyper::http::{HeaderName, HeaderValue};
+use ipnet::IpNet;
+use log::{debug, warn};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Debug, Formatter};
+use std::hash::{BuildHasher, DefaultHasher, Hash, Hasher};
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{fmt, io, iter};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::select;
+use tokio::sync::Semaphore;
+use tokio_stream::wrappers::TcpListenerStream;
+
+use tokio_rustls::rustls::pki_types::{CertificateDer, DnsName, ServerName};
+use tokio_rustls::TlsConnector;
+
+use tracing::{error, info};
+
+use crate::auth_hook::{AuthHook, HttpAuthHook};
+use crate::circuit_breaker::CircuitBreaker;
+use crate::cookie_jar::CookieJar;
+use crate::dest_filter::DestinationFilter;
+use crate::dns::{DnsResolver, IpFamily};
+use crate::fatal::{or_exit_on_failure, Failure, Fatal, OrExit, OrFail, EXIT_CODES_HELP};
+use crate::geoip::GeoIp;
+use crate::htpasswd::Htpasswd;
+use crate::jwks::JwksValidator;
+use crate::netns::NetNs;
+use crate::redact::Redacted;
+use crate::restrictions::Restrictions;
+use crate::socks5::Socks5Limits;
+use crate::tls::{TlsCryptoProvider, TlsFingerprint};
+use crate::totp::{parse_totp_secret, Totp};
+use crate::tunnel::client::{ListenerOptions, TunnelReply};
+use crate::tunnel::connection_pool::{parse_connection_pool, ConnectionPool};
+use crate::tunnel::port_knocking::{parse_knock_step, KnockStep};
+use crate::tunnel::transport::boxed::DynTunnelTransport;
+use crate::tunnel::{to_host_port, CloseReason, RemoteAddr, TransportAddr, TransportScheme};
+use crate::tunnel::{
+    AdaptiveKeepalive, Keepalive, LongPollingFallback, TunnelPriority, TunnelScheduler, WriteBatching,
+};
+use crate::udp::MyUdpSocket;
+use crate::version::{parse_version, Version};
+use tracing_subscriber::filter::Directive;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+use url::{Host, Url};
+
+/// Use Websocket or HTTP2 protocol to tunnel {TCP,UDP} traffic
+/// wsTunnelClient <---> wsTunnelServer <---> RemoteHost
+#[derive(clap::Parser, Debug)]
+#[command(author, version, about, verbatim_doc_comment, long_about = None, after_help = EXIT_CODES_HELP)]
+struct Wstunnel {
+    #[command(subcommand)]
+    commands: Commands,
+
+    /// Disable color output in logs
+    #[arg(long, global = true, verbatim_doc_comment, env = "NO_COLOR")]
+    no_color: Option<String>,
+
+    /// *WARNING* The flag does nothing, you need to set the env variable *WARNING*
+    /// Control the number of threads that will be used.
+    /// By default it is equal the number of cpus
+    #[arg(
+        long,
+        global = true,
+        value_name = "INT",
+        verbatim_doc_comment,
+        env = "TOKIO_WORKER_THREADS"
+    )]
+    nb_worker_threads: Option<u32>,
+
+    /// Control the log verbosity. i.e: TRACE, DEBUG, INFO, WARN, ERROR, OFF
+    /// for more details: https://docs.rs/tracing-subscriber/latest/tracing_subscriber/filter/struct.EnvFilter.html#example-syntax
+    #[arg(
+        long,
+        global = true,
+        value_name = "LOG_LEVEL",
+        verbatim_doc_comment,
+        env = "RUST_LOG",
+        default_value = "INFO"
+    )]
+    log_lvl: String,
+
+    /// Send metrics (active tunnels, bytes, errors) to a StatsD/DogStatsD agent over udp. i.e: localhost:8125
+    /// Metrics are flushed every 10 seconds
+    #[arg(
+        long,
+        global = true,
+        value_name = "HOST:PORT",
+        verbatim_doc_comment,
+        env = "WSTUNNEL_STATSD_ADDR"
+    )]
+    statsd_addr: Option<String>,
+
+    /// Prefix of the metrics sent to StatsD
+    #[arg(
+        long,
+        global = true,
+        value_name = "PREFIX",
+        default_value = "wstunnel",
+        verbatim_doc_comment
+    )]
+    statsd_prefix: String,
+
+    /// Shell command run each time a tunnel is opened, i.e: to update firewall rules or send a desktop notification.
+    /// Metadata of the tunnel is given in env variables:
+    ///   WSTUNNEL_EVENT, WSTUNNEL_TUNNEL_ID, WSTUNNEL_PROTOCOL, WSTUNNEL_REMOTE and WSTUNNEL_PEER_IP (server only)
+    /// The command runs in the background, its stdout is discarded
+    #[arg(long, global = true, value_name = "CMD", verbatim_doc_comment)]
+    on_connect_cmd: Option<String>,
+
+    /// Record the decrypted payload of all tunnels in this pcap file, to debug the protocol inside a tunnel with wireshark.
+    /// Each tunnel is written as a tcp/udp stream 10.0.0.1:<port> <-> 10.0.0.2:<destination port>, the mapping with
+    /// the tunnel id is logged when the tunnel opens.
+    /// *WARNING* The file contains everything going through the tunnels, including secrets
+    #[arg(long, global = true, value_name = "FILE_PATH", verbatim_doc_comment)]
+    pcap_dump: Option<PathBuf>,
+
+    /// Keep the last INT events of each tunnel in memory (state changes, ping round trip times, errors),
+    /// and log them when the tunnel fails. Makes intermittent failures diagnosable without running at trace level.
+    /// The client also logs the events of all live tunnels on SIGUSR2, the server uses it to hand over its listener
+    #[arg(long, global = true, value_name = "INT", verbatim_doc_comment)]
+    flight_recorder: Option<usize>,
+
+    /// Serve the tasks of the runtime to tokio-console on this address, i.e: 127.0.0.1:6669
+    /// to diagnose leaked tunnel tasks and executor stalls. Only with wstunnel built for it:
+    ///   RUSTFLAGS="--cfg tokio_unstable --cfg uuid_unstable" cargo build --release --features tokio-console
+    #[arg(long, global = true, value_name = "ADDR", verbatim_doc_comment)]
+    tokio_console: Option<SocketAddr>,
+
+    /// Max number of connections waiting to be accepted by the TCP listeners, before the kernel refuses new ones.
+    /// The soft limit of open files is raised to the hard one at startup, when it runs out anyway accepting is paused
+    #[arg(
+        long,
+        global = true,
+        value_name = "INT",
+        default_value = "1024",
+        verbatim_doc_comment
+    )]
+    listen_backlog: u32,
+
+    /// Shell command run each time a tunnel is closed, with the same env variables as --on-connect-cmd plus
+    /// WSTUNNEL_BYTES_TX, WSTUNNEL_BYTES_RX and WSTUNNEL_DURATION_MS
+    #[arg(long, global = true, value_name = "CMD", verbatim_doc_comment)]
+    on_disconnect_cmd: Option<String>,
+
+    /// Implementation of the cryptography used by TLS: ring, aws-lc-rs or fips.
+    /// fips uses only the FIPS validated module of aws-lc, for environments that must run validated cryptography.
+    /// Available providers depend on the cargo features wstunnel is built with, by default only ring
+    #[arg(
+        long,
+        global = true,
+        value_name = "PROVIDER",
+        default_value_t = TlsCryptoProvider::default(),
+        verbatim_doc_comment,
+        env = "WSTUNNEL_TLS_CRYPTO_PROVIDER"
+    )]
+    tls_crypto_provider: TlsCryptoProvider,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Commands {
+    Client(Box<Client>),
+    Server(Box<Server>),
+    Healthcheck(Box<Healthcheck>),
+    Bench(Box<Bench>),
+}
+
+impl Commands {
+    /// Only validating the configuration with --check, nothing must be bound nor written
+    fn is_check(&self) -> bool {
+        match self {
+            Commands::Client(args) => args.check,
+            Commands::Server(args) => args.check,
+            Commands::Healthcheck(_) | Commands::Bench(_) => false,
+        }
+    }
+}
+
+/// Connect to a wstunnel server with the given client configuration, do an upgrade request and exit.
+/// Exit code is 0 if the upgrade succeeded, otherwise one of the exit codes listed in --help. Useful for monitoring and container healthchecks
+#[derive(clap::Args, Debug)]
+struct Healthcheck {
+    /// Destination requested to the server during the upgrade. The tunnel is closed as soon as it is opened.
+    /// By default an udp destination is used, as opening it does not send any traffic.
+    /// If the server is started with --restrict-to, you need to use an allowed destination
+    #[arg(
+        long,
+        value_name = "{tcp,udp}://HOST:PORT",
+        default_value = "udp://127.0.0.1:9",
+        value_parser = parse_healthcheck_destination,
+        verbatim_doc_comment
+    )]
+    destination: RemoteAddr,
+
+    /// Maximum time allowed to connect to the server and get the upgrade accepted
+    #[arg(long, value_name = "seconds", default_value = "10", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    timeout_sec: Duration,
+
+    #[command(flatten)]
+    client: Client,
+}
+/// Measure echo round trip time, throughput and cpu usage through a tunnel for several frame sizes, and exit.
+/// A server, a client and an echo target are started in this process, talking over loopback,
+/// to compare versions and tuning options reproducibly, without the noise of a real network.
+/// Use `wstunnel client --speed-test` to measure against a remote server
+#[derive(clap::Args, Debug)]
+struct Bench {
+    /// Sizes of the frames written in the tunnel, the measures are done for each of them
+    #[arg(
+        long,
+        value_name = "BYTES",
+        value_delimiter = ',',
+        default_value = "64,1024,16384,65536",
+        verbatim_doc_comment
+    )]
+    frame_size: Vec<usize>,
+
+    /// Duration of the throughput measure of each frame size
+    #[arg(long, value_name = "seconds", default_value = "5", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    duration_sec: Duration,
+
+    /// Use TLS between the client and the server, with the embedded self-signed certificate
+    #[arg(long, default_value = "false", verbatim_doc_comment)]
+    tls: bool,
+
+    /// Use http2 as transport between the client and the server, instead of websocket
+    #[arg(long, default_value = "false", verbatim_doc_comment)]
+    http2: bool,
+
+    /// Extra argument of the server, to evaluate a tuning option. Can be specified multiple times
+    /// example: --server-arg=--websocket-mask-frame
+    #[arg(long, value_name = "ARG", allow_hyphen_values = true, verbatim_doc_comment)]
+    server_arg: Vec<String>,
+
+    /// Extra argument of the client, to evaluate a tuning option. Can be specified multiple times
+    /// example: --client-arg=--write-batching-ms=1
+    #[arg(long, value_name = "ARG", allow_hyphen_values = true, verbatim_doc_comment)]
+    client_arg: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct Client {
+    /// Listen on local and forwards traffic from remote. Can be specified multiple times
+    /// examples:
+    /// 'tcp://1212:google.com:443'      =>       listen locally on tcp on port 1212 and forward to google.com on port 443
+    /// 'tcp://2:n.lan:4?proxy_protocol' =>       listen locally on tcp on port 2 and forward to n.lan on port 4
+    ///                                           Send a proxy protocol header v2 when establishing connection to n.lan
+    ///
+    /// 'udp://1212:1.1.1.1:53'          =>       listen locally on udp on port 1212 and forward to cloudflare dns 1.1.1.1 on port 53
+    /// 'udp://1212:1.1.1.1:53?timeout_sec=10'    timeout_sec on udp force close the tunnel after 10sec. Set it to 0 to disable the timeout [default: 30]
+    ///                                           datagrams cross the tunnel in order and exactly once, as it runs over tcp
+    ///
+    /// 'socks5://[::1]:1212'            =>       listen locally with socks5 on port 1212 and forward dynamically requested tunnel
+    /// 'socks5://[::1]:1212?allow=*.corp.example:*,10.0.0.1:22&deny=vault.corp.example:*'
+    ///                                           only tunnel the requests to matching HOST:PORT, * matching anything. Deny wins over allow
+    /// 'socks5://[::1]:1212?handshake_timeout_sec=5&max_handshakes=64&idle_timeout_sec=600'
+    ///                                           disconnect the clients not done negotiating within 5sec [default: 10], stop accepting new ones
+    ///                                           while 64 are negotiating [default: 128], and close the tcp sessions idle for 10min [default: never]
+    ///
+    /// 'tproxy+tcp://[::1]:1212'        =>       listen locally on tcp on port 1212 as a *transparent proxy* and forward dynamically requested tunnel
+    /// 'tproxy+udp://[::1]:1212?timeout_sec=10'  listen locally on udp on port 1212 as a *transparent proxy* and forward dynamically requested tunnel
+    ///                                           linux only and requires sudo/CAP_NET_ADMIN
+    ///
+    /// 'stdio://google.com:443'         =>       listen for data from stdio, mainly for `ssh -o ProxyCommand="wstunnel client -L stdio://%h:%p ws://localhost:8080" my-server`
+    ///
+    /// 'unix:///tmp/wstunnel.sock:g.com:443' =>  listen for data from unix socket of path /tmp/wstunnel.sock and forward to g.com:443
+    ///
+    /// 'tcp://2222:n.lan:22?priority=interactive' priority of the tunnel when several tunnels compete for bandwidth, one of interactive, normal or bulk [default: normal]
+    ///                                           bulk tunnels back off while interactive tunnels have data to send
+    /// 'tcp://1212:grafana.lan:443?name=grafana' name of the tunnel, shown in logs, metrics and the admin socket instead of only its port
+    /// 'tcp://1212:n.lan:80?max_duration_sec=3600' close the listener and its live connections after 1 hour, i.e: for a temporary debugging forward
+    /// 'tcp://1212:n.lan:80?reuse'      =>       keep the websocket open once a connection is done, and reuse it for the next one instead of doing a new handshake
+    ///                                           useful for clients opening many short connections. Only for tcp and unix, with websocket transport
+    /// 'tcp://1212:n.lan:80?max_conn=100' =>     allow at most 100 simultaneous connections through this listener, the extra ones are refused
+    /// 'tcp://1212:a.lan:80|b.lan:80' =>       each new connection goes to the next destination in turn, to balance them over redundant backends
+    ///                                           Only for tcp, udp and unix. A udp flow always goes to the same destination, picked from its source address
+    /// 'tcp://1212:a.lan:443,b.lan:443' =>     the server connects to b.lan:443 when it cannot reach a.lan:443, for active/passive backends
+    ///                                           Only for tcp, udp, unix and stdio
+    /// 'tcp://2222:final.lan:22?via=wss://jump.lan:443' => reach final.lan through the wstunnel server jump.lan, itself reached through the server
+    ///                                           The same tls, auth and headers options are used toward both servers. Only for tcp
+    /// 'tcp://1212:n.lan:80?reuseport=true' =>   set SO_REUSEPORT on the listener, for several wstunnel clients to share the port
+    ///                                           and the kernel to balance the connections among them. Only for tcp, on unix
+    /// 'tcp://1212:n.lan:80?bind=dual'  =>       listen on both 127.0.0.1 and [::1], or on both ipv4 and ipv6 with a dual-stack socket for 0.0.0.0 and [::]
+    ///                                           Only for tcp, udp, socks5 and tproxy
+    #[arg(short='L', long, value_name = "{tcp,udp,socks5,stdio,unix}://[BIND:]PORT:HOST:PORT", value_parser = parse_tunnel_arg, verbatim_doc_comment)]
+    local_to_remote: Vec<LocalToRemote>,
+
+    /// Listen on remote and forwards traffic from local. Can be specified multiple times. Only tcp is supported
+    /// examples:
+    /// 'tcp://1212:google.com:443'      =>     listen on server for incoming tcp cnx on port 1212 and forward to google.com on port 443 from local machine
+    /// 'udp://1212:1.1.1.1:53'          =>     listen on server for incoming udp on port 1212 and forward to cloudflare dns 1.1.1.1 on port 53 from local machine
+    /// 'socks5://[::1]:1212'            =>     listen on server for incoming socks5 request on port 1212 and forward dynamically request from local machine
+    /// 'unix://wstunnel.sock:g.com:443' =>     listen on server for incoming data from unix socket of path wstunnel.sock and forward to g.com:443 from local machine
+    /// 'tcp://1212:g.com:443?name=web'  =>     name of the tunnel, shown in logs, metrics and the admin socket
+    #[arg(short='R', long, value_name = "{tcp,udp,socks5,unix}://[BIND:]PORT:HOST:PORT", value_parser = parse_reverse_tunnel_arg, verbatim_doc_comment)]
+    remote_to_local: Vec<LocalToRemote>,
+
+    /// (linux only) Mark network packet with SO_MARK sockoption with the specified value.
+    /// You need to use {root, sudo, capabilities} to run wstunnel when using this option
+    #[arg(long, value_name = "INT", verbatim_doc_comment)]
+    socket_so_mark: Option<u32>,
+
+    /// Connect to the server only over IPv4, i.e: when the IPv6 route of a dual-stack host is broken and stalls connections
+    #[arg(short = '4', long, conflicts_with = "ipv6_only", verbatim_doc_comment)]
+    ipv4_only: bool,
+
+    /// Connect to the server only over IPv6
+    #[arg(short = '6', long, verbatim_doc_comment)]
+    ipv6_only: bool,
+
+    /// Client will maintain a pool of open connection to the server, in order to speed up the connection process.
+    /// This option set the maximum number of connection that will be kept open.
+    /// This is useful if you plan to create/destroy a lot of tunnel (i.e: with socks5 to navigate with a browser)
+    /// It will avoid the latency of doing tcp + tls handshake with the server
+    #[arg(short = 'c', long, value_name = "INT", default_value = "0", verbatim_doc_comment)]
+    connection_min_idle: u32,
+
+    /// Resolve the address of the server again once this interval elapsed, even if its dns records have a longer TTL.
+    /// Useful to follow dns round-robin or failover changes. The address is always resolved again after a failed connection
+    #[arg(long, value_name = "seconds", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    dns_refresh_interval: Option<Duration>,
+
+    /// Dns resolver used to lookup the server, and its SRV record with a srv+ url. Can be specified multiple time
+    /// Example: dns://1.1.1.1, dns+https://1.1.1.1 or dns+tls://8.8.8.8. Use system://0.0.0.0 for the libc resolver
+    /// By default, the name servers of resolv.conf are used
+    #[arg(long, verbatim_doc_comment)]
+    dns_resolver: Option<Vec<Url>>,
+
+    /// Instead of starting tunnels, measure latency and throughput against the bench endpoint of the server and exit.
+    /// Upgrade RTT, echo RTT, upload and download throughput are reported.
+    /// The server must be started with --enable-bench-endpoint and allow it, so it does not work if the server uses --restrict-to
+    #[arg(long, default_value = "false", verbatim_doc_comment)]
+    speed_test: bool,
+
+    /// When the server cannot be reached, try other ways to connect to it before giving up.
+    /// In order: wss://host:443, wss://host:<port>, ws://host:80
+    /// The first one that works is used for all tunnels. Useful on restrictive networks that only allow web traffic
+    /// For a wss server, ws://host:80 is only tried without credentials, totp, custom headers or path prefix to leak,
+    /// unless --auto-fallback-allow-plaintext is set
+    #[arg(long, default_value = "false", verbatim_doc_comment)]
+    auto_fallback: bool,
+
+    /// Allow --auto-fallback to send the upgrade request of a wss server unencrypted on ws://host:80,
+    /// even if it carries secrets anyone on the path could read
+    #[arg(long, default_value = "false", requires = "auto_fallback", verbatim_doc_comment)]
+    auto_fallback_allow_plaintext: bool,
+
+    /// After this number of consecutive failures to connect to the server, stop trying for --circuit-breaker-cooldown-sec.
+    /// Tunnels fail fast meanwhile and a single error is logged, instead of hammering a down server with retries.
+    /// Disabled by default
+    #[arg(long, value_name = "INT", verbatim_doc_comment)]
+    circuit_breaker_threshold: Option<u32>,
+
+    /// Time during which the client does not try to connect to the server, once the circuit breaker is open
+    #[arg(long, value_name = "seconds", default_value = "30", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    circuit_breaker_cooldown_sec: Duration,
+
+    /// (unix only) Listen for admin commands on this unix socket, to manage the running client from scripts or GUIs.
+    /// Commands are sent one per line, and each response ends with an empty line. Available commands:
+    ///   status           : version, transport in use and list of active tunnels
+    ///   stats            : number of tunnels and bytes transferred since the start
+    ///   reload           : try again the websocket transport, if the client had to fallback to long polling
+    ///   close-tunnel ID  : close the tunnel with this ID
+    /// example: echo status | socat - UNIX-CONNECT:/run/wstunnel.sock
+    #[arg(long, value_name = "SOCKET_PATH", verbatim_doc_comment)]
+    admin_socket: Option<PathBuf>,
+
+    /// Serve Prometheus metrics of the local listeners (-L and -R) on http://ADDR/metrics
+    /// For each listener: connections, active connections, errors, reconnects, bytes and upgrade latency histogram
+    /// and the tasks of the runtime, with details when built with RUSTFLAGS="--cfg tokio_unstable"
+    /// example: --metrics-addr 127.0.0.1:9090
+    #[arg(long, value_name = "ADDR", verbatim_doc_comment)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Validate the configuration and exit, without connecting to the server nor binding any local port.
+    /// Tunnels are parsed, TLS material is loaded and the address of the server is resolved.
+    /// Exit with a non zero status and the detailed error if something is wrong, i.e: to validate a change in CI
+    #[arg(long, default_value = "false", verbatim_doc_comment)]
+    check: bool,
+
+    /// Domain name that will be use as SNI during TLS handshake
+    /// Warning: If you are behind a CDN (i.e: Cloudflare) you must set this domain also in the http HOST header.
+    ///          or it will be flagged as fishy and your request rejected
+    #[arg(long, value_name = "DOMAIN_NAME", value_parser = parse_sni_override, verbatim_doc_comment)]
+    tls_sni_override: Option<DnsName<'static>>,
+
+    /// Disable sending SNI during TLS handshake
+    /// Warning: Most reverse proxies rely on it
+    #[arg(long, verbatim_doc_comment)]
+    tls_sni_disable: bool,
+
+    /// Order the cipher suites and key exchange groups of the TLS handshake like a browser, one of rustls, chrome or firefox
+    /// Useful when a firewall flags the default ClientHello of rustls. It is not a full mimicry of the browser JA3/JA4 fingerprint,
+    /// as rustls cannot send GREASE values nor change the order of its extensions
+    #[arg(long, value_name = "BROWSER", default_value_t = TlsFingerprint::default(), verbatim_doc_comment)]
+    tls_fingerprint: TlsFingerprint,
+
+    /// Encrypt the TLS ClientHello with ECH, for on-path observers to not see the real SNI of the server
+    /// Either `dns` to fetch the ECH config from the HTTPS record of the server, or the path of a file containing
+    /// the ECHConfigList, raw or base64 encoded. Requires wstunnel to be built with the aws-lc-rs crypto provider
+    /// i.e: --tls-ech dns
+    #[arg(
+        long,
+        value_name = "dns|FILE_PATH",
+        conflicts_with = "tls_sni_disable",
+        verbatim_doc_comment
+    )]
+    tls_ech: Option<String>,
+
+    /// Enable TLS certificate verification.
+    /// Disabled by default. The client will happily connect to any server with self signed certificate.
+    #[arg(long, verbatim_doc_comment)]
+    tls_verify_certificate: bool,
+
+    /// If set, will use this http proxy to connect to the server
+    /// Credentials are sent with Basic auth, or answer the Digest challenge of the proxy if it asks for one
+    /// If not set, the proxy is taken from the HTTPS_PROXY (for a wss/https server) or HTTP_PROXY env variables,
+    /// unless the server is listed in NO_PROXY, i.e: NO_PROXY=localhost,.corp.example,10.0.0.0/8
+    #[arg(short = 'p', long, value_name = "USER:PASS@HOST:PORT", verbatim_doc_comment)]
+    http_proxy: Option<String>,
+
+    /// Same as --http-proxy, read from the environment variable with this name, as it contains the credentials of the proxy
+    #[arg(
+        long,
+        value_name = "VAR",
+        value_parser = from_env(|value| Ok(value.to_string())),
+        conflicts_with = "http_proxy",
+        verbatim_doc_comment
+    )]
+    http_proxy_env: Option<String>,
+
+    /// Ignore the HTTP_PROXY, HTTPS_PROXY and NO_PROXY env variables
+    #[arg(long, default_value_t = false, verbatim_doc_comment)]
+    no_env_proxy: bool,
+
+    /// Choose the http proxy with the PAC script at this url (http(s)://, file:// or a path), as browsers do.
+    /// Ignored if --http-proxy is set. A DIRECT answer connects without proxy, even if HTTP_PROXY is set.
+    /// If the script cannot be fetched or evaluated, the proxy of the env variables is used
+    #[arg(long, value_name = "URL", verbatim_doc_comment, env = "WSTUNNEL_PROXY_PAC_URL")]
+    proxy_pac_url: Option<String>,
+
+    /// If set, will use this login to connect to the http proxy. Override the one from --http-proxy
+    #[arg(long, value_name = "LOGIN", verbatim_doc_comment, env = "WSTUNNEL_HTTP_PROXY_LOGIN")]
+    http_proxy_login: Option<String>,
+
+    /// If set, will use this password to connect to the http proxy. Override the one from --http-proxy
+    #[arg(
+        long,
+        value_name = "PASSWORD",
+        verbatim_doc_comment,
+        env = "WSTUNNEL_HTTP_PROXY_PASSWORD"
+    )]
+    http_proxy_password: Option<String>,
+
+    /// Same as --http-proxy-password, read from the environment variable with this name
+    #[arg(
+        long,
+        value_name = "VAR",
+        value_parser = from_env(|value| Ok(value.to_string())),
+        conflicts_with = "http_proxy_password",
+        verbatim_doc_comment
+    )]
+    http_proxy_password_env: Option<String>,
+
+    /// Authenticate to the http proxy with the single sign-on credentials of the logged user, when it asks for
+    /// Negotiate (Kerberos/SPNEGO) or NTLM. Uses SSPI on windows and the GSSAPI library (i.e: MIT kerberos) elsewhere.
+    /// Off by default, as a rogue proxy could crack the NTLM answer offline
+    #[arg(long, default_value_t = false, verbatim_doc_comment)]
+    http_proxy_negotiate: bool,
+
+    /// Use a specific prefix that will show up in the http path during the upgrade request.
+    /// Useful if you need to route requests server side but don't have vhosts
+    /// Ignored if the url of the server has a path, i.e: wss://wstunnel.example.com/mysecretprefix
+    #[arg(
+        short = 'P',
+        long,
+        default_value = "v1",
+        verbatim_doc_comment,
+        env = "WSTUNNEL_HTTP_UPGRADE_PATH_PREFIX"
+    )]
+    http_upgrade_path_prefix: String,
+
+    /// Sub-path under which a reverse proxy in front of the server mounts it, prepended to the upgrade path prefix.
+    /// For a proxy forwarding https://example.com/wstunnel/* to the server after stripping /wstunnel, use --http-upgrade-external-prefix /wstunnel
+    /// The server validates its path prefix restriction with the X-Forwarded-Prefix header sent by such proxy
+    #[arg(long, value_name = "PATH", verbatim_doc_comment)]
+    http_upgrade_external_prefix: Option<String>,
+
+    /// Pass authorization header with basic auth credentials during the upgrade request.
+    /// If you need more customization, you can use the http_headers option.
+    /// Use @/path/to/file to read them from a file, to not leak them in process list or shell history
+    #[arg(
+        long,
+        value_name = "USER[:PASS]",
+        value_parser = parse_http_credentials,
+        verbatim_doc_comment,
+        env = "WSTUNNEL_HTTP_UPGRADE_CREDENTIALS"
+    )]
+    http_upgrade_credentials: Option<HeaderValue>,
+
+    /// Same as --http-upgrade-credentials, read from the environment variable with this name
+    #[arg(
+        long,
+        value_name = "VAR",
+        value_parser = from_env(parse_http_credentials),
+        conflicts_with = "http_upgrade_credentials",
+        verbatim_doc_comment
+    )]
+    http_upgrade_credentials_env: Option<HeaderValue>,
+
+    /// Send a time based one-time password (TOTP) computed from this base32 secret during the upgrade request.
+    /// The server must be started with the same secret in --auth-totp. Clocks of client and server must be in sync
+    /// The code is sent bound to the tunnel id and a random nonce, so it cannot be replayed by someone seeing the request
+    /// Use @/path/to/file to read the secret from a file
+    #[arg(
+        long,
+        value_name = "BASE32_SECRET",
+        value_parser = parse_totp_secret,
+        verbatim_doc_comment,
+        env = "WSTUNNEL_AUTH_TOTP"
+    )]
+    auth_totp: Option<Totp>,
+
+    /// Same as --auth-totp, read from the environment variable with this name
+    #[arg(
+        long,
+        value_name = "VAR",
+        value_parser = from_env(parse_totp_secret),
+        conflicts_with = "auth_totp",
+        verbatim_doc_comment
+    )]
+    auth_totp_env: Option<Totp>,
+
+    /// Frequency at which the client will send websocket ping to the server.
+    #[arg(long, value_name = "seconds", default_value = "30", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    websocket_ping_frequency_sec: Option<Duration>,
+
+    /// Adapt the ping frequency to the idle timeout of the NATs and proxies on the path, up to this many seconds.
+    /// Pings start every --websocket-ping-frequency-sec and get sparser while idle connections survive them,
+    /// then get closer again once a connection dies, to save traffic and battery on mobile.
+    /// A tunnel is closed when the server does not answer a ping before the next one, to detect dead connections
+    #[arg(long, value_name = "seconds", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    websocket_ping_adaptive_max_sec: Option<Duration>,
+
+    /// Keep tcp tunnels alive across network changes, i.e: when switching from Wi-Fi to LTE.
+    /// When the websocket of a tunnel breaks, the client reconnects for up to this many seconds and the tunnel resumes
+    /// over the new websocket, without closing the connection to the destination. The server must allow it too.
+    /// If data in flight was lost with the broken websocket, the tunnel is closed instead
+    #[arg(long, value_name = "seconds", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    resume_timeout_sec: Option<Duration>,
+
+    /// Keep up to this many bytes sent by a resumed tunnel until the server acknowledges them, to send them again
+    /// if the websocket breaks before they arrive. Reading the local connection pauses while the buffer is full.
+    /// Makes resumed tunnels lossless when the server also sets it. At least 65536, per tunnel
+    #[arg(long, value_name = "INT", requires = "resume_timeout_sec", verbatim_doc_comment)]
+    resume_buffer_bytes: Option<usize>,
+
+    /// Stripe each tcp tunnel across this many websockets, put back in order by the server, for more throughput
+    /// when middleboxes throttle each connection. The server must support it, tunnels are opened normally otherwise.
+    /// Bonded tunnels are neither resumed nor reused
+    #[arg(long, value_name = "COUNT", value_parser = clap::value_parser!(u8).range(2..=16), verbatim_doc_comment)]
+    bond: Option<u8>,
+
+    /// Enable the masking of websocket frames. Default is false
+    /// Enable this option only if you use unsecure (non TLS) websocket server and you see some issues. Otherwise, it is just overhead.
+    #[arg(long, default_value = "false", verbatim_doc_comment)]
+    websocket_mask_frame: bool,
+
+    /// Wait up to this many milliseconds for more bytes after a small read, before sending them in a websocket frame.
+    /// It reduces the number of frames and TLS records for protocols doing many tiny writes, at the cost of latency.
+    /// Disabled by default, keep it disabled for latency sensitive traffic. UDP tunnels are never batched
+    #[arg(long, value_name = "ms", value_parser = parse_duration_ms, verbatim_doc_comment)]
+    write_batching_ms: Option<Duration>,
+
+    /// Send the batched bytes without waiting more, once this many bytes are buffered
+    #[arg(long, value_name = "INT", default_value = "16384", verbatim_doc_comment)]
+    write_batching_bytes: usize,
+
+    /// Send custom headers in the upgrade request
+    /// Can be specified multiple time
+    #[arg(short='H', long, value_name = "HEADER_NAME: HEADER_VALUE", value_parser = parse_http_headers, verbatim_doc_comment)]
+    http_headers: Vec<(HeaderName, HeaderValue)>,
+
+    /// Same as --http-headers, read from the environment variable with this name, for headers carrying a secret, i.e: a bearer token
+    /// Can be specified multiple time
+    #[arg(long, value_name = "VAR", value_parser = from_env(parse_http_headers), verbatim_doc_comment)]
+    http_headers_env: Vec<(HeaderName, HeaderValue)>,
+
+    /// Send custom headers in the upgrade request reading them from a file.
+    /// It overrides http_headers specified from command line.
+    /// File is read everytime and file format must contains lines with `HEADER_NAME: HEADER_VALUE`
+    #[arg(long, value_name = "FILE_PATH", verbatim_doc_comment)]
+    http_headers_file: Option<PathBuf>,
+
+    /// Send the websocket upgrade request with the headers of a browser, in the same order, i.e: User-Agent, Accept, Origin...
+    /// For the handshake to blend in with the websocket traffic of web pages, when a DPI looks at it
+    /// Headers given with --http-headers still take precedence
+    #[arg(long, default_value = "false", verbatim_doc_comment)]
+    mimic_browser: bool,
+
+    /// Carry the authorization, TOTP and version headers of the websocket upgrade request as tokens of the
+    /// Sec-WebSocket-Protocol header, for proxies that strip custom headers but preserve the websocket ones
+    /// i.e: Sec-WebSocket-Protocol: v1, authorization.bearer.xxx, header.x-wstunnel-totp.MTIzNDU2
+    /// Requires the server to be of the same version or newer
+    #[arg(long, default_value = "false", verbatim_doc_comment)]
+    http_upgrade_headers_in_protocol: bool,
+
+    /// Keep the cookies set in the responses to the upgrade requests, and send them back with the next ones
+    /// For the session affinity and bot mitigation cookies of a CDN or load balancer in front of the server to keep working
+    /// With http2 transport, requires the server to be of the same version or newer
+    #[arg(long, default_value = "false", verbatim_doc_comment)]
+    http_cookies: bool,
+
+    /// Follow up to this number of redirects (301, 302, 307...) answered to the websocket upgrade request, instead of failing
+    /// For hosting setups redirecting between domains or paths. The redirect is followed for each new tunnel
+    /// A redirect from wss to ws is refused. The credentials, totp, custom headers and cookies are not sent to another host
+    #[arg(long, value_name = "COUNT", default_value_t = 0, verbatim_doc_comment)]
+    http_upgrade_max_redirects: u8,
+
+    /// Retry the upgrade request up to this number of times when the server, or a CDN in front of it, answers 429 or 503
+    /// Waits for the delay of the Retry-After header, or 1s, 2s, 4s... without it. A delay above 60s is not waited for
+    #[arg(long, value_name = "COUNT", default_value_t = 3, verbatim_doc_comment)]
+    http_upgrade_retries: u8,
+
+    /// Address of the wstunnel server
+    /// You can either use websocket or http2 as transport protocol. Use websocket if you are unsure.
+    /// Example: For websocket with TLS wss://wstunnel.example.com or without ws://wstunnel.example.com
+    ///          For http2 with TLS https://wstunnel.example.com or without http://wstunnel.example.com
+    ///
+    /// *WARNING* HTTP2 as transport protocol is harder to make it works because:
+    ///   - If you are behind a (reverse) proxy/CDN they are going to buffer the whole request before forwarding it to the server
+    ///     Obviously, this is not going to work for tunneling traffic
+    ///   - if you have wstunnel behind a reverse proxy, most of them (i.e: nginx) are going to turn http2 request into http1
+    ///     This is not going to work, because http1 does not support streaming naturally
+    /// The only way to make it works with http2 is to have wstunnel directly exposed to the internet without any reverse proxy in front of it
+    ///
+    /// A path in the url, i.e: wss://wstunnel.example.com/mysecretprefix, is used as --http-upgrade-path-prefix
+    ///
+    /// Prefix the scheme with srv+ to discover the host and port of the server with a DNS SRV record
+    /// Example: srv+wss://_wstunnel._tcp.example.com
+    ///          The target with the lowest priority is used, picked according to the weights if there are several
+    #[arg(value_name = "[srv+]ws[s]|http[s]://wstunnel.server.com[:port][/path_prefix]", value_parser = parse_client_server_url, verbatim_doc_comment)]
+    remote_addr: Url,
+}
+
+#[derive(clap::Args, Debug)]
+struct Server {
+    /// Address of the wstunnel server to bind to
+    /// Example: With TLS wss://0.0.0.0:8080 or without ws://[::]:8080
+    ///
+    /// The server is capable of detecting by itself if the request is websocket or http2. So you don't need to specify it.
+    /// A path in the url, i.e: wss://0.0.0.0:8080/mysecretprefix, is added to the path prefixes of --restrict-http-upgrade-path-prefix
+    #[arg(value_name = "ws[s]://0.0.0.0[:port][/path_prefix]", value_parser = parse_server_url, verbatim_doc_comment)]
+    remote_addr: Url,
+
+    /// (linux only) Mark network packet with SO_MARK sockoption with the specified value.
+    /// You need to use {root, sudo, capabilities} to run wstunnel when using this option
+    #[arg(long, value_name = "INT", verbatim_doc_comment)]
+    socket_so_mark: Option<u32>,
+
+    /// Connect to the destinations of the tunnels only over IPv4, i.e: when the IPv6 route of a dual-stack host is broken
+    #[arg(short = '4', long, conflicts_with = "ipv6_only", verbatim_doc_comment)]
+    ipv4_only: bool,
+
+    /// Connect to the destinations of the tunnels only over IPv6
+    #[arg(short = '6', long, verbatim_doc_comment)]
+    ipv6_only: bool,
+
+    /// Frequency at which the server will send websocket ping to client.
+    #[arg(long, value_name = "seconds", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    websocket_ping_frequency_sec: Option<Duration>,
+
+    /// Enable the masking of websocket frames. Default is false
+    /// Enable this option only if you use unsecure (non TLS) websocket server and you see some issues. Otherwise, it is just overhead.
+    #[arg(long, default_value = "false", verbatim_doc_comment)]
+    websocket_mask_frame: bool,
+
+    /// Wait up to this many milliseconds for more bytes after a small read, before sending them in a websocket frame.
+    /// It reduces the number of frames and TLS records for protocols doing many tiny writes, at the cost of latency.
+    /// Disabled by default, keep it disabled for latency sensitive traffic. UDP tunnels are never batched
+    #[arg(long, value_name = "ms", value_parser = parse_duration_ms, verbatim_doc_comment)]
+    write_batching_ms: Option<Duration>,
+
+    /// Send the batched bytes without waiting more, once this many bytes are buffered
+    #[arg(long, value_name = "INT", default_value = "16384", verbatim_doc_comment)]
+    write_batching_bytes: usize,
+
+    /// (linux only) Enable path MTU discovery on UDP tunnels toward their destination.
+    /// Datagrams are sent with the don't fragment bit, instead of being fragmented when bigger than the path MTU.
+    /// This trades fragmentation for drops: oversized datagrams are always dropped, even when their fragments would have
+    /// been delivered. Only useful when firewalls drop fragments, as drops are then logged instead of silently blackholed
+    /// Oversized datagrams are neither split by wstunnel nor signalled to their sender with an ICMP message
+    #[arg(long, default_value = "false", verbatim_doc_comment)]
+    udp_pmtu_discovery: bool,
+
+    /// Relay back to the client the UDP datagrams sent by any peer to the port allocated for a tunnel, not only the ones of its destination.
+    /// This endpoint independent mapping (full cone NAT) is needed by protocols like STUN/WebRTC and some games.
+    /// By default, only the destination of the tunnel can answer
+    #[arg(long, default_value = "false", verbatim_doc_comment)]
+    udp_full_cone: bool,
+
+    /// Close the UDP flows toward the destinations of the server after this duration without any datagram in either direction.
+    /// By default a flow lives until the client closes its tunnel. Use a long one for quiet flows, like mosh
+    #[arg(long, value_name = "seconds", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    udp_egress_timeout_sec: Option<Duration>,
+
+    /// Maximum number of concurrent UDP flows toward the destinations of the server, each one holding a socket.
+    /// Tunnels above the limit are refused, with a quota exceeded reason
+    #[arg(long, value_name = "INT", verbatim_doc_comment)]
+    udp_max_flows: Option<usize>,
+
+    /// (linux only) Connect to the destinations of tunnels from this network namespace, instead of the one of the server.
+    /// Either a NAME of `ip netns`, or a path like /proc/PID/ns/net. Needs CAP_SYS_ADMIN.
+    /// The server keeps listening, and resolving destinations, from its own namespace. i.e: to route tunnels over a vpn only
+    #[arg(long, value_name = "NAME", verbatim_doc_comment)]
+    egress_netns: Option<String>,
+
+    /// Ask the router at this ip to forward the ports of reverse tunnel listeners (-R) to the server, with NAT-PMP.
+    /// Useful when the server runs at home behind a NAT, the external address is logged once the port is mapped.
+    /// The mapping is renewed while the listener is open, and removed after
+    #[arg(long, value_name = "GATEWAY_IP", verbatim_doc_comment)]
+    nat_pmp_gateway: Option<Ipv4Addr>,
+
+    /// Server will only accept connection from the specified tunnel information.
+    /// Prefix with tcp: or udp: to only allow this protocol toward the destination
+    /// Can be specified multiple time
+    /// Example: --restrict-to "google.com:443" --restrict-to "tcp:localhost:22" --restrict-to "udp:1.1.1.1:53"
+    #[arg(long, value_name = "[tcp:|udp:]DEST:PORT", verbatim_doc_comment)]
+    restrict_to: Option<Vec<String>>,
+
+    /// Yaml file of named rules, to use instead of --restrict-to. A tunnel is admitted by the first rule allowing it,
+    /// whose name is logged with the tunnel. Rules can also require a user of --auth-htpasswd or a path prefix.
+    /// Example:
+    ///  restrictions:
+    ///    - name: ssh-bastion
+    ///      destinations: ["bastion.corp:22"]
+    ///      protocols: [tcp]            # tcp, udp, reverse_tcp, reverse_udp, reverse_socks5, reverse_unix. All if absent
+    ///      users: [alice, bob]         # Any if absent
+    ///      path_prefixes: [ops]        # Any if absent
+    ///      log_level: debug            # Of the line logged when the rule admits a tunnel. info if absent
+    #[arg(long, value_name = "FILE_PATH", conflicts_with = "restrict_to", verbatim_doc_comment)]
+    restrict_config: Option<PathBuf>,
+
+    /// Connect to another destination than the one requested by the client, i.e: to map a public name to an internal VIP.
+    /// Clients keep asking for the requested destination, and --restrict-to applies to it. Fallbacks are rewritten too.
+    /// Only for tcp and udp tunnels. Can be specified multiple time
+    /// Example: --rewrite db.example.com:5432=10.0.12.4:5432
+    #[arg(long, value_name = "REQUESTED_HOST:PORT=ACTUAL_HOST:PORT", value_parser = parse_rewrite, verbatim_doc_comment)]
+    rewrite: Vec<DestinationRewrite>,
+
+    /// Open a tcp tunnel toward this destination for upgrade requests without tunnel info, instead of refusing them.
+    /// Allows plain websocket clients (i.e: websocat ws://server:8080/v1/events) to connect, and clients to not send their destination.
+    /// --restrict-to and --rewrite apply to it like to a requested destination
+    #[arg(long, value_name = "HOST:PORT", value_parser = parse_destination, verbatim_doc_comment)]
+    default_destination: Option<Destination>,
+
+    /// Connect every tunnel to this destination, whatever the destination requested by the client.
+    /// Only tcp and udp tunnels are accepted, reverse tunnels are refused. Upgrade requests without tunnel info are accepted too.
+    /// The safest way to expose a single internal service
+    #[arg(
+        long,
+        value_name = "HOST:PORT",
+        value_parser = parse_destination,
+        conflicts_with = "default_destination",
+        verbatim_doc_comment
+    )]
+    force_destination: Option<Destination>,
+
+    /// Keep this many tcp connections opened in advance to a destination, usually one of --restrict-to,
+    /// so tunnels toward it do not wait for the tcp handshake. i.e: for an ssh bastion far from the server.
+    /// Idle connections are replaced every minute, and the ones closed by the destination are skipped.
+    /// Can be specified multiple time, once per destination
+    /// Example: --connection-pool bastion.corp:22=4
+    #[arg(long, value_name = "DEST:PORT=SIZE", value_parser = parse_connection_pool, verbatim_doc_comment)]
+    connection_pool: Vec<ConnectionPool>,
+
+    /// Dns resolver to use to lookup ips of domain name
+    /// This option is not going to work if you use transparent proxy
+    /// Can be specified multiple time
+    /// Example:
+    ///  dns://1.1.1.1 for using udp
+    ///  dns+https://1.1.1.1 for using dns over HTTPS
+    ///  dns+tls://8.8.8.8 for using dns over TLS
+    /// To use libc resolver, use
+    /// system://0.0.0.0
+    #[arg(long, verbatim_doc_comment)]
+    dns_resolver: Option<Vec<Url>>,
+
+    /// Number of resolved destinations kept in cache, for the TTL of their dns records.
+    /// Not used with the system:// resolver, as libc does not give the TTL of records
+    #[arg(long, value_name = "INT", default_value = "1024", verbatim_doc_comment)]
+    dns_cache_size: usize,
+
+    /// Maximum time a resolved destination is kept in cache, even if its dns records have a longer TTL
+    #[arg(long, value_name = "seconds", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    dns_cache_max_ttl_sec: Option<Duration>,
+
+    /// Give up on a dns query to a name server after this time, instead of the timeout of resolv.conf (5s by default).
+    /// With the system:// resolver, the whole lookup is abandoned after it. A destination that cannot be resolved
+    /// in time fails the tunnel, instead of stalling it
+    #[arg(long, value_name = "seconds", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    dns_timeout_sec: Option<Duration>,
+
+    /// Number of times a dns query is sent before giving up, instead of the attempts of resolv.conf (2 by default).
+    /// Not used with the system:// resolver
+    #[arg(long, value_name = "INT", verbatim_doc_comment)]
+    dns_attempts: Option<usize>,
+
+    /// Number of name servers a dns query is sent to at once, the first answer is used (2 by default).
+    /// Not used with the system:// resolver
+    #[arg(long, value_name = "INT", verbatim_doc_comment)]
+    dns_concurrency: Option<usize>,
+
+    /// Resolve this host to a static ip, before asking the dns resolver. i.e: for split-horizon or to target a staging server
+    /// Format is host=ip, or @/path/to/file to read overrides in /etc/hosts format.
+    /// Can be specified multiple time. Repeat a host to give it multiple ips
+    /// Example: --dns-override api.example.com=10.0.0.12 --dns-override @/etc/wstunnel/hosts
+    #[arg(long, value_name = "HOST=IP", verbatim_doc_comment)]
+    dns_override: Vec<String>,
+
+    /// Server will only accept connection from if this specific path prefix is used during websocket upgrade.
+    /// Useful if you specify in the client a custom path prefix and you want the server to only allow this one.
+    /// The path prefix act as a secret to authenticate clients
+    /// Disabled by default. Accept all path prefix. Can be specified multiple time
+    /// Behind a reverse proxy stripping the sub-path it mounts the server under, the prefix of its X-Forwarded-Prefix header is accepted too
+    #[arg(
+        short = 'r',
+        long,
+        verbatim_doc_comment,
+        env = "WSTUNNEL_RESTRICT_HTTP_UPGRADE_PATH_PREFIX"
+    )]
+    restrict_http_upgrade_path_prefix: Option<Vec<String>>,
+
+    /// [Optional] Use custom certificate (pem) instead of the default embedded self signed certificate.
+    /// The certificate will be automatically reloaded if it changes
+    #[arg(long, value_name = "FILE_PATH", verbatim_doc_comment)]
+    tls_certificate: Option<PathBuf>,
+
+    /// [Optional] Use a custom tls key (pem, ec, rsa) that the server will use instead of the default embedded one
+    /// The private key will be automatically reloaded if it changes
+    /// It can also be a PKCS#11 uri (RFC 7512) to keep the key in an HSM or smartcard. Only RSA and EC P-256/P-384 keys are supported
+    /// i.e: pkcs11:token=wstunnel;object=server?module-path=/usr/lib/softhsm/libsofthsm2.so&pin-source=/etc/wstunnel/pin
+    #[arg(long, value_name = "FILE_PATH", verbatim_doc_comment)]
+    tls_private_key: Option<PathBuf>,
+
+    /// [Optional] File containing the passphrase of an encrypted --tls-private-key (PKCS#8, or legacy PKCS#1/SEC1 pem).
+    /// Only the first line of the file is used. If the key is encrypted and no file is given, the passphrase is asked on the terminal
+    #[arg(long, value_name = "FILE_PATH", verbatim_doc_comment)]
+    tls_key_password_file: Option<PathBuf>,
+
+    /// [Optional] Once the TLS handshake is done, let the kernel encrypt and decrypt the records (kTLS)
+    /// to avoid copying the data of the tunnels through the TLS library. Linux only, needs the tls kernel module.
+    /// Only TLS 1.3 connections with an AES-GCM or CHACHA20-POLY1305 cipher are offloaded, others stay in userspace
+    #[arg(long, default_value_t = false, verbatim_doc_comment)]
+    tls_ktls: bool,
+
+    /// [Optional] Abort the TLS handshake of clients not presenting this hostname as SNI, before reaching the http layer
+    /// For scanners connecting directly to the ip address of the server to not get a response. Can be specified multiple times
+    /// i.e: --require-sni wstunnel.example.com
+    #[arg(long, value_name = "HOSTNAME", verbatim_doc_comment)]
+    require_sni: Vec<String>,
+
+    /// Server will only accept upgrade requests carrying a valid time based one-time password (TOTP) for this base32 secret.
+    /// A code is valid 30s, with a tolerance of one step for clock drift. It is sent bound to the tunnel id and a nonce,
+    /// and each of these proofs is accepted only once.
+    /// Clients must use the same secret with --auth-totp. Use @/path/to/file to read the secret from a file
+    #[arg(
+        long,
+        value_name = "BASE32_SECRET",
+        value_parser = parse_totp_secret,
+        verbatim_doc_comment,
+        env = "WSTUNNEL_AUTH_TOTP"
+    )]
+    auth_totp: Option<Totp>,
+
+    /// Same as --auth-totp, read from the environment variable with this name
+    #[arg(
+        long,
+        value_name = "VAR",
+        value_parser = from_env(parse_totp_secret),
+        conflicts_with = "auth_totp",
+        verbatim_doc_comment
+    )]
+    auth_totp_env: Option<Totp>,
+
+    /// Server will only accept upgrade requests carrying a JWT signed by one of the keys published at this JWKS url.
+    /// Signature and expiration of the token are verified. The keys are refreshed every hour or when an unknown key id is seen.
+    /// Clients must send the token with -H "Authorization: Bearer <token>"
+    #[arg(long, value_name = "URL", conflicts_with = "auth_htpasswd", verbatim_doc_comment)]
+    auth_jwks_url: Option<Url>,
+
+    /// [Optional] Audience (aud claim) that the JWT must have when using --auth-jwks-url
+    #[arg(long, value_name = "AUDIENCE", requires = "auth_jwks_url", verbatim_doc_comment)]
+    auth_jwks_audience: Option<String>,
+
+    /// Server will only accept upgrade requests with basic auth credentials matching a user of this htpasswd file.
+    /// Only bcrypt (htpasswd -B) and apr1 (htpasswd -m) hashes are supported. The file is reloaded when it changes.
+    /// Clients must use --http-upgrade-credentials USER:PASS
+    #[arg(long, value_name = "FILE_PATH", verbatim_doc_comment)]
+    auth_htpasswd: Option<PathBuf>,
+
+    /// POST each upgrade request, once accepted by the other --auth-* checks, as a JSON document to this url to let it
+    /// allow, deny or route the tunnel. Allows to plug an existing authentication or authorization system. Request:
+    /// {"headers":{"authorization":"..."},"path":"/v1/events","peer_addr":"1.2.3.4:5678","protocol":...,"host":"localhost","port":22,"user":null}
+    /// The response must be one of {"decision":"allow"}, {"decision":"deny","reason":"..."}, {"decision":"route","host":"10.0.0.1","port":22}
+    /// The tunnel is denied if the url cannot be reached within 5s. --force-destination and --restrict-to still apply
+    /// The url must be https, unless it is on a loopback address
+    #[arg(long, value_name = "URL", verbatim_doc_comment)]
+    auth_hook_url: Option<Url>,
+
+    /// Header of the upgrade request to send to --auth-hook-url, i.e: authorization. Can be specified multiple times
+    /// No header is sent by default, as they carry the tunnel jwt and the credentials of the other --auth-* checks
+    #[arg(long, value_name = "HEADER_NAME", requires = "auth_hook_url", verbatim_doc_comment)]
+    auth_hook_header: Vec<HeaderName>,
+
+    /// Refuse clients older than this version, so a security relevant upgrade can be enforced. i.e: 9.2.4
+    /// Clients advertise their version during the upgrade request. Clients too old to do it are refused too
+    #[arg(long, value_name = "VERSION", value_parser = parse_version, verbatim_doc_comment)]
+    min_client_version: Option<Version>,
+
+    /// Refuse clients that do not advertise support of all these features during the upgrade request.
+    /// Features currently advertised by clients are: totp, speed-test, half-close,
+    /// and depending on their config: reuse, resume, retransmit, bond
+    #[arg(long, value_name = "FEATURE,...", value_delimiter = ',', verbatim_doc_comment)]
+    require_client_features: Vec<String>,
+
+    /// Serve the bench endpoint used by `wstunnel client --speed-test`, which echoes, discards or generates traffic.
+    /// Disabled by default, as any client allowed to open a tunnel can use it to make the server send traffic
+    #[arg(long, default_value = "false", verbatim_doc_comment)]
+    enable_bench_endpoint: bool,
+
+    /// Write one line per tunnel, and per rejected request, to this file in the Combined Log Format of apache/nginx.
+    /// Tunnels are logged when they close, with the destination as request and the bytes sent to the client.
+    /// Allows to use existing log analyzers (i.e: GoAccess, fail2ban)
+    #[arg(long, value_name = "FILE_PATH", verbatim_doc_comment)]
+    access_log: Option<PathBuf>,
+
+    /// POST a JSON document to this url for each tunnel opened, closed or rejected, with the metadata of the tunnel.
+    /// Allows to trigger alerts or do custom accounting without scraping logs. Events are sent one at a time, and are
+    /// dropped if the endpoint cannot keep up, it never slows down tunnels. Example of event:
+    /// {"event":"tunnel_closed","timestamp":1709210096,"id":"...","client_ip":"1.2.3.4","protocol":...,"remote":"localhost:22","bytes_tx":2326,"bytes_rx":1234,"duration_ms":4500}
+    #[arg(long, value_name = "URL", verbatim_doc_comment)]
+    webhook_url: Option<Url>,
+
+    /// Hide the server behind a port knocking sequence.
+    /// The server will silently drop connections from an ip until it has knocked, in order, on every port of the sequence.
+    /// Knocking on tcp port is done by opening a connection, on udp by sending any datagram.
+    /// Once the sequence is completed, the ip is allowed as long as it keeps connecting at least once per hour
+    /// Example: --knock udp:7000,tcp:8000,udp:9000
+    #[arg(long, value_name = "{tcp,udp}:PORT,...", value_delimiter = ',', value_parser = parse_knock_step, verbatim_doc_comment)]
+    knock: Vec<KnockStep>,
+
+    /// Time allowed for a client to complete the whole knock sequence
+    #[arg(long, value_name = "seconds", default_value = "10", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    knock_timeout_sec: Duration,
+
+    /// Only accept connections coming from these networks. Can be specified multiple times
+    /// The check is done right after accepting the connection, before the TLS handshake
+    /// Example: --allow-from 192.168.0.0/16 --allow-from 2001:db8::/32 --allow-from 1.2.3.4
+    #[arg(long, value_name = "CIDR", value_parser = parse_cidr, verbatim_doc_comment)]
+    allow_from: Vec<IpNet>,
+
+    /// Refuse connections coming from these networks. Can be specified multiple times
+    /// Takes precedence over --allow-from
+    #[arg(long, value_name = "CIDR", value_parser = parse_cidr, verbatim_doc_comment)]
+    deny_from: Vec<IpNet>,
+
+    /// Path of a MaxMind country database (i.e: GeoLite2-Country.mmdb) used by --allow-countries and --deny-countries
+    #[arg(long, value_name = "FILE_PATH", verbatim_doc_comment)]
+    geoip_db: Option<PathBuf>,
+
+    /// Only accept connections coming from these countries, as ISO 3166-1 codes. i.e: FR,DE
+    /// Connections from an ip without a known country are refused
+    #[arg(
+        long,
+        value_name = "COUNTRY,...",
+        value_delimiter = ',',
+        requires = "geoip_db",
+        verbatim_doc_comment
+    )]
+    allow_countries: Vec<String>,
+
+    /// Refuse connections coming from these countries, as ISO 3166-1 codes. i.e: FR,DE
+    #[arg(
+        long,
+        value_name = "COUNTRY,...",
+        value_delimiter = ',',
+        requires = "geoip_db",
+        verbatim_doc_comment
+    )]
+    deny_countries: Vec<String>,
+
+    /// On SIGTERM/SIGINT, stop accepting new connections and wait up to this time for active tunnels to finish before exiting.
+    /// A second signal forces the exit right away. Allows rolling restarts behind a load balancer without cutting tunnels.
+    /// Without this option, the server exits immediately
+    ///
+    /// (unix only) On SIGUSR2, the server starts its binary again with the same arguments and hands it the listening socket.
+    /// Once the new process serves, the old one drains its tunnels like on SIGTERM, without limit if this option is not set.
+    /// It upgrades the server without refusing a connection. Not available with --knock
+    #[arg(long, value_name = "seconds", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    shutdown_grace_sec: Option<Duration>,
+
+    /// Keep the connection to the destination of a tcp tunnel for up to this many seconds when its websocket breaks,
+    /// for the client to resume the tunnel over a new websocket, i.e: after a network change of the client.
+    /// Only for clients asking for it with --resume-timeout-sec
+    #[arg(long, value_name = "seconds", value_parser = parse_duration_sec, verbatim_doc_comment)]
+    resume_timeout_sec: Option<Duration>,
+
+    /// Keep up to this many bytes sent by a resumable tunnel until the client acknowledges them, to send them again
+    /// if the websocket breaks before they arrive. Only for clients setting --resume-buffer-bytes too.
+    /// At least 65536, per tunnel
+    #[arg(long, value_name = "INT", requires = "resume_timeout_sec", verbatim_doc_comment)]
+    resume_buffer_bytes: Option<usize>,
+
+    /// Validate the configuration and exit, without binding anything.
+    /// TLS certificate and key, auth files and geoip database are loaded and the bind address is resolved.
+    /// Nothing is written either, i.e: the --pcap-dump file is left as is, and the --auth-jwks-url is not fetched.
+    /// Exit with a non zero status and the detailed error if something is wrong, i.e: before restarting the server
+    #[arg(long, default_value = "false", verbatim_doc_comment)]
+    check: bool,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+enum LocalProtocol {
+    Tcp {
+        proxy_protocol: bool,
+    },
+    Udp {
+        timeout: Option<Duration>,
+    },
+    Stdio,
+    Socks5 {
+        timeout: Option<Duration>,
+        limits: Socks5Limits,
+    },
+    TProxyTcp,
+    TProxyUdp {
+        timeout: Option<Duration>,
+    },
+    ReverseTcp,
+    ReverseUdp {
+        timeout: Option<Duration>,
+    },
+    ReverseSocks5,
+    ReverseUnix {
+        path: PathBuf,
+    },
+    Unix {
+        path: PathBuf,
+    },
+    // Bench endpoint of the server, used for speed tests
+    Bench,
+}
+
+impl LocalProtocol {
+    /// Udp tunnels carry one datagram per frame, their frames must not be merged
+    pub fn is_datagram(&self) -> bool {
+        matches!(
+            self,
+            LocalProtocol::Udp { .. } | LocalProtocol::TProxyUdp { .. } | LocalProtocol::ReverseUdp { .. }
+        )
+    }
+
+    pub fn is_reverse_tunnel(&self) -> bool {
+        matches!(
+            self,
+            LocalProtocol::ReverseTcp
+                | LocalProtocol::ReverseUdp { .. }
+                | LocalProtocol::ReverseSocks5
+                | LocalProtocol::ReverseUnix { .. }
+        )
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LocalProtocol::Tcp { .. } => "tcp",
+            LocalProtocol::Udp { .. } => "udp",
+            LocalProtocol::Stdio => "stdio",
+            LocalProtocol::Socks5 { .. } => "socks5",
+            LocalProtocol::TProxyTcp => "tproxy_tcp",
+            LocalProtocol::TProxyUdp { .. } => "tproxy_udp",
+            LocalProtocol::ReverseTcp => "reverse_tcp",
+            LocalProtocol::ReverseUdp { .. } => "reverse_udp",
+            LocalProtocol::ReverseSocks5 => "reverse_socks5",
+            LocalProtocol::ReverseUnix { .. } => "reverse_unix",
+            LocalProtocol::Unix { .. } => "unix",
+            LocalProtocol::Bench => "bench",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LocalToRemote {
+    local_protocol: LocalProtocol,
+    local: SocketAddr,
+    remote: (Host<String>, u16),
+    priority: TunnelPriority,
+    name: Option<String>,
+    max_duration: Option<Duration>,
+    reuse: bool,
+    max_conn: Option<usize>,
+    dual_stack: bool,
+    /// Share the port with other processes listening on it, with SO_REUSEPORT
+    reuse_port: bool,
+    /// Other destinations than remote, to balance the connections over in turn
+    alternates: Vec<Destination>,
+    /// Tried in order by the server when it cannot connect to remote
+    fallbacks: Vec<Destination>,
+    dest_filter: Option<DestinationFilter>,
+    /// Wstunnel server reached through the server, that connects to remote in its place
+    via: Option<Url>,
+}
+
+type Destination = (Host<String>, u16);
+
+/// Destination requested by clients, and the one the server connects to in its place
+#[derive(Clone, Debug)]
+pub struct DestinationRewrite {
+    pub requested: Destination,
+    pub actual: Destination,
+}
+
+impl LocalToRemote {
+    fn destinations(&self) -> Vec<Destination> {
+        iter::once(self.remote.clone())
+            .chain(self.alternates.iter().cloned())
+            .collect()
+    }
+
+    /// Destination of the next connection, in turn among all the ones of the tunnel
+    fn round_robin(&self) -> impl FnMut() -> Destination {
+        let mut destinations = self.destinations().into_iter().cycle();
+        move || destinations.next().unwrap()
+    }
+
+    /// Destination of a udp flow, picked from the hash of its source address.
+    /// A client coming back after its flow expired keeps talking to the same backend, as stateful protocols expect
+    fn sticky(&self) -> impl Fn(&SocketAddr) -> Destination {
+        let destinations = self.destinations();
+        move |peer| {
+            let mut hasher = DefaultHasher::new();
+            peer.hash(&mut hasher);
+            destinations[(hasher.finish() % destinations.len() as u64) as usize].clone()
+        }
+    }
+}
+
+fn parse_duration_sec(arg: &str) -> Result<Duration, io::Error> {
+    use std::io::Error;
+
+    let Ok(secs) = arg.parse::<u64>() else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot duration of seconds from {}", arg),
+        ));
+    };
+
+    Ok(Duration::from_secs(secs))
+}
+
+fn parse_duration_ms(arg: &str) -> Result<Duration, io::Error> {
+    let Ok(millis) = arg.parse::<u64>() else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse duration of milliseconds from {}", arg),
+        ));
+    };
+
+    Ok(Duration::from_millis(millis))
+}
+
+fn parse_local_bind(arg: &str) -> Result<(SocketAddr, &str), io::Error> {
+    use std::io::Error;
+
+    let (bind, remaining) = if arg.starts_with('[') {
+        // ipv6 bind
+        let Some((ipv6_str, remaining)) = arg.split_once(']') else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("cannot parse IPv6 bind from {}", arg),
+            ));
+        };
+        let Ok(ipv6_addr) = Ipv6Addr::from_str(&ipv6_str[1..]) else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("cannot parse IPv6 bind from {}", ipv6_str),
+            ));
+        };
+
+        (IpAddr::V6(ipv6_addr), remaining)
+    } else {
+        // Maybe ipv4 addr
+        let (ipv4_str, remaining) = arg.split_once(':').unwrap_or((arg, ""));
+
+        match Ipv4Addr::from_str(ipv4_str) {
+            Ok(ip4_addr) => (IpAddr::V4(ip4_addr), remaining),
+            // Must be the port, so we default to ipv4 bind
+            Err(_) => (IpAddr::V4(Ipv4Addr::from_str("127.0.0.1").unwrap()), arg),
+        }
+    };
+
+    let remaining = remaining.trim_start_matches(':');
+    let (port_str, remaining) = remaining.split_once([':', '?']).unwrap_or((remaining, ""));
+
+    let Ok(bind_port): Result<u16, _> = port_str.parse() else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse bind port from {}", port_str),
+        ));
+    };
+
+    Ok((SocketAddr::new(bind, bind_port), remaining))
+}
+
+#[allow(clippy::type_complexity)]
+fn parse_tunnel_dest(remaining: &str) -> Result<(Host<String>, u16, BTreeMap<String, String>), io::Error> {
+    use std::io::Error;
+
+    let Ok(remote) = Url::parse(&format!("fake://{}", remaining)) else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse remote from {}", remaining),
+        ));
+    };
+
+    let Some(remote_host) = remote.host() else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse remote host from {}", remaining),
+        ));
+    };
+
+    let Some(remote_port) = remote.port() else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse remote port from {}", remaining),
+        ));
+    };
+
+    let options: BTreeMap<String, String> = remote.query_pairs().into_owned().collect();
+    Ok((remote_host.to_owned(), remote_port, options))
+}
+
+/// Split hostA:80|hostB:80?options into hostA:80?options and the other destinations
+fn split_tunnel_dests(remaining: &str, separator: char) -> Result<(String, Vec<Destination>), io::Error> {
+    let (dests, query) = match remaining.split_once('?') {
+        Some((dests, query)) => (dests, Some(query)),
+        None => (remaining, None),
+    };
+    let mut dests = dests.split(separator);
+    let first = dests.next().unwrap_or_default();
+    let alternates = dests
+        .map(|dest| parse_tunnel_dest(dest).map(|(host, port, _)| (host, port)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let first = match query {
+        Some(query) => format!("{}?{}", first, query),
+        None => first.to_string(),
+    };
+    Ok((first, alternates))
+}
+
+/// Destinations balanced in turn with hostA:80|hostB:80, or tried in order by the server with primary:443,backup:443
+fn parse_tunnel_dests(remaining: &str) -> Result<(String, Vec<Destination>, Vec<Destination>), io::Error> {
+    let (remaining, alternates) = split_tunnel_dests(remaining, '|')?;
+    let (remaining, fallbacks) = split_tunnel_dests(&remaining, ',')?;
+    if !alternates.is_empty() && !fallbacks.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot use both | and , between destinations in {}", remaining),
+        ));
+    }
+    Ok((remaining, alternates, fallbacks))
+}
+
+fn parse_tunnel_priority(options: &BTreeMap<String, String>) -> Result<TunnelPriority, io::Error> {
+    match options.get("priority").map(|x| x.as_str()) {
+        None | Some("normal") => Ok(TunnelPriority::Normal),
+        Some("interactive") => Ok(TunnelPriority::Interactive),
+        Some("bulk") => Ok(TunnelPriority::Bulk),
+        Some(priority) => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid tunnel priority {}, expected interactive, normal or bulk", priority),
+        )),
+    }
+}
+
+fn parse_tunnel_max_duration(options: &BTreeMap<String, String>) -> Result<Option<Duration>, io::Error> {
+    options
+        .get("max_duration_sec")
+        .map(|secs| parse_duration_sec(secs))
+        .transpose()
+}
+
+fn parse_tunnel_max_conn(options: &BTreeMap<String, String>) -> Result<Option<usize>, io::Error> {
+    options
+        .get("max_conn")
+        .map(|max_conn| {
+            max_conn.parse::<usize>().map_err(|_| {
+                io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid max_conn {}, expected a number of connections", max_conn),
+                )
+            })
+        })
+        .transpose()
+}
+
+fn parse_socks5_limits(options: &BTreeMap<String, String>) -> Result<Socks5Limits, io::Error> {
+    let mut limits = Socks5Limits::default();
+    if let Some(secs) = options.get("handshake_timeout_sec") {
+        limits.handshake_timeout = parse_duration_sec(secs)?;
+        if limits.handshake_timeout.is_zero() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "handshake_timeout_sec must be at least 1 second",
+            ));
+        }
+    }
+    if let Some(max) = options.get("max_handshakes") {
+        limits.max_pending_handshakes = match max.parse::<usize>() {
+            Ok(max) if max > 0 => max,
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid max_handshakes {}, expected a positive number of clients", max),
+                ))
+            }
+        };
+    }
+    limits.idle_timeout = options
+        .get("idle_timeout_sec")
+        .map(|secs| parse_duration_sec(secs))
+        .transpose()?
+        .filter(|timeout| !timeout.is_zero());
+    Ok(limits)
+}
+
+/// Wstunnel server to reach through the server, i.e: via=wss://jump.internal:443 to reach a host only visible from it
+fn parse_tunnel_via(options: &BTreeMap<String, String>) -> Result<Option<Url>, io::Error> {
+    options.get("via").map(|via| parse_server_url(via)).transpose()
+}
+
+fn parse_tunnel_reuse_port(options: &BTreeMap<String, String>) -> Result<bool, io::Error> {
+    match options.get("reuseport").map(String::as_str) {
+        None | Some("false") => Ok(false),
+        Some("true") => Ok(true),
+        Some(reuse_port) => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid reuseport {}, expected true or false", reuse_port),
+        )),
+    }
+}
+
+fn parse_tunnel_dual_stack(local: &SocketAddr, options: &BTreeMap<String, String>) -> Result<bool, io::Error> {
+    match options.get("bind").map(String::as_str) {
+        None => Ok(false),
+        Some("dual") if local.ip().is_loopback() || local.ip().is_unspecified() => Ok(true),
+        Some("dual") => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "bind=dual requires a loopback or unspecified address to listen on, got {}",
+                local
+            ),
+        )),
+        Some(bind) => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid bind {}, expected dual", bind),
+        )),
+    }
+}
+
+/// Addresses to listen on for both ipv4 and ipv6 clients.
+/// Unspecified is a single dual-stack socket, as separate 0.0.0.0 and [::] ones conflict on Linux
+fn dual_stack_binds(local: SocketAddr) -> Vec<SocketAddr> {
+    let port = local.port();
+    if local.ip().is_unspecified() {
+        return vec![SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0))];
+    }
+    vec![
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port)),
+        SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, port, 0, 0)),
+    ]
+}
+
+/// One tunnel per address to listen on, for the bind=dual ones
+fn expand_dual_stack(tunnel: LocalToRemote) -> Vec<LocalToRemote> {
+    if !tunnel.dual_stack {
+        return vec![tunnel];
+    }
+    dual_stack_binds(tunnel.local)
+        .into_iter()
+        .map(|local| LocalToRemote {
+            local,
+            ..tunnel.clone()
+        })
+        .collect()
+}
+
+/// Tunnel with the options shared by all local protocols, the ones specific to a protocol are set over it
+fn base_tunnel(
+    local_protocol: LocalProtocol,
+    local: SocketAddr,
+    remote: Destination,
+    options: &BTreeMap<String, String>,
+) -> Result<LocalToRemote, io::Error> {
+    Ok(LocalToRemote {
+        local_protocol,
+        local,
+        remote,
+        priority: parse_tunnel_priority(options)?,
+        name: options.get("name").cloned(),
+        max_duration: parse_tunnel_max_duration(options)?,
+        reuse: false,
+        max_conn: parse_tunnel_max_conn(options)?,
+        dual_stack: false,
+        reuse_port: false,
+        alternates: vec![],
+        fallbacks: vec![],
+        dest_filter: None,
+        via: None,
+    })
+}
+
+fn parse_tunnel_arg(arg: &str) -> Result<LocalToRemote, io::Error> {
+    use std::io::Error;
+
+    match &arg[..6] {
+        "tcp://" => {
+            let (local_bind, remaining) = parse_local_bind(&arg[6..])?;
+            let (remaining, alternates, fallbacks) = parse_tunnel_dests(remaining)?;
+            let (dest_host, dest_port, options) = parse_tunnel_dest(&remaining)?;
+            let proxy_protocol = options.contains_key("proxy_protocol");
+            Ok(LocalToRemote {
+                reuse: options.contains_key("reuse"),
+                dual_stack: parse_tunnel_dual_stack(&local_bind, &options)?,
+                reuse_port: parse_tunnel_reuse_port(&options)?,
+                alternates,
+                fallbacks,
+                via: parse_tunnel_via(&options)?,
+                ..base_tunnel(
+                    LocalProtocol::Tcp { proxy_protocol },
+                    local_bind,
+                    (dest_host, dest_port),
+                    &options,
+                )?
+            })
+        }
+        "udp://" => {
+            let (local_bind, remaining) = parse_local_bind(&arg[6..])?;
+            let (remaining, alternates, fallbacks) = parse_tunnel_dests(remaining)?;
+            let (dest_host, dest_port, options) = parse_tunnel_dest(&remaining)?;
+            let timeout = options
+                .get("timeout_sec")
+                .and_then(|x| x.parse::<u64>().ok())
+                .map(|d| if d == 0 { None } else { Some(Duration::from_secs(d)) })
+                .unwrap_or(Some(Duration::from_secs(30)));
+
+            Ok(LocalToRemote {
+                dual_stack: parse_tunnel_dual_stack(&local_bind, &options)?,
+                alternates,
+                fallbacks,
+                ..base_tunnel(LocalProtocol::Udp { timeout }, local_bind, (dest_host, dest_port), &options)?
+            })
+        }
+        "unix:/" => {
+            let Some((path, remote)) = arg[7..].split_once(':') else {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("cannot parse unix socket path from {}", arg),
+                ));
+            };
+            let (remote, alternates, fallbacks) = parse_tunnel_dests(remote)?;
+            let (dest_host, dest_port, options) = parse_tunnel_dest(&remote)?;
+            Ok(LocalToRemote {
+                reuse: options.contains_key("reuse"),
+                alternates,
+                fallbacks,
+                ..base_tunnel(
+                    LocalProtocol::Unix {
+                        path: PathBuf::from(path),
+                    },
+                    SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)),
+                    (dest_host, dest_port),
+                    &options,
+                )?
+            })
+        }
+        _ => match &arg[..8] {
+            "socks5:/" => {
+                let (local_bind, remaining) = parse_local_bind(&arg[9..])?;
+                let x = format!("0.0.0.0:0?{}", remaining);
+                let (dest_host, dest_port, options) = parse_tunnel_dest(&x)?;
+                let timeout = options
+                    .get("timeout_sec")
+                    .and_then(|x| x.parse::<u64>().ok())
+                    .map(|d| if d == 0 { None } else { Some(Duration::from_secs(d)) })
+                    .unwrap_or(Some(Duration::from_secs(30)));
+                let local_protocol = LocalProtocol::Socks5 {
+                    timeout,
+                    limits: parse_socks5_limits(&options)?,
+                };
+                Ok(LocalToRemote {
+                    dual_stack: parse_tunnel_dual_stack(&local_bind, &options)?,
+                    dest_filter: DestinationFilter::parse(
+                        options.get("allow").map(String::as_str),
+                        options.get("deny").map(String::as_str),
+                    )?,
+                    ..base_tunnel(local_protocol, local_bind, (dest_host, dest_port), &options)?
+                })
+            }
+            "stdio://" => {
+                let (remaining, fallbacks) = split_tunnel_dests(&arg[8..], ',')?;
+                let (dest_host, dest_port, options) = parse_tunnel_dest(&remaining)?;
+                Ok(LocalToRemote {
+                    fallbacks,
+                    ..base_tunnel(
+                        LocalProtocol::Stdio,
+                        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(0), 0)),
+                        (dest_host, dest_port),
+                        &options,
+                    )?
+                })
+            }
+            "tproxy+t" => {
+                let (local_bind, remaining) = parse_local_bind(&arg["tproxy+tcp://".len()..])?;
+                let x = format!("0.0.0.0:0?{}", remaining);
+                let (dest_host, dest_port, options) = parse_tunnel_dest(&x)?;
+                Ok(LocalToRemote {
+                    dual_stack: parse_tunnel_dual_stack(&local_bind, &options)?,
+                    ..base_tunnel(LocalProtocol::TProxyTcp, local_bind, (dest_host, dest_port), &options)?
+                })
+            }
+            "tproxy+u" => {
+                let (local_bind, remaining) = parse_local_bind(&arg["tproxy+udp://".len()..])?;
+                let x = format!("0.0.0.0:0?{}", remaining);
+                let (dest_host, dest_port, options) = parse_tunnel_dest(&x)?;
+                let timeout = options
+                    .get("timeout_sec")
+                    .and_then(|x| x.parse::<u64>().ok())
+                    .map(|d| if d == 0 { None } else { Some(Duration::from_secs(d)) })
+                    .unwrap_or(Some(Duration::from_secs(30)));
+                Ok(LocalToRemote {
+                    dual_stack: parse_tunnel_dual_stack(&local_bind, &options)?,
+                    ..base_tunnel(
+                        LocalProtocol::TProxyUdp { timeout },
+                        local_bind,
+                        (dest_host, dest_port),
+                        &options,
+                    )?
+                })
+            }
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid local protocol for tunnel {}", arg),
+            )),
+        },
+    }
+}
+
+fn parse_rewrite(arg: &str) -> Result<DestinationRewrite, io::Error> {
+    let Some((requested, actual)) = arg.split_once('=') else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "cannot parse rewrite from {}, expected REQUESTED_HOST:PORT=ACTUAL_HOST:PORT",
+                arg
+            ),
+        ));
+    };
+
+    let (requested_host, requested_port, _) = parse_tunnel_dest(requested)?;
+    let (actual_host, actual_port, _) = parse_tunnel_dest(actual)?;
+    Ok(DestinationRewrite {
+        requested: (requested_host, requested_port),
+        actual: (actual_host, actual_port),
+    })
+}
+
+fn parse_destination(arg: &str) -> Result<Destination, io::Error> {
+    let (host, port, _) = parse_tunnel_dest(arg)?;
+    Ok((host, port))
+}
+
+fn parse_reverse_tunnel_arg(arg: &str) -> Result<LocalToRemote, io::Error> {
+    let tunnel = parse_tunnel_arg(arg)?;
+    if tunnel.via.is_some() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("jump servers are only supported by -L tunnels, got {}", arg),
+        ));
+    }
+    if tunnel.reuse_port {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("reuseport is only supported by -L tunnels, got {}", arg),
+        ));
+    }
+    if !tunnel.alternates.is_empty() || !tunnel.fallbacks.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("multiple destinations are only supported by -L tunnels, got {}", arg),
+        ));
+    }
+    // Options of the local listener, the one of a reverse tunnel is on the server
+    let socks5_limits = match &tunnel.local_protocol {
+        LocalProtocol::Socks5 { limits, .. } => *limits != Socks5Limits::default(),
+        _ => false,
+    };
+    let listener_options = [
+        ("max_duration_sec", tunnel.max_duration.is_some()),
+        ("max_conn", tunnel.max_conn.is_some()),
+        ("reuse", tunnel.reuse),
+        ("priority", tunnel.priority != TunnelPriority::default()),
+        ("allow/deny", tunnel.dest_filter.is_some()),
+        ("handshake_timeout_sec/max_handshakes/idle_timeout_sec", socks5_limits),
+    ];
+    if let Some((option, _)) = listener_options.iter().find(|(_, is_set)| *is_set) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} is only supported by -L tunnels, got {}", option, arg),
+        ));
+    }
+    Ok(tunnel)
+}
+
+fn parse_cidr(arg: &str) -> Result<IpNet, io::Error> {
+    if let Ok(net) = IpNet::from_str(arg) {
+        return Ok(net);
+    }
+
+    match IpAddr::from_str(arg) {
+        Ok(ip) => Ok(IpNet::from(ip)),
+        Err(_) => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse network from {}, expected CIDR i.e: 10.0.0.0/8", arg),
+        )),
+    }
+}
+
+fn parse_sni_override(arg: &str) -> Result<DnsName<'static>, io::Error> {
+    match DnsName::try_from(arg.to_string()) {
+        Ok(val) => Ok(val),
+        Err(err) => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid sni override: {}", err),
+        )),
+    }
+}
+
+fn parse_http_headers(arg: &str) -> Result<(HeaderName, HeaderValue), io::Error> {
+    let Some((key, value)) = arg.split_once(':') else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse http header from {}", arg),
+        ));
+    };
+
+    let value = match HeaderValue::from_str(value.trim()) {
+        Ok(value) => value,
+        Err(err) => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("cannot parse http header value from {} due to {:?}", value, err),
+            ))
+        }
+    };
+
+    Ok((HeaderName::from_str(key).unwrap(), value))
+}
+
+/// Value parser of the --*-env flags, which give the name of the environment variable holding the secret.
+/// Secrets on the command line are visible to other users in the process list
+fn from_env<T: 'static>(
+    parser: fn(&str) -> Result<T, io::Error>,
+) -> impl Fn(&str) -> Result<T, io::Error> + Clone + Send + Sync + 'static {
+    move |var| match std::env::var(var) {
+        Ok(value) => parser(&value),
+        Err(err) => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot read env variable {}: {}", var, err),
+        )),
+    }
+}
+
+/// Read a secret given on the command line. If it starts with @, the secret is read from the file at this path
+fn read_secret(arg: &str) -> Result<String, io::Error> {
+    let Some(path) = arg.strip_prefix('@') else {
+        return Ok(arg.to_string());
+    };
+
+    let secret = std::fs::read_to_string(path)
+        .map_err(|err| io::Error::new(err.kind(), format!("cannot read secret from file {}: {}", path, err)))?;
+    Ok(secret.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn parse_http_credentials(arg: &str) -> Result<HeaderValue, io::Error> {
+    let arg = read_secret(arg)?;
+    let encoded = base64::engine::general_purpose::STANDARD.encode(arg.trim().as_bytes());
+    let Ok(header) = HeaderValue::from_str(&format!("Basic {}", encoded)) else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "cannot parse http credentials".to_string(),
+        ));
+    };
+
+    Ok(header)
+}
+
+fn parse_healthcheck_destination(arg: &str) -> Result<RemoteAddr, io::Error> {
+    let Ok(url) = Url::parse(arg) else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse healthcheck destination from {}", arg),
+        ));
+    };
+
+    let protocol = match url.scheme() {
+        "tcp" => LocalProtocol::Tcp { proxy_protocol: false },
+        "udp" => LocalProtocol::Udp { timeout: None },
+        _ => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid healthcheck destination protocol {}, expected tcp or udp", url.scheme()),
+            ))
+        }
+    };
+    let (Some(host), Some(port)) = (url.host(), url.port()) else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "healthcheck destination must be in the form {{tcp,udp}}://HOST:PORT, got {}",
+                arg
+            ),
+        ));
+    };
+
+    Ok(RemoteAddr {
+        protocol,
+        host: host.to_owned(),
+        port,
+        fallbacks: vec![],
+    })
+}
+
+fn parse_server_url(arg: &str) -> Result<Url, io::Error> {
+    let Ok(url) = Url::parse(arg) else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse server url {}", arg),
+        ));
+    };
+
+    if !TransportScheme::values().iter().any(|x| x.to_str() == url.scheme()) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid scheme {}", url.scheme()),
+        ));
+    }
+
+    if url.host().is_none() {
+        return Err(io::Error::new(ErrorKind::InvalidInput, format!("invalid server host {}", arg)));
+    }
+
+    Ok(url)
+}
+
+#[derive(Clone)]
+pub struct TlsClientConfig {
+    pub tls_sni_disabled: bool,
+    pub tls_sni_override: Option<DnsName<'static>>,
+    pub tls_verify_certificate: bool,
+    pub tls_connector: TlsConnector,
+}
+
+#[derive(Debug)]
+pub struct TlsServerConfig {
+    pub tls_certificate: Mutex<Vec<CertificateDer<'static>>>,
+    pub tls_key: Mutex<tls::TlsPrivateKey>,
+    pub tls_certificate_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
+    pub tls_key_password: Option<Redacted<String>>,
+    pub ktls: bool,
+    pub require_sni: Vec<String>,
+}
+
+pub struct WsServerConfig {
+    pub socket_so_mark: Option<u32>,
+    pub bind: SocketAddr,
+    pub restrict_to: Option<Vec<String>>,
+    pub restrictions: Option<Restrictions>,
+    pub rewrites: Vec<DestinationRewrite>,
+    pub default_destination: Option<Destination>,
+    pub force_destination: Option<Destination>,
+    pub connection_pools: Vec<ConnectionPool>,
+    pub restrict_http_upgrade_path_prefix: Option<Vec<String>>,
+    pub websocket_ping_frequency: Option<Duration>,
+    pub timeout_connect: Duration,
+    pub websocket_mask_frame: bool,
+    pub write_batching: Option<WriteBatching>,
+    pub udp_pmtu_discovery: bool,
+    pub udp_full_cone: bool,
+    pub udp_egress_timeout: Option<Duration>,
+    pub udp_flows: Option<Arc<Semaphore>>,
+    pub nat_pmp_gateway: Option<Ipv4Addr>,
+    pub egress_netns: Option<NetNs>,
+    pub tls: Option<TlsServerConfig>,
+    pub dns_resolver: DnsResolver,
+    pub knock_sequence: Vec<KnockStep>,
+    pub knock_timeout: Duration,
+    pub allow_from: Vec<IpNet>,
+    pub deny_from: Vec<IpNet>,
+    pub geoip: Option<GeoIp>,
+    pub auth_totp: Option<Totp>,
+    pub auth_jwks: Option<JwksValidator>,
+    pub auth_htpasswd: Option<Htpasswd>,
+    pub auth_hook: Option<Arc<dyn AuthHook>>,
+    pub min_client_version: Option<Version>,
+    pub required_client_features: Vec<String>,
+    pub bench_endpoint: bool,
+    pub resume_timeout: Option<Duration>,
+    pub resume_buffer: Option<usize>,
+}
+
+impl Debug for WsServerConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WsServerConfig")
+            .field("socket_so_mark", &self.socket_so_mark)
+            .field("bind", &self.bind)
+            .field("restrict_to", &self.restrict_to)
+            .field("restrictions", &self.restrictions)
+            .field("rewrites", &self.rewrites)
+            .field("default_destination", &self.default_destination)
+            .field("force_destination", &self.force_destination)
+            .field("connection_pools", &self.connection_pools)
+            .field(
+                "restrict_http_upgrade_path_prefix",
+                &self.restrict_http_upgrade_path_prefix.as_ref().map(Redacted),
+            )
+            .field("websocket_ping_frequency", &self.websocket_ping_frequency)
+            .field("timeout_connect", &self.timeout_connect)
+            .field("websocket_mask_frame", &self.websocket_mask_frame)
+            .field("write_batching", &self.write_batching)
+            .field("udp_pmtu_discovery", &self.udp_pmtu_discovery)
+            .field("udp_full_cone", &self.udp_full_cone)
+            .field("udp_egress_timeout", &self.udp_egress_timeout)
+            .field("udp_flows", &self.udp_flows)
+            .field("nat_pmp_gateway", &self.nat_pmp_gateway)
+            .field("egress_netns", &self.egress_netns)
+            .field("tls", &self.tls.is_some())
+            .field("knock_sequence", &self.knock_sequence)
+            .field("knock_timeout", &self.knock_timeout)
+            .field("allow_from", &self.allow_from)
+            .field("deny_from", &self.deny_from)
+            .field("geoip", &self.geoip)
+            .field("auth_totp", &self.auth_totp)
+            .field("auth_jwks", &self.auth_jwks)
+            .field("auth_htpasswd", &self.auth_htpasswd)
+            .field("auth_hook", &self.auth_hook.is_some())
+            .field("min_client_version", &self.min_client_version)
+            .field("required_client_features", &self.required_client_features)
+            .field("bench_endpoint", &self.bench_endpoint)
+            .field("resume_timeout", &self.resume_timeout)
+            .field("resume_buffer", &self.resume_buffer)
+            .finish()
+    }
+}
+
+/// Path of the server url, used as the upgrade path prefix instead of the one of the flag
+fn url_path_prefix(url: &Url) -> Option<String> {
+    let path = url.path().trim_matches('/');
+    (!path.is_empty()).then(|| path.to_string())
+}
+
+/// Path prefix as seen by the reverse proxy in front of the server, which strips the external part before forwarding
+fn external_path_prefix(external_prefix: Option<&str>, path_prefix: String) -> String {
+    match external_prefix.map(|prefix| prefix.trim_matches('/')) {
+        Some(external_prefix) if !external_prefix.is_empty() => format!("{}/{}", external_prefix, path_prefix),
+        _ => path_prefix,
+    }
+}
+
+/// Url of the server for the client, which can be the name of a SRV record to discover it with the srv+ prefix
+fn parse_client_server_url(arg: &str) -> Result<Url, io::Error> {
+    let Some(url) = arg.strip_prefix("srv+") else {
+        return parse_server_url(arg);
+    };
+
+    if parse_server_url(url)?.port().is_some() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("port of the server is given by the SRV record, remove it from {}", arg),
+        ));
+    }
+    Url::parse(arg).map_err(|_| io::Error::new(ErrorKind::InvalidInput, format!("cannot parse server url {}", arg)))
+}
+
+/// Resolve the SRV record of a srv+ server url into the url of the server to use
+async fn resolve_server_srv(url: &Url, dns_resolver: &DnsResolver) -> anyhow::Result<Url> {
+    let Some(scheme) = url.scheme().strip_prefix("srv+") else {
+        return Ok(url.clone());
+    };
+
+    let name = url.host_str().unwrap_or_default();
+    let targets = dns_resolver
+        .lookup_srv(name)
+        .await
+        .with_context(|| format!("cannot resolve SRV record {}", name))?;
+    let rand = std::collections::hash_map::RandomState::new().build_hasher().finish();
+    let Some(target) = dns::select_srv_target(&targets, rand) else {
+        return Err(anyhow!("no server advertised by SRV record {}", name));
+    };
+    info!("Using server {}:{} advertised by SRV record {}", target.host, target.port, name);
+
+    let mut server = Url::parse(&format!("{}://{}:{}", scheme, target.host, target.port))?;
+    server.set_path(url.path());
+    server.set_query(url.query());
+    Ok(server)
+}
+
+/// Resolver of the --dns-resolver urls, or of the name servers of resolv.conf if none is given.
+/// None if resolv.conf cannot be read, for the caller to fall back to the system resolver
+fn build_dns_resolver(resolvers: Option<&[Url]>, configure: impl Fn(&mut ResolverOpts)) -> Option<DnsResolver> {
+    let Some(resolvers) = resolvers else {
+        let (cfg, mut opts) = hickory_resolver::system_conf::read_system_conf().ok()?;
+        configure(&mut opts);
+        return Some(DnsResolver::trust_dns(hickory_resolver::AsyncResolver::tokio(cfg, opts)));
+    };
+
+    if resolvers.iter().any(|r| r.scheme() == "system") {
+        return Some(DnsResolver::system());
+    }
+
+    let mut cfg = ResolverConfig::new();
+    for resolver in resolvers {
+        let (protocol, port) = match resolver.scheme() {
+            "dns" => (hickory_resolver::config::Protocol::Udp, resolver.port().unwrap_or(53)),
+            "dns+https" => (hickory_resolver::config::Protocol::Https, resolver.port().unwrap_or(443)),
+            "dns+tls" => (hickory_resolver::config::Protocol::Tls, resolver.port().unwrap_or(853)),
+            _ => Fatal::InvalidConfig.exit("invalid protocol for dns resolver"),
+        };
+        let sock = match resolver.host().unwrap() {
+            Host::Domain(host) => match Host::parse(host) {
+                Ok(Host::Ipv4(ip)) => SocketAddr::V4(SocketAddrV4::new(ip, port)),
+                Ok(Host::Ipv6(ip)) => SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0)),
+                Ok(Host::Domain(_)) | Err(_) => {
+                    Fatal::InvalidConfig.exit(format_args!("Dns resolver must be an ip address, got {}", host))
+                }
+            },
+            Host::Ipv4(ip) => SocketAddr::V4(SocketAddrV4::new(ip, port)),
+            Host::Ipv6(ip) => SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0)),
+        };
+        cfg.add_name_server(NameServerConfig::new(sock, protocol))
+    }
+
+    let mut opts = ResolverOpts::default();
+    configure(&mut opts);
+    Some(DnsResolver::trust_dns(hickory_resolver::AsyncResolver::tokio(cfg, opts)))
+}
+
+/// Resolver of the client, used to lookup the server
+fn client_dns_resolver(args: &Client) -> DnsResolver {
+    let ip_family = IpFamily::from_flags(args.ipv4_only, args.ipv6_only);
+    let dns_resolver = build_dns_resolver(args.dns_resolver.as_deref(), |opts| {
+        let cache_size = opts.cache_size;
+        dns::configure_cache(opts, cache_size, args.dns_refresh_interval);
+        dns::configure_ip_family(opts, ip_family);
+    });
+    dns_resolver
+        .unwrap_or_else(|| {
+            debug!("Fall-backing to system dns resolver");
+            DnsResolver::system()
+        })
+        .with_ip_family(ip_family)
+}
+
+#[derive(Clone)]
+pub struct WsClientConfig {
+    pub remote_addr: TransportAddr,
+    pub socket_so_mark: Option<u32>,
+    pub http_upgrade_path_prefix: String,
+    pub http_upgrade_credentials: Option<HeaderValue>,
+    pub auth_totp: Option<Totp>,
+    pub http_headers: HashMap<HeaderName, HeaderValue>,
+    pub http_headers_file: Option<PathBuf>,
+    pub http_header_host: HeaderValue,
+    pub mimic_browser: bool,
+    pub headers_in_protocol: bool,
+    pub cookie_jar: Option<Arc<CookieJar>>,
+    pub max_redirects: u8,
+    // To build the tls config of the server a redirect leads to, the one of remote_addr only fits this server
+    pub redirect_tls: tls::TlsClientOptions,
+    pub upgrade_retries: u8,
+    pub timeout_connect: Duration,
+    pub websocket_ping_frequency: Duration,
+    pub keepalive: Keepalive,
+    pub websocket_mask_frame: bool,
+    pub write_batching: Option<WriteBatching>,
+    // Set when the server could only be reached with long polling, to not try websocket upgrades for a while
+    pub long_polling_fallback: Arc<LongPollingFallback>,
+    // Shares the bandwidth between the tunnels according to their priority
+    pub scheduler: Arc<TunnelScheduler>,
+    pub http_proxy: Option<Url>,
+    pub http_proxy_negotiate: bool,
+    cnx_pool: Option<bb8::Pool<WsClientConfig>>,
+    pub dns_resolver: DnsResolver,
+    pub circuit_breaker: Option<Arc<CircuitBreaker>>,
+    pub resume_timeout: Option<Duration>,
+    pub resume_buffer: Option<usize>,
+    pub bond: Option<u8>,
+    // Carries the tunnels instead of the transport of the scheme of remote_addr, i.e: an in-memory one for tests
+    pub transport: Option<Arc<dyn DynTunnelTransport>>,
+}
+
+impl WsClientConfig {
+    pub fn websocket_scheme(&self) -> &'static str {
+        match self.remote_addr.tls().is_some() {
+            false => "ws",
+            true => "wss",
+        }
+    }
+
+    pub fn cnx_pool(&self) -> &bb8::Pool<WsClientConfig> {
+        self.cnx_pool.as_ref().unwrap()
+    }
+
+    pub fn websocket_host_url(&self) -> String {
+        format!("{}:{}", self.remote_addr.host(), self.remote_addr.port())
+    }
+
+    pub fn tls_server_name(&self) -> ServerName<'static> {
+        static INVALID_DNS_NAME: Lazy<DnsName<'static>> =
+            Lazy::new(|| DnsName::try_from("dns-name-invalid.com").unwrap());
+
+        match self.remote_addr.tls().and_then(|tls| tls.tls_sni_override.as_ref()) {
+            None => match &self.remote_addr.host() {
+                Host::Domain(domain) => {
+                    ServerName::DnsName(DnsName::try_from(domain.clone()).unwrap_or_else(|_| INVALID_DNS_NAME.clone()))
+                }
+                Host::Ipv4(ip) => ServerName::IpAddress(IpAddr::V4(*ip).into()),
+                Host::Ipv6(ip) => ServerName::IpAddress(IpAddr::V6(*ip).into()),
+            },
+            Some(sni_override) => ServerName::DnsName(sni_override.clone()),
+        }
+    }
+}
+
+fn client_tls_connector(
+    args: &Client,
+    alpn_protocol: &[u8],
+    ech_config: Option<&[u8]>,
+) -> anyhow::Result<TlsConnector> {
+    tls::tls_connector(
+        args.tls_verify_certificate,
+        Some(vec![alpn_protocol.to_vec()]),
+        !args.tls_sni_disable,
+        args.tls_fingerprint,
+        ech_config,
+    )
+    .or_fail(Fatal::TlsFailed, "Cannot create tls connector")
+}
+
+/// ECHConfigList to use for the server, from --tls-ech
+async fn client_ech_config(args: &Client, client_config: &WsClientConfig) -> anyhow::Result<Option<Vec<u8>>> {
+    let Some(source) = &args.tls_ech else {
+        return Ok(None);
+    };
+    if client_config.remote_addr.tls().is_none() {
+        return Err(anyhow!("ECH requires the server to be reached with wss:// or https://"));
+    }
+    if source != "dns" {
+        return tls::load_ech_config(Path::new(source)).map(Some);
+    }
+
+    let ServerName::DnsName(host) = client_config.tls_server_name() else {
+        return Err(anyhow!("ECH requires the server to have a domain name"));
+    };
+    let ech_config = client_config
+        .dns_resolver
+        .lookup_ech_config(host.as_ref(), client_config.remote_addr.port())
+        .await
+        .with_context(|| format!("Cannot resolve HTTPS record of {}", host.as_ref()))?
+        .ok_or_else(|| anyhow!("No ECH config in the HTTPS record of {}", host.as_ref()))?;
+    Ok(Some(ech_config))
+}
+
+fn client_config_for(args: &Client, remote_addr: &Url) -> anyhow::Result<WsClientConfig> {
+    let tls = match TransportScheme::from_str(remote_addr.scheme())
+        .or_fail(Fatal::InvalidConfig, "invalid scheme in server url")?
+    {
+        TransportScheme::Ws | TransportScheme::Http => None,
+        TransportScheme::Wss => Some(TlsClientConfig {
+            tls_connector: client_tls_connector(args, b"http/1.1", None)?,
+            tls_sni_override: args.tls_sni_override.clone(),
+            tls_verify_certificate: args.tls_verify_certificate,
+            tls_sni_disabled: args.tls_sni_disable,
+        }),
+        TransportScheme::Https => Some(TlsClientConfig {
+            tls_connector: client_tls_connector(args, b"h2", None)?,
+            tls_sni_override: args.tls_sni_override.clone(),
+            tls_verify_certificate: args.tls_verify_certificate,
+            tls_sni_disabled: args.tls_sni_disable,
+        }),
+    };
+
+    // Extract host header from http_headers
+    let http_headers = || args.http_headers.iter().chain(&args.http_headers_env);
+    let host_header = if let Some((_, host_val)) = http_headers().find(|(h, _)| *h == HOST) {
+        host_val.clone()
+    } else {
+        let host = match remote_addr.port_or_known_default() {
+            None | Some(80) | Some(443) => remote_addr.host().unwrap().to_string(),
+            Some(port) => format!("{}:{}", remote_addr.host().unwrap(), port),
+        };
+        HeaderValue::from_str(&host).unwrap()
+    };
+    if let Some(path) = &args.http_headers_file {
+        if !path.exists() {
+            return Err(Failure::new(
+                Fatal::InvalidConfig,
+                format_args!("http headers file does not exists: {}", path.display()),
+            ));
+        }
+    }
+    let http_proxy = match args
+        .http_proxy
+        .clone()
+        .or_else(|| args.http_proxy_env.clone())
+        .or_else(|| {
+            (!args.no_env_proxy)
+                .then(|| env_proxy::env_proxy(remote_addr, |name| std::env::var(name).ok()))
+                .flatten()
+        }) {
+        Some(proxy) => {
+            let mut proxy = if proxy.starts_with("http://") {
+                Url::parse(&proxy).or_fail(Fatal::InvalidConfig, "Invalid http proxy url")?
+            } else {
+                Url::parse(&format!("http://{}", proxy)).or_fail(Fatal::InvalidConfig, "Invalid http proxy url")?
+            };
+
+            if let Some(login) = &args.http_proxy_login {
+                proxy
+                    .set_username(login.as_str())
+                    .or_fail(Fatal::InvalidConfig, "Cannot set http proxy login")?;
+            }
+            if let Some(password) = args
+                .http_proxy_password
+                .as_ref()
+                .or(args.http_proxy_password_env.as_ref())
+            {
+                proxy
+                    .set_password(Some(password.as_str()))
+                    .or_fail(Fatal::InvalidConfig, "Cannot set http proxy password")?;
+            }
+            Some(proxy)
+        }
+        None => None,
+    };
+    Ok(WsClientConfig {
+        remote_addr: TransportAddr::new(
+            TransportScheme::from_str(remote_addr.scheme()).unwrap(),
+            remote_addr.host().unwrap().to_owned(),
+            remote_addr.port_or_known_default().unwrap(),
+            tls,
+        )
+        .unwrap(),
+        socket_so_mark: args.socket_so_mark,
+        http_upgrade_path_prefix: external_path_prefix(
+            args.http_upgrade_external_prefix.as_deref(),
+            url_path_prefix(remote_addr).unwrap_or_else(|| args.http_upgrade_path_prefix.clone()),
+        ),
+        http_upgrade_credentials: args
+            .http_upgrade_credentials
+            .clone()
+            .or_else(|| args.http_upgrade_credentials_env.clone()),
+        auth_totp: args.auth_totp.clone().or_else(|| args.auth_totp_env.clone()),
+        http_headers: http_headers().filter(|(k, _)| k != HOST).cloned().collect(),
+        http_headers_file: args.http_headers_file.clone(),
+        http_header_host: host_header,
+        mimic_browser: args.mimic_browser,
+        headers_in_protocol: args.http_upgrade_headers_in_protocol,
+        cookie_jar: args.http_cookies.then(|| Arc::new(CookieJar::default())),
+        max_redirects: args.http_upgrade_max_redirects,
+        redirect_tls: tls::TlsClientOptions {
+            verify_certificate: args.tls_verify_certificate,
+            sni_disabled: args.tls_sni_disable,
+            fingerprint: args.tls_fingerprint,
+        },
+        upgrade_retries: args.http_upgrade_retries,
+        timeout_connect: Duration::from_secs(10),
+        websocket_ping_frequency: args.websocket_ping_frequency_sec.unwrap_or(Duration::from_secs(30)),
+        keepalive: {
+            let frequency = args.websocket_ping_frequency_sec.unwrap_or(Duration::from_secs(30));
+            match args.websocket_ping_adaptive_max_sec {
+                Some(max) => Keepalive::Adaptive(Arc::new(AdaptiveKeepalive::new(frequency, max))),
+                None => Keepalive::Fixed(frequency),
+            }
+        },
+        websocket_mask_frame: args.websocket_mask_frame,
+        write_batching: args.write_batching_ms.map(|delay| WriteBatching {
+            delay,
+            max_bytes: args.write_batching_bytes,
+        }),
+        long_polling_fallback: Arc::new(LongPollingFallback::default()),
+        scheduler: Arc::new(TunnelScheduler::default()),
+        http_proxy,
+        http_proxy_negotiate: args.http_proxy_negotiate,
+        cnx_pool: None,
+        dns_resolver: client_dns_resolver(args),
+        circuit_breaker: args
+            .circuit_breaker_threshold
+            .filter(|threshold| *threshold > 0)
+            .map(|threshold| Arc::new(CircuitBreaker::new(threshold, args.circuit_breaker_cooldown_sec))),
+        resume_timeout: args.resume_timeout_sec.filter(|timeout| !timeout.is_zero()),
+        resume_buffer: args.resume_buffer_bytes,
+        bond: args.bond,
+        transport: None,
+    })
+}
+
+/// Probe the server with the different fallbacks of --auto-fallback, and return the config of the first one reachable
+async fn client_config_with_fallback(args: &Client) -> anyhow::Result<WsClientConfig> {
+    let mut candidates: Vec<Url> = Vec::with_capacity(3);
+    for (scheme, port) in [
+        ("wss", 443),
+        ("wss", args.remote_addr.port_or_known_default().unwrap_or(443)),
+        ("ws", 80),
+    ] {
+        let mut url = args.remote_addr.clone();
+        if url.set_scheme(scheme).is_err() || url.set_port(Some(port)).is_err() {
+            continue;
+        }
+        if !candidates.contains(&url) {
+            candidates.push(url);
+        }
+    }
+
+    // Credentials, totp or custom headers/path prefix, which are meant to be protected by tls
+    let has_secrets = args.http_upgrade_credentials.is_some()
+        || args.http_upgrade_credentials_env.is_some()
+        || args.auth_totp.is_some()
+        || args.auth_totp_env.is_some()
+        || !args.http_headers.is_empty()
+        || !args.http_headers_env.is_empty()
+        || args.http_headers_file.is_some()
+        || args.http_upgrade_path_prefix != "v1"
+        || url_path_prefix(&args.remote_addr).is_some();
+    let is_tls = matches!(args.remote_addr.scheme(), "wss" | "https");
+
+    for url in &candidates {
+        if is_tls && url.scheme() == "ws" {
+            if has_secrets && !args.auto_fallback_allow_plaintext {
+                warn!(
+                    "Auto fallback: not trying {} without tls, as the upgrade request carries secrets. Use --auto-fallback-allow-plaintext to allow it",
+                    url
+                );
+                continue;
+            }
+            warn!(
+                "Auto fallback: trying {} without tls, the tunnels will not be encrypted by wstunnel",
+                url
+            );
+        }
+
+        let client_config = client_config_for(args, url)?;
+        match tokio::time::timeout(client_config.timeout_connect, client_config.connect()).await {
+            Ok(Ok(_)) => {
+                info!("Auto fallback: server is reachable with {}", url);
+                return Ok(client_config);
+            }
+            Ok(Err(err)) => warn!("Auto fallback: cannot reach server with {}: {:?}", url, err),
+            Err(_) => warn!("Auto fallback: timeout while trying to reach server with {}", url),
+        }
+    }
+
+    error!("Auto fallback: server is not reachable, using {}", args.remote_addr);
+    client_config_for(args, &args.remote_addr)
+}
+
+/// Validate the configuration of the client, without connecting to the server nor binding local ports
+async fn check_client_config(args: &Client) -> anyhow::Result<()> {
+    let client_config = client_config_for(args, &args.remote_addr)?;
+
+    let mut hosts = vec![(
+        "server",
+        client_config.remote_addr.host().to_string(),
+        client_config.remote_addr.port(),
+    )];
+    if let Some(proxy) = &client_config.http_proxy {
+        hosts.push((
+            "http proxy",
+            proxy.host_str().unwrap_or_default().to_string(),
+            proxy.port_or_known_default().unwrap_or(80),
+        ));
+    }
+    for (name, host, port) in hosts {
+        if !matches!(Host::parse(&host), Ok(Host::Domain(_))) {
+            continue;
+        }
+        let addrs = client_config
+            .dns_resolver
+            .lookup_host(&host, port)
+            .await
+            .with_context(|| format!("Cannot resolve address of the {} {}", name, host))?;
+        if addrs.is_empty() {
+            return Err(anyhow!("No address found for the {} {}", name, host));
+        }
+        info!("Address of the {} {} resolves to {:?}", name, host, addrs);
+    }
+
+    if client_ech_config(args, &client_config).await?.is_some() {
+        info!("ECH config found for the server");
+    }
+
+    for tunnel in args.local_to_remote.iter() {
+        info!(
+            "Tunnel {:?} {} => {}:{}",
+            tunnel.local_protocol, tunnel.local, tunnel.remote.0, tunnel.remote.1
+        );
+    }
+    for tunnel in args.remote_to_local.iter() {
+        info!(
+            "Reverse tunnel {:?} {} <= {}:{}",
+            tunnel.local_protocol, tunnel.local, tunnel.remote.0, tunnel.remote.1
+        );
+    }
+
+    Ok(())
+}
+
+async fn create_client_config(args: &Client) -> anyhow::Result<Arc<WsClientConfig>> {
+    let mut client_config = if args.auto_fallback {
+        client_config_with_fallback(args).await?
+    } else {
+        client_config_for(args, &args.remote_addr)?
+    };
+
+    let ech_config = client_ech_config(args, &client_config)
+        .await
+        .or_fail(Fatal::TlsFailed, "Cannot get the ECH config of the server")?;
+    if let Some(ech_config) = ech_config {
+        let alpn_protocol: &[u8] = if client_config.remote_addr.is_http2() {
+            b"h2"
+        } else {
+            b"http/1.1"
+        };
+        let tls_connector = client_tls_connector(args, alpn_protocol, Some(&ech_config))?;
+        if let Some(tls) = client_config.remote_addr.tls_mut() {
+            tls.tls_connector = tls_connector;
+        }
+        info!("Using ECH to hide the server name in the TLS handshake");
+    }
+
+    with_cnx_pool(args, client_config).await
+}
+
+async fn with_cnx_pool(args: &Client, mut client_config: WsClientConfig) -> anyhow::Result<Arc<WsClientConfig>> {
+    let pool = bb8::Pool::builder()
+        .max_size(1000)
+        .min_idle(Some(args.connection_min_idle))
+        .max_lifetime(Some(Duration::from_secs(30)))
+        .retry_connection(true)
+        .build(client_config.clone())
+        .await
+        .map_err(|err| {
+            Failure::new(Fatal::from_error(&err), format_args!("Cannot connect to the server: {:?}", err))
+        })?;
+    client_config.cnx_pool = Some(pool);
+    Ok(Arc::new(client_config))
+}
+
+/// Config to reach the wstunnel server `via` of a tunnel, with the same options as the server.
+/// A local port is tunneled to it through the server, the jump server is then reached as if it was local
+async fn jump_client_config(
+    args: &Client,
+    client_config: Arc<WsClientConfig>,
+    via: &Url,
+) -> anyhow::Result<Arc<WsClientConfig>> {
+    let (Some(jump_host), Some(jump_port)) = (via.host(), via.port_or_known_default()) else {
+        return Err(Failure::new(Fatal::InvalidConfig, format_args!("Invalid jump server {}", via)));
+    };
+    let listener = tokio::net::TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+        .await
+        .or_fail(Fatal::BindFailed, "Cannot bind local port toward jump server")?;
+    let local_port = listener.local_addr().map(|addr| addr.port()).unwrap_or_default();
+    info!(
+        "Reaching jump server {} through the server, from local port {}",
+        via, local_port
+    );
+
+    let remote = RemoteAddr {
+        protocol: LocalProtocol::Tcp { proxy_protocol: false },
+        host: jump_host.to_owned(),
+        port: jump_port,
+        fallbacks: vec![],
+    };
+    let server = TcpListenerStream::new(listener)
+        .map_err(anyhow::Error::new)
+        .map_ok(move |stream| (stream.into_split(), remote.clone(), None));
+    let options = ListenerOptions {
+        name: Some(format!("jump {}", via)),
+        ..Default::default()
+    };
+    tokio::spawn(async move {
+        if let Err(err) = tunnel::client::run_tunnel(client_config, options, None, server).await {
+            error!("{:?}", err);
+        }
+    });
+
+    let mut local_url = via.clone();
+    let _ = local_url.set_host(Some("127.0.0.1"));
+    let _ = local_url.set_port(Some(local_port));
+    let mut jump_config = client_config_for(args, &local_url)?;
+    // The proxy is only needed to reach the server, and the jump server is expected under its own name
+    jump_config.http_proxy = None;
+    let host_header = match jump_port {
+        80 | 443 => jump_host.to_string(),
+        port => format!("{}:{}", jump_host, port),
+    };
+    if let Ok(host_header) = HeaderValue::from_str(&host_header) {
+        jump_config.http_header_host = host_header;
+    }
+    if let (Some(tls), Host::Domain(domain)) = (jump_config.remote_addr.tls_mut(), jump_host) {
+        tls.tls_sni_override = DnsName::try_from(domain.to_string()).ok();
+    }
+    with_cnx_pool(args, jump_config).await
+}
+
+/// Validate the configuration of the server, without binding anything nor contacting the jwks or auth hook url.
+/// An encrypted private key is only decrypted with --tls-key-password-file, to not prompt for its passphrase
+fn check_server_config(args: &Server) -> anyhow::Result<()> {
+    if args.remote_addr.scheme() == "wss" {
+        if let Some(cert_path) = &args.tls_certificate {
+            tls::load_certificates_from_pem(cert_path).context("Cannot load tls certificate")?;
+        }
+        let pkcs11_uri = args
+            .tls_private_key
+            .as_ref()
+            .and_then(|key_path| key_path.to_str())
+            .filter(|key_path| pkcs11::is_pkcs11_uri(key_path));
+        match (pkcs11_uri, &args.tls_private_key) {
+            (Some(uri), _) => {
+                pkcs11::Pkcs11Key::load(uri).context("Cannot load tls private key from PKCS#11")?;
+            }
+            (None, Some(key_path)) => {
+                let password = args
+                    .tls_key_password_file
+                    .as_deref()
+                    .map(tls::read_private_key_password)
+                    .transpose()
+                    .context("Cannot read tls private key passphrase")?;
+                if password.is_none() && tls::is_private_key_encrypted(key_path) {
+                    warn!(
+                        "Tls private key {} is encrypted, it is not checked without --tls-key-password-file",
+                        key_path.display()
+                    );
+                } else {
+                    tls::load_private_key_from_file(key_path, password.as_deref())
+                        .context("Cannot load tls private key")?;
+                }
+            }
+            (None, None) => {}
+        }
+    }
+
+    let bind = args
+        .remote_addr
+        .socket_addrs(|| Some(8080))
+        .ok()
+        .and_then(|addrs| addrs.first().copied())
+        .ok_or_else(|| anyhow!("Cannot resolve bind address {}", args.remote_addr))?;
+    info!("Server would listen on {}", bind);
+
+    dns::parse_overrides(&args.dns_override).context("Invalid dns override")?;
+    if let Some(path) = &args.restrict_config {
+        Restrictions::from_file(path).context("Cannot load restriction rules")?;
+    }
+    if let Some(path) = &args.auth_htpasswd {
+        Htpasswd::from_file(path).context("Cannot load htpasswd file")?;
+    }
+    if let Some(path) = &args.geoip_db {
+        GeoIp::new(path, args.allow_countries.clone(), args.deny_countries.clone())
+            .context("Cannot load geoip database")?;
+    }
+    if let Some(name) = &args.egress_netns {
+        NetNs::open(name).context("Cannot use egress network namespace")?;
+    }
+    if let Some(url) = &args.auth_hook_url {
+        HttpAuthHook::new(url.clone(), args.auth_hook_header.clone(), DnsResolver::system())
+            .context("Cannot setup auth hook")?;
+    }
+    if let Some(url) = &args.auth_jwks_url {
+        info!("Jwks are not fetched from {} to check the configuration", url);
+    }
+
+    Ok(())
+}
+
+/// Exit with the result of --check
+fn exit_checked(ret: anyhow::Result<()>) -> ! {
+    match ret {
+        Ok(_) => {
+            info!("Configuration is valid");
+            std::process::exit(0);
+        }
+        Err(err) => match Fatal::from_error(&err) {
+            Fatal::Other => Fatal::InvalidConfig.exit(format_args!("Invalid configuration: {:?}", err)),
+            kind => kind.exit(format_args!("Invalid configuration: {:?}", err)),
+        },
+    }
+}
+
+/// Setup what is global to the process, once the logging is. Skipped by --check, as it binds or writes files
+async fn init_process(args: &Wstunnel) {
+    if args.commands.is_check() {
+        return;
+    }
+
+    if let Some(statsd_addr) = &args.statsd_addr {
+        statsd::init(statsd_addr, args.statsd_prefix.clone())
+            .await
+            .or_exit(Fatal::InvalidConfig, "Cannot setup statsd metrics");
+    }
+    if let Some(path) = &args.pcap_dump {
+        pcap::init(path).or_exit(Fatal::InvalidConfig, "Cannot setup pcap dump");
+    }
+    tcp::set_listen_backlog(args.listen_backlog);
+    tcp::raise_fd_limit();
+    hooks::init(args.on_connect_cmd.clone(), args.on_disconnect_cmd.clone())
+        .or_exit(Fatal::InvalidConfig, "Cannot setup hook commands");
+    if let Some(capacity) = args.flight_recorder {
+        let dump_on_signal = matches!(args.commands, Commands::Client(_));
+        flight_recorder::init(capacity, dump_on_signal).or_exit(Fatal::InvalidConfig, "Cannot setup flight recorder");
+    }
+}
+
+async fn create_server_config(args: Server) -> anyhow::Result<WsServerConfig> {
+    let tls_config = if args.remote_addr.scheme() == "wss" {
+        let tls_certificate = if let Some(cert_path) = &args.tls_certificate {
+            tls::load_certificates_from_pem(cert_path).or_fail(Fatal::TlsFailed, "Cannot load tls certificate")?
+        } else {
+            embedded_certificate::TLS_CERTIFICATE.clone()
+        };
+
+        let tls_key_password = match (&args.tls_key_password_file, &args.tls_private_key) {
+            (Some(password_file), _) => Some(
+                tls::read_private_key_password(password_file)
+                    .or_fail(Fatal::InvalidConfig, "Cannot read tls private key passphrase")?,
+            ),
+            (None, Some(key_path)) if tls::is_private_key_encrypted(key_path) => Some(
+                rpassword::prompt_password(format!("Passphrase for tls private key {}: ", key_path.display()))
+                    .or_fail(Fatal::InvalidConfig, "Cannot read tls private key passphrase")?,
+            ),
+            (None, _) => None,
+        };
+
+        let pkcs11_uri = args
+            .tls_private_key
+            .as_ref()
+            .and_then(|key_path| key_path.to_str())
+            .filter(|key_path| pkcs11::is_pkcs11_uri(key_path));
+        let tls_key = match (pkcs11_uri, &args.tls_private_key) {
+            (Some(uri), _) => tls::TlsPrivateKey::Pkcs11(
+                pkcs11::Pkcs11Key::load(uri).or_fail(Fatal::TlsFailed, "Cannot load tls private key from PKCS#11")?,
+            ),
+            (None, Some(key_path)) => tls::TlsPrivateKey::Der(
+                tls::load_private_key_from_file(key_path, tls_key_password.as_deref())
+                    .or_fail(Fatal::TlsFailed, "Cannot load tls private key")?,
+            ),
+            (None, None) => tls::TlsPrivateKey::Der(embedded_certificate::TLS_PRIVATE_KEY.clone_key()),
+        };
+        // A key in a PKCS#11 token is not a file that can be watched for changes
+        let tls_key_path = if pkcs11_uri.is_some() {
+            None
+        } else {
+            args.tls_private_key.clone()
+        };
+
+        if args.tls_ktls && !cfg!(target_os = "linux") {
+            warn!("kTLS is only supported on linux, TLS records will be handled in userspace");
+        }
+
+        Some(TlsServerConfig {
+            tls_certificate: Mutex::new(tls_certificate),
+            tls_key: Mutex::new(tls_key),
+            tls_certificate_path: args.tls_certificate,
+            tls_key_path,
+            tls_key_password: tls_key_password.map(Redacted),
+            ktls: args.tls_ktls,
+            require_sni: args.require_sni.iter().map(|sni| sni.to_ascii_lowercase()).collect(),
+        })
+    } else {
+        None
+    };
+
+    let ip_family = IpFamily::from_flags(args.ipv4_only, args.ipv6_only);
+    let dns_resolver = build_dns_resolver(args.dns_resolver.as_deref(), |opts| {
+        dns::configure_cache(opts, args.dns_cache_size, args.dns_cache_max_ttl_sec);
+        dns::configure_lookup(opts, args.dns_timeout_sec, args.dns_attempts, args.dns_concurrency);
+        dns::configure_ip_family(opts, ip_family);
+    })
+    .unwrap_or_else(|| {
+        warn!("Fall-backing to system dns resolver. You should consider specifying a dns resolver. To avoid performance issue");
+        DnsResolver::system()
+    })
+    .with_overrides(dns::parse_overrides(&args.dns_override).or_fail(Fatal::InvalidConfig, "Invalid dns override")?)
+    .with_ip_family(ip_family)
+    .with_system_lookup_timeout(args.dns_timeout_sec);
+
+    let auth_jwks = match args.auth_jwks_url {
+        Some(url) => Some(
+            JwksValidator::new(url, args.auth_jwks_audience, dns_resolver.clone())
+                .await
+                .or_fail(Fatal::Other, "Cannot fetch jwks")?,
+        ),
+        None => None,
+    };
+    let auth_hook = match args.auth_hook_url {
+        Some(url) => {
+            let hook = HttpAuthHook::new(url, args.auth_hook_header, dns_resolver.clone())
+                .or_fail(Fatal::InvalidConfig, "Cannot setup auth hook")?;
+            Some(Arc::new(hook) as Arc<dyn AuthHook>)
+        }
+        None => None,
+    };
+    Ok(WsServerConfig {
+        socket_so_mark: args.socket_so_mark,
+        bind: args
+            .remote_addr
+            .socket_addrs(|| Some(8080))
+            .ok()
+            .and_then(|addrs| addrs.first().copied())
+            .ok_or_else(|| {
+                Failure::new(
+                    Fatal::DnsFailed,
+                    format_args!("Cannot resolve bind address {}", args.remote_addr),
+                )
+            })?,
+        restrict_to: args.restrict_to,
+        restrictions: args
+            .restrict_config
+            .map(|path| Restrictions::from_file(&path).or_fail(Fatal::InvalidConfig, "Cannot load restriction rules"))
+            .transpose()?,
+        rewrites: args.rewrite,
+        default_destination: args.default_destination,
+        force_destination: args.force_destination,
+        connection_pools: args.connection_pool,
+        restrict_http_upgrade_path_prefix: match url_path_prefix(&args.remote_addr) {
+            Some(prefix) => Some(
+                args.restrict_http_upgrade_path_prefix
+                    .unwrap_or_default()
+                    .into_iter()
+                    .chain([prefix])
+                    .collect(),
+            ),
+            None => args.restrict_http_upgrade_path_prefix,
+        },
+        websocket_ping_frequency: args.websocket_ping_frequency_sec,
+        timeout_connect: Duration::from_secs(10),
+        websocket_mask_frame: args.websocket_mask_frame,
+        write_batching: args.write_batching_ms.map(|delay| WriteBatching {
+            delay,
+            max_bytes: args.write_batching_bytes,
+        }),
+        udp_pmtu_discovery: args.udp_pmtu_discovery,
+        udp_full_cone: args.udp_full_cone,
+        udp_egress_timeout: args.udp_egress_timeout_sec.filter(|timeout| !timeout.is_zero()),
+        udp_flows: args.udp_max_flows.map(|max| Arc::new(Semaphore::new(max))),
+        nat_pmp_gateway: args.nat_pmp_gateway,
+        egress_netns: args
+            .egress_netns
+            .map(|name| NetNs::open(&name).or_fail(Fatal::InvalidConfig, "Cannot use egress network namespace"))
+            .transpose()?,
+        tls: tls_config,
+        dns_resolver,
+        knock_sequence: args.knock,
+        knock_timeout: args.knock_timeout_sec,
+        allow_from: args.allow_from,
+        deny_from: args.deny_from,
+        geoip: match &args.geoip_db {
+            Some(path) => Some(
+                GeoIp::new(path, args.allow_countries, args.deny_countries)
+                    .or_fail(Fatal::InvalidConfig, "Cannot load geoip database")?,
+            ),
+            None => None,
+        },
+        auth_totp: args.auth_totp.or(args.auth_totp_env),
+        auth_jwks,
+        auth_htpasswd: args
+            .auth_htpasswd
+            .map(|path| Htpasswd::from_file(&path).or_fail(Fatal::InvalidConfig, "Cannot load htpasswd file"))
+            .transpose()?,
+        auth_hook,
+        min_client_version: args.min_client_version,
+        required_client_features: args.require_client_features,
+        bench_endpoint: args.enable_bench_endpoint,
+        resume_timeout: args.resume_timeout_sec.filter(|timeout| !timeout.is_zero()),
+        resume_buffer: args.resume_buffer_bytes,
+    })
+}
+
+/// Parse the arguments of a subcommand from a list, to get the same defaults as on the command line
+fn parse_args<T: clap::Args + clap::FromArgMatches>(args: impl IntoIterator<Item = String>) -> anyhow::Result<T> {
+    let matches = T::augment_args(clap::Command::new("wstunnel"))
+        .try_get_matches_from(args)
+        .map_err(|err| Failure::new(Fatal::InvalidConfig, err))?;
+    T::from_arg_matches(&matches).map_err(|err| Failure::new(Fatal::InvalidConfig, err))
+}
+
+/// Listen locally for the tunnel, and forward the connections it accepts through the server
+async fn start_local_tunnel(
+    args: &Client,
+    client_config: &Arc<WsClientConfig>,
+    tunnel: LocalToRemote,
+) -> anyhow::Result<()> {
+    let client_config = match &tunnel.via {
+        Some(via) => jump_client_config(args, client_config.clone(), via).await?,
+        None => client_config.clone(),
+    };
+    let options = ListenerOptions {
+        priority: tunnel.priority,
+        name: tunnel.name.clone(),
+        max_duration: tunnel.max_duration,
+        reuse: tunnel.reuse,
+        max_conn: tunnel.max_conn,
+    };
+    let metrics = metrics::ListenerMetrics::new(
+        format!("{}://{}", tunnel.local_protocol.name(), tunnel.local),
+        tunnel.name.clone(),
+    );
+
+    match &tunnel.local_protocol {
+        LocalProtocol::Tcp { proxy_protocol } => {
+            let proxy_protocol = *proxy_protocol;
+            let mut next_destination = tunnel.round_robin();
+            let fallbacks = tunnel.fallbacks.clone();
+            let server = tcp::run_server(tunnel.local, false, tunnel.reuse_port)
+                .await
+                .map_err(|err| {
+                    Failure::new(
+                        Fatal::BindFailed,
+                        format_args!("Cannot start TCP server on {}: {}", tunnel.local, err),
+                    )
+                })?
+                .map_err(anyhow::Error::new)
+                .map_ok(move |stream| {
+                    let (host, port) = next_destination();
+                    let remote = RemoteAddr {
+                        protocol: LocalProtocol::Tcp { proxy_protocol },
+                        host,
+                        port,
+                        fallbacks: fallbacks.clone(),
+                    };
+                    (stream.into_split(), remote, None)
+                });
+
+            tokio::spawn(async move {
+                if let Err(err) = tunnel::client::run_tunnel(client_config, options, metrics, server).await {
+                    error!("{:?}", err);
+                }
+            });
+        }
+        #[cfg(target_os = "linux")]
+        LocalProtocol::TProxyTcp => {
+            let server = tcp::run_server(tunnel.local, true, false)
+                .await
+                .map_err(|err| {
+                    Failure::new(
+                        Fatal::BindFailed,
+                        format_args!("Cannot start TProxy TCP server on {}: {}", tunnel.local, err),
+                    )
+                })?
+                .map_err(anyhow::Error::new)
+                .map_ok(move |stream| {
+                    // In TProxy mode local destination is the final ip:port destination
+                    let (host, port) = to_host_port(stream.local_addr().unwrap());
+                    let remote = RemoteAddr {
+                        protocol: LocalProtocol::Tcp { proxy_protocol: false },
+                        host,
+                        port,
+                        fallbacks: vec![],
+                    };
+                    (stream.into_split(), remote, None)
+                });
+
+            tokio::spawn(async move {
+                if let Err(err) = tunnel::client::run_tunnel(client_config, options, metrics, server).await {
+                    error!("{:?}", err);
+                }
+            });
+        }
+        #[cfg(unix)]
+        LocalProtocol::Unix { path } => {
+            let mut next_destination = tunnel.round_robin();
+            let fallbacks = tunnel.fallbacks.clone();
+            let server = unix_socket::run_server(path)
+                .await
+                .map_err(|err| {
+                    Failure::new(
+                        Fatal::BindFailed,
+                        format_args!("Cannot start Unix domain server on {}: {}", tunnel.local, err),
+                    )
+                })?
+                .map_err(anyhow::Error::new)
+                .map_ok(move |stream| {
+                    let (host, port) = next_destination();
+                    let remote = RemoteAddr {
+                        protocol: LocalProtocol::Tcp { proxy_protocol: false },
+                        host,
+                        port,
+                        fallbacks: fallbacks.clone(),
+                    };
+                    (stream.into_split(), remote, None)
+                });
+
+            tokio::spawn(async move {
+                if let Err(err) = tunnel::client::run_tunnel(client_config, options, metrics, server).await {
+                    error!("{:?}", err);
+                }
+            });
+        }
+        #[cfg(not(unix))]
+        LocalProtocol::Unix { .. } => {
+            return Err(Failure::new(
+                Fatal::InvalidConfig,
+                "Unix socket is not available for non Unix platform",
+            ))
+        }
+
+        #[cfg(target_os = "linux")]
+        LocalProtocol::TProxyUdp { timeout } => {
+            let timeout = *timeout;
+            let server = udp::run_server(tunnel.local, timeout, udp::configure_tproxy, udp::mk_send_socket_tproxy)
+                .await
+                .map_err(|err| {
+                    Failure::new(
+                        Fatal::BindFailed,
+                        format_args!("Cannot start TProxy UDP server on {}: {}", tunnel.local, err),
+                    )
+                })?
+                .map_err(anyhow::Error::new)
+                .map_ok(move |stream| {
+                    // In TProxy mode local destination is the final ip:port destination
+                    let (host, port) = to_host_port(stream.local_addr().unwrap());
+                    let remote = RemoteAddr {
+                        protocol: LocalProtocol::Udp { timeout },
+                        host,
+                        port,
+                        fallbacks: vec![],
+                    };
+                    (tokio::io::split(stream), remote, None)
+                });
+
+            tokio::spawn(async move {
+                if let Err(err) = tunnel::client::run_tunnel(client_config, options, metrics, server).await {
+                    error!("{:?}", err);
+                }
+            });
+        }
+        #[cfg(not(target_os = "linux"))]
+        LocalProtocol::TProxyTcp | LocalProtocol::TProxyUdp { .. } => {
+            return Err(Failure::new(
+                Fatal::InvalidConfig,
+                "Transparent proxy is not available for non Linux platform",
+            ))
+        }
+        LocalProtocol::Udp { timeout } => {
+            let flow_destination = tunnel.sticky();
+            let fallbacks = tunnel.fallbacks.clone();
+            let timeout = *timeout;
+            let server = udp::run_server(tunnel.local, timeout, |_| Ok(()), |s| Ok(s.clone()))
+                .await
+                .map_err(|err| {
+                    Failure::new(
+                        Fatal::BindFailed,
+                        format_args!("Cannot start UDP server on {}: {}", tunnel.local, err),
+                    )
+                })?
+                .map_err(anyhow::Error::new)
+                .map_ok(move |stream| {
+                    let (host, port) = flow_destination(&stream.peer_addr());
+                    let remote = RemoteAddr {
+                        protocol: LocalProtocol::Udp { timeout },
+                        host,
+                        port,
+                        fallbacks: fallbacks.clone(),
+                    };
+                    (tokio::io::split(stream), remote, None)
+                });
+
+            tokio::spawn(async move {
+                if let Err(err) = tunnel::client::run_tunnel(client_config, options, metrics, server).await {
+                    error!("{:?}", err);
+                }
+            });
+        }
+        LocalProtocol::Socks5 { timeout, limits } => {
+            let dest_filter = tunnel.dest_filter.clone();
+            let server = socks5::run_server(tunnel.local, *timeout, *limits)
+                .await
+                .map_err(|err| {
+                    Failure::new(
+                        Fatal::BindFailed,
+                        format_args!("Cannot start Socks5 server on {}: {}", tunnel.local, err),
+                    )
+                })?
+                .try_filter_map(move |(stream, (host, port), reply)| {
+                    if dest_filter.as_ref().is_some_and(|f| !f.is_allowed(&host, port)) {
+                        warn!("Refusing socks5 request to {}:{}, not allowed by the listener", host, port);
+                        if let Some(reply) = reply {
+                            reply.send(Err(CloseReason::Restricted));
+                        }
+                        return future::ready(Ok(None));
+                    }
+                    future::ready(Ok(Some((stream, (host, port), reply))))
+                })
+                .map_ok(|(stream, (host, port), reply)| {
+                    let remote = RemoteAddr {
+                        protocol: stream.local_protocol(),
+                        host,
+                        port,
+                        fallbacks: vec![],
+                    };
+                    let reply = reply.map(|reply| Box::new(move |tunnel| reply.send(tunnel)) as TunnelReply);
+                    (tokio::io::split(stream), remote, reply)
+                });
+
+            tokio::spawn(async move {
+                if let Err(err) = tunnel::client::run_tunnel(client_config, options, metrics, server).await {
+                    error!("{:?}", err);
+                }
+            });
+        }
+
+        LocalProtocol::Stdio => {
+            let server = stdio::server::run_server()
+                .await
+                .map_err(|err| Failure::new(Fatal::Other, format_args!("Cannot start STDIO server: {}", err)))?;
+            tokio::spawn(async move {
+                if let Err(err) = tunnel::client::run_tunnel(
+                    client_config,
+                    options,
+                    metrics,
+                    stream::once(async move {
+                        let remote = RemoteAddr {
+                            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+                            host: tunnel.remote.0,
+                            port: tunnel.remote.1,
+                            fallbacks: tunnel.fallbacks,
+                        };
+                        Ok((server, remote, None))
+                    }),
+                )
+                .await
+                {
+                    error!("{:?}", err);
+                }
+            });
+        }
+        LocalProtocol::ReverseTcp => {}
+        LocalProtocol::ReverseUdp { .. } => {}
+        LocalProtocol::ReverseSocks5 => {}
+        LocalProtocol::ReverseUnix { .. } => {}
+        LocalProtocol::Bench => {}
+    }
+
+    Ok(())
+}
+
+/// Entry point of the wstunnel binary: parse the command line and run the subcommand.
+/// It exits the process on failure, with one of the codes of `--help`
+pub async fn run() {
+    let args = Wstunnel::parse();
+
+    // Setup logging
+    match &args.commands {
+        // Disable logging if there is a stdio tunnel
+        Commands::Client(args)
+            if args
+                .local_to_remote
+                .iter()
+                .filter(|x| x.local_protocol == LocalProtocol::Stdio)
+                .count()
+                > 0 => {}
+        _ => {
+            let mut env_filter = EnvFilter::builder()
+                .parse(&args.log_lvl)
+                .or_exit(Fatal::InvalidConfig, "Invalid log level");
+            if !(args.log_lvl.contains("h2::") || args.log_lvl.contains("h2=")) {
+                env_filter =
+                    env_filter.add_directive(Directive::from_str("h2::codec=off").expect("Invalid log directive"));
+            }
+            // The log level only filters the logs, tokio-console needs the traces of the runtime whatever it is
+            let logs = tracing_subscriber::fmt::layer()
+                .with_ansi(args.no_color.is_none())
+                .with_filter(env_filter);
+            let registry = tracing_subscriber::registry().with(logs);
+            match args.tokio_console.filter(|_| !args.commands.is_check()) {
+                None => registry.init(),
+                Some(addr) => registry
+                    .with(tokio_console::layer(addr).or_exit(Fatal::InvalidConfig, "Cannot serve tokio-console"))
+                    .init(),
+            }
+        }
+    }
+
+    tls::install_crypto_provider(args.tls_crypto_provider)
+        .or_exit(Fatal::InvalidConfig, "Cannot use TLS crypto provider");
+    init_process(&args).await;
+
+    match args.commands {
+        Commands::Client(mut args) => {
+            args.remote_addr = resolve_server_srv(&args.remote_addr, &client_dns_resolver(&args))
+                .await
+                .unwrap_or_else(|err| Fatal::DnsFailed.exit(format_args!("Cannot discover the server: {:?}", err)));
+            if let (None, None, Some(pac_url)) = (&args.http_proxy, &args.http_proxy_env, &args.proxy_pac_url) {
+                match pac::find_proxy(pac_url, &args.remote_addr, Duration::from_secs(10)).await {
+                    Ok(proxy) => {
+                        info!("PAC script chose {}", proxy.as_deref().unwrap_or("to connect directly"));
+                        args.no_env_proxy |= proxy.is_none();
+                        args.http_proxy = proxy;
+                    }
+                    Err(err) => warn!("Cannot use PAC script {}: {:?}", pac_url, err),
+                }
+            }
+            if args.check {
+                exit_checked(check_client_config(&args).await);
+            }
+
+            let client_config = or_exit_on_failure(create_client_config(&args).await);
+            if args.speed_test {
+                match speed_test::run_speed_test(client_config).await {
+                    Ok(_) => std::process::exit(0),
+                    Err(err) => Fatal::from_error(&err).exit(format_args!("Speed test failed: {:?}", err)),
+                }
+            }
+
+            if let Some(socket_path) = &args.admin_socket {
+                #[cfg(unix)]
+                {
+                    admin::run_server(socket_path, client_config.clone())
+                        .await
+                        .or_exit(Fatal::BindFailed, "Cannot start admin socket");
+                }
+                #[cfg(not(unix))]
+                {
+                    Fatal::InvalidConfig.exit(format_args!("Admin socket {:?} is only supported on unix", socket_path));
+                }
+            }
+
+            if let Some(metrics_addr) = args.metrics_addr {
+                metrics::init(metrics_addr)
+                    .await
+                    .or_exit(Fatal::BindFailed, "Cannot start metrics endpoint");
+            }
+
+            // Start tunnels
+            for tunnel in std::mem::take(&mut args.remote_to_local) {
+                let client_config = client_config.clone();
+                let name = tunnel.name.clone();
+                let metrics = metrics::ListenerMetrics::new(
+                    format!("{}://{}", tunnel.local_protocol.name(), tunnel.local),
+                    tunnel.name.clone(),
+                );
+                match &tunnel.local_protocol {
+                    LocalProtocol::Tcp { proxy_protocol: _ } => {
+                        tokio::spawn(async move {
+                            let remote = tunnel.remote.clone();
+                            let cfg = client_config.clone();
+                            let connect_to_dest = |_| async {
+                                tcp::connect(
+                                    &remote.0,
+                                    remote.1,
+                                    cfg.socket_so_mark,
+                                    cfg.timeout_connect,
+                                    &cfg.dns_resolver,
+                                )
+                                .await
+                            };
+
+                            let (host, port) = to_host_port(tunnel.local);
+                            let remote = RemoteAddr {
+                                protocol: LocalProtocol::ReverseTcp,
+                                host,
+                                port,
+                                fallbacks: vec![],
+                            };
+                            if let Err(err) = tunnel::client::run_reverse_tunnel(
+                                client_config,
+                                remote,
+                                name,
+                                metrics,
+                                connect_to_dest,
+                            )
+                            .await
+                            {
+                                error!("{:?}", err);
+                            }
+                        });
+                    }
+                    LocalProtocol::Udp { timeout } => {
+                        let timeout = *timeout;
+
+                        tokio::spawn(async move {
+                            let cfg = client_config.clone();
+                            let (host, port) = to_host_port(tunnel.local);
+                            let remote = RemoteAddr {
+                                protocol: LocalProtocol::ReverseUdp { timeout },
+                                host,
+                                port,
+                                fallbacks: vec![],
+                            };
+                            let connect_to_dest = |_| async {
+                                udp::connect(&tunnel.remote.0, tunnel.remote.1, cfg.timeout_connect, &cfg.dns_resolver)
+                                    .await
+                            };
+
+                            if let Err(err) = tunnel::client::run_reverse_tunnel(
+                                client_config,
+                                remote,
+                                name,
+                                metrics,
+                                connect_to_dest,
+                            )
+                            .await
+                            {
+                                error!("{:?}", err);
+                            }
+                        });
+                    }
+                    LocalProtocol::Socks5 { .. } => {
+                        trait T: AsyncWrite + AsyncRead + Unpin + Send {}
+                        impl T for TcpStream {}
+                        impl T for MyUdpSocket {}
+
+                        tokio::spawn(async move {
+                            let cfg = client_config.clone();
+                            let (host, port) = to_host_port(tunnel.local);
+                            let remote = RemoteAddr {
+                                protocol: LocalProtocol::ReverseSocks5,
+                                host,
+                                port,
+                                fallbacks: vec![],
+                            };
+                            let connect_to_dest = |remote: Option<RemoteAddr>| {
+                                let so_mark = cfg.socket_so_mark;
+                                let timeout = cfg.timeout_connect;
+                                let dns_resolver = &cfg.dns_resolver;
+                                async move {
+                                    let Some(remote) = remote else {
+                                        return Err(anyhow!("Missing remote destination for reverse socks5"));
+                                    };
+
+                                    match remote.protocol {
+                                        LocalProtocol::Tcp { proxy_protocol: _ } => {
+                                            tcp::connect(&remote.host, remote.port, so_mark, timeout, dns_resolver)
+                                                .await
+                                                .map(|s| Box::new(s) as Box<dyn T>)
+                                        }
+                                        LocalProtocol::Udp { .. } => {
+                                            udp::connect(&remote.host, remote.port, timeout, dns_resolver)
+                                                .await
+                                                .map(|s| Box::new(s) as Box<dyn T>)
+                                        }
+                                        _ => Err(anyhow!("Invalid protocol for reverse socks5 {:?}", remote.protocol)),
+                                    }
+                                }
+                            };
+
+                            if let Err(err) = tunnel::client::run_reverse_tunnel(
+                                client_config,
+                                remote,
+                                name,
+                                metrics,
+                                connect_to_dest,
+                            )
+                            .await
+                            {
+                                error!("{:?}", err);
+                            }
+                        });
+                    }
+                    #[cfg(unix)]
+                    LocalProtocol::Unix { path } => {
+                        let path = path.clone();
+                        tokio::spawn(async move {
+                            let remote = tunnel.remote.clone();
+                            let cfg = client_config.clone();
+                            let connect_to_dest = |_| async {
+                                tcp::connect(
+                                    &remote.0,
+                                    remote.1,
+                                    cfg.socket_so_mark,
+                                    cfg.timeout_connect,
+                                    &cfg.dns_resolver,
+                                )
+                                .await
+                            };
+
+                            let (host, port) = to_host_port(tunnel.local);
+                            let remote = RemoteAddr {
+                                protocol: LocalProtocol::ReverseUnix { path: path.clone() },
+                                host,
+                                port,
+                                fallbacks: vec![],
+                            };
+                            if let Err(err) = tunnel::client::run_reverse_tunnel(
+                                client_config,
+                                remote,
+                                name,
+                                metrics,
+                                connect_to_dest,
+                            )
+                            .await
+                            {
+                                error!("{:?}", err);
+                            }
+                        });
+                    }
+                    #[cfg(not(unix))]
+                    LocalProtocol::Unix { path } => {
+                        Fatal::InvalidConfig.exit("Unix socket is not available for non Unix platform")
+                    }
+                    LocalProtocol::Stdio
+                    | LocalProtocol::TProxyTcp
+                    | LocalProtocol::TProxyUdp { .. }
+                    | LocalProtocol::ReverseTcp
+                    | LocalProtocol::ReverseUdp { .. }
+                    | LocalProtocol::ReverseSocks5
+                    | LocalProtocol::ReverseUnix { .. }
+                    | LocalProtocol::Bench => {
+                        Fatal::InvalidConfig.exit("Invalid protocol for reverse tunnel");
+                    }
+                }
+            }
+
+            for tunnel in std::mem::take(&mut args.local_to_remote)
+                .into_iter()
+                .flat_map(expand_dual_stack)
+            {
+                or_exit_on_failure(start_local_tunnel(&args, &client_config, tunnel).await);
+            }
+        }
+        Commands::Server(mut args) => {
+            if args.check {
+                exit_checked(check_server_config(&args));
+            }
+            let (access_log, webhook_url, shutdown_grace) =
+                (args.access_log.take(), args.webhook_url.take(), args.shutdown_grace_sec);
+            let server_config = or_exit_on_failure(create_server_config(*args).await);
+
+            info!(
+                "Starting wstunnel server v{} with config {:?}",
+                env!("CARGO_PKG_VERSION"),
+                server_config
+            );
+
+            if let Some(path) = &access_log {
+                access_log::init(path).or_exit(Fatal::InvalidConfig, "Cannot setup access log");
+            }
+            if let Some(url) = webhook_url {
+                webhook::init(url, server_config.dns_resolver.clone())
+                    .or_exit(Fatal::InvalidConfig, "Cannot setup webhook");
+            }
+            // The server only returns once its listener is handed over to a new process, that serves in its place
+            let server = tunnel::server::run_server(Arc::new(server_config));
+            let Some(shutdown_grace) = shutdown_grace else {
+                server.await.or_exit(Fatal::BindFailed, "Cannot start wstunnel server");
+                shutdown::drain(None).await;
+                return;
+            };
+
+            // Dropping the server stops the listener, while already spawned tunnels keep running
+            select! {
+                ret = server => ret.or_exit(Fatal::BindFailed, "Cannot start wstunnel server"),
+                _ = shutdown::signal() => {},
+            }
+            shutdown::drain(Some(shutdown_grace)).await;
+            return;
+        }
+        Commands::Healthcheck(args) => {
+            let client_config = or_exit_on_failure(create_client_config(&args.client).await);
+            match tunnel::client::healthcheck(&client_config, &args.destination, args.timeout_sec).await {
+                Ok(elapsed) => {
+                    info!(
+                        "Healthcheck succeeded, upgrade to {} done in {:?}",
+                        args.client.remote_addr, elapsed
+                    );
+                    std::process::exit(0);
+                }
+                Err(err) => Fatal::from_error(&err)
+                    .exit(format_args!("Healthcheck failed for {}: {:?}", args.client.remote_addr, err)),
+            }
+        }
+        Commands::Bench(args) => {
+            let scheme = match (args.http2, args.tls) {
+                (false, false) => "ws",
+                (false, true) => "wss",
+                (true, false) => "http",
+                (true, true) => "https",
+            };
+            let server = or_exit_on_failure(
+                harness::LocalServer::start(scheme, args.server_arg)
+                    .await
+                    .context("Cannot start local server"),
+            );
+            let client_config =
+                or_exit_on_failure(server.client(args.client_arg).await.context("Invalid client arguments"));
+            match speed_test::run_local_bench(client_config, &args.frame_size, args.duration_sec).await {
+                Ok(_) => std::process::exit(0),
+                Err(err) => Fatal::from_error(&err).exit(format_args!("Local bench failed: {:?}", err)),
+            }
+        }
+    }
+
+    tokio::signal::ctrl_c().await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_check_has_no_side_effect() {
+        let path = std::env::temp_dir().join(format!("wstunnel-check-{}.pcap", uuid::Uuid::now_v7()));
+        std::fs::write(&path, b"previous capture").unwrap();
+
+        let args = Wstunnel::try_parse_from([
+            "wstunnel",
+            "--pcap-dump",
+            path.to_str().unwrap(),
+            "server",
+            "--check",
+            "ws://127.0.0.1:0",
+        ])
+        .unwrap();
+        init_process(&args).await;
+        let Commands::Server(server) = args.commands else {
+            panic!("expected the server command");
+        };
+        assert!(check_server_config(&server).is_ok());
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"previous capture");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_reverse_tunnel_listener_options() {
+        assert!(parse_reverse_tunnel_arg("tcp://8080:localhost:80?name=web").is_ok());
+        assert!(parse_reverse_tunnel_arg("socks5://[::1]:1212").is_ok());
+        for arg in [
+            "tcp://8080:localhost:80?max_duration_sec=60",
+            "tcp://8080:localhost:80?max_conn=10",
+            "tcp://8080:localhost:80?reuse",
+            "tcp://8080:localhost:80?priority=bulk",
+            "socks5://[::1]:1212?allow=example.com:*",
+            "socks5://[::1]:1212?max_handshakes=10",
+        ] {
+            let err = parse_reverse_tunnel_arg(arg).unwrap_err();
+            assert!(err.to_string().contains("only supported by -L tunnels"), "{}", arg);
+        }
+    }
+}
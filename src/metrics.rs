@@ -0,0 +1,510 @@
+use anyhow::Context;
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::header::CONTENT_TYPE;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::{Display, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+// Upper bounds of the latency histograms, in seconds
+const LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+// Upper bounds of the histograms of tunnel durations, in seconds
+const DURATION_BUCKETS: [f64; 9] = [1.0, 5.0, 30.0, 60.0, 300.0, 900.0, 3600.0, 14400.0, 86400.0];
+
+// Upper bounds of the histograms of bytes sent and received by a tunnel
+const BYTES_BUCKETS: [f64; 8] = [1e3, 1e4, 1e5, 1e6, 1e7, 1e8, 1e9, 1e10];
+
+// Past this number of destinations, the tunnels of a listener to new ones are accounted under "other",
+// for socks5 listeners not to grow the metrics without bounds
+const MAX_DESTINATIONS: usize = 100;
+const OTHER_DESTINATIONS: &str = "other";
+
+// Listeners are only tracked when the metrics endpoint is enabled, to not pay for it otherwise
+static METRICS_ENABLED: OnceCell<()> = OnceCell::new();
+static LISTENERS: Lazy<Mutex<Vec<Arc<ListenerMetrics>>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Metrics of one local listener of the client (-L or -R)
+#[derive(Default)]
+pub struct ListenerMetrics {
+    listener: String,
+    name: Option<String>,
+    connections: AtomicU64,
+    active_connections: AtomicU64,
+    errors: AtomicU64,
+    reconnects: AtomicU64,
+    bytes_tx: AtomicU64,
+    bytes_rx: AtomicU64,
+    upgrade_latency: Histogram,
+    rtt: Histogram,
+    destinations: Mutex<BTreeMap<String, Arc<DestinationMetrics>>>,
+}
+
+struct Histogram {
+    bounds: &'static [f64],
+    buckets: Box<[AtomicU64]>,
+    // Sum of the observed values, in 1/scale of the unit of the metric for it to stay an integer
+    sum: AtomicU64,
+    scale: f64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64], scale: f64) -> Self {
+        Self {
+            bounds,
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            scale,
+            count: AtomicU64::new(0),
+        }
+    }
+
+    fn latency() -> Self {
+        Self::new(&LATENCY_BUCKETS, 1_000_000.0)
+    }
+
+    fn observe(&self, value: u64) {
+        let value_in_unit = value as f64 / self.scale;
+        if let Some(bucket) = self.bounds.iter().position(|bound| value_in_unit <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Only for histograms in seconds, scaled to microseconds
+    fn observe_duration(&self, duration: Duration) {
+        self.observe(duration.as_micros() as u64);
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::latency()
+    }
+}
+
+/// Metrics of the tunnels of a listener toward one destination
+pub struct DestinationMetrics {
+    duration: Histogram,
+    bytes: Histogram,
+    time_to_first_byte: Histogram,
+}
+
+impl DestinationMetrics {
+    fn new() -> Self {
+        Self {
+            duration: Histogram::new(&DURATION_BUCKETS, 1_000_000.0),
+            bytes: Histogram::new(&BYTES_BUCKETS, 1.0),
+            time_to_first_byte: Histogram::latency(),
+        }
+    }
+
+    /// Time from the tunnel opened to the first byte received from the remote
+    pub fn first_byte(&self, elapsed: Duration) {
+        self.time_to_first_byte.observe_duration(elapsed);
+    }
+
+    pub fn tunnel_done(&self, duration: Duration, bytes: u64) {
+        self.duration.observe_duration(duration);
+        self.bytes.observe(bytes);
+    }
+}
+
+impl ListenerMetrics {
+    /// Return None if the metrics endpoint is not enabled, to not pay for it
+    pub fn new(listener: String, name: Option<String>) -> Option<Arc<Self>> {
+        METRICS_ENABLED.get()?;
+        let metrics = Arc::new(Self {
+            listener,
+            name,
+            ..Default::default()
+        });
+        LISTENERS.lock().push(metrics.clone());
+        Some(metrics)
+    }
+
+    pub fn connection_accepted(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn tunnel_opened(&self) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn tunnel_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn add_bytes_tx(&self, value: u64) {
+        self.bytes_tx.fetch_add(value, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn add_bytes_rx(&self, value: u64) {
+        self.bytes_rx.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn upgrade_done(&self, latency: Duration) {
+        self.upgrade_latency.observe_duration(latency);
+    }
+
+    pub fn rtt(&self, rtt: Duration) {
+        self.rtt.observe_duration(rtt);
+    }
+
+    /// Metrics of the tunnels toward a destination, as HOST:PORT
+    pub fn destination(&self, destination: &str) -> Arc<DestinationMetrics> {
+        let mut destinations = self.destinations.lock();
+        if let Some(metrics) = destinations.get(destination) {
+            return metrics.clone();
+        }
+        let destination = if destinations.len() < MAX_DESTINATIONS {
+            destination
+        } else {
+            OTHER_DESTINATIONS
+        };
+        destinations
+            .entry(destination.to_string())
+            .or_insert_with(|| Arc::new(DestinationMetrics::new()))
+            .clone()
+    }
+
+    fn labels(&self) -> String {
+        format!(
+            "listener=\"{}\",name=\"{}\"",
+            escape(&self.listener),
+            escape(self.name.as_deref().unwrap_or_default())
+        )
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: impl Fn(&ListenerMetrics) -> u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for listener in LISTENERS.lock().iter() {
+        let _ = writeln!(out, "{}{{{}}} {}", name, listener.labels(), value(listener));
+    }
+}
+
+fn write_histogram(out: &mut String, name: &str, help: &str, histogram: impl Fn(&ListenerMetrics) -> &Histogram) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for listener in LISTENERS.lock().iter() {
+        write_histogram_samples(out, name, &listener.labels(), histogram(listener));
+    }
+}
+
+fn write_destination_histogram(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    histogram: impl Fn(&DestinationMetrics) -> &Histogram,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for listener in LISTENERS.lock().iter() {
+        for (destination, metrics) in listener.destinations.lock().iter() {
+            let labels = format!("{},destination=\"{}\"", listener.labels(), escape(destination));
+            write_histogram_samples(out, name, &labels, histogram(metrics));
+        }
+    }
+}
+
+fn write_histogram_samples(out: &mut String, name: &str, labels: &str, histogram: &Histogram) {
+    let mut cumulative = 0;
+    for (bound, bucket) in histogram.bounds.iter().zip(histogram.buckets.iter()) {
+        cumulative += bucket.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, cumulative);
+    }
+    let count = histogram.count.load(Ordering::Relaxed);
+    let sum = histogram.sum.load(Ordering::Relaxed) as f64 / histogram.scale;
+    let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+    let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+    let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+}
+
+fn write_gauge(out: &mut String, name: &str, kind: &str, help: &str, value: impl Display) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Metrics of the tokio runtime, to see tasks leaking or workers stalled by a long poll.
+/// Most of them are only available when built with RUSTFLAGS="--cfg tokio_unstable"
+fn write_runtime_metrics(out: &mut String) {
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+        return;
+    };
+    let metrics = runtime.metrics();
+    write_gauge(
+        out,
+        "wstunnel_runtime_workers",
+        "gauge",
+        "Worker threads of the runtime",
+        metrics.num_workers(),
+    );
+
+    #[cfg(tokio_unstable)]
+    {
+        let workers = 0..metrics.num_workers();
+        write_gauge(
+            out,
+            "wstunnel_runtime_active_tasks",
+            "gauge",
+            "Tasks alive in the runtime, i.e: one per tunnel and one per listener",
+            metrics.active_tasks_count(),
+        );
+        write_gauge(
+            out,
+            "wstunnel_runtime_blocking_threads",
+            "gauge",
+            "Threads spawned for blocking operations, like reading files",
+            metrics.num_blocking_threads(),
+        );
+        write_gauge(
+            out,
+            "wstunnel_runtime_polls_total",
+            "counter",
+            "Polls of the tasks by the workers",
+            workers.clone().map(|w| metrics.worker_poll_count(w)).sum::<u64>(),
+        );
+        write_gauge(
+            out,
+            "wstunnel_runtime_busy_seconds_total",
+            "counter",
+            "Time spent by the workers polling tasks",
+            workers
+                .clone()
+                .map(|w| metrics.worker_total_busy_duration(w))
+                .sum::<Duration>()
+                .as_secs_f64(),
+        );
+        write_gauge(
+            out,
+            "wstunnel_runtime_max_mean_poll_seconds",
+            "gauge",
+            "Highest mean poll duration of a worker, a long one stalls the other tasks of the worker",
+            workers
+                .map(|w| metrics.worker_mean_poll_time(w))
+                .max()
+                .unwrap_or_default()
+                .as_secs_f64(),
+        );
+    }
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Render the metrics of all listeners in the Prometheus text format
+fn render() -> String {
+    let mut out = String::with_capacity(4096);
+    write_metric(
+        &mut out,
+        "wstunnel_client_connections_total",
+        "counter",
+        "Connections accepted by the listener",
+        |m| m.connections.load(Ordering::Relaxed),
+    );
+    write_metric(
+        &mut out,
+        "wstunnel_client_active_connections",
+        "gauge",
+        "Connections of the listener currently tunneled",
+        |m| m.active_connections.load(Ordering::Relaxed),
+    );
+    write_metric(
+        &mut out,
+        "wstunnel_client_errors_total",
+        "counter",
+        "Connections of the listener that could not be tunneled",
+        |m| m.errors.load(Ordering::Relaxed),
+    );
+    write_metric(
+        &mut out,
+        "wstunnel_client_reconnects_total",
+        "counter",
+        "Reconnections to the server of a reverse tunnel after a failure",
+        |m| m.reconnects.load(Ordering::Relaxed),
+    );
+    write_metric(
+        &mut out,
+        "wstunnel_client_bytes_tx_total",
+        "counter",
+        "Bytes sent to the server",
+        |m| m.bytes_tx.load(Ordering::Relaxed),
+    );
+    write_metric(
+        &mut out,
+        "wstunnel_client_bytes_rx_total",
+        "counter",
+        "Bytes received from the server",
+        |m| m.bytes_rx.load(Ordering::Relaxed),
+    );
+
+    write_histogram(
+        &mut out,
+        "wstunnel_client_upgrade_latency_seconds",
+        "Time to connect to the server and get the tunnel accepted",
+        |m| &m.upgrade_latency,
+    );
+    write_histogram(
+        &mut out,
+        "wstunnel_client_rtt_seconds",
+        "Round trip time of the pings sent to the server",
+        |m| &m.rtt,
+    );
+    write_destination_histogram(
+        &mut out,
+        "wstunnel_client_tunnel_duration_seconds",
+        "Duration of the tunnels, until both sides are closed",
+        |m| &m.duration,
+    );
+    write_destination_histogram(
+        &mut out,
+        "wstunnel_client_tunnel_bytes",
+        "Bytes sent and received by a tunnel",
+        |m| &m.bytes,
+    );
+    write_destination_histogram(
+        &mut out,
+        "wstunnel_client_time_to_first_byte_seconds",
+        "Time from the tunnel opened to the first byte received from the remote",
+        |m| &m.time_to_first_byte,
+    );
+    write_runtime_metrics(&mut out);
+
+    out
+}
+
+async fn handle(req: Request<hyper::body::Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+    let response = if req.uri().path() == "/metrics" {
+        Response::builder()
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Full::new(Bytes::from(render())))
+    } else {
+        Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Full::new(Bytes::from_static(b"not found, metrics are under /metrics\n")))
+    };
+    Ok(response.unwrap_or_default())
+}
+
+/// Expose the metrics of the client listeners in the Prometheus text format, under http://<bind>/metrics
+/// Must be called before the listeners are started, for them to be tracked
+pub async fn init(bind: SocketAddr) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(bind)
+        .await
+        .with_context(|| format!("Cannot bind metrics endpoint on {}", bind))?;
+    let _ = METRICS_ENABLED.set(());
+
+    info!("Serving Prometheus metrics on http://{}/metrics", listener.local_addr()?);
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    warn!("Cannot accept metrics connection: {}", err);
+                    continue;
+                }
+            };
+            tokio::spawn(async move {
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service_fn(handle))
+                    .await;
+            });
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_listener_metrics() {
+        let _ = METRICS_ENABLED.set(());
+        let metrics = ListenerMetrics::new("tcp://127.0.0.1:8080".to_string(), Some("grafana".to_string())).unwrap();
+        metrics.connection_accepted();
+        metrics.add_bytes_tx(42);
+        metrics.upgrade_done(Duration::from_millis(20));
+        metrics.upgrade_done(Duration::from_secs(10));
+        metrics.rtt(Duration::from_millis(30));
+
+        let out = render();
+        assert!(
+            out.contains("wstunnel_client_connections_total{listener=\"tcp://127.0.0.1:8080\",name=\"grafana\"} 1\n")
+        );
+        assert!(out.contains("wstunnel_client_bytes_tx_total{listener=\"tcp://127.0.0.1:8080\",name=\"grafana\"} 42\n"));
+        assert!(out.contains(
+            "wstunnel_client_upgrade_latency_seconds_bucket{listener=\"tcp://127.0.0.1:8080\",name=\"grafana\",le=\"0.01\"} 0\n"
+        ));
+        assert!(out.contains(
+            "wstunnel_client_upgrade_latency_seconds_bucket{listener=\"tcp://127.0.0.1:8080\",name=\"grafana\",le=\"0.025\"} 1\n"
+        ));
+        assert!(out.contains(
+            "wstunnel_client_upgrade_latency_seconds_bucket{listener=\"tcp://127.0.0.1:8080\",name=\"grafana\",le=\"+Inf\"} 2\n"
+        ));
+        assert!(out.contains(
+            "wstunnel_client_upgrade_latency_seconds_sum{listener=\"tcp://127.0.0.1:8080\",name=\"grafana\"} 10.02\n"
+        ));
+        assert!(out.contains(
+            "wstunnel_client_rtt_seconds_bucket{listener=\"tcp://127.0.0.1:8080\",name=\"grafana\",le=\"0.05\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn test_render_destination_metrics() {
+        let _ = METRICS_ENABLED.set(());
+        let metrics = ListenerMetrics::new("socks5://127.0.0.1:1080".to_string(), None).unwrap();
+        let grafana = metrics.destination("grafana.lan:443");
+        grafana.first_byte(Duration::from_millis(40));
+        grafana.tunnel_done(Duration::from_secs(20), 50_000);
+        grafana.tunnel_done(Duration::from_millis(500), 500);
+        for port in 0..MAX_DESTINATIONS as u16 {
+            metrics.destination(&format!("10.0.0.1:{}", port));
+        }
+        assert_eq!(metrics.destinations.lock().len(), MAX_DESTINATIONS + 1);
+
+        let out = render();
+        let labels = "listener=\"socks5://127.0.0.1:1080\",name=\"\",destination=\"grafana.lan:443\"";
+        assert!(out.contains(&format!(
+            "wstunnel_client_tunnel_duration_seconds_bucket{{{},le=\"1\"}} 1\n",
+            labels
+        )));
+        assert!(out.contains(&format!(
+            "wstunnel_client_tunnel_duration_seconds_bucket{{{},le=\"30\"}} 2\n",
+            labels
+        )));
+        assert!(out.contains(&format!("wstunnel_client_tunnel_bytes_sum{{{}}} 50500\n", labels)));
+        assert!(out.contains(&format!("wstunnel_client_tunnel_bytes_bucket{{{},le=\"1000\"}} 1\n", labels)));
+        assert!(out.contains(&format!("wstunnel_client_time_to_first_byte_seconds_count{{{}}} 1\n", labels)));
+        assert!(out.contains("destination=\"other\""));
+    }
+}
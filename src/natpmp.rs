@@ -0,0 +1,154 @@
+use anyhow::{anyhow, Context};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+// https://datatracker.ietf.org/doc/html/rfc6886
+const NAT_PMP_PORT: u16 = 5351;
+const MAPPING_LIFETIME: Duration = Duration::from_secs(7200);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Protocol {
+    Udp = 1,
+    Tcp = 2,
+}
+
+fn mapping_request(protocol: Protocol, port: u16, lifetime: Duration) -> [u8; 12] {
+    let mut req = [0u8; 12];
+    req[1] = protocol as u8;
+    req[4..6].copy_from_slice(&port.to_be_bytes());
+    req[6..8].copy_from_slice(&port.to_be_bytes());
+    req[8..12].copy_from_slice(&(lifetime.as_secs() as u32).to_be_bytes());
+    req
+}
+
+fn parse_response(opcode: u8, resp: &[u8], expected_len: usize) -> anyhow::Result<&[u8]> {
+    if resp.len() < expected_len || resp[0] != 0 || resp[1] != 128 + opcode {
+        return Err(anyhow!("invalid NAT-PMP response {:?}", resp));
+    }
+    match u16::from_be_bytes([resp[2], resp[3]]) {
+        0 => Ok(&resp[8..expected_len]),
+        1 => Err(anyhow!("NAT-PMP version not supported by the router")),
+        2 => Err(anyhow!("NAT-PMP port mapping refused by the router")),
+        3 => Err(anyhow!("router is not connected to the internet")),
+        4 => Err(anyhow!("router is out of resources for port mapping")),
+        code => Err(anyhow!("NAT-PMP request failed with result code {}", code)),
+    }
+}
+
+/// Send the request and wait for the response of the router, retrying with the back-off of the rfc
+async fn request(gateway: Ipv4Addr, req: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect(SocketAddrV4::new(gateway, NAT_PMP_PORT)).await?;
+    let mut buf = [0u8; 16];
+    let mut wait = Duration::from_millis(250);
+    for _ in 0..5 {
+        socket.send(req).await?;
+        if let Ok(len) = timeout(wait, socket.recv(&mut buf)).await {
+            return Ok(buf[..len?].to_vec());
+        }
+        wait *= 2;
+    }
+    Err(anyhow!("no NAT-PMP response from gateway {}", gateway))
+}
+
+async fn external_ip(gateway: Ipv4Addr) -> anyhow::Result<Ipv4Addr> {
+    let resp = request(gateway, &[0, 0]).await?;
+    let ip = parse_response(0, &resp, 12)?;
+    Ok(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]))
+}
+
+/// Map the port and return the external port and the lifetime granted by the router
+async fn map_port(gateway: Ipv4Addr, protocol: Protocol, port: u16, lifetime: Duration) -> anyhow::Result<(u16, u32)> {
+    let resp = request(gateway, &mapping_request(protocol, port, lifetime)).await?;
+    let mapping = parse_response(protocol as u8, &resp, 16)?;
+    Ok((
+        u16::from_be_bytes([mapping[2], mapping[3]]),
+        u32::from_be_bytes([mapping[4], mapping[5], mapping[6], mapping[7]]),
+    ))
+}
+
+struct UnmapOnDrop {
+    gateway: Ipv4Addr,
+    protocol: Protocol,
+    port: u16,
+}
+
+impl Drop for UnmapOnDrop {
+    fn drop(&mut self) {
+        let (gateway, protocol, port) = (self.gateway, self.protocol, self.port);
+        tokio::spawn(async move {
+            match map_port(gateway, protocol, port, Duration::ZERO).await {
+                Ok(_) => info!("Removed NAT-PMP port mapping of {:?} port {}", protocol, port),
+                Err(err) => warn!("Cannot remove NAT-PMP port mapping of {:?} port {}: {:?}", protocol, port, err),
+            }
+        });
+    }
+}
+
+/// Ask the router to forward the port to us, and renew the mapping until the future is dropped.
+/// The mapping is removed from the router on drop
+pub async fn keep_mapped(gateway: Ipv4Addr, protocol: Protocol, port: u16) {
+    let _unmap = UnmapOnDrop {
+        gateway,
+        protocol,
+        port,
+    };
+
+    let mut reported = false;
+    loop {
+        let renew_in = match map_port(gateway, protocol, port, MAPPING_LIFETIME).await {
+            Ok((external_port, lifetime)) => {
+                if !reported {
+                    let external_ip = external_ip(gateway).await.context("cannot get external ip");
+                    match external_ip {
+                        Ok(ip) => info!(
+                            "{:?} port {} is reachable from internet on {}",
+                            protocol,
+                            port,
+                            SocketAddr::new(ip.into(), external_port)
+                        ),
+                        Err(err) => warn!(
+                            "{:?} port {} mapped to external port {}, {:?}",
+                            protocol, port, external_port, err
+                        ),
+                    }
+                    reported = true;
+                }
+                Duration::from_secs(lifetime.max(2) as u64 / 2)
+            }
+            Err(err) => {
+                warn!(
+                    "Cannot map {:?} port {} with NAT-PMP on gateway {}: {:?}",
+                    protocol, port, gateway, err
+                );
+                Duration::from_secs(60)
+            }
+        };
+        tokio::time::sleep(renew_in).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nat_pmp_messages() {
+        assert_eq!(
+            mapping_request(Protocol::Tcp, 8080, Duration::from_secs(7200)),
+            [0, 2, 0, 0, 0x1f, 0x90, 0x1f, 0x90, 0, 0, 0x1c, 0x20]
+        );
+
+        let resp = [0, 130, 0, 0, 0, 0, 0, 42, 0x1f, 0x90, 0x23, 0x82, 0, 0, 0x0e, 0x10];
+        assert_eq!(parse_response(2, &resp, 16).unwrap(), &resp[8..16]);
+        // Response to an udp request, or error code from the router
+        assert!(parse_response(1, &resp, 16).is_err());
+        let refused = [0, 130, 0, 2, 0, 0, 0, 42, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert!(parse_response(2, &refused, 16).is_err());
+        assert!(parse_response(2, &resp[..12], 16).is_err());
+    }
+}
@@ -0,0 +1,461 @@
+use anyhow::anyhow;
+
+/// Http authentication schemes answered with the credentials of the logged user, by order of preference
+pub const SSO_SCHEMES: [&str; 2] = ["Negotiate", "NTLM"];
+
+/// Security context of a Negotiate (SPNEGO/Kerberos) or NTLM authentication, done with the single sign-on credentials
+/// of the logged user: its kerberos ticket with GSSAPI, or its windows session with SSPI.
+/// NTLM takes several round trips, that must all happen on the same connection
+pub struct NegotiateContext {
+    #[cfg(unix)]
+    inner: gssapi::Context,
+    #[cfg(windows)]
+    inner: sspi::Context,
+}
+
+impl NegotiateContext {
+    pub fn new(scheme: &str, host: &str) -> anyhow::Result<Self> {
+        let ntlm = if scheme.eq_ignore_ascii_case("Negotiate") {
+            false
+        } else if scheme.eq_ignore_ascii_case("NTLM") {
+            true
+        } else {
+            return Err(anyhow!("Unsupported single sign-on authentication scheme {}", scheme));
+        };
+
+        Ok(Self {
+            #[cfg(unix)]
+            inner: gssapi::Context::new(ntlm, host)?,
+            #[cfg(windows)]
+            inner: sspi::Context::new(ntlm, host)?,
+        })
+    }
+
+    /// Token to send to the server. None for the first one, then the token of each challenge of the server
+    pub fn step(&mut self, challenge: Option<&[u8]>) -> anyhow::Result<Vec<u8>> {
+        self.inner.step(challenge)
+    }
+}
+
+#[cfg(unix)]
+mod gssapi {
+    use anyhow::{anyhow, Context as _};
+    use std::ffi::c_void;
+    use std::ptr;
+
+    // Subset of the GSSAPI v2 api, from gssapi.h
+    const GSS_C_GSS_CODE: i32 = 1;
+    const GSS_C_MECH_CODE: i32 = 2;
+    const GSS_ERROR_MASK: u32 = 0xffff_0000;
+
+    // DER encoded OIDs, without the tag and length
+    // GSS_C_NT_HOSTBASED_SERVICE 1.2.840.113554.1.2.1.4
+    const NT_HOSTBASED_SERVICE: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x12, 0x01, 0x02, 0x01, 0x04];
+    // SPNEGO 1.3.6.1.5.5.2
+    const MECH_SPNEGO: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x02];
+    // NTLMSSP 1.3.6.1.4.1.311.2.2.10, provided by the gss-ntlmssp plugin
+    const MECH_NTLM: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37, 0x02, 0x02, 0x0a];
+
+    #[cfg(target_os = "macos")]
+    const LIBRARIES: &[&str] = &["/System/Library/Frameworks/GSS.framework/GSS"];
+    #[cfg(not(target_os = "macos"))]
+    const LIBRARIES: &[&str] = &["libgssapi_krb5.so.2", "libgssapi.so.3", "libgssapi_krb5.so"];
+
+    // Structs are packed on macos, as done by its gssapi.h
+    #[cfg_attr(target_os = "macos", repr(C, packed(2)))]
+    #[cfg_attr(not(target_os = "macos"), repr(C))]
+    struct GssBuffer {
+        length: usize,
+        value: *mut c_void,
+    }
+
+    #[cfg_attr(target_os = "macos", repr(C, packed(2)))]
+    #[cfg_attr(not(target_os = "macos"), repr(C))]
+    struct GssOid {
+        length: u32,
+        elements: *const c_void,
+    }
+
+    fn oid(der: &'static [u8]) -> GssOid {
+        GssOid {
+            length: der.len() as u32,
+            elements: der.as_ptr() as *const c_void,
+        }
+    }
+
+    type InitSecContext = unsafe extern "C" fn(
+        *mut u32,
+        *mut c_void,
+        *mut *mut c_void,
+        *mut c_void,
+        *const GssOid,
+        u32,
+        u32,
+        *mut c_void,
+        *const GssBuffer,
+        *mut *const GssOid,
+        *mut GssBuffer,
+        *mut u32,
+        *mut u32,
+    ) -> u32;
+
+    struct Functions {
+        import_name: unsafe extern "C" fn(*mut u32, *const GssBuffer, *const GssOid, *mut *mut c_void) -> u32,
+        release_name: unsafe extern "C" fn(*mut u32, *mut *mut c_void) -> u32,
+        init_sec_context: InitSecContext,
+        delete_sec_context: unsafe extern "C" fn(*mut u32, *mut *mut c_void, *mut GssBuffer) -> u32,
+        release_buffer: unsafe extern "C" fn(*mut u32, *mut GssBuffer) -> u32,
+        display_status: unsafe extern "C" fn(*mut u32, u32, i32, *const GssOid, *mut u32, *mut GssBuffer) -> u32,
+    }
+
+    impl Functions {
+        fn check(&self, function: &str, major: u32, minor: u32) -> anyhow::Result<()> {
+            if major & GSS_ERROR_MASK == 0 {
+                return Ok(());
+            }
+
+            let mut messages = vec![self.status_message(major, GSS_C_GSS_CODE)];
+            if minor != 0 {
+                messages.push(self.status_message(minor, GSS_C_MECH_CODE));
+            }
+            Err(anyhow!("{} failed: {}", function, messages.join(", ")))
+        }
+
+        // Only the first message of the status, it is enough to understand what is wrong
+        fn status_message(&self, status: u32, status_type: i32) -> String {
+            let mut minor = 0;
+            let mut message_context = 0;
+            let mut message = GssBuffer {
+                length: 0,
+                value: ptr::null_mut(),
+            };
+            let major = unsafe {
+                (self.display_status)(&mut minor, status, status_type, ptr::null(), &mut message_context, &mut message)
+            };
+            let (value, length) = (message.value, message.length);
+            let text = if major & GSS_ERROR_MASK != 0 || value.is_null() {
+                format!("status {:#x}", status)
+            } else {
+                String::from_utf8_lossy(unsafe { std::slice::from_raw_parts(value as *const u8, length) })
+                    .trim()
+                    .to_string()
+            };
+            unsafe { (self.release_buffer)(&mut minor, &mut message) };
+            text
+        }
+    }
+
+    pub struct Context {
+        functions: Functions,
+        mech: &'static [u8],
+        name: *mut c_void,
+        context: *mut c_void,
+        // Must be kept loaded, the functions point into it
+        _library: libloading::Library,
+    }
+
+    // The handles are only used by the task doing the authentication, never concurrently
+    unsafe impl Send for Context {}
+
+    fn symbol<T: Copy>(library: &libloading::Library, name: &str) -> anyhow::Result<T> {
+        let symbol = unsafe { library.get::<T>(format!("{}\0", name).as_bytes()) }
+            .with_context(|| format!("Not a GSSAPI library, {} is missing", name))?;
+        Ok(*symbol)
+    }
+
+    impl Context {
+        pub fn new(ntlm: bool, host: &str) -> anyhow::Result<Self> {
+            let library = LIBRARIES
+                .iter()
+                .find_map(|path| unsafe { libloading::Library::new(path) }.ok())
+                .with_context(|| format!("Cannot load the GSSAPI library, tried {:?}", LIBRARIES))?;
+            let functions = Functions {
+                import_name: symbol(&library, "gss_import_name")?,
+                release_name: symbol(&library, "gss_release_name")?,
+                init_sec_context: symbol(&library, "gss_init_sec_context")?,
+                delete_sec_context: symbol(&library, "gss_delete_sec_context")?,
+                release_buffer: symbol(&library, "gss_release_buffer")?,
+                display_status: symbol(&library, "gss_display_status")?,
+            };
+
+            let service = format!("HTTP@{}", host);
+            let service_name = GssBuffer {
+                length: service.len(),
+                value: service.as_ptr() as *mut c_void,
+            };
+            let mut minor = 0;
+            let mut name = ptr::null_mut();
+            let major =
+                unsafe { (functions.import_name)(&mut minor, &service_name, &oid(NT_HOSTBASED_SERVICE), &mut name) };
+            functions.check("gss_import_name", major, minor)?;
+
+            Ok(Self {
+                functions,
+                mech: if ntlm { MECH_NTLM } else { MECH_SPNEGO },
+                name,
+                context: ptr::null_mut(),
+                _library: library,
+            })
+        }
+
+        pub fn step(&mut self, challenge: Option<&[u8]>) -> anyhow::Result<Vec<u8>> {
+            let input = challenge.map(|challenge| GssBuffer {
+                length: challenge.len(),
+                value: challenge.as_ptr() as *mut c_void,
+            });
+            let mut output = GssBuffer {
+                length: 0,
+                value: ptr::null_mut(),
+            };
+            let mut minor = 0;
+            let major = unsafe {
+                (self.functions.init_sec_context)(
+                    &mut minor,
+                    ptr::null_mut(),
+                    &mut self.context,
+                    self.name,
+                    &oid(self.mech),
+                    0,
+                    0,
+                    ptr::null_mut(),
+                    input.as_ref().map_or(ptr::null(), |input| input as *const GssBuffer),
+                    ptr::null_mut(),
+                    &mut output,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                )
+            };
+
+            let (value, length) = (output.value, output.length);
+            let token = if value.is_null() {
+                vec![]
+            } else {
+                unsafe { std::slice::from_raw_parts(value as *const u8, length) }.to_vec()
+            };
+            unsafe { (self.functions.release_buffer)(&mut 0, &mut output) };
+            self.functions.check("gss_init_sec_context", major, minor)?;
+            Ok(token)
+        }
+    }
+
+    impl Drop for Context {
+        fn drop(&mut self) {
+            unsafe {
+                if !self.context.is_null() {
+                    (self.functions.delete_sec_context)(&mut 0, &mut self.context, ptr::null_mut());
+                }
+                (self.functions.release_name)(&mut 0, &mut self.name);
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+mod sspi {
+    use anyhow::{anyhow, Context as _};
+    use std::ffi::c_void;
+    use std::ptr;
+
+    // Subset of the SSPI api, from sspi.h
+    const SECPKG_CRED_OUTBOUND: u32 = 2;
+    const SECURITY_NATIVE_DREP: u32 = 0x10;
+    const ISC_REQ_ALLOCATE_MEMORY: u32 = 0x100;
+    const ISC_REQ_CONNECTION: u32 = 0x800;
+    const SECBUFFER_VERSION: u32 = 0;
+    const SECBUFFER_TOKEN: u32 = 2;
+    const SEC_E_OK: i32 = 0;
+    const SEC_I_CONTINUE_NEEDED: i32 = 0x0009_0312;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct SecHandle {
+        lower: usize,
+        upper: usize,
+    }
+
+    #[repr(C)]
+    struct SecBuffer {
+        cb_buffer: u32,
+        buffer_type: u32,
+        pv_buffer: *mut c_void,
+    }
+
+    #[repr(C)]
+    struct SecBufferDesc {
+        ul_version: u32,
+        c_buffers: u32,
+        p_buffers: *mut SecBuffer,
+    }
+
+    type AcquireCredentialsHandle = unsafe extern "system" fn(
+        *const u16,
+        *const u16,
+        u32,
+        *mut c_void,
+        *mut c_void,
+        *mut c_void,
+        *mut c_void,
+        *mut SecHandle,
+        *mut i64,
+    ) -> i32;
+
+    type InitializeSecurityContext = unsafe extern "system" fn(
+        *mut SecHandle,
+        *mut SecHandle,
+        *const u16,
+        u32,
+        u32,
+        u32,
+        *mut SecBufferDesc,
+        u32,
+        *mut SecHandle,
+        *mut SecBufferDesc,
+        *mut u32,
+        *mut i64,
+    ) -> i32;
+
+    struct Functions {
+        initialize_security_context: InitializeSecurityContext,
+        free_context_buffer: unsafe extern "system" fn(*mut c_void) -> i32,
+        delete_security_context: unsafe extern "system" fn(*mut SecHandle) -> i32,
+        free_credentials_handle: unsafe extern "system" fn(*mut SecHandle) -> i32,
+    }
+
+    pub struct Context {
+        functions: Functions,
+        credentials: SecHandle,
+        context: Option<SecHandle>,
+        target: Vec<u16>,
+        // Must be kept loaded, the functions point into it
+        _library: libloading::Library,
+    }
+
+    fn wide(value: &str) -> Vec<u16> {
+        value.encode_utf16().chain(Some(0)).collect()
+    }
+
+    fn symbol<T: Copy>(library: &libloading::Library, name: &str) -> anyhow::Result<T> {
+        let symbol = unsafe { library.get::<T>(format!("{}\0", name).as_bytes()) }
+            .with_context(|| format!("Not a SSPI library, {} is missing", name))?;
+        Ok(*symbol)
+    }
+
+    impl Context {
+        pub fn new(ntlm: bool, host: &str) -> anyhow::Result<Self> {
+            let library = unsafe { libloading::Library::new("secur32.dll") }.context("Cannot load secur32.dll")?;
+            let acquire_credentials_handle: AcquireCredentialsHandle = symbol(&library, "AcquireCredentialsHandleW")?;
+            let functions = Functions {
+                initialize_security_context: symbol(&library, "InitializeSecurityContextW")?,
+                free_context_buffer: symbol(&library, "FreeContextBuffer")?,
+                delete_security_context: symbol(&library, "DeleteSecurityContext")?,
+                free_credentials_handle: symbol(&library, "FreeCredentialsHandle")?,
+            };
+
+            let package = wide(if ntlm { "NTLM" } else { "Negotiate" });
+            let mut credentials = SecHandle::default();
+            let mut expiry = 0;
+            let status = unsafe {
+                acquire_credentials_handle(
+                    ptr::null(),
+                    package.as_ptr(),
+                    SECPKG_CRED_OUTBOUND,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    &mut credentials,
+                    &mut expiry,
+                )
+            };
+            if status != SEC_E_OK {
+                return Err(anyhow!("AcquireCredentialsHandle failed with status {:#x}", status));
+            }
+
+            Ok(Self {
+                functions,
+                credentials,
+                context: None,
+                target: wide(&format!("HTTP/{}", host)),
+                _library: library,
+            })
+        }
+
+        pub fn step(&mut self, challenge: Option<&[u8]>) -> anyhow::Result<Vec<u8>> {
+            let mut input_buffer = SecBuffer {
+                cb_buffer: challenge.map_or(0, |challenge| challenge.len() as u32),
+                buffer_type: SECBUFFER_TOKEN,
+                pv_buffer: challenge.map_or(ptr::null_mut(), |challenge| challenge.as_ptr() as *mut c_void),
+            };
+            let mut input = SecBufferDesc {
+                ul_version: SECBUFFER_VERSION,
+                c_buffers: 1,
+                p_buffers: &mut input_buffer,
+            };
+            let mut output_buffer = SecBuffer {
+                cb_buffer: 0,
+                buffer_type: SECBUFFER_TOKEN,
+                pv_buffer: ptr::null_mut(),
+            };
+            let mut output = SecBufferDesc {
+                ul_version: SECBUFFER_VERSION,
+                c_buffers: 1,
+                p_buffers: &mut output_buffer,
+            };
+
+            let mut new_context = SecHandle::default();
+            let mut attributes = 0;
+            let mut expiry = 0;
+            let status = unsafe {
+                (self.functions.initialize_security_context)(
+                    &mut self.credentials,
+                    self.context
+                        .as_mut()
+                        .map_or(ptr::null_mut(), |context| context as *mut SecHandle),
+                    self.target.as_ptr(),
+                    ISC_REQ_ALLOCATE_MEMORY | ISC_REQ_CONNECTION,
+                    0,
+                    SECURITY_NATIVE_DREP,
+                    if challenge.is_some() {
+                        &mut input
+                    } else {
+                        ptr::null_mut()
+                    },
+                    0,
+                    &mut new_context,
+                    &mut output,
+                    &mut attributes,
+                    &mut expiry,
+                )
+            };
+            if self.context.is_none() && (status == SEC_E_OK || status == SEC_I_CONTINUE_NEEDED) {
+                self.context = Some(new_context);
+            }
+
+            let token = if output_buffer.pv_buffer.is_null() {
+                vec![]
+            } else {
+                let token = unsafe {
+                    std::slice::from_raw_parts(output_buffer.pv_buffer as *const u8, output_buffer.cb_buffer as usize)
+                }
+                .to_vec();
+                unsafe { (self.functions.free_context_buffer)(output_buffer.pv_buffer) };
+                token
+            };
+            if status != SEC_E_OK && status != SEC_I_CONTINUE_NEEDED {
+                return Err(anyhow!("InitializeSecurityContext failed with status {:#x}", status));
+            }
+            Ok(token)
+        }
+    }
+
+    impl Drop for Context {
+        fn drop(&mut self) {
+            unsafe {
+                if let Some(context) = &mut self.context {
+                    (self.functions.delete_security_context)(context);
+                }
+                (self.functions.free_credentials_handle)(&mut self.credentials);
+            }
+        }
+    }
+}
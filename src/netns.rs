@@ -0,0 +1,82 @@
+use socket2::{Domain, Socket, Type};
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::sync::mpsc;
+use tokio::sync::oneshot;
+
+type SocketRequest = (Domain, Type, oneshot::Sender<io::Result<Socket>>);
+
+/// Network namespace in which the sockets toward the destinations of tunnels are created.
+/// The namespace of a socket is the one of the thread creating it, so a thread dedicated to it creates them,
+/// and they are then used from the runtime like any other socket
+#[derive(Clone)]
+pub struct NetNs {
+    name: String,
+    requests: mpsc::Sender<SocketRequest>,
+}
+
+impl Debug for NetNs {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("NetNs").field(&self.name).finish()
+    }
+}
+
+impl NetNs {
+    /// Enter the namespace NAME of `ip netns`, or the one of a path like /proc/PID/ns/net.
+    /// Needs CAP_SYS_ADMIN
+    #[cfg(target_os = "linux")]
+    pub fn open(name: &str) -> anyhow::Result<Self> {
+        use anyhow::Context;
+        use nix::sched::{setns, CloneFlags};
+
+        let path = if name.contains('/') {
+            std::path::PathBuf::from(name)
+        } else {
+            std::path::Path::new("/var/run/netns").join(name)
+        };
+        let netns = std::fs::File::open(&path).with_context(|| format!("cannot open network namespace {:?}", path))?;
+
+        let (requests, requests_rx) = mpsc::channel::<SocketRequest>();
+        let (ready_tx, ready_rx) = mpsc::channel();
+        std::thread::Builder::new()
+            .name(format!("netns-{}", name))
+            .spawn(move || {
+                if let Err(err) = setns(&netns, CloneFlags::CLONE_NEWNET) {
+                    let _ = ready_tx.send(Err(err));
+                    return;
+                }
+                let _ = ready_tx.send(Ok(()));
+                while let Ok((domain, ty, reply)) = requests_rx.recv() {
+                    let _ = reply.send(Socket::new(domain, ty, None));
+                }
+            })?;
+
+        ready_rx
+            .recv()
+            .context("network namespace thread stopped")?
+            .with_context(|| format!("cannot enter network namespace {:?}, CAP_SYS_ADMIN is needed", path))?;
+
+        Ok(Self {
+            name: name.to_string(),
+            requests,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn open(name: &str) -> anyhow::Result<Self> {
+        Err(anyhow::anyhow!(
+            "cannot use network namespace {}, only available on linux",
+            name
+        ))
+    }
+
+    /// New non-blocking socket, created in the namespace
+    pub async fn socket(&self, domain: Domain, ty: Type) -> io::Result<Socket> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let stopped = || io::Error::other(format!("network namespace {} thread stopped", self.name));
+        self.requests.send((domain, ty, reply_tx)).map_err(|_| stopped())?;
+        let socket = reply_rx.await.map_err(|_| stopped())??;
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    }
+}
@@ -0,0 +1,238 @@
+use crate::dns::DnsResolver;
+use crate::http_client;
+use anyhow::{anyhow, Context};
+use std::time::Duration;
+use tracing::{debug, warn};
+use url::Url;
+
+/// Http proxy the PAC script asks to use to reach the server, None to connect directly.
+/// The script is fetched from an http(s):// or file:// url, or a path
+pub async fn find_proxy(pac_url: &str, server: &Url, timeout: Duration) -> anyhow::Result<Option<String>> {
+    let script = match Url::parse(pac_url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => http_client::get(&url, &DnsResolver::system(), timeout)
+            .await
+            .with_context(|| format!("Cannot fetch PAC script {}", url))?
+            .to_vec(),
+        Ok(url) if url.scheme() == "file" => {
+            let path = url
+                .to_file_path()
+                .map_err(|_| anyhow!("Invalid PAC script path {}", url))?;
+            tokio::fs::read(&path)
+                .await
+                .with_context(|| format!("Cannot read PAC script {:?}", path))?
+        }
+        _ => tokio::fs::read(pac_url)
+            .await
+            .with_context(|| format!("Cannot read PAC script {}", pac_url))?,
+    };
+    let script = String::from_utf8(script).context("PAC script is not valid utf-8")?;
+
+    // Like browsers, the script sees the websocket url as its http counterpart, without the path
+    let scheme = match server.scheme() {
+        "wss" | "https" => "https",
+        _ => "http",
+    };
+    let host = server.host_str().context("Server url has no host")?.to_string();
+    let url = match server.port() {
+        Some(port) => format!("{}://{}:{}/", scheme, host, port),
+        None => format!("{}://{}/", scheme, host),
+    };
+
+    let result = tokio::task::spawn_blocking(move || evaluate(&script, &url, &host)).await??;
+    debug!("PAC script returned {}", result);
+    parse_pac_result(&result)
+}
+
+// i.e: PROXY proxy.corp:3128; SOCKS socks.corp:1080; DIRECT. The first entry we can use is picked
+fn parse_pac_result(result: &str) -> anyhow::Result<Option<String>> {
+    for entry in result.split(';').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let (kind, proxy) = entry.split_once(char::is_whitespace).unwrap_or((entry, ""));
+        match kind.to_ascii_uppercase().as_str() {
+            "DIRECT" => return Ok(None),
+            "PROXY" | "HTTP" if !proxy.trim().is_empty() => return Ok(Some(proxy.trim().to_string())),
+            _ => warn!("Ignoring unsupported entry {} returned by the PAC script", entry),
+        }
+    }
+
+    Err(anyhow!("PAC script returned no usable proxy: {}", result))
+}
+
+#[cfg(not(feature = "pac"))]
+fn evaluate(_script: &str, _url: &str, _host: &str) -> anyhow::Result<String> {
+    Err(anyhow!(
+        "wstunnel is built without the pac feature, PAC scripts cannot be evaluated"
+    ))
+}
+
+#[cfg(feature = "pac")]
+fn evaluate(script: &str, url: &str, host: &str) -> anyhow::Result<String> {
+    use rquickjs::{CatchResultExt, Ctx, Function, Runtime};
+    use std::net::{IpAddr, ToSocketAddrs, UdpSocket};
+
+    // Only ipv4 addresses, as most scripts give them to isInNet
+    fn dns_resolve(host: String) -> Option<String> {
+        let mut addrs = (host.as_str(), 0).to_socket_addrs().ok()?;
+        addrs.find(|addr| addr.is_ipv4()).map(|addr| addr.ip().to_string())
+    }
+
+    // Address of the interface that routes to the internet. Nothing is sent, connecting an udp socket only picks it
+    fn my_ip_address() -> String {
+        UdpSocket::bind("0.0.0.0:0")
+            .and_then(|socket| socket.connect("198.51.100.1:53").map(|_| socket))
+            .and_then(|socket| socket.local_addr())
+            .map(|addr| addr.ip())
+            .unwrap_or(IpAddr::from([127, 0, 0, 1]))
+            .to_string()
+    }
+
+    fn find_proxy_for_url(ctx: &Ctx, script: &str, url: &str, host: &str) -> rquickjs::Result<String> {
+        let globals = ctx.globals();
+        globals.set("dnsResolve", Function::new(ctx.clone(), dns_resolve)?)?;
+        globals.set("myIpAddress", Function::new(ctx.clone(), my_ip_address)?)?;
+        ctx.eval::<(), _>(PAC_UTILS)?;
+        ctx.eval::<(), _>(script)?;
+        let find_proxy: Function = globals.get("FindProxyForURL")?;
+        find_proxy.call((url, host))
+    }
+
+    let runtime = Runtime::new().context("Cannot create javascript runtime")?;
+    let context = rquickjs::Context::full(&runtime).context("Cannot create javascript context")?;
+    context.with(|ctx| {
+        find_proxy_for_url(&ctx, script, url, host)
+            .catch(&ctx)
+            .map_err(|err| anyhow!("PAC script failed: {}", err))
+    })
+}
+
+// Helper functions available to PAC scripts, dnsResolve and myIpAddress are native
+#[cfg(feature = "pac")]
+const PAC_UTILS: &str = r#"
+var PAC_DAYS = ['SUN', 'MON', 'TUE', 'WED', 'THU', 'FRI', 'SAT'];
+var PAC_MONTHS = ['JAN', 'FEB', 'MAR', 'APR', 'MAY', 'JUN', 'JUL', 'AUG', 'SEP', 'OCT', 'NOV', 'DEC'];
+
+function isPlainHostName(host) { return host.indexOf('.') < 0; }
+function dnsDomainIs(host, domain) {
+    return host.length >= domain.length && host.substring(host.length - domain.length) == domain;
+}
+function localHostOrDomainIs(host, hostdom) { return host == hostdom || hostdom.lastIndexOf(host + '.', 0) == 0; }
+function isResolvable(host) { return dnsResolve(host) != null; }
+function dnsDomainLevels(host) { return host.split('.').length - 1; }
+function convert_addr(ip) {
+    var bytes = ip.split('.');
+    return ((bytes[0] & 0xff) << 24) | ((bytes[1] & 0xff) << 16) | ((bytes[2] & 0xff) << 8) | (bytes[3] & 0xff);
+}
+function isInNet(host, pattern, mask) {
+    var ip = /^\d+\.\d+\.\d+\.\d+$/.test(host) ? host : dnsResolve(host);
+    if (ip == null) return false;
+    return (convert_addr(ip) & convert_addr(mask)) == (convert_addr(pattern) & convert_addr(mask));
+}
+function shExpMatch(str, shexp) {
+    var re = shexp.replace(/[.+^${}()|[\]\\]/g, '\\$&').replace(/\*/g, '.*').replace(/\?/g, '.');
+    return new RegExp('^' + re + '$').test(str);
+}
+
+// Ranges include their bounds, and wrap around when the start is after the end
+function pacInRange(value, from, to) { return from <= to ? value >= from && value <= to : value >= from || value <= to; }
+function pacArgs(args) {
+    var values = Array.prototype.slice.call(args);
+    var gmt = values[values.length - 1] == 'GMT';
+    if (gmt) values.pop();
+    return { values: values, now: new Date(), gmt: gmt };
+}
+function weekdayRange() {
+    var a = pacArgs(arguments);
+    var day = a.gmt ? a.now.getUTCDay() : a.now.getDay();
+    var from = PAC_DAYS.indexOf(a.values[0]);
+    var to = a.values.length > 1 ? PAC_DAYS.indexOf(a.values[1]) : from;
+    return pacInRange(day, from, to);
+}
+function timeRange() {
+    var a = pacArgs(arguments);
+    var now = a.gmt
+        ? [a.now.getUTCHours(), a.now.getUTCMinutes(), a.now.getUTCSeconds()]
+        : [a.now.getHours(), a.now.getMinutes(), a.now.getSeconds()];
+    var half = a.values.length == 1 ? 1 : a.values.length / 2;
+    var seconds = function (values) {
+        var total = 0;
+        for (var i = 0; i < half; i++) total = total * 60 + Number(values[i]);
+        return total;
+    };
+    var to = a.values.length == 1 ? a.values : a.values.slice(half);
+    return pacInRange(seconds(now), seconds(a.values), seconds(to));
+}
+function dateRange() {
+    var a = pacArgs(arguments);
+    var now = a.gmt
+        ? [a.now.getUTCFullYear(), a.now.getUTCMonth(), a.now.getUTCDate()]
+        : [a.now.getFullYear(), a.now.getMonth(), a.now.getDate()];
+    var half = a.values.length == 1 ? 1 : a.values.length / 2;
+    var date = function (values) {
+        var date = [null, null, null];
+        values.forEach(function (v) {
+            if (typeof v == 'string') date[1] = PAC_MONTHS.indexOf(v.toUpperCase());
+            else if (v > 31) date[0] = v;
+            else date[2] = v;
+        });
+        return date;
+    };
+    var from = date(a.values.slice(0, half));
+    var to = date(a.values.length == 1 ? a.values : a.values.slice(half));
+    // Only the fields given in the range are compared
+    var key = function (d) {
+        return (from[0] != null ? d[0] : 0) * 372 + (from[1] != null ? d[1] : 0) * 31 + (from[2] != null ? d[2] : 0);
+    };
+    return pacInRange(key(now), key(from), key(to));
+}
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pac_result() {
+        assert_eq!(parse_pac_result("DIRECT").unwrap(), None);
+        assert_eq!(
+            parse_pac_result("PROXY proxy.corp:3128; DIRECT").unwrap().as_deref(),
+            Some("proxy.corp:3128")
+        );
+        assert_eq!(
+            parse_pac_result("SOCKS5 socks.corp:1080;  proxy  backup.corp:8080")
+                .unwrap()
+                .as_deref(),
+            Some("backup.corp:8080")
+        );
+        assert!(parse_pac_result("SOCKS socks.corp:1080").is_err());
+        assert!(parse_pac_result("").is_err());
+    }
+
+    #[cfg(feature = "pac")]
+    #[test]
+    fn test_evaluate() {
+        let script = r#"
+            function FindProxyForURL(url, host) {
+                if (isPlainHostName(host) || dnsDomainIs(host, ".corp.example"))
+                    return "DIRECT";
+                if (/^\d+\.\d+\.\d+\.\d+$/.test(host) && isInNet(host, "10.0.0.0", "255.0.0.0"))
+                    return "DIRECT";
+                if (shExpMatch(url, "https://*.example.com*") && weekdayRange("SUN", "SAT") && timeRange(0, 23))
+                    return "PROXY secure-proxy.corp.example:3128; DIRECT";
+                return "PROXY proxy.corp.example:3128";
+            }
+        "#;
+        let eval = |url: &str, host: &str| evaluate(script, url, host).unwrap();
+
+        assert_eq!(eval("https://intranet/", "intranet"), "DIRECT");
+        assert_eq!(eval("https://git.corp.example/", "git.corp.example"), "DIRECT");
+        assert_eq!(eval("http://10.1.2.3:8080/", "10.1.2.3"), "DIRECT");
+        assert_eq!(
+            eval("https://ws.example.com/", "ws.example.com"),
+            "PROXY secure-proxy.corp.example:3128; DIRECT"
+        );
+        assert_eq!(
+            eval("http://ws.example.com/", "ws.example.com"),
+            "PROXY proxy.corp.example:3128"
+        );
+        assert!(evaluate("var x = 1;", "http://a/", "a").is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use anyhow::Context;
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use std::fs::File;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+use std::time::SystemTime;
+use tracing::{info, warn};
+
+// Tunnels are written as fake tcp/udp flows between these 2 hosts, the payload is the one of the tunnel.
+// The port of the destination is kept, for wireshark to pick the right dissector (i.e: 443 for TLS)
+const CLIENT_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const SERVER_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const LINKTYPE_IPV4: u32 = 228;
+const IPV4_HEADER_LEN: usize = 20;
+const TCP_HEADER_LEN: usize = 20;
+const UDP_HEADER_LEN: usize = 8;
+const MAX_PAYLOAD: usize = u16::MAX as usize - IPV4_HEADER_LEN - TCP_HEADER_LEN;
+
+static PCAP: OnceCell<Mutex<File>> = OnceCell::new();
+static NEXT_CLIENT_PORT: AtomicU16 = AtomicU16::new(0);
+
+/// Record the payload of all tunnels in this pcap file, to be analyzed with wireshark
+pub fn init(path: &Path) -> anyhow::Result<()> {
+    let mut file = File::create(path).with_context(|| format!("cannot create pcap file {}", path.display()))?;
+
+    let mut header = Vec::with_capacity(24);
+    header.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&4u16.to_le_bytes());
+    header.extend_from_slice(&0i32.to_le_bytes()); // timezone
+    header.extend_from_slice(&0u32.to_le_bytes()); // sigfigs
+    header.extend_from_slice(&(u16::MAX as u32).to_le_bytes()); // snaplen
+    header.extend_from_slice(&LINKTYPE_IPV4.to_le_bytes());
+    file.write_all(&header)?;
+
+    warn!(
+        "Recording the payload of tunnels in {}, it may contain sensitive data",
+        path.display()
+    );
+    let _ = PCAP.set(Mutex::new(file));
+    Ok(())
+}
+
+fn checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Capture of the payload of one tunnel
+pub struct TunnelCapture {
+    client_port: u16,
+    server_port: u16,
+    datagram: bool,
+    // If the local side of the tunnel is the one serving the port, i.e: the destination of the server
+    server_is_local: bool,
+    // Next tcp sequence number of client => server and server => client
+    seq: [AtomicU32; 2],
+}
+
+impl TunnelCapture {
+    /// Return None if the capture is not enabled
+    pub fn new(tunnel_id: &str, port: u16, datagram: bool, server_is_local: bool) -> Option<Self> {
+        PCAP.get()?;
+        let client_port = 1024 + NEXT_CLIENT_PORT.fetch_add(1, Ordering::Relaxed) % (u16::MAX - 1024);
+        info!(
+            "Capturing tunnel {} as {} stream {}:{} <-> {}:{}",
+            tunnel_id,
+            if datagram { "udp" } else { "tcp" },
+            CLIENT_IP,
+            client_port,
+            SERVER_IP,
+            port
+        );
+        Some(Self {
+            client_port,
+            server_port: port,
+            datagram,
+            server_is_local,
+            seq: [AtomicU32::new(1), AtomicU32::new(1)],
+        })
+    }
+
+    pub fn local_to_remote(&self, data: &[u8]) {
+        self.record(!self.server_is_local, data);
+    }
+
+    pub fn remote_to_local(&self, data: &[u8]) {
+        self.record(self.server_is_local, data);
+    }
+
+    fn record(&self, from_client: bool, data: &[u8]) {
+        let Some(pcap) = PCAP.get() else {
+            return;
+        };
+        for payload in data.chunks(MAX_PAYLOAD) {
+            let packet = self.packet(from_client, payload, SystemTime::now());
+            if let Err(err) = pcap.lock().write_all(&packet) {
+                warn!("Cannot write to pcap file: {}", err);
+                return;
+            }
+        }
+    }
+
+    fn packet(&self, from_client: bool, payload: &[u8], now: SystemTime) -> Vec<u8> {
+        let (src, dst) = if from_client {
+            ((CLIENT_IP, self.client_port), (SERVER_IP, self.server_port))
+        } else {
+            ((SERVER_IP, self.server_port), (CLIENT_IP, self.client_port))
+        };
+        let transport_len = if self.datagram { UDP_HEADER_LEN } else { TCP_HEADER_LEN };
+        let ip_len = IPV4_HEADER_LEN + transport_len + payload.len();
+        let now = now.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+
+        let mut packet = Vec::with_capacity(16 + ip_len);
+        packet.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+        packet.extend_from_slice(&now.subsec_micros().to_le_bytes());
+        packet.extend_from_slice(&(ip_len as u32).to_le_bytes());
+        packet.extend_from_slice(&(ip_len as u32).to_le_bytes());
+
+        let ip_start = packet.len();
+        packet.extend_from_slice(&[0x45, 0]);
+        packet.extend_from_slice(&(ip_len as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0x40, 0, 64, if self.datagram { 17 } else { 6 }, 0, 0]);
+        packet.extend_from_slice(&src.0.octets());
+        packet.extend_from_slice(&dst.0.octets());
+        let ip_checksum = checksum(&packet[ip_start..]);
+        packet[ip_start + 10..ip_start + 12].copy_from_slice(&ip_checksum.to_be_bytes());
+
+        packet.extend_from_slice(&src.1.to_be_bytes());
+        packet.extend_from_slice(&dst.1.to_be_bytes());
+        if self.datagram {
+            packet.extend_from_slice(&((UDP_HEADER_LEN + payload.len()) as u16).to_be_bytes());
+            packet.extend_from_slice(&[0, 0]);
+        } else {
+            // Checksum is left empty, wireshark does not validate it by default
+            let (seq, ack) = if from_client { (0, 1) } else { (1, 0) };
+            let seq = self.seq[seq].fetch_add(payload.len() as u32, Ordering::Relaxed);
+            let ack = self.seq[ack].load(Ordering::Relaxed);
+            packet.extend_from_slice(&seq.to_be_bytes());
+            packet.extend_from_slice(&ack.to_be_bytes());
+            packet.extend_from_slice(&[(TCP_HEADER_LEN as u8 / 4) << 4, 0x18, 0xff, 0xff, 0, 0, 0, 0]);
+        }
+        packet.extend_from_slice(payload);
+
+        packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_pcap_packet() {
+        let capture = TunnelCapture {
+            client_port: 1024,
+            server_port: 443,
+            datagram: false,
+            server_is_local: false,
+            seq: [AtomicU32::new(1), AtomicU32::new(1)],
+        };
+        let now = SystemTime::UNIX_EPOCH + Duration::from_micros(1_500_000);
+
+        let packet = capture.packet(true, b"hello", now);
+        assert_eq!(&packet[..16], &[1, 0, 0, 0, 0x20, 0xa1, 0x07, 0, 45, 0, 0, 0, 45, 0, 0, 0]);
+        let ip = &packet[16..36];
+        assert_eq!(&ip[2..4], &45u16.to_be_bytes());
+        assert_eq!(checksum(ip), 0);
+        assert_eq!(&ip[12..16], &[10, 0, 0, 1]);
+        assert_eq!(&packet[36..38], &1024u16.to_be_bytes());
+        assert_eq!(&packet[38..40], &443u16.to_be_bytes());
+        assert_eq!(&packet[56..], b"hello");
+
+        // The answer acknowledges the bytes sent by the client
+        let packet = capture.packet(false, b"hi", now);
+        assert_eq!(&packet[16 + 12..16 + 16], &[10, 0, 0, 2]);
+        assert_eq!(&packet[40..44], &1u32.to_be_bytes());
+        assert_eq!(&packet[44..48], &6u32.to_be_bytes());
+    }
+}
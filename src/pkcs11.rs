@@ -0,0 +1,589 @@
+use anyhow::{anyhow, Context};
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256, Sha384};
+use std::ffi::c_void;
+use std::fmt::{Debug, Formatter};
+use std::os::raw::c_ulong;
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::Arc;
+use tokio_rustls::rustls;
+use tokio_rustls::rustls::sign::{Signer, SigningKey};
+use tokio_rustls::rustls::{SignatureAlgorithm, SignatureScheme};
+use tracing::info;
+
+// Subset of the PKCS#11 v2.40 api, from pkcs11.h
+type CkUlong = c_ulong;
+type CkRv = CkUlong;
+
+const CKR_OK: CkRv = 0;
+const CKR_USER_ALREADY_LOGGED_IN: CkRv = 0x100;
+const CKR_CRYPTOKI_ALREADY_INITIALIZED: CkRv = 0x191;
+const CKF_SERIAL_SESSION: CkUlong = 1 << 2;
+const CKU_USER: CkUlong = 1;
+const CKO_PRIVATE_KEY: CkUlong = 3;
+const CKA_CLASS: CkUlong = 0x0;
+const CKA_LABEL: CkUlong = 0x3;
+const CKA_KEY_TYPE: CkUlong = 0x100;
+const CKA_ID: CkUlong = 0x102;
+const CKA_EC_PARAMS: CkUlong = 0x180;
+const CKK_RSA: CkUlong = 0;
+const CKK_EC: CkUlong = 3;
+const CKM_SHA256_RSA_PKCS: CkUlong = 0x40;
+const CKM_SHA384_RSA_PKCS: CkUlong = 0x41;
+const CKM_SHA256_RSA_PKCS_PSS: CkUlong = 0x43;
+const CKM_SHA384_RSA_PKCS_PSS: CkUlong = 0x44;
+const CKM_SHA256: CkUlong = 0x250;
+const CKM_SHA384: CkUlong = 0x260;
+const CKM_ECDSA: CkUlong = 0x1041;
+const CKG_MGF1_SHA256: CkUlong = 0x2;
+const CKG_MGF1_SHA384: CkUlong = 0x3;
+
+// DER encoded OIDs of the curves, as found in CKA_EC_PARAMS
+const OID_NISTP256: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_NISTP384: &[u8] = &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x22];
+
+// Big enough for the signature of an 8192 bits RSA key
+const MAX_SIGNATURE_LEN: usize = 1024;
+// Bigger than CK_TOKEN_INFO on every platform
+const TOKEN_INFO_LEN: usize = 512;
+
+type Unused = Option<unsafe extern "C" fn()>;
+
+// Structs are packed on windows, as mandated by the PKCS#11 specification
+#[cfg_attr(windows, repr(C, packed(1)))]
+#[cfg_attr(not(windows), repr(C))]
+struct CkFunctionList {
+    version: [u8; 2],
+    c_initialize: Option<unsafe extern "C" fn(*mut c_void) -> CkRv>,
+    _c_finalize_to_c_get_function_list: [Unused; 3],
+    c_get_slot_list: Option<unsafe extern "C" fn(u8, *mut CkUlong, *mut CkUlong) -> CkRv>,
+    _c_get_slot_info: Unused,
+    c_get_token_info: Option<unsafe extern "C" fn(CkUlong, *mut u8) -> CkRv>,
+    _c_get_mechanism_list_to_c_set_pin: [Unused; 5],
+    c_open_session: Option<unsafe extern "C" fn(CkUlong, CkUlong, *mut c_void, *mut c_void, *mut CkUlong) -> CkRv>,
+    _c_close_session_to_c_set_operation_state: [Unused; 5],
+    c_login: Option<unsafe extern "C" fn(CkUlong, CkUlong, *const u8, CkUlong) -> CkRv>,
+    _c_logout_to_c_get_object_size: [Unused; 5],
+    c_get_attribute_value: Option<unsafe extern "C" fn(CkUlong, CkUlong, *mut CkAttribute, CkUlong) -> CkRv>,
+    _c_set_attribute_value: Unused,
+    c_find_objects_init: Option<unsafe extern "C" fn(CkUlong, *mut CkAttribute, CkUlong) -> CkRv>,
+    c_find_objects: Option<unsafe extern "C" fn(CkUlong, *mut CkUlong, CkUlong, *mut CkUlong) -> CkRv>,
+    c_find_objects_final: Option<unsafe extern "C" fn(CkUlong) -> CkRv>,
+    _c_encrypt_init_to_c_digest_final: [Unused; 13],
+    c_sign_init: Option<unsafe extern "C" fn(CkUlong, *mut CkMechanism, CkUlong) -> CkRv>,
+    c_sign: Option<unsafe extern "C" fn(CkUlong, *const u8, CkUlong, *mut u8, *mut CkUlong) -> CkRv>,
+}
+
+#[cfg_attr(windows, repr(C, packed(1)))]
+#[cfg_attr(not(windows), repr(C))]
+struct CkAttribute {
+    kind: CkUlong,
+    value: *mut c_void,
+    value_len: CkUlong,
+}
+
+#[cfg_attr(windows, repr(C, packed(1)))]
+#[cfg_attr(not(windows), repr(C))]
+struct CkMechanism {
+    mechanism: CkUlong,
+    parameter: *mut c_void,
+    parameter_len: CkUlong,
+}
+
+#[cfg_attr(windows, repr(C, packed(1)))]
+#[cfg_attr(not(windows), repr(C))]
+struct CkRsaPkcsPssParams {
+    hash_alg: CkUlong,
+    mgf: CkUlong,
+    salt_len: CkUlong,
+}
+
+/// Functions of the module used by wstunnel, checked to be present when the module is loaded
+struct Functions {
+    initialize: unsafe extern "C" fn(*mut c_void) -> CkRv,
+    get_slot_list: unsafe extern "C" fn(u8, *mut CkUlong, *mut CkUlong) -> CkRv,
+    get_token_info: unsafe extern "C" fn(CkUlong, *mut u8) -> CkRv,
+    open_session: unsafe extern "C" fn(CkUlong, CkUlong, *mut c_void, *mut c_void, *mut CkUlong) -> CkRv,
+    login: unsafe extern "C" fn(CkUlong, CkUlong, *const u8, CkUlong) -> CkRv,
+    get_attribute_value: unsafe extern "C" fn(CkUlong, CkUlong, *mut CkAttribute, CkUlong) -> CkRv,
+    find_objects_init: unsafe extern "C" fn(CkUlong, *mut CkAttribute, CkUlong) -> CkRv,
+    find_objects: unsafe extern "C" fn(CkUlong, *mut CkUlong, CkUlong, *mut CkUlong) -> CkRv,
+    find_objects_final: unsafe extern "C" fn(CkUlong) -> CkRv,
+    sign_init: unsafe extern "C" fn(CkUlong, *mut CkMechanism, CkUlong) -> CkRv,
+    sign: unsafe extern "C" fn(CkUlong, *const u8, CkUlong, *mut u8, *mut CkUlong) -> CkRv,
+}
+
+fn check(function: &str, rv: CkRv) -> anyhow::Result<()> {
+    if rv != CKR_OK {
+        return Err(anyhow!("{} failed with error 0x{:x}", function, rv));
+    }
+    Ok(())
+}
+
+/// Location of a private key in a PKCS#11 module, as described by RFC 7512
+#[derive(Debug, Default, PartialEq)]
+struct Pkcs11Uri {
+    module_path: PathBuf,
+    token: Option<String>,
+    serial: Option<String>,
+    slot_id: Option<CkUlong>,
+    object: Option<String>,
+    id: Option<Vec<u8>>,
+    pin: Option<String>,
+}
+
+fn decode(value: &str) -> Vec<u8> {
+    urlencoding::decode_binary(value.as_bytes()).into_owned()
+}
+
+fn decode_str(name: &str, value: &str) -> anyhow::Result<String> {
+    String::from_utf8(decode(value)).with_context(|| format!("invalid {} in pkcs11 uri", name))
+}
+
+impl Pkcs11Uri {
+    fn parse(uri: &str) -> anyhow::Result<Self> {
+        let Some(uri) = uri.strip_prefix("pkcs11:") else {
+            return Err(anyhow!("pkcs11 uri must start with pkcs11:"));
+        };
+        let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+
+        let mut parsed = Pkcs11Uri::default();
+        for attr in path.split(';').filter(|attr| !attr.is_empty()) {
+            let (name, value) = attr
+                .split_once('=')
+                .with_context(|| format!("invalid attribute {} in pkcs11 uri", attr))?;
+            match name {
+                "token" => parsed.token = Some(decode_str(name, value)?),
+                "serial" => parsed.serial = Some(decode_str(name, value)?),
+                "slot-id" => parsed.slot_id = Some(value.parse().with_context(|| "invalid slot-id in pkcs11 uri")?),
+                "object" => parsed.object = Some(decode_str(name, value)?),
+                "id" => parsed.id = Some(decode(value)),
+                "type" if value != "private" => return Err(anyhow!("pkcs11 uri must point to a private key")),
+                // manufacturer, model, library-* do not help to find the key
+                _ => {}
+            }
+        }
+
+        let mut module_path = None;
+        for attr in query.split('&').filter(|attr| !attr.is_empty()) {
+            let (name, value) = attr
+                .split_once('=')
+                .with_context(|| format!("invalid attribute {} in pkcs11 uri", attr))?;
+            match name {
+                "module-path" => module_path = Some(PathBuf::from(decode_str(name, value)?)),
+                "pin-value" => parsed.pin = Some(decode_str(name, value)?),
+                "pin-source" => {
+                    let source = decode_str(name, value)?;
+                    let path = source.strip_prefix("file:").unwrap_or(&source);
+                    let pin =
+                        std::fs::read_to_string(path).with_context(|| format!("Cannot read pin from {}", path))?;
+                    parsed.pin = Some(pin.lines().next().unwrap_or_default().to_string());
+                }
+                _ => {}
+            }
+        }
+
+        parsed.module_path = module_path.with_context(|| "pkcs11 uri must contain a module-path")?;
+        Ok(parsed)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum KeyKind {
+    Rsa,
+    EcdsaP256,
+    EcdsaP384,
+}
+
+struct Pkcs11KeyInner {
+    // Only the path part of the uri, the query can contain the pin
+    uri: String,
+    // Must be kept loaded, the functions point into it
+    _library: libloading::Library,
+    functions: Functions,
+    // Operations of a session cannot be interleaved, signatures are done one at a time
+    session: Mutex<CkUlong>,
+    key: CkUlong,
+    kind: KeyKind,
+}
+
+/// Private key stored in an HSM or a smartcard. It never leaves the device, only signatures are requested to it
+#[derive(Clone)]
+pub struct Pkcs11Key(Arc<Pkcs11KeyInner>);
+
+impl Debug for Pkcs11Key {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Pkcs11Key({}, {:?})", self.0.uri, self.0.kind)
+    }
+}
+
+pub fn is_pkcs11_uri(value: &str) -> bool {
+    value.starts_with("pkcs11:")
+}
+
+impl Pkcs11Key {
+    /// Load the PKCS#11 module of the uri, login on the token and find the private key.
+    /// i.e: pkcs11:token=wstunnel;object=server?module-path=/usr/lib/softhsm/libsofthsm2.so&pin-source=/etc/wstunnel/pin
+    pub fn load(uri: &str) -> anyhow::Result<Self> {
+        let parsed = Pkcs11Uri::parse(uri)?;
+        info!("Loading tls private key from PKCS#11 module {:?}", parsed.module_path);
+
+        let library = unsafe { libloading::Library::new(&parsed.module_path) }
+            .with_context(|| format!("Cannot load PKCS#11 module {:?}", parsed.module_path))?;
+        let functions = unsafe {
+            let get_function_list = library
+                .get::<unsafe extern "C" fn(*mut *const CkFunctionList) -> CkRv>(b"C_GetFunctionList\0")
+                .with_context(|| "Not a PKCS#11 module, C_GetFunctionList is missing")?;
+            let mut list: *const CkFunctionList = ptr::null();
+            check("C_GetFunctionList", get_function_list(&mut list))?;
+            let list = list
+                .as_ref()
+                .with_context(|| "C_GetFunctionList returned no functions")?;
+            Functions {
+                initialize: list.c_initialize.with_context(|| "C_Initialize is missing")?,
+                get_slot_list: list.c_get_slot_list.with_context(|| "C_GetSlotList is missing")?,
+                get_token_info: list.c_get_token_info.with_context(|| "C_GetTokenInfo is missing")?,
+                open_session: list.c_open_session.with_context(|| "C_OpenSession is missing")?,
+                login: list.c_login.with_context(|| "C_Login is missing")?,
+                get_attribute_value: list
+                    .c_get_attribute_value
+                    .with_context(|| "C_GetAttributeValue is missing")?,
+                find_objects_init: list
+                    .c_find_objects_init
+                    .with_context(|| "C_FindObjectsInit is missing")?,
+                find_objects: list.c_find_objects.with_context(|| "C_FindObjects is missing")?,
+                find_objects_final: list
+                    .c_find_objects_final
+                    .with_context(|| "C_FindObjectsFinal is missing")?,
+                sign_init: list.c_sign_init.with_context(|| "C_SignInit is missing")?,
+                sign: list.c_sign.with_context(|| "C_Sign is missing")?,
+            }
+        };
+
+        let rv = unsafe { (functions.initialize)(ptr::null_mut()) };
+        if rv != CKR_CRYPTOKI_ALREADY_INITIALIZED {
+            check("C_Initialize", rv)?;
+        }
+
+        let slot = find_slot(&functions, &parsed)?;
+        let mut session: CkUlong = 0;
+        check("C_OpenSession", unsafe {
+            (functions.open_session)(slot, CKF_SERIAL_SESSION, ptr::null_mut(), ptr::null_mut(), &mut session)
+        })?;
+
+        if let Some(pin) = &parsed.pin {
+            let rv = unsafe { (functions.login)(session, CKU_USER, pin.as_ptr(), pin.len() as CkUlong) };
+            if rv != CKR_USER_ALREADY_LOGGED_IN {
+                check("C_Login", rv).with_context(|| "Cannot login on the PKCS#11 token, is the pin correct ?")?;
+            }
+        }
+
+        let key = find_private_key(&functions, session, &parsed)?;
+        let kind = key_kind(&functions, session, key)?;
+        info!("Using {:?} private key from PKCS#11 token", kind);
+
+        Ok(Self(Arc::new(Pkcs11KeyInner {
+            uri: format!(
+                "pkcs11:{}",
+                uri.trim_start_matches("pkcs11:").split('?').next().unwrap_or_default()
+            ),
+            _library: library,
+            functions,
+            session: Mutex::new(session),
+            key,
+            kind,
+        })))
+    }
+
+    fn sign(&self, mechanism: CkUlong, pss_params: Option<CkRsaPkcsPssParams>, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let this = &self.0;
+        let mut pss_params = pss_params;
+        let mut mechanism = CkMechanism {
+            mechanism,
+            parameter: pss_params
+                .as_mut()
+                .map_or(ptr::null_mut(), |params| params as *mut CkRsaPkcsPssParams as *mut c_void),
+            parameter_len: pss_params
+                .as_ref()
+                .map_or(0, |_| std::mem::size_of::<CkRsaPkcsPssParams>() as CkUlong),
+        };
+
+        let mut signature = vec![0u8; MAX_SIGNATURE_LEN];
+        let mut signature_len = signature.len() as CkUlong;
+        let session = this.session.lock();
+        unsafe {
+            check("C_SignInit", (this.functions.sign_init)(*session, &mut mechanism, this.key))?;
+            check(
+                "C_Sign",
+                (this.functions.sign)(
+                    *session,
+                    data.as_ptr(),
+                    data.len() as CkUlong,
+                    signature.as_mut_ptr(),
+                    &mut signature_len,
+                ),
+            )?;
+        }
+        drop(session);
+
+        signature.truncate(signature_len as usize);
+        Ok(signature)
+    }
+}
+
+fn find_slot(functions: &Functions, uri: &Pkcs11Uri) -> anyhow::Result<CkUlong> {
+    let mut count: CkUlong = 0;
+    check("C_GetSlotList", unsafe {
+        (functions.get_slot_list)(1, ptr::null_mut(), &mut count)
+    })?;
+    let mut slots = vec![0 as CkUlong; count as usize];
+    check("C_GetSlotList", unsafe {
+        (functions.get_slot_list)(1, slots.as_mut_ptr(), &mut count)
+    })?;
+    slots.truncate(count as usize);
+
+    for slot in slots {
+        if uri.slot_id.is_some_and(|id| id != slot) {
+            continue;
+        }
+
+        // Label and serial number are the 1st and 4th fields of CK_TOKEN_INFO, padded with spaces
+        let mut token_info = [0u8; TOKEN_INFO_LEN];
+        check("C_GetTokenInfo", unsafe {
+            (functions.get_token_info)(slot, token_info.as_mut_ptr())
+        })?;
+        let label = String::from_utf8_lossy(&token_info[..32]);
+        let serial = String::from_utf8_lossy(&token_info[80..96]);
+        if uri.token.as_ref().is_some_and(|token| token != label.trim_end()) {
+            continue;
+        }
+        if uri.serial.as_ref().is_some_and(|s| s != serial.trim_end()) {
+            continue;
+        }
+
+        return Ok(slot);
+    }
+
+    Err(anyhow!("No PKCS#11 token matches the uri"))
+}
+
+fn find_private_key(functions: &Functions, session: CkUlong, uri: &Pkcs11Uri) -> anyhow::Result<CkUlong> {
+    let mut class = CKO_PRIVATE_KEY;
+    let mut template = vec![CkAttribute {
+        kind: CKA_CLASS,
+        value: &mut class as *mut CkUlong as *mut c_void,
+        value_len: std::mem::size_of::<CkUlong>() as CkUlong,
+    }];
+    if let Some(label) = &uri.object {
+        template.push(CkAttribute {
+            kind: CKA_LABEL,
+            value: label.as_ptr() as *mut c_void,
+            value_len: label.len() as CkUlong,
+        });
+    }
+    if let Some(id) = &uri.id {
+        template.push(CkAttribute {
+            kind: CKA_ID,
+            value: id.as_ptr() as *mut c_void,
+            value_len: id.len() as CkUlong,
+        });
+    }
+
+    let mut keys = [0 as CkUlong; 2];
+    let mut count: CkUlong = 0;
+    unsafe {
+        check(
+            "C_FindObjectsInit",
+            (functions.find_objects_init)(session, template.as_mut_ptr(), template.len() as CkUlong),
+        )?;
+        let rv = (functions.find_objects)(session, keys.as_mut_ptr(), keys.len() as CkUlong, &mut count);
+        (functions.find_objects_final)(session);
+        check("C_FindObjects", rv)?;
+    }
+
+    match count {
+        0 => Err(anyhow!("No private key matches the pkcs11 uri, is a pin needed to see it ?")),
+        1 => Ok(keys[0]),
+        _ => Err(anyhow!(
+            "Several private keys match the pkcs11 uri, use object= or id= to select one"
+        )),
+    }
+}
+
+fn key_kind(functions: &Functions, session: CkUlong, key: CkUlong) -> anyhow::Result<KeyKind> {
+    let mut key_type: CkUlong = 0;
+    let mut attribute = CkAttribute {
+        kind: CKA_KEY_TYPE,
+        value: &mut key_type as *mut CkUlong as *mut c_void,
+        value_len: std::mem::size_of::<CkUlong>() as CkUlong,
+    };
+    check("C_GetAttributeValue", unsafe {
+        (functions.get_attribute_value)(session, key, &mut attribute, 1)
+    })?;
+
+    match key_type {
+        CKK_RSA => Ok(KeyKind::Rsa),
+        CKK_EC => {
+            let mut ec_params = [0u8; 64];
+            let mut attribute = CkAttribute {
+                kind: CKA_EC_PARAMS,
+                value: ec_params.as_mut_ptr() as *mut c_void,
+                value_len: ec_params.len() as CkUlong,
+            };
+            check("C_GetAttributeValue", unsafe {
+                (functions.get_attribute_value)(session, key, &mut attribute, 1)
+            })?;
+            match &ec_params[..(attribute.value_len as usize).min(ec_params.len())] {
+                OID_NISTP256 => Ok(KeyKind::EcdsaP256),
+                OID_NISTP384 => Ok(KeyKind::EcdsaP384),
+                _ => Err(anyhow!(
+                    "Unsupported curve for PKCS#11 private key, only P-256 and P-384 are supported"
+                )),
+            }
+        }
+        _ => Err(anyhow!("Unsupported PKCS#11 private key type {}, expected RSA or EC", key_type)),
+    }
+}
+
+/// Convert the r || s signature of PKCS#11 to the DER encoding expected by TLS
+fn ecdsa_signature_to_der(signature: &[u8]) -> Vec<u8> {
+    fn der_integer(value: &[u8]) -> Vec<u8> {
+        let start = value
+            .iter()
+            .position(|b| *b != 0)
+            .unwrap_or(value.len().saturating_sub(1));
+        let value = &value[start..];
+        let mut integer = vec![0x02];
+        if value.first().is_some_and(|b| b & 0x80 != 0) {
+            integer.push(value.len() as u8 + 1);
+            integer.push(0);
+        } else {
+            integer.push(value.len() as u8);
+        }
+        integer.extend_from_slice(value);
+        integer
+    }
+
+    let (r, s) = signature.split_at(signature.len() / 2);
+    let mut content = der_integer(r);
+    content.extend(der_integer(s));
+
+    let mut der = vec![0x30];
+    if content.len() >= 0x80 {
+        der.push(0x81);
+    }
+    der.push(content.len() as u8);
+    der.extend(content);
+    der
+}
+
+impl SigningKey for Pkcs11Key {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<dyn Signer>> {
+        let supported: &[SignatureScheme] = match self.0.kind {
+            KeyKind::Rsa => &[
+                SignatureScheme::RSA_PSS_SHA256,
+                SignatureScheme::RSA_PSS_SHA384,
+                SignatureScheme::RSA_PKCS1_SHA256,
+                SignatureScheme::RSA_PKCS1_SHA384,
+            ],
+            KeyKind::EcdsaP256 => &[SignatureScheme::ECDSA_NISTP256_SHA256],
+            KeyKind::EcdsaP384 => &[SignatureScheme::ECDSA_NISTP384_SHA384],
+        };
+
+        let scheme = *supported.iter().find(|scheme| offered.contains(scheme))?;
+        Some(Box::new(Pkcs11Signer {
+            key: self.clone(),
+            scheme,
+        }))
+    }
+
+    fn algorithm(&self) -> SignatureAlgorithm {
+        match self.0.kind {
+            KeyKind::Rsa => SignatureAlgorithm::RSA,
+            KeyKind::EcdsaP256 | KeyKind::EcdsaP384 => SignatureAlgorithm::ECDSA,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Pkcs11Signer {
+    key: Pkcs11Key,
+    scheme: SignatureScheme,
+}
+
+impl Signer for Pkcs11Signer {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, rustls::Error> {
+        let pss = |hash_alg, mgf, salt_len| {
+            Some(CkRsaPkcsPssParams {
+                hash_alg,
+                mgf,
+                salt_len,
+            })
+        };
+        let signature = match self.scheme {
+            SignatureScheme::RSA_PSS_SHA256 => {
+                self.key
+                    .sign(CKM_SHA256_RSA_PKCS_PSS, pss(CKM_SHA256, CKG_MGF1_SHA256, 32), message)
+            }
+            SignatureScheme::RSA_PSS_SHA384 => {
+                self.key
+                    .sign(CKM_SHA384_RSA_PKCS_PSS, pss(CKM_SHA384, CKG_MGF1_SHA384, 48), message)
+            }
+            SignatureScheme::RSA_PKCS1_SHA256 => self.key.sign(CKM_SHA256_RSA_PKCS, None, message),
+            SignatureScheme::RSA_PKCS1_SHA384 => self.key.sign(CKM_SHA384_RSA_PKCS, None, message),
+            // The token only does the raw ECDSA operation, the message must be hashed before
+            SignatureScheme::ECDSA_NISTP256_SHA256 => self
+                .key
+                .sign(CKM_ECDSA, None, &Sha256::digest(message))
+                .map(|signature| ecdsa_signature_to_der(&signature)),
+            SignatureScheme::ECDSA_NISTP384_SHA384 => self
+                .key
+                .sign(CKM_ECDSA, None, &Sha384::digest(message))
+                .map(|signature| ecdsa_signature_to_der(&signature)),
+            scheme => Err(anyhow!("unsupported signature scheme {:?}", scheme)),
+        };
+
+        signature.map_err(|err| rustls::Error::General(format!("PKCS#11 signature failed: {:?}", err)))
+    }
+
+    fn scheme(&self) -> SignatureScheme {
+        self.scheme
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pkcs11_uri() {
+        let uri = Pkcs11Uri::parse(
+            "pkcs11:token=my%20token;object=server;id=%01%02;type=private?module-path=/usr/lib/libsofthsm2.so&pin-value=1234",
+        )
+        .unwrap();
+        assert_eq!(
+            uri,
+            Pkcs11Uri {
+                module_path: PathBuf::from("/usr/lib/libsofthsm2.so"),
+                token: Some("my token".to_string()),
+                object: Some("server".to_string()),
+                id: Some(vec![1, 2]),
+                pin: Some("1234".to_string()),
+                ..Default::default()
+            }
+        );
+
+        assert!(Pkcs11Uri::parse("pkcs11:token=my%20token").is_err());
+        assert!(Pkcs11Uri::parse("pkcs11:type=cert?module-path=/usr/lib/libsofthsm2.so").is_err());
+
+        // High bit of r is set, and s has leading zeros
+        let mut raw = vec![0x80; 32];
+        raw.extend([0x00, 0x00, 0x01]);
+        raw.extend([0x11; 29]);
+        let der = ecdsa_signature_to_der(&raw);
+        assert_eq!(&der[..5], &[0x30, 0x43, 0x02, 0x21, 0x00]);
+        assert_eq!(&der[37..40], &[0x02, 0x1e, 0x01]);
+        assert_eq!(der.len(), 0x43 + 2);
+    }
+}
@@ -0,0 +1,163 @@
+use anyhow::anyhow;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+/// Authentication scheme asked by a Proxy-Authenticate header, i.e: Digest realm="corp", nonce="abc", qop="auth"
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Challenge {
+    pub scheme: String,
+    value: String,
+    params: Vec<(String, String)>,
+}
+
+impl Challenge {
+    pub fn parse(header: &str) -> Option<Self> {
+        let header = header.trim();
+        let (scheme, params) = header.split_once(' ').unwrap_or((header, ""));
+        if scheme.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            scheme: scheme.to_string(),
+            value: params.trim().to_string(),
+            params: parse_params(params),
+        })
+    }
+
+    pub fn is(&self, scheme: &str) -> bool {
+        self.scheme.eq_ignore_ascii_case(scheme)
+    }
+
+    /// Opaque token of the challenge, i.e: the base64 blob in Negotiate YII...=
+    pub fn token(&self) -> Option<&str> {
+        (!self.value.is_empty() && !self.value.contains(' ')).then_some(self.value.as_str())
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// key=value, key="quoted, value"
+fn parse_params(params: &str) -> Vec<(String, String)> {
+    let mut parsed = Vec::new();
+    let mut rest = params.trim();
+    while let Some((key, after_key)) = rest.split_once('=') {
+        let after_key = after_key.trim_start();
+        let (value, after_value) = match after_key.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                (quoted[..end].to_string(), quoted.get(end + 1..).unwrap_or_default())
+            }
+            None => {
+                let end = after_key.find(',').unwrap_or(after_key.len());
+                (after_key[..end].trim().to_string(), &after_key[end..])
+            }
+        };
+        parsed.push((key.trim().to_string(), value));
+        rest = after_value.trim_start().trim_start_matches(',').trim_start();
+    }
+    parsed
+}
+
+/// Challenges of the Proxy-Authenticate headers of a raw http response
+pub fn proxy_challenges(response: &[u8]) -> Vec<Challenge> {
+    String::from_utf8_lossy(response)
+        .lines()
+        .filter_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim().eq_ignore_ascii_case("proxy-authenticate").then_some(value)
+        })
+        .filter_map(Challenge::parse)
+        .collect()
+}
+
+/// Value of the Proxy-Authorization header answering a Digest challenge (RFC 7616), with MD5 or SHA-256
+pub fn digest_authorization(
+    challenge: &Challenge,
+    user: &str,
+    password: &str,
+    method: &str,
+    uri: &str,
+    cnonce: &str,
+) -> anyhow::Result<String> {
+    let realm = challenge.param("realm").unwrap_or_default();
+    let nonce = challenge
+        .param("nonce")
+        .ok_or_else(|| anyhow!("Digest challenge of the proxy has no nonce"))?;
+    let algorithm = challenge.param("algorithm").unwrap_or("MD5");
+    let hash: fn(&str) -> String = match algorithm.trim_end_matches("-sess").to_ascii_uppercase().as_str() {
+        "MD5" => |data| hex(&Md5::digest(data.as_bytes())),
+        "SHA-256" => |data| hex(&Sha256::digest(data.as_bytes())),
+        _ => return Err(anyhow!("Unsupported digest algorithm {} asked by the proxy", algorithm)),
+    };
+    let qop = challenge
+        .param("qop")
+        .map(|qop| qop.split(',').any(|qop| qop.trim() == "auth"));
+    if qop == Some(false) {
+        return Err(anyhow!("Digest challenge of the proxy does not allow qop=auth"));
+    }
+
+    let mut ha1 = hash(&format!("{}:{}:{}", user, realm, password));
+    if algorithm.to_ascii_lowercase().ends_with("-sess") {
+        ha1 = hash(&format!("{}:{}:{}", ha1, nonce, cnonce));
+    }
+    let ha2 = hash(&format!("{}:{}", method, uri));
+    let nc = "00000001";
+    let response = match qop {
+        Some(_) => hash(&format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2)),
+        None => hash(&format!("{}:{}:{}", ha1, nonce, ha2)),
+    };
+
+    let mut authorization = format!(
+        r#"Digest username="{}", realm="{}", nonce="{}", uri="{}", algorithm={}, response="{}""#,
+        user, realm, nonce, uri, algorithm, response
+    );
+    if qop.is_some() {
+        authorization.push_str(&format!(r#", qop=auth, nc={}, cnonce="{}""#, nc, cnonce));
+    }
+    if let Some(opaque) = challenge.param("opaque") {
+        authorization.push_str(&format!(r#", opaque="{}""#, opaque));
+    }
+    Ok(authorization)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digest_authorization() {
+        let response = b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+            Proxy-Authenticate: Negotiate\r\n\
+            Proxy-Authenticate: Digest realm=\"testrealm@host.com\", qop=\"auth,auth-int\", \
+            nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\", opaque=\"5ccc069c403ebaf9f0171e9517f40e41\"\r\n\r\n";
+        let challenges = proxy_challenges(response);
+        assert_eq!(challenges.len(), 2);
+        assert!(challenges[0].is("negotiate"));
+        assert_eq!(challenges[0].token(), None);
+        let digest = &challenges[1];
+        assert!(digest.is("Digest"));
+        assert_eq!(digest.param("qop"), Some("auth,auth-int"));
+
+        // Example of RFC 2617
+        let authorization =
+            digest_authorization(digest, "Mufasa", "Circle Of Life", "GET", "/dir/index.html", "0a4f113b").unwrap();
+        assert!(authorization.contains(r#"response="6629fae49393a05397450978507c4ef1""#));
+        assert!(authorization.contains(r#"opaque="5ccc069c403ebaf9f0171e9517f40e41""#));
+
+        let negotiate = Challenge::parse("Negotiate TlRMTVNTUAACAAAA==").unwrap();
+        assert_eq!(negotiate.token(), Some("TlRMTVNTUAACAAAA=="));
+
+        let unsupported = Challenge::parse(r#"Digest realm="r", nonce="n", algorithm=SHA-512-256"#).unwrap();
+        assert!(digest_authorization(&unsupported, "u", "p", "CONNECT", "h:443", "c").is_err());
+    }
+}
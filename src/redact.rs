@@ -0,0 +1,144 @@
+use crate::tunnel::{CLOSE_REASON_HEADER, TRANSPORT_HEADER};
+use crate::version::{CLIENT_FEATURES_HEADER, CLIENT_VERSION_HEADER};
+use hyper::header::{
+    ACCEPT, ACCEPT_ENCODING, CACHE_CONTROL, CONNECTION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, DATE,
+    FORWARDED, HOST, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, SERVER,
+    TRANSFER_ENCODING, UPGRADE, USER_AGENT, VIA,
+};
+use hyper::http::HeaderName;
+use hyper::{HeaderMap, Request, Uri};
+use std::fmt::{Debug, Display, Formatter};
+
+const REDACTED: &str = "***";
+
+static SAFE_HEADERS: [HeaderName; 20] = [
+    ACCEPT,
+    ACCEPT_ENCODING,
+    CACHE_CONTROL,
+    CONNECTION,
+    CONTENT_ENCODING,
+    CONTENT_LENGTH,
+    CONTENT_TYPE,
+    DATE,
+    FORWARDED,
+    HOST,
+    SEC_WEBSOCKET_ACCEPT,
+    SEC_WEBSOCKET_EXTENSIONS,
+    SEC_WEBSOCKET_KEY,
+    SEC_WEBSOCKET_VERSION,
+    SERVER,
+    TRANSFER_ENCODING,
+    UPGRADE,
+    USER_AGENT,
+    VIA,
+    HeaderName::from_static("x-forwarded-for"),
+];
+
+// Headers known to carry nothing secret, the values of all the others never end up in logs.
+// Besides credentials and the tunnel jwt, the custom headers of --http-headers often hold a token for a proxy or a CDN
+fn is_safe_header(name: &HeaderName) -> bool {
+    SAFE_HEADERS.contains(name)
+        || name == CLIENT_VERSION_HEADER
+        || name == CLIENT_FEATURES_HEADER
+        || name == CLOSE_REASON_HEADER
+        || name == TRANSPORT_HEADER
+}
+
+/// Hide the wrapped value when printed, while still showing that something is set
+pub struct Redacted<T>(pub T);
+
+impl<T> Debug for Redacted<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl<T> Display for Redacted<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+/// Print an upgrade uri without its path prefix, as it can be used as a secret with --restrict-http-upgrade-path-prefix
+pub struct RedactedUri<'a>(pub &'a Uri);
+
+impl Display for RedactedUri<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(scheme) = self.0.scheme_str() {
+            write!(f, "{}://", scheme)?;
+        }
+        if let Some(authority) = self.0.authority() {
+            write!(f, "{}", authority.host())?;
+            if let Some(port) = authority.port_u16() {
+                write!(f, ":{}", port)?;
+            }
+        }
+
+        // Only the last segment of the path (i.e: /events) is meaningful
+        match self.0.path().trim_start_matches('/').rsplit_once('/') {
+            Some((_prefix, last)) => write!(f, "/{}/{}", REDACTED, last),
+            None => write!(f, "{}", self.0.path()),
+        }
+    }
+}
+
+impl Debug for RedactedUri<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+pub struct RedactedHeaders<'a>(pub &'a HeaderMap);
+
+impl Debug for RedactedHeaders<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut map = f.debug_map();
+        for (name, value) in self.0.iter() {
+            if is_safe_header(name) {
+                map.entry(name, value);
+            } else {
+                map.entry(name, &Redacted(value));
+            }
+        }
+        map.finish()
+    }
+}
+
+pub struct RedactedRequest<'a, B>(pub &'a Request<B>);
+
+impl<B> Debug for RedactedRequest<'_, B> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Request")
+            .field("method", self.0.method())
+            .field("uri", &RedactedUri(self.0.uri()))
+            .field("version", &self.0.version())
+            .field("headers", &RedactedHeaders(self.0.headers()))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redacted_request() {
+        use hyper::header::AUTHORIZATION;
+
+        let req = Request::builder()
+            .uri("http://example.com:8080/my-secret-prefix/events")
+            .header(AUTHORIZATION, "Basic dXNlcjpwYXNz")
+            .header(USER_AGENT, "wstunnel/10.1.0")
+            .header("x-cdn-token", "my-cdn-token")
+            .body(())
+            .unwrap();
+
+        let output = format!("{:?}", RedactedRequest(&req));
+        assert!(output.contains("http://example.com:8080/***/events"));
+        assert!(output.contains("wstunnel/10.1.0"));
+        assert!(output.contains("x-cdn-token"));
+        assert!(!output.contains("my-secret-prefix"));
+        assert!(!output.contains("dXNlcjpwYXNz"));
+        assert!(!output.contains("my-cdn-token"));
+    }
+}
@@ -0,0 +1,196 @@
+use crate::LocalProtocol;
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+use std::path::Path;
+use tracing::{debug, error, info, trace, warn};
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleLogLevel {
+    Trace,
+    Debug,
+    #[default]
+    Info,
+    Warn,
+    Error,
+}
+
+/// Rule of the restriction policy. A tunnel is admitted by the first rule allowing its protocol, destination and credentials.
+/// Empty lists allow anything, except for destinations that must always be listed
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RestrictionRule {
+    pub name: String,
+    /// DEST:PORT requested by the client, the bind address for reverse tunnels
+    destinations: Vec<String>,
+    /// Names of the tunnel protocols, i.e: tcp, udp, reverse_tcp, reverse_socks5
+    #[serde(default)]
+    protocols: Vec<String>,
+    /// Users authenticated by --auth-htpasswd
+    #[serde(default)]
+    users: Vec<String>,
+    /// First segment of the path of the upgrade request, as set by the client with --http-upgrade-path-prefix
+    #[serde(default)]
+    path_prefixes: Vec<String>,
+    /// Level of the line logged when the rule admits a tunnel
+    #[serde(default)]
+    log_level: RuleLogLevel,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RestrictionFile {
+    restrictions: Vec<RestrictionRule>,
+}
+
+/// Who asks for a tunnel, and toward which destinations
+pub struct TunnelRequest<'a> {
+    pub protocol: &'a LocalProtocol,
+    pub destinations: &'a [String],
+    pub user: Option<&'a str>,
+    pub path: &'a str,
+    /// Path before a reverse proxy stripped the prefix of its X-Forwarded-Prefix header, as for --restrict-http-upgrade-path-prefix
+    pub forwarded_path: Option<&'a str>,
+}
+
+impl RestrictionRule {
+    fn allows<'a>(&self, req: &TunnelRequest<'a>) -> bool {
+        let path_prefix = |path: &'a str| path.trim_start_matches('/').split('/').next().unwrap_or_default();
+        let path_prefixes = [Some(req.path), req.forwarded_path].map(|path| path.map(path_prefix));
+        (self.protocols.is_empty() || self.protocols.iter().any(|p| p == req.protocol.name()))
+            && req.destinations.iter().all(|dest| self.destinations.contains(dest))
+            && (self.users.is_empty() || req.user.is_some_and(|user| self.users.iter().any(|u| u == user)))
+            && (self.path_prefixes.is_empty()
+                || self
+                    .path_prefixes
+                    .iter()
+                    .any(|p| path_prefixes.iter().flatten().any(|prefix| p == *prefix)))
+    }
+
+    pub fn log_admitted(&self, req: &TunnelRequest) {
+        let dest = req.destinations.first().map(String::as_str).unwrap_or_default();
+        let (rule, protocol) = (&self.name, req.protocol.name());
+        match self.log_level {
+            RuleLogLevel::Trace => trace!("Tunnel {} {} admitted by restriction rule {}", protocol, dest, rule),
+            RuleLogLevel::Debug => debug!("Tunnel {} {} admitted by restriction rule {}", protocol, dest, rule),
+            RuleLogLevel::Info => info!("Tunnel {} {} admitted by restriction rule {}", protocol, dest, rule),
+            RuleLogLevel::Warn => warn!("Tunnel {} {} admitted by restriction rule {}", protocol, dest, rule),
+            RuleLogLevel::Error => error!("Tunnel {} {} admitted by restriction rule {}", protocol, dest, rule),
+        }
+    }
+}
+
+/// Named rules replacing the flat --restrict-to list, loaded from a yaml file
+#[derive(Clone, Debug)]
+pub struct Restrictions {
+    rules: Vec<RestrictionRule>,
+}
+
+impl Restrictions {
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content =
+            std::fs::read_to_string(path).with_context(|| format!("Cannot read restriction file {:?}", path))?;
+        let restrictions = Self::from_yaml(&content).with_context(|| format!("Invalid restriction file {:?}", path))?;
+        info!("Loaded {} restriction rules from {:?}", restrictions.rules.len(), path);
+        Ok(restrictions)
+    }
+
+    fn from_yaml(content: &str) -> anyhow::Result<Self> {
+        let file: RestrictionFile = serde_yaml::from_str(content)?;
+        for rule in &file.restrictions {
+            if rule.destinations.is_empty() {
+                return Err(anyhow!("restriction rule {} does not allow any destination", rule.name));
+            }
+            if let Some(dest) = rule.destinations.iter().find(|dest| !dest.contains(':')) {
+                return Err(anyhow!(
+                    "invalid destination {} in restriction rule {}, expected DEST:PORT",
+                    dest,
+                    rule.name
+                ));
+            }
+        }
+        Ok(Self {
+            rules: file.restrictions,
+        })
+    }
+
+    /// First rule admitting the tunnel, if any
+    pub fn find(&self, req: &TunnelRequest) -> Option<&RestrictionRule> {
+        self.rules.iter().find(|rule| rule.allows(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: &str = r#"
+restrictions:
+  - name: ssh-bastion
+    destinations: ["bastion.corp:22"]
+    protocols: [tcp]
+    users: [alice]
+    log_level: debug
+  - name: dns
+    destinations: ["1.1.1.1:53", "8.8.8.8:53"]
+    protocols: [udp]
+    path_prefixes: [resolvers]
+"#;
+
+    fn req<'a>(
+        protocol: &'a LocalProtocol,
+        destinations: &'a [String],
+        user: Option<&'a str>,
+        path: &'a str,
+    ) -> TunnelRequest<'a> {
+        TunnelRequest {
+            protocol,
+            destinations,
+            user,
+            path,
+            forwarded_path: None,
+        }
+    }
+
+    #[test]
+    fn test_restrictions() {
+        let restrictions = Restrictions::from_yaml(POLICY).unwrap();
+        let tcp = LocalProtocol::Tcp { proxy_protocol: false };
+        let udp = LocalProtocol::Udp { timeout: None };
+        let bastion = ["bastion.corp:22".to_string()];
+
+        let rule = restrictions.find(&req(&tcp, &bastion, Some("alice"), "/v1/events"));
+        assert_eq!(rule.unwrap().name, "ssh-bastion");
+        assert_eq!(rule.unwrap().log_level, RuleLogLevel::Debug);
+        assert!(restrictions
+            .find(&req(&tcp, &bastion, Some("bob"), "/v1/events"))
+            .is_none());
+        assert!(restrictions.find(&req(&tcp, &bastion, None, "/v1/events")).is_none());
+        assert!(restrictions
+            .find(&req(&udp, &bastion, Some("alice"), "/v1/events"))
+            .is_none());
+
+        // Fallbacks must be allowed by the same rule
+        let resolvers = ["1.1.1.1:53".to_string(), "8.8.8.8:53".to_string()];
+        let rule = restrictions.find(&req(&udp, &resolvers, None, "/resolvers/events"));
+        assert_eq!(rule.unwrap().name, "dns");
+        assert!(restrictions.find(&req(&udp, &resolvers, None, "/v1/events")).is_none());
+        // Behind a reverse proxy which stripped the prefix
+        let forwarded = TunnelRequest {
+            forwarded_path: Some("/resolvers/events"),
+            ..req(&udp, &resolvers, None, "/events")
+        };
+        assert_eq!(restrictions.find(&forwarded).unwrap().name, "dns");
+        let resolvers = ["1.1.1.1:53".to_string(), "9.9.9.9:53".to_string()];
+        assert!(restrictions
+            .find(&req(&udp, &resolvers, None, "/resolvers/events"))
+            .is_none());
+    }
+
+    #[test]
+    fn test_invalid_restrictions() {
+        assert!(Restrictions::from_yaml("restrictions:\n  - name: empty\n    destinations: []\n").is_err());
+        assert!(Restrictions::from_yaml("restrictions:\n  - name: noport\n    destinations: [localhost]\n").is_err());
+        assert!(Restrictions::from_yaml("restrictions:\n  - name: typo\n    destination: [localhost:22]\n").is_err());
+    }
+}
@@ -0,0 +1,62 @@
+use crate::tunnel;
+use std::time::Duration;
+use tokio::select;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Resolve on the first SIGTERM or ctrl-c received
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sigterm = signal(SignalKind::terminate()).expect("Cannot listen for SIGTERM");
+        select! {
+            _ = sigterm.recv() => info!("Received SIGTERM"),
+            _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        info!("Received ctrl-c");
+    }
+}
+
+/// Wait for the active tunnels to finish, for at most `grace` time if any.
+/// A second signal during the wait stops it right away
+pub async fn drain(grace: Option<Duration>) {
+    match grace {
+        Some(grace) => info!(
+            "Not accepting new connections, waiting up to {:?} for {} tunnels to finish",
+            grace,
+            tunnel::active_tunnels()
+        ),
+        None => info!(
+            "Not accepting new connections, waiting for {} tunnels to finish",
+            tunnel::active_tunnels()
+        ),
+    }
+    let deadline = async {
+        match grace {
+            Some(grace) => tokio::time::sleep_until(Instant::now() + grace).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    let wait_tunnels = async {
+        while tunnel::active_tunnels() > 0 {
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+    };
+
+    select! {
+        _ = wait_tunnels => info!("All tunnels are closed, exiting"),
+        _ = deadline => {
+            warn!("Shutdown grace period elapsed, exiting with {} tunnels still active", tunnel::active_tunnels())
+        }
+        _ = signal() => warn!("Forcing exit with {} tunnels still active", tunnel::active_tunnels()),
+    }
+}
@@ -1,35 +1,98 @@
 use crate::socks5_udp::Socks5UdpStream;
+use crate::tunnel::CloseReason;
+use crate::udp::IdleWatchdog;
 use crate::{socks5_udp, LocalProtocol};
 use anyhow::Context;
-use fast_socks5::server::{Config, DenyAuthentication, Socks5Server};
+use fast_socks5::server::{Config, DenyAuthentication, Socks5Server, Socks5Socket};
 use fast_socks5::util::target_addr::TargetAddr;
 use fast_socks5::{consts, ReplyError};
+use futures_util::future::BoxFuture;
+use futures_util::stream::FuturesUnordered;
 use futures_util::{stream, Stream, StreamExt};
-use std::io::{Error, IoSlice};
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind, IoSlice, Write};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::pin::Pin;
-use std::task::Poll;
+use std::task::{ready, Poll};
 use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 use tokio::net::TcpStream;
 use tokio::select;
+use tokio::time::error::Elapsed;
 use tracing::{info, warn};
 use url::Host;
 
-#[allow(clippy::type_complexity)]
+type Socks5Item = (Socks5Stream, (Host, u16), Option<Socks5Reply>);
+
 pub struct Socks5Listener {
-    socks_server: Pin<Box<dyn Stream<Item = anyhow::Result<(Socks5Stream, (Host, u16))>> + Send>>,
+    socks_server: Pin<Box<dyn Stream<Item = anyhow::Result<Socks5Item>> + Send>>,
+}
+
+/// Bounds on the clients of a listener, for a misbehaving one not to exhaust it with half-open negotiations
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Socks5Limits {
+    /// Clients not done negotiating within it are disconnected
+    pub handshake_timeout: Duration,
+    /// New connections wait in the backlog while this many clients are negotiating
+    pub max_pending_handshakes: usize,
+    /// Tcp sessions without any data sent nor received during it are closed
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for Socks5Limits {
+    fn default() -> Self {
+        Self {
+            handshake_timeout: Duration::from_secs(10),
+            max_pending_handshakes: 128,
+            idle_timeout: None,
+        }
+    }
+}
+
+/// Reply to a socks5 CONNECT, sent once the tunnel to the destination is opened or refused by the server
+pub struct Socks5Reply(std::net::TcpStream);
+
+impl Socks5Reply {
+    /// Keep a handle on the socket of the client to reply to it, while the stream is tunneled
+    fn defer(cnx: TcpStream) -> Result<(TcpStream, Self), Error> {
+        let cnx = cnx.into_std()?;
+        let reply = Self(cnx.try_clone()?);
+        Ok((TcpStream::from_std(cnx)?, reply))
+    }
+
+    pub fn send(self, tunnel: Result<(), CloseReason>) {
+        let reply = match tunnel {
+            Ok(()) => ReplyError::Succeeded,
+            Err(reason) => reply_error(reason),
+        };
+        // The socket has just been accepted, its send buffer has room for the few bytes of the reply
+        let ret = (&self.0).write_all(&new_reply(&reply, SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0)));
+        if let Err(err) = ret {
+            warn!("Cannot reply to socks5 client: {}", err);
+        }
+    }
+}
+
+fn reply_error(reason: CloseReason) -> ReplyError {
+    match reason {
+        CloseReason::ConnectionRefused => ReplyError::ConnectionRefused,
+        CloseReason::Restricted | CloseReason::Unauthorized | CloseReason::QuotaExceeded => {
+            ReplyError::ConnectionNotAllowed
+        }
+        CloseReason::Timeout => ReplyError::ConnectionTimeout,
+        CloseReason::Normal | CloseReason::ConnectionReset | CloseReason::Error => ReplyError::GeneralFailure,
+    }
 }
 
 pub enum Socks5Stream {
-    Tcp(TcpStream),
+    Tcp(TcpStream, Option<IdleWatchdog>),
     Udp(Socks5UdpStream),
 }
 
 impl Socks5Stream {
     pub fn local_protocol(&self) -> LocalProtocol {
         match self {
-            Socks5Stream::Tcp(_) => LocalProtocol::Tcp { proxy_protocol: false },
+            Socks5Stream::Tcp(..) => LocalProtocol::Tcp { proxy_protocol: false },
             Socks5Stream::Udp(s) => LocalProtocol::Udp {
                 timeout: s.watchdog_deadline.as_ref().map(|x| x.period()),
             },
@@ -38,14 +101,18 @@ impl Socks5Stream {
 }
 
 impl Stream for Socks5Listener {
-    type Item = anyhow::Result<(Socks5Stream, (Host, u16))>;
+    type Item = anyhow::Result<Socks5Item>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Option<Self::Item>> {
         unsafe { self.map_unchecked_mut(|x| &mut x.socks_server) }.poll_next(cx)
     }
 }
 
-pub async fn run_server(bind: SocketAddr, timeout: Option<Duration>) -> Result<Socks5Listener, anyhow::Error> {
+pub async fn run_server(
+    bind: SocketAddr,
+    timeout: Option<Duration>,
+    limits: Socks5Limits,
+) -> Result<Socks5Listener, anyhow::Error> {
     info!("Starting SOCKS5 server listening cnx on {}", bind);
 
     let server = Socks5Server::<DenyAuthentication>::bind(bind)
@@ -60,97 +127,61 @@ pub async fn run_server(bind: SocketAddr, timeout: Option<Duration>) -> Result<S
 
     let udp_server = socks5_udp::run_server(bind, timeout).await?;
     let server = server.with_config(cfg);
-    let stream = stream::unfold((server, Box::pin(udp_server)), move |(server, mut udp_server)| async move {
-        let mut acceptor = server.incoming();
-        loop {
-            let cnx = select! {
-                biased;
-
-                cnx = acceptor.next() => match cnx {
-                    None => return None,
-                    Some(Err(err)) => {
-                        drop(acceptor);
-                        return Some((Err(anyhow::Error::new(err)), (server, udp_server)));
-                    }
-                    Some(Ok(cnx)) => cnx,
-                },
-
-                // new incoming udp stream
-                udp_conn = udp_server.next() => {
-                    drop(acceptor);
-                    return match udp_conn {
-                        Some(Ok(stream)) => {
-                            let dest = stream.destination();
-                            Some((Ok((Socks5Stream::Udp(stream), dest)), (server, udp_server)))
+    // Handshakes are run concurrently, for a client slow to negotiate not to block the others
+    let handshakes = FuturesUnordered::<BoxFuture<'static, Result<Option<Socks5Item>, Elapsed>>>::new();
+    let stream = stream::unfold(
+        (server, Box::pin(udp_server), handshakes),
+        move |(server, mut udp_server, mut handshakes)| async move {
+            let mut acceptor = server.incoming();
+            loop {
+                select! {
+                    biased;
+
+                    Some(handshake) = handshakes.next(), if !handshakes.is_empty() => match handshake {
+                        Ok(Some(cnx)) => {
+                            drop(acceptor);
+                            return Some((Ok(cnx), (server, udp_server, handshakes)));
                         }
+                        Ok(None) => continue,
+                        Err(_) => {
+                            warn!("Rejecting socks5 cnx: no handshake within {:?}", limits.handshake_timeout);
+                            continue;
+                        }
+                    },
+
+                    // Stop accepting while too many clients are negotiating, they wait in the backlog of the socket
+                    cnx = acceptor.next(), if handshakes.len() < limits.max_pending_handshakes => match cnx {
+                        None => return None,
                         Some(Err(err)) => {
-                            Some((Err(anyhow::Error::new(err)), (server, udp_server)))
+                            drop(acceptor);
+                            return Some((Err(anyhow::Error::new(err)), (server, udp_server, handshakes)));
                         }
-                        None => {
-                            None
+                        Some(Ok(cnx)) => {
+                            let negotiation = handshake(cnx, bind, limits.idle_timeout);
+                            handshakes.push(Box::pin(tokio::time::timeout(limits.handshake_timeout, negotiation)));
                         }
-                    };
-                }
-            };
+                    },
 
-            let cnx = match cnx.upgrade_to_socks5().await {
-                Ok(cnx) => cnx,
-                Err(err) => {
-                    warn!("Rejecting socks5 cnx: {}", err);
-                    continue;
-                }
-            };
-
-            let Some(target) = cnx.target_addr() else {
-                warn!("Rejecting socks5 cnx: no target addr");
-                continue;
-            };
-
-            let (host, port) = match target {
-                TargetAddr::Ip(SocketAddr::V4(ip)) => (Host::Ipv4(*ip.ip()), ip.port()),
-                TargetAddr::Ip(SocketAddr::V6(ip)) => (Host::Ipv6(*ip.ip()), ip.port()),
-                TargetAddr::Domain(host, port) => (Host::Domain(host.clone()), *port),
-            };
-
-            // Special case for UDP Associate where we return the bind addr of the udp server
-            if let Some(fast_socks5::Socks5Command::UDPAssociate) = cnx.cmd() {
-                let mut cnx = cnx.into_inner();
-                let ret = cnx.write_all(&new_reply(&ReplyError::Succeeded, bind)).await;
-
-                if let Err(err) = ret {
-                    warn!("Cannot reply to socks5 udp client: {}", err);
-                    continue;
-                }
-                tokio::spawn(async move {
-                    let mut buf = [0u8; 8];
-                    loop {
-                        match cnx.read(&mut buf).await {
-                            Ok(0) => return,
-                            Err(_) => return,
-                            _ => {}
-                        }
+                    // new incoming udp stream
+                    udp_conn = udp_server.next() => {
+                        drop(acceptor);
+                        return match udp_conn {
+                            Some(Ok(stream)) => {
+                                let dest = stream.destination();
+                                Some((Ok((Socks5Stream::Udp(stream), dest, None)), (server, udp_server, handshakes)))
+                            }
+                            Some(Err(err)) => {
+                                Some((Err(anyhow::Error::new(err)), (server, udp_server, handshakes)))
+                            }
+                            None => {
+                                None
+                            }
+                        };
                     }
-                });
-                continue;
-            };
-
-            let mut cnx = cnx.into_inner();
-            let ret = cnx
-                .write_all(&new_reply(
-                    &ReplyError::Succeeded,
-                    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0),
-                ))
-                .await;
-
-            if let Err(err) = ret {
-                warn!("Cannot reply to socks5 client: {}", err);
-                continue;
+                }
             }
-
-            drop(acceptor);
-            return Some((Ok((Socks5Stream::Tcp(cnx), (host, port))), (server, udp_server)));
-        }
-    });
+        },
+    );
 
     let listener = Socks5Listener {
         socks_server: Box::pin(stream),
@@ -159,6 +190,70 @@ pub async fn run_server(bind: SocketAddr, timeout: Option<Duration>) -> Result<S
     Ok(listener)
 }
 
+/// Negotiate socks5 with a new client. None if it is rejected, or only asks for an udp association
+async fn handshake(
+    cnx: Socks5Socket<TcpStream, DenyAuthentication>,
+    bind: SocketAddr,
+    idle_timeout: Option<Duration>,
+) -> Option<Socks5Item> {
+    let cnx = match cnx.upgrade_to_socks5().await {
+        Ok(cnx) => cnx,
+        Err(err) => {
+            warn!("Rejecting socks5 cnx: {}", err);
+            return None;
+        }
+    };
+
+    let Some(target) = cnx.target_addr() else {
+        warn!("Rejecting socks5 cnx: no target addr");
+        return None;
+    };
+
+    let (host, port) = match target {
+        TargetAddr::Ip(SocketAddr::V4(ip)) => (Host::Ipv4(*ip.ip()), ip.port()),
+        TargetAddr::Ip(SocketAddr::V6(ip)) => (Host::Ipv6(*ip.ip()), ip.port()),
+        TargetAddr::Domain(host, port) => (Host::Domain(host.clone()), *port),
+    };
+
+    // Special case for UDP Associate where we return the bind addr of the udp server
+    if let Some(fast_socks5::Socks5Command::UDPAssociate) = cnx.cmd() {
+        let mut cnx = cnx.into_inner();
+        let ret = cnx.write_all(&new_reply(&ReplyError::Succeeded, bind)).await;
+
+        if let Err(err) = ret {
+            warn!("Cannot reply to socks5 udp client: {}", err);
+            return None;
+        }
+        tokio::spawn(async move {
+            let mut buf = [0u8; 8];
+            loop {
+                match cnx.read(&mut buf).await {
+                    Ok(0) => return,
+                    Err(_) => return,
+                    _ => {}
+                }
+            }
+        });
+        return None;
+    };
+
+    // Do not tell the client that the connection is established before the server opened the tunnel,
+    // for it to get the reason of a refusal instead of an immediate close
+    let (cnx, reply) = match Socks5Reply::defer(cnx.into_inner()) {
+        Ok(ret) => ret,
+        Err(err) => {
+            warn!("Cannot reply to socks5 client: {}", err);
+            return None;
+        }
+    };
+
+    Some((
+        Socks5Stream::Tcp(cnx, idle_timeout.map(IdleWatchdog::new)),
+        (host, port),
+        Some(reply),
+    ))
+}
+
 fn new_reply(error: &ReplyError, sock_addr: SocketAddr) -> Vec<u8> {
     let (addr_type, mut ip_oct, mut port) = match sock_addr {
         SocketAddr::V4(sock) => (
@@ -193,7 +288,21 @@ impl AsyncRead for Socks5Stream {
         buf: &mut ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
         match self.get_mut() {
-            Socks5Stream::Tcp(s) => unsafe { Pin::new_unchecked(s) }.poll_read(cx, buf),
+            Socks5Stream::Tcp(s, watchdog) => {
+                if let Some(watchdog) = watchdog {
+                    if watchdog.poll_expired(cx) {
+                        return Poll::Ready(Err(Error::new(
+                            ErrorKind::TimedOut,
+                            format!("SOCKS5 session idle for {:?}", watchdog.timeout()),
+                        )));
+                    }
+                }
+                let ret = ready!(unsafe { Pin::new_unchecked(s) }.poll_read(cx, buf));
+                if let (Ok(()), Some(watchdog)) = (&ret, watchdog) {
+                    watchdog.touch();
+                }
+                Poll::Ready(ret)
+            }
             Socks5Stream::Udp(s) => unsafe { Pin::new_unchecked(s) }.poll_read(cx, buf),
         }
     }
@@ -202,21 +311,27 @@ impl AsyncRead for Socks5Stream {
 impl AsyncWrite for Socks5Stream {
     fn poll_write(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
         match self.get_mut() {
-            Socks5Stream::Tcp(s) => unsafe { Pin::new_unchecked(s) }.poll_write(cx, buf),
+            Socks5Stream::Tcp(s, watchdog) => {
+                let ret = ready!(unsafe { Pin::new_unchecked(s) }.poll_write(cx, buf));
+                if let (Ok(_), Some(watchdog)) = (&ret, watchdog) {
+                    watchdog.touch();
+                }
+                Poll::Ready(ret)
+            }
             Socks5Stream::Udp(s) => unsafe { Pin::new_unchecked(s) }.poll_write(cx, buf),
         }
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Error>> {
         match self.get_mut() {
-            Socks5Stream::Tcp(s) => unsafe { Pin::new_unchecked(s) }.poll_flush(cx),
+            Socks5Stream::Tcp(s, _) => unsafe { Pin::new_unchecked(s) }.poll_flush(cx),
             Socks5Stream::Udp(s) => unsafe { Pin::new_unchecked(s) }.poll_flush(cx),
         }
     }
 
     fn poll_shutdown(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Error>> {
         match self.get_mut() {
-            Socks5Stream::Tcp(s) => unsafe { Pin::new_unchecked(s) }.poll_shutdown(cx),
+            Socks5Stream::Tcp(s, _) => unsafe { Pin::new_unchecked(s) }.poll_shutdown(cx),
             Socks5Stream::Udp(s) => unsafe { Pin::new_unchecked(s) }.poll_shutdown(cx),
         }
     }
@@ -227,19 +342,63 @@ impl AsyncWrite for Socks5Stream {
         bufs: &[IoSlice<'_>],
     ) -> Poll<Result<usize, Error>> {
         match self.get_mut() {
-            Socks5Stream::Tcp(s) => unsafe { Pin::new_unchecked(s) }.poll_write_vectored(cx, bufs),
+            Socks5Stream::Tcp(s, watchdog) => {
+                let ret = ready!(unsafe { Pin::new_unchecked(s) }.poll_write_vectored(cx, bufs));
+                if let (Ok(_), Some(watchdog)) = (&ret, watchdog) {
+                    watchdog.touch();
+                }
+                Poll::Ready(ret)
+            }
             Socks5Stream::Udp(s) => unsafe { Pin::new_unchecked(s) }.poll_write_vectored(cx, bufs),
         }
     }
 
     fn is_write_vectored(&self) -> bool {
         match self {
-            Socks5Stream::Tcp(s) => s.is_write_vectored(),
+            Socks5Stream::Tcp(s, _) => s.is_write_vectored(),
             Socks5Stream::Udp(s) => s.is_write_vectored(),
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reply_error() {
+        assert_eq!(
+            reply_error(CloseReason::ConnectionRefused).as_u8(),
+            consts::SOCKS5_REPLY_CONNECTION_REFUSED
+        );
+        assert_eq!(
+            reply_error(CloseReason::Restricted).as_u8(),
+            consts::SOCKS5_REPLY_CONNECTION_NOT_ALLOWED
+        );
+        assert_eq!(reply_error(CloseReason::Timeout).as_u8(), consts::SOCKS5_REPLY_TTL_EXPIRED);
+        assert_eq!(reply_error(CloseReason::Error).as_u8(), consts::SOCKS5_REPLY_GENERAL_FAILURE);
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (cnx, _) = listener.accept().await.unwrap();
+        let mut session = Socks5Stream::Tcp(cnx, Some(IdleWatchdog::new(Duration::from_millis(200))));
+
+        // Data received keeps the session alive
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        session.read_exact(&mut buf).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        session.write_all(b"pong").await.unwrap();
+
+        let err = session.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+}
+
 //#[cfg(test)]
 //mod test {
 //    use super::*;
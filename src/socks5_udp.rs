@@ -9,6 +9,7 @@ use std::io::{Error, ErrorKind};
 use std::net::SocketAddr;
 
 use crate::tunnel::to_host_port;
+use crate::udp;
 use bytes::{Buf, Bytes, BytesMut};
 use fast_socks5::new_udp_header;
 use fast_socks5::util::target_addr::TargetAddr;
@@ -219,9 +220,7 @@ pub async fn run_server(
     bind: SocketAddr,
     timeout: Option<Duration>,
 ) -> Result<impl Stream<Item = io::Result<Socks5UdpStream>>, anyhow::Error> {
-    let listener = UdpSocket::bind(bind)
-        .await
-        .with_context(|| format!("Cannot create UDP server {:?}", bind))?;
+    let listener = udp::bind_listener(bind).with_context(|| format!("Cannot create UDP server {:?}", bind))?;
 
     let udp_server = Socks5UdpServer::new(listener, timeout);
     static MAX_PACKET_LENGTH: usize = 64 * 1024;
@@ -0,0 +1,300 @@
+use crate::tunnel::RemoteAddr;
+use crate::tunnel::TunnelPriority;
+use crate::{tunnel, LocalProtocol, WsClientConfig};
+use anyhow::{anyhow, Context};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::TcpListener;
+use tokio::select;
+use tracing::{error, info};
+use url::Host;
+use uuid::Uuid;
+
+const BENCH_BUFFER_SIZE: usize = 64 * 1024;
+const UPGRADE_SAMPLES: usize = 5;
+const ECHO_SAMPLES: usize = 20;
+const ECHO_PAYLOAD_SIZE: usize = 64;
+const THROUGHPUT_DURATION: Duration = Duration::from_secs(5);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Behavior of the bench endpoint of the server, selected by the host of the requested destination
+#[derive(Copy, Clone, Debug)]
+enum BenchMode {
+    // Send back everything received
+    Echo,
+    // Drop everything received, to measure upload
+    Discard,
+    // Send zeros as fast as possible, to measure download
+    Source,
+}
+
+impl BenchMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            BenchMode::Echo => "echo",
+            BenchMode::Discard => "discard",
+            BenchMode::Source => "source",
+        }
+    }
+
+    fn remote_addr(self) -> RemoteAddr {
+        RemoteAddr {
+            protocol: LocalProtocol::Bench,
+            host: Host::Domain(self.as_str().to_string()),
+            port: 0,
+            fallbacks: vec![],
+        }
+    }
+}
+
+/// Server side of the bench endpoint used by `wstunnel client --speed-test`
+#[allow(clippy::type_complexity)]
+pub fn bench_endpoint(mode: &str) -> anyhow::Result<(Pin<Box<dyn AsyncRead + Send>>, Pin<Box<dyn AsyncWrite + Send>>)> {
+    let mode = match mode {
+        "echo" => BenchMode::Echo,
+        "discard" => BenchMode::Discard,
+        "source" => BenchMode::Source,
+        _ => return Err(anyhow!("invalid bench mode {}", mode)),
+    };
+
+    let (local, bench) = tokio::io::duplex(BENCH_BUFFER_SIZE);
+    let (mut bench_rx, mut bench_tx) = tokio::io::split(bench);
+    match mode {
+        BenchMode::Echo => tokio::spawn(async move {
+            let _ = tokio::io::copy(&mut bench_rx, &mut bench_tx).await;
+        }),
+        BenchMode::Discard => tokio::spawn(async move {
+            let _ = tokio::io::copy(&mut bench_rx, &mut tokio::io::sink()).await;
+            drop(bench_tx);
+        }),
+        BenchMode::Source => tokio::spawn(async move {
+            let zeros = vec![0u8; BENCH_BUFFER_SIZE];
+            let mut sink = tokio::io::sink();
+            select! {
+                _ = tokio::io::copy(&mut bench_rx, &mut sink) => {},
+                _ = async { while bench_tx.write_all(&zeros).await.is_ok() {} } => {},
+            }
+        }),
+    };
+
+    let (local_rx, local_tx) = tokio::io::split(local);
+    Ok((Box::pin(local_rx), Box::pin(local_tx)))
+}
+
+fn open_tunnel(client_cfg: &Arc<WsClientConfig>, remote: RemoteAddr) -> DuplexStream {
+    let (local, tunnel) = tokio::io::duplex(BENCH_BUFFER_SIZE);
+    let client_cfg = client_cfg.clone();
+    tokio::spawn(async move {
+        if let Err(err) = tunnel::client::connect_to_server(
+            Uuid::now_v7(),
+            &client_cfg,
+            &remote,
+            TunnelPriority::Normal,
+            None,
+            None,
+            None,
+            tokio::io::split(tunnel),
+        )
+        .await
+        {
+            error!("Cannot open bench tunnel to {}:{}: {:?}", remote.host, remote.port, err);
+        }
+    });
+
+    local
+}
+
+fn summary(samples: &[Duration]) -> String {
+    let min = samples.iter().min().copied().unwrap_or_default();
+    let max = samples.iter().max().copied().unwrap_or_default();
+    let avg = samples.iter().sum::<Duration>() / samples.len().max(1) as u32;
+    format!("min {:?} avg {:?} max {:?}", min, avg, max)
+}
+
+fn throughput(bytes: u64, elapsed: Duration) -> String {
+    format!(
+        "{:.2} Mbit/s ({} bytes in {:?})",
+        bytes as f64 * 8.0 / elapsed.as_secs_f64() / 1_000_000.0,
+        bytes,
+        elapsed
+    )
+}
+
+/// Measure upgrade and echo round trip times, and upload/download throughput against the bench endpoint of the server
+pub async fn run_speed_test(client_cfg: Arc<WsClientConfig>) -> anyhow::Result<()> {
+    info!("Starting speed test against {}", client_cfg.remote_addr.host());
+
+    let mut samples = Vec::with_capacity(UPGRADE_SAMPLES);
+    for _ in 0..UPGRADE_SAMPLES {
+        samples.push(tunnel::client::healthcheck(&client_cfg, &BenchMode::Echo.remote_addr(), TIMEOUT).await?);
+    }
+    info!("Upgrade RTT: {}", summary(&samples));
+
+    let mut echo = open_tunnel(&client_cfg, BenchMode::Echo.remote_addr());
+    let payload = [0x42u8; ECHO_PAYLOAD_SIZE];
+    let mut buf = [0u8; ECHO_PAYLOAD_SIZE];
+    let mut samples = Vec::with_capacity(ECHO_SAMPLES);
+    for _ in 0..ECHO_SAMPLES {
+        let started_at = Instant::now();
+        echo.write_all(&payload).await?;
+        tokio::time::timeout(TIMEOUT, echo.read_exact(&mut buf))
+            .await
+            .context("timeout while waiting for echo")?
+            .context("echo tunnel closed")?;
+        samples.push(started_at.elapsed());
+    }
+    drop(echo);
+    info!("Echo RTT: {}", summary(&samples));
+
+    let mut discard = open_tunnel(&client_cfg, BenchMode::Discard.remote_addr());
+    let zeros = vec![0u8; BENCH_BUFFER_SIZE];
+    let mut sent = 0u64;
+    let started_at = Instant::now();
+    while started_at.elapsed() < THROUGHPUT_DURATION {
+        tokio::time::timeout(TIMEOUT, discard.write_all(&zeros))
+            .await
+            .context("timeout while uploading")?
+            .context("upload tunnel closed")?;
+        sent += zeros.len() as u64;
+    }
+    let _ = discard.shutdown().await;
+    info!("Upload: {}", throughput(sent, started_at.elapsed()));
+
+    let mut source = open_tunnel(&client_cfg, BenchMode::Source.remote_addr());
+    let mut buf = vec![0u8; BENCH_BUFFER_SIZE];
+    // Wait for the first bytes, to not count the upgrade request in the measure
+    tokio::time::timeout(TIMEOUT, source.read_exact(&mut buf[..1]))
+        .await
+        .context("timeout while downloading")?
+        .context("download tunnel closed")?;
+    let mut received = 0u64;
+    let started_at = Instant::now();
+    while started_at.elapsed() < THROUGHPUT_DURATION {
+        let read_len = tokio::time::timeout(TIMEOUT, source.read(&mut buf))
+            .await
+            .context("timeout while downloading")??;
+        if read_len == 0 {
+            return Err(anyhow!("download tunnel closed"));
+        }
+        received += read_len as u64;
+    }
+    info!("Download: {}", throughput(received, started_at.elapsed()));
+
+    Ok(())
+}
+
+/// Tcp server sending back everything it receives, used as destination of the tunnels of `wstunnel bench`
+async fn start_echo_target() -> anyhow::Result<SocketAddr> {
+    let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let _ = stream.set_nodelay(true);
+            tokio::spawn(async move {
+                let (mut rx, mut tx) = stream.split();
+                let _ = tokio::io::copy(&mut rx, &mut tx).await;
+            });
+        }
+    });
+
+    Ok(addr)
+}
+
+/// Cpu time used by the whole process, so by the server, the client and the echo target together
+#[cfg(unix)]
+fn cpu_time() -> Option<Duration> {
+    use nix::libc;
+
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::zeroed();
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let usage = unsafe { usage.assume_init() };
+    let to_duration = |tv: libc::timeval| Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
+    Some(to_duration(usage.ru_utime) + to_duration(usage.ru_stime))
+}
+
+#[cfg(not(unix))]
+fn cpu_time() -> Option<Duration> {
+    None
+}
+
+/// Measure round trip time, throughput and cpu usage through a tunnel to a local echo target, for each frame size.
+/// The client and the server behind `client_cfg` are expected to run in this process, over loopback
+pub async fn run_local_bench(
+    client_cfg: Arc<WsClientConfig>,
+    frame_sizes: &[usize],
+    duration: Duration,
+) -> anyhow::Result<()> {
+    let echo_addr = start_echo_target().await?;
+    let remote = RemoteAddr {
+        protocol: LocalProtocol::Tcp { proxy_protocol: false },
+        host: Host::Ipv4(Ipv4Addr::LOCALHOST),
+        port: echo_addr.port(),
+        fallbacks: vec![],
+    };
+    info!(
+        "Starting local bench through {} to echo target {}, for {:?} per frame size",
+        client_cfg.remote_addr.host(),
+        echo_addr,
+        duration
+    );
+
+    for &frame_size in frame_sizes {
+        if frame_size == 0 {
+            return Err(anyhow!("frame size must be at least 1 byte"));
+        }
+
+        let mut tunnel = open_tunnel(&client_cfg, remote.clone());
+        let frame = vec![0x42u8; frame_size];
+        let mut buf = vec![0u8; frame_size.max(BENCH_BUFFER_SIZE)];
+        let mut samples = Vec::with_capacity(ECHO_SAMPLES);
+        for _ in 0..ECHO_SAMPLES {
+            let started_at = Instant::now();
+            tunnel.write_all(&frame).await?;
+            tokio::time::timeout(TIMEOUT, tunnel.read_exact(&mut buf[..frame_size]))
+                .await
+                .context("timeout while waiting for echo")?
+                .context("echo tunnel closed")?;
+            samples.push(started_at.elapsed());
+        }
+
+        // Frames are written and read back concurrently, the bytes still in flight at the end are not counted
+        let (mut rx, mut tx) = tokio::io::split(tunnel);
+        let mut received = 0u64;
+        let cpu_before = cpu_time();
+        let started_at = Instant::now();
+        let writer = async { while tx.write_all(&frame).await.is_ok() {} };
+        let reader = async {
+            while let Ok(read_len @ 1..) = rx.read(&mut buf).await {
+                received += read_len as u64;
+            }
+        };
+        select! {
+            _ = writer => return Err(anyhow!("echo tunnel closed while writing")),
+            _ = reader => return Err(anyhow!("echo tunnel closed while reading")),
+            _ = tokio::time::sleep(duration) => {},
+        }
+        let elapsed = started_at.elapsed();
+        let cpu = match (cpu_before, cpu_time()) {
+            (Some(before), Some(after)) => format!(
+                "{:.0}% of a core",
+                after.saturating_sub(before).as_secs_f64() / elapsed.as_secs_f64() * 100.0
+            ),
+            _ => "unknown".to_string(),
+        };
+
+        info!(
+            "Frame of {} bytes: echo RTT {}, throughput {}, cpu {}",
+            frame_size,
+            summary(&samples),
+            throughput(received, elapsed),
+            cpu
+        );
+    }
+
+    Ok(())
+}
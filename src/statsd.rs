@@ -0,0 +1,124 @@
+use anyhow::{anyhow, Context};
+use once_cell::sync::OnceCell;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tracing::{info, warn};
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+static STATSD: OnceCell<Statsd> = OnceCell::new();
+
+#[derive(Copy, Clone, Debug)]
+pub enum Counter {
+    TunnelsOpened = 0,
+    BytesTx = 1,
+    BytesRx = 2,
+    Errors = 3,
+}
+
+impl Counter {
+    const ALL: [Counter; 4] = [
+        Counter::TunnelsOpened,
+        Counter::BytesTx,
+        Counter::BytesRx,
+        Counter::Errors,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Counter::TunnelsOpened => "tunnels.opened",
+            Counter::BytesTx => "bytes.tx",
+            Counter::BytesRx => "bytes.rx",
+            Counter::Errors => "errors",
+        }
+    }
+}
+
+struct Statsd {
+    prefix: String,
+    counters: [AtomicU64; Counter::ALL.len()],
+    active_tunnels: AtomicI64,
+    // Last round trip time measured, in microseconds. 0 if none since the last flush
+    rtt_us: AtomicU64,
+}
+
+/// Start sending metrics to a StatsD/DogStatsD agent listening on `addr` (i.e: localhost:8125)
+/// Metrics are aggregated in memory and flushed every 10 seconds over UDP
+pub async fn init(addr: &str, prefix: String) -> anyhow::Result<()> {
+    let peer = tokio::net::lookup_host(addr)
+        .await
+        .with_context(|| format!("Cannot resolve statsd address {}", addr))?
+        .next()
+        .ok_or_else(|| anyhow!("No address found for statsd {}", addr))?;
+    let bind = if peer.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(peer).await?;
+
+    let statsd = Statsd {
+        prefix,
+        counters: Default::default(),
+        active_tunnels: AtomicI64::new(0),
+        rtt_us: AtomicU64::new(0),
+    };
+    if STATSD.set(statsd).is_err() {
+        return Err(anyhow!("statsd is already initialized"));
+    }
+
+    info!("Sending statsd metrics to {}", peer);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        let mut payload = String::with_capacity(512);
+        loop {
+            interval.tick().await;
+            let Some(statsd) = STATSD.get() else {
+                continue;
+            };
+
+            payload.clear();
+            for counter in Counter::ALL {
+                let value = statsd.counters[counter as usize].swap(0, Ordering::Relaxed);
+                let _ = writeln!(payload, "{}.{}:{}|c", statsd.prefix, counter.name(), value);
+            }
+            let active_tunnels = statsd.active_tunnels.load(Ordering::Relaxed);
+            let _ = writeln!(payload, "{}.tunnels.active:{}|g", statsd.prefix, active_tunnels);
+            let rtt_us = statsd.rtt_us.swap(0, Ordering::Relaxed);
+            if rtt_us > 0 {
+                let _ = writeln!(payload, "{}.rtt:{}|ms", statsd.prefix, rtt_us as f64 / 1000.0);
+            }
+
+            if let Err(err) = socket.send(payload.as_bytes()).await {
+                warn!("Cannot send metrics to statsd: {}", err);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[inline]
+pub fn incr(counter: Counter, value: u64) {
+    if let Some(statsd) = STATSD.get() {
+        statsd.counters[counter as usize].fetch_add(value, Ordering::Relaxed);
+    }
+}
+
+pub fn tunnel_opened() {
+    if let Some(statsd) = STATSD.get() {
+        statsd.counters[Counter::TunnelsOpened as usize].fetch_add(1, Ordering::Relaxed);
+        statsd.active_tunnels.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn tunnel_closed() {
+    if let Some(statsd) = STATSD.get() {
+        statsd.active_tunnels.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn rtt(rtt: Duration) {
+    if let Some(statsd) = STATSD.get() {
+        statsd.rtt_us.store(rtt.as_micros().max(1) as u64, Ordering::Relaxed);
+    }
+}
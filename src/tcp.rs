@@ -1,19 +1,27 @@
 use anyhow::{anyhow, Context};
+use std::io::ErrorKind;
 use std::{io, vec};
 
 use crate::dns::DnsResolver;
+use crate::negotiate::{NegotiateContext, SSO_SCHEMES};
+use crate::netns::NetNs;
+use crate::proxy_auth::{digest_authorization, proxy_challenges};
+use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
 use bytes::BytesMut;
+use futures_util::{stream, Stream};
 use log::warn;
+use socket2::{Domain, Type};
 use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpSocket, TcpStream};
 use tokio::time::timeout;
-use tokio_stream::wrappers::TcpListenerStream;
 use tracing::log::info;
 use tracing::{debug, instrument};
 use url::{Host, Url};
+use uuid::Uuid;
 
 fn configure_socket(socket: &mut TcpSocket, so_mark: &Option<u32>) -> Result<(), anyhow::Error> {
     socket
@@ -43,6 +51,29 @@ pub async fn connect(
     so_mark: Option<u32>,
     connect_timeout: Duration,
     dns_resolver: &DnsResolver,
+) -> Result<TcpStream, anyhow::Error> {
+    connect_in(host, port, so_mark, connect_timeout, dns_resolver, None).await
+}
+
+async fn new_socket(addr: &SocketAddr, netns: Option<&NetNs>) -> io::Result<TcpSocket> {
+    match (netns, addr) {
+        (Some(netns), _) => {
+            let socket = netns.socket(Domain::for_address(*addr), Type::STREAM).await?;
+            Ok(TcpSocket::from_std_stream(socket.into()))
+        }
+        (None, SocketAddr::V4(_)) => TcpSocket::new_v4(),
+        (None, SocketAddr::V6(_)) => TcpSocket::new_v6(),
+    }
+}
+
+/// Like connect, with the socket created in the network namespace `netns` if any
+pub async fn connect_in(
+    host: &Host<String>,
+    port: u16,
+    so_mark: Option<u32>,
+    connect_timeout: Duration,
+    dns_resolver: &DnsResolver,
+    netns: Option<&NetNs>,
 ) -> Result<TcpStream, anyhow::Error> {
     info!("Opening TCP connection to {}:{}", host, port);
 
@@ -60,10 +91,7 @@ pub async fn connect(
     for addr in socket_addrs {
         debug!("Connecting to {}", addr);
 
-        let mut socket = match &addr {
-            SocketAddr::V4(_) => TcpSocket::new_v4()?,
-            SocketAddr::V6(_) => TcpSocket::new_v6()?,
-        };
+        let mut socket = new_socket(&addr, netns).await?;
 
         configure_socket(&mut socket, &so_mark)?;
         match timeout(connect_timeout, socket.connect(addr)).await {
@@ -80,19 +108,19 @@ pub async fn connect(
                     "Cannot connect to tcp endpoint {addr} due to timeout of {}s elapsed",
                     connect_timeout.as_secs()
                 );
+                last_err = Some(io::Error::new(ErrorKind::TimedOut, "connection timeout"));
             }
         }
     }
 
-    if let Some(cnx) = cnx {
-        Ok(cnx)
-    } else {
-        Err(anyhow!(
-            "Cannot connect to tcp endpoint {}:{} reason {:?}",
-            host,
-            port,
-            last_err
-        ))
+    // Keep the io error in the chain, for the peer to know the real cause
+    match (cnx, last_err) {
+        (Some(cnx), _) => Ok(cnx),
+        (None, Some(err)) => {
+            let msg = format!("Cannot connect to tcp endpoint {}:{} reason {:?}", host, port, err);
+            Err(anyhow::Error::new(err).context(msg))
+        }
+        (None, None) => Err(anyhow!("Cannot connect to tcp endpoint {}:{} reason None", host, port)),
     }
 }
 
@@ -104,26 +132,131 @@ pub async fn connect_with_http_proxy(
     so_mark: Option<u32>,
     connect_timeout: Duration,
     dns_resolver: &DnsResolver,
+    negotiate: bool,
 ) -> Result<TcpStream, anyhow::Error> {
     let proxy_host = proxy.host().context("Cannot parse proxy host")?.to_owned();
     let proxy_port = proxy.port_or_known_default().unwrap_or(80);
 
-    info!("Connecting to http proxy {}:{}", proxy_host, proxy_port);
-    let mut socket = connect(&proxy_host, proxy_port, so_mark, connect_timeout, dns_resolver).await?;
-    debug!("Connected to http proxy {}", socket.peer_addr().unwrap());
-
-    let authorization = if let Some((user, password)) = proxy.password().map(|p| (proxy.username(), p)) {
+    let credentials = if let Some((user, password)) = proxy.password().map(|p| (proxy.username(), p)) {
         let user = urlencoding::decode(user).with_context(|| format!("Cannot urldecode proxy user: {}", user))?;
-        let password =
-            urlencoding::decode(password).with_context(|| format!("Cannot urldecode proxy password: {}", password))?;
-        let creds = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password));
-        format!("Proxy-Authorization: Basic {}\r\n", creds)
+        let password = urlencoding::decode(password).context("Cannot urldecode proxy password")?;
+        Some((user.into_owned(), password.into_owned()))
+    } else {
+        None
+    };
+    let connect_proxy = || async {
+        info!("Connecting to http proxy {}:{}", proxy_host, proxy_port);
+        let socket = connect(&proxy_host, proxy_port, so_mark, connect_timeout, dns_resolver).await?;
+        debug!("Connected to http proxy {}", socket.peer_addr().unwrap());
+        Ok::<_, anyhow::Error>(socket)
+    };
+
+    // Basic credentials are sent right away, other schemes need the challenge of the proxy first
+    let basic = credentials
+        .as_ref()
+        .map(|(user, password)| format!("Basic {}", STANDARD.encode(format!("{}:{}", user, password))));
+    let mut socket = connect_proxy().await?;
+    let mut response =
+        send_http_proxy_connect(&mut socket, host, port, basic.as_deref(), false, connect_timeout).await?;
+
+    let challenges = if is_proxy_auth_required(&response) {
+        proxy_challenges(&response)
     } else {
-        "".to_string()
+        vec![]
     };
+    let sso_scheme = SSO_SCHEMES
+        .into_iter()
+        .find(|scheme| negotiate && challenges.iter().any(|c| c.is(scheme)));
+    let digest = challenges.iter().find(|c| c.is("Digest"));
+    if let Some(scheme) = sso_scheme {
+        info!(
+            "Http proxy requires {} authentication, using the credentials of the logged user",
+            scheme
+        );
+        let mut context = NegotiateContext::new(scheme, &proxy_host.to_string())?;
+        let mut token = context.step(None)?;
+        socket = connect_proxy().await?;
+        // NTLM needs several round trips, they must all happen on the same connection
+        for _ in 0..MAX_SSO_ROUND_TRIPS {
+            let authorization = format!("{} {}", scheme, STANDARD.encode(&token));
+            response =
+                send_http_proxy_connect(&mut socket, host, port, Some(&authorization), true, connect_timeout).await?;
+            let challenge = proxy_challenges(&response)
+                .into_iter()
+                .find(|c| c.is(scheme))
+                .and_then(|c| c.token().map(str::to_string));
+            let Some(challenge) = challenge.filter(|_| is_proxy_auth_required(&response)) else {
+                break;
+            };
+            let challenge = STANDARD
+                .decode(challenge)
+                .with_context(|| format!("Invalid {} challenge from http proxy", scheme))?;
+            discard_http_body(&mut socket, &response, connect_timeout).await?;
+            token = context.step(Some(&challenge))?;
+        }
+    } else if let (Some((user, password)), Some(digest)) = (&credentials, digest) {
+        info!("Http proxy requires digest authentication");
+        let uri = format!("{}:{}", host, port);
+        let cnonce = Uuid::now_v7().simple().to_string();
+        let authorization = digest_authorization(digest, user, password, "CONNECT", &uri, &cnonce)?;
+        socket = connect_proxy().await?;
+        response =
+            send_http_proxy_connect(&mut socket, host, port, Some(&authorization), false, connect_timeout).await?;
+    }
 
-    let connect_request = format!("CONNECT {host}:{port} HTTP/1.0\r\nHost: {host}:{port}\r\n{authorization}\r\n");
-    debug!("Sending request:\n{}", connect_request);
+    static OK_RESPONSE_10: &[u8] = b"HTTP/1.0 200 ";
+    static OK_RESPONSE_11: &[u8] = b"HTTP/1.1 200 ";
+    if !response
+        .windows(OK_RESPONSE_10.len())
+        .any(|window| window == OK_RESPONSE_10 || window == OK_RESPONSE_11)
+    {
+        return Err(anyhow!(
+            "Cannot connect to http proxy. Proxy returned an invalid response: {}",
+            String::from_utf8_lossy(&response)
+        ));
+    }
+
+    debug!("Got response from proxy:\n{}", String::from_utf8_lossy(&response));
+    info!("Http proxy accepted connection to remote host {}:{}", host, port);
+    Ok(socket)
+}
+
+const MAX_SSO_ROUND_TRIPS: usize = 4;
+
+fn is_proxy_auth_required(response: &[u8]) -> bool {
+    static PROXY_AUTH_REQUIRED: &[u8] = b" 407 ";
+    response
+        .windows(PROXY_AUTH_REQUIRED.len())
+        .any(|window| window == PROXY_AUTH_REQUIRED)
+}
+
+/// Send the CONNECT request to the proxy, and return its response
+async fn send_http_proxy_connect(
+    socket: &mut TcpStream,
+    host: &Host<String>,
+    port: u16,
+    authorization: Option<&str>,
+    keep_alive: bool,
+    connect_timeout: Duration,
+) -> Result<BytesMut, anyhow::Error> {
+    let authorization = authorization
+        .map(|authorization| format!("Proxy-Authorization: {}\r\n", authorization))
+        .unwrap_or_default();
+    let keep_alive = if keep_alive {
+        "Proxy-Connection: keep-alive\r\n"
+    } else {
+        ""
+    };
+    let connect_request =
+        format!("CONNECT {host}:{port} HTTP/1.0\r\nHost: {host}:{port}\r\n{keep_alive}{authorization}\r\n");
+    if authorization.is_empty() {
+        debug!("Sending request:\n{}", connect_request);
+    } else {
+        debug!(
+            "Sending request:\n{}",
+            connect_request.replace(&authorization, "Proxy-Authorization: ***\r\n")
+        );
+    }
     socket.write_all(connect_request.as_bytes()).await?;
 
     let mut buf = BytesMut::with_capacity(1024);
@@ -154,29 +287,145 @@ pub async fn connect_with_http_proxy(
         }
     }
 
-    static OK_RESPONSE_10: &[u8] = b"HTTP/1.0 200 ";
-    static OK_RESPONSE_11: &[u8] = b"HTTP/1.1 200 ";
-    if !buf
-        .windows(OK_RESPONSE_10.len())
-        .any(|window| window == OK_RESPONSE_10 || window == OK_RESPONSE_11)
-    {
-        return Err(anyhow!(
-            "Cannot connect to http proxy. Proxy returned an invalid response: {}",
-            String::from_utf8_lossy(&buf)
-        ));
+    Ok(buf)
+}
+
+// The connection is reused for the next round trip of the authentication, the body must not be mistaken for its response
+async fn discard_http_body(
+    socket: &mut TcpStream,
+    response: &[u8],
+    connect_timeout: Duration,
+) -> Result<(), anyhow::Error> {
+    static END_HTTP_HEADERS: &[u8] = b"\r\n\r\n";
+    let Some(headers_len) = response
+        .windows(END_HTTP_HEADERS.len())
+        .position(|window| window == END_HTTP_HEADERS)
+        .map(|pos| pos + END_HTTP_HEADERS.len())
+    else {
+        return Ok(());
+    };
+    let content_length = String::from_utf8_lossy(&response[..headers_len])
+        .lines()
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case("content-length")
+                .then_some(value.trim().parse::<usize>().ok())?
+        })
+        .unwrap_or(0);
+
+    let mut remaining = content_length.saturating_sub(response.len() - headers_len);
+    let mut buf = [0u8; 1024];
+    while remaining > 0 {
+        let len = remaining.min(buf.len());
+        match tokio::time::timeout(connect_timeout, socket.read(&mut buf[..len])).await {
+            Ok(Ok(0)) => return Err(anyhow!("Http proxy closed the connection during the authentication")),
+            Ok(Ok(nb_bytes)) => remaining -= nb_bytes,
+            Ok(Err(err)) => return Err(anyhow!("Cannot read response of http proxy. {err}")),
+            Err(_) => return Err(anyhow!("Http proxy took too long to send its response")),
+        }
     }
+    Ok(())
+}
 
-    debug!("Got response from proxy:\n{}", String::from_utf8_lossy(&buf));
-    info!("Http proxy accepted connection to remote host {}:{}", host, port);
-    Ok(socket)
+static LISTEN_BACKLOG: AtomicU32 = AtomicU32::new(1024);
+static FD_EXHAUSTED: AtomicBool = AtomicBool::new(false);
+
+/// Max number of connections waiting to be accepted, for listeners bound after the call
+pub fn set_listen_backlog(backlog: u32) {
+    LISTEN_BACKLOG.store(backlog, Ordering::Relaxed);
+}
+
+/// With reuse_port, other processes can listen on the same port, and the kernel balances the connections among them
+pub fn bind_listener(bind: SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+    let socket = match bind {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    if reuse_port {
+        #[cfg(unix)]
+        socket.set_reuseport(true)?;
+        #[cfg(not(unix))]
+        return Err(io::Error::new(ErrorKind::Unsupported, "SO_REUSEPORT is only available on unix"));
+    }
+    // Accept ipv4 clients too on [::], whatever the default of the OS
+    if bind.is_ipv6() && bind.ip().is_unspecified() {
+        socket2::SockRef::from(&socket).set_only_v6(false)?;
+    }
+    socket.bind(bind)?;
+    socket.listen(LISTEN_BACKLOG.load(Ordering::Relaxed))
+}
+
+/// Raise the soft limit of open files to the hard one, as each tunnel holds a few sockets
+#[cfg(unix)]
+pub fn raise_fd_limit() {
+    use nix::libc;
+
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 || limit.rlim_cur >= limit.rlim_max {
+        return;
+    }
+
+    let soft_limit = limit.rlim_cur;
+    limit.rlim_cur = limit.rlim_max;
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } == 0 {
+        debug!("Raised the limit of open files from {} to {}", soft_limit, limit.rlim_cur);
+    } else {
+        debug!(
+            "Cannot raise the limit of open files above {}: {}",
+            soft_limit,
+            io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(unix))]
+pub fn raise_fd_limit() {}
+
+#[cfg(unix)]
+fn is_fd_exhausted(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(nix::libc::EMFILE | nix::libc::ENFILE))
+}
+
+#[cfg(not(unix))]
+fn is_fd_exhausted(_err: &io::Error) -> bool {
+    false
 }
 
-pub async fn run_server(bind: SocketAddr, ip_transparent: bool) -> Result<TcpListenerStream, anyhow::Error> {
+/// Accept the next connection. When the process runs out of file descriptors, accepting is paused instead of
+/// failing in a loop, and the connections wait in the backlog until some descriptors are released
+pub async fn accept(listener: &TcpListener) -> io::Result<(TcpStream, SocketAddr)> {
+    loop {
+        match listener.accept().await {
+            Err(err) if is_fd_exhausted(&err) => {
+                if !FD_EXHAUSTED.swap(true, Ordering::Relaxed) {
+                    warn!("Out of file descriptors, pausing accepting new connections: {}", err);
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            ret => {
+                if ret.is_ok() && FD_EXHAUSTED.swap(false, Ordering::Relaxed) {
+                    info!("File descriptors available again, accepting new connections");
+                }
+                return ret;
+            }
+        }
+    }
+}
+
+pub async fn run_server(
+    bind: SocketAddr,
+    ip_transparent: bool,
+    reuse_port: bool,
+) -> Result<impl Stream<Item = io::Result<TcpStream>>, anyhow::Error> {
     info!("Starting TCP server listening cnx on {}", bind);
 
-    let listener = TcpListener::bind(bind)
-        .await
-        .with_context(|| format!("Cannot create TCP server {:?}", bind))?;
+    let listener = bind_listener(bind, reuse_port).with_context(|| format!("Cannot create TCP server {:?}", bind))?;
 
     #[cfg(target_os = "linux")]
     if ip_transparent {
@@ -184,7 +433,10 @@ pub async fn run_server(bind: SocketAddr, ip_transparent: bool) -> Result<TcpLis
         socket2::SockRef::from(&listener).set_ip_transparent(ip_transparent)?;
     }
 
-    Ok(TcpListenerStream::new(listener))
+    Ok(stream::unfold(listener, |listener| async move {
+        let cnx = accept(&listener).await.map(|(stream, _)| stream);
+        Some((cnx, listener))
+    }))
 }
 
 #[cfg(test)]
@@ -237,7 +489,8 @@ mod tests {
             1236,
             None,
             Duration::from_secs(1),
-            &DnsResolver::System,
+            &DnsResolver::system(),
+            false,
         )
         .await
         .unwrap();
@@ -258,4 +511,15 @@ mod tests {
         let _ = client.read(&mut buf).await.unwrap();
         assert!(buf.starts_with(b"HTTP/1.1 200 OK\r\n\r\n"));
     }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_bind_listener_reuse_port() {
+        let first = bind_listener("127.0.0.1:0".parse().unwrap(), true).unwrap();
+        let bind = first.local_addr().unwrap();
+        assert!(bind_listener(bind, true).is_ok());
+
+        let first = bind_listener("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        assert!(bind_listener(first.local_addr().unwrap(), false).is_err());
+    }
 }
@@ -0,0 +1,22 @@
+use std::net::SocketAddr;
+
+/// Layer serving the tasks of the runtime to tokio-console on `addr`, to find leaked tunnel tasks and stalled executors.
+/// Tasks are only instrumented when built with the tokio-console feature and RUSTFLAGS="--cfg tokio_unstable"
+#[cfg(feature = "tokio-console")]
+pub fn layer(addr: SocketAddr) -> anyhow::Result<console_subscriber::ConsoleLayer> {
+    if !cfg!(tokio_unstable) {
+        return Err(anyhow::anyhow!(
+            "wstunnel is built without --cfg tokio_unstable, tokio does not instrument its tasks"
+        ));
+    }
+
+    tracing::info!("Serving tokio-console on {}", addr);
+    Ok(console_subscriber::ConsoleLayer::builder().server_addr(addr).spawn())
+}
+
+#[cfg(not(feature = "tokio-console"))]
+pub fn layer(_addr: SocketAddr) -> anyhow::Result<tracing_subscriber::layer::Identity> {
+    Err(anyhow::anyhow!(
+        "wstunnel is built without the tokio-console feature, tasks cannot be served to tokio-console"
+    ))
+}
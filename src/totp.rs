@@ -0,0 +1,193 @@
+use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
+use hmac::{Hmac, Mac};
+use hyper::http::HeaderName;
+use parking_lot::Mutex;
+use sha1::Sha1;
+use std::collections::HashSet;
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+pub static TOTP_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-totp");
+
+const TOTP_STEP_SEC: u64 = 30;
+const TOTP_DIGITS: u32 = 6;
+// Number of steps before/after the current one that are still accepted, to cope with clock drift
+const TOTP_ALLOWED_SKEW: u64 = 1;
+
+/// Time-based one-time password as described in RFC 6238 (HMAC-SHA1, 30s step, 6 digits).
+/// The code is not sent as is, but bound to the tunnel id and a random nonce of each upgrade request,
+/// so a proof seen on the wire can neither be replayed nor reused for another tunnel
+#[derive(Clone)]
+pub struct Totp {
+    secret: Vec<u8>,
+    // Server side, (step, proof) already accepted and not expired yet
+    used_proofs: Arc<Mutex<HashSet<(u64, Vec<u8>)>>>,
+}
+
+impl Debug for Totp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Totp(***)")
+    }
+}
+
+impl Totp {
+    fn new(secret: Vec<u8>) -> Self {
+        Self {
+            secret,
+            used_proofs: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    fn code_at_step(&self, step: u64) -> u32 {
+        let mut mac = Hmac::<Sha1>::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+        mac.update(&step.to_be_bytes());
+        let hash = mac.finalize().into_bytes();
+
+        // Dynamic truncation from RFC 4226
+        let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+        let code = u32::from_be_bytes([
+            hash[offset] & 0x7f,
+            hash[offset + 1],
+            hash[offset + 2],
+            hash[offset + 3],
+        ]);
+        code % 10u32.pow(TOTP_DIGITS)
+    }
+
+    // Keyed with the secret and not only the code, as 6 digits are brute forced offline in no time
+    fn proof_mac(&self, code: u32, tunnel_id: &str, nonce: &str) -> Hmac<Sha1> {
+        let mut mac = Hmac::<Sha1>::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+        mac.update(&code.to_be_bytes());
+        mac.update(tunnel_id.as_bytes());
+        mac.update(b".");
+        mac.update(nonce.as_bytes());
+        mac
+    }
+
+    /// Value of the TOTP header for an upgrade request of this tunnel id: nonce.hex(HMAC(secret, code|tunnel id|nonce))
+    pub fn proof(&self, tunnel_id: &str, unix_time: u64) -> String {
+        let nonce = Uuid::new_v4().simple().to_string();
+        let mac = self.proof_mac(self.code_at_step(unix_time / TOTP_STEP_SEC), tunnel_id, &nonce);
+        format!("{}.{}", nonce, HEXLOWER.encode(&mac.finalize().into_bytes()))
+    }
+
+    pub fn proof_now(&self, tunnel_id: &str) -> String {
+        self.proof(tunnel_id, unix_time_now())
+    }
+
+    fn matching_step(&self, tunnel_id: &str, nonce: &str, proof: &[u8], unix_time: u64) -> Option<u64> {
+        let current_step = unix_time / TOTP_STEP_SEC;
+        let mut matching_step = None;
+        // Do not short-circuit, to not leak through timing which step matched
+        for step in current_step.saturating_sub(TOTP_ALLOWED_SKEW)..=current_step + TOTP_ALLOWED_SKEW {
+            let mac = self.proof_mac(self.code_at_step(step), tunnel_id, nonce);
+            if mac.verify_slice(proof).is_ok() {
+                matching_step = Some(step);
+            }
+        }
+
+        matching_step
+    }
+
+    /// Verify the proof sent for this tunnel id and remember it until it expires, as it could otherwise be replayed
+    /// by anyone seeing the upgrade request
+    pub fn verify_once(&self, header: &str, tunnel_id: &str, unix_time: u64) -> Result<(), &'static str> {
+        let Some((step, proof)) = header.trim().split_once('.').and_then(|(nonce, proof)| {
+            let proof = HEXLOWER_PERMISSIVE.decode(proof.as_bytes()).ok()?;
+            Some((self.matching_step(tunnel_id, nonce, &proof, unix_time)?, proof))
+        }) else {
+            return Err("invalid TOTP code");
+        };
+
+        let oldest_valid_step = (unix_time / TOTP_STEP_SEC).saturating_sub(TOTP_ALLOWED_SKEW);
+        let mut used_proofs = self.used_proofs.lock();
+        used_proofs.retain(|(step, _)| *step >= oldest_valid_step);
+        if !used_proofs.insert((step, proof)) {
+            return Err("TOTP code already used");
+        }
+
+        Ok(())
+    }
+
+    pub fn verify_once_now(&self, header: &str, tunnel_id: &str) -> Result<(), &'static str> {
+        self.verify_once(header, tunnel_id, unix_time_now())
+    }
+}
+
+fn unix_time_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn parse_totp_secret(arg: &str) -> Result<Totp, io::Error> {
+    let arg = crate::read_secret(arg)?;
+    // Authenticator apps display secrets in lowercase and grouped by 4 chars
+    let secret: String = arg
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-' && *c != '=')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+
+    match data_encoding::BASE32_NOPAD.decode(secret.as_bytes()) {
+        Ok(secret) if !secret.is_empty() => Ok(Totp::new(secret)),
+        Ok(_) => Err(io::Error::new(ErrorKind::InvalidInput, "TOTP secret cannot be empty")),
+        Err(err) => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("TOTP secret must be base32 encoded: {}", err),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_totp_rfc6238_vectors() {
+        // Secret "12345678901234567890" from RFC 6238 appendix B, truncated to 6 digits
+        let totp = parse_totp_secret("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap();
+        assert_eq!(totp.code_at_step(59 / TOTP_STEP_SEC), 287082);
+        assert_eq!(totp.code_at_step(1111111109 / TOTP_STEP_SEC), 81804);
+        assert_eq!(totp.code_at_step(1234567890 / TOTP_STEP_SEC), 5924);
+        assert_eq!(totp.code_at_step(2000000000 / TOTP_STEP_SEC), 279037);
+    }
+
+    #[test]
+    fn test_totp_verify_with_skew() {
+        let server = parse_totp_secret("gezd gnbv gy3t qojq").unwrap();
+        let client = parse_totp_secret("GEZDGNBVGY3TQOJQ").unwrap();
+        let verify = |proof: &str, unix_time: u64| server.verify_once(proof, "tunnel", unix_time).is_ok();
+        assert!(verify(&client.proof("tunnel", 1_000_000), 1_000_000));
+        assert!(verify(&client.proof("tunnel", 1_000_000), 1_000_000 + TOTP_STEP_SEC));
+        assert!(!verify(&client.proof("tunnel", 1_000_000), 1_000_000 + 3 * TOTP_STEP_SEC));
+        assert!(!verify("not a code", 1_000_000));
+        // The bare code is not accepted
+        let code = format!("{:06}", client.code_at_step(1_000_000 / TOTP_STEP_SEC));
+        assert!(!verify(&code, 1_000_000));
+    }
+
+    #[test]
+    fn test_totp_proof_used_once() {
+        let server = parse_totp_secret("gezd gnbv gy3t qojq").unwrap();
+        let client = server.clone();
+        let now = 1_000_000;
+
+        // Many upgrades in the same step, even for the same tunnel id as retries and redirects do
+        let proofs: Vec<String> = (0..10).map(|_| client.proof("tunnel", now)).collect();
+        for proof in &proofs {
+            assert_eq!(server.verify_once(proof, "tunnel", now), Ok(()));
+            assert_eq!(server.verify_once(proof, "tunnel", now), Err("TOTP code already used"));
+        }
+
+        // A proof is only valid for the tunnel it was computed for
+        let proof = client.proof("tunnel", now);
+        assert_eq!(server.verify_once(&proof, "other tunnel", now), Err("invalid TOTP code"));
+        assert_eq!(server.verify_once(&proof, "tunnel", now), Ok(()));
+    }
+}
@@ -0,0 +1,335 @@
+use crate::tunnel::resume::{self, TunnelOwner};
+use crate::tunnel::transport::io::{count_local, TunnelStats};
+use crate::tunnel::transport::websocket::{WebsocketTunnelRead, WebsocketTunnelWrite};
+use crate::tunnel::transport::{TunnelRead, TunnelWrite};
+use crate::tunnel::{CloseReason, RemoteAddr};
+use crate::{LocalProtocol, WsClientConfig};
+use ahash::{HashMap, HashMapExt};
+use anyhow::{anyhow, Context};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures_util::future::try_join_all;
+use hyper::http::{HeaderName, HeaderValue};
+use hyper::HeaderMap;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::io;
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::select;
+use tokio::sync::mpsc;
+
+/// Index of the websocket in the bond of the tunnel and their count, i.e: 0/4 for the first of 4
+pub static BOND_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-bond");
+
+/// Most websockets a tunnel can be striped across
+pub const MAX_BOND: u8 = 16;
+
+// Bytes read at most from the local side for a chunk
+const CHUNK_LEN: usize = 64 * 1024;
+// Chunks received ahead of a missing one at most, before the websocket carrying it is considered stalled
+const MAX_REORDER: usize = 1024;
+
+pub type Websocket = (WebsocketTunnelRead, WebsocketTunnelWrite);
+
+/// Tunnels the client stripes across several websockets. Only tcp streams, datagrams would not gain from it
+pub fn is_bonded(client_cfg: &WsClientConfig, dest_addr: &RemoteAddr) -> bool {
+    client_cfg.bond.is_some() && matches!(dest_addr.protocol, LocalProtocol::Tcp { .. })
+}
+
+pub fn header(index: u8, count: u8) -> HeaderValue {
+    HeaderValue::from_str(&format!("{}/{}", index, count)).unwrap()
+}
+
+pub fn parse_header(headers: &HeaderMap) -> Option<(u8, u8)> {
+    let (index, count) = headers.get(&BOND_HEADER)?.to_str().ok()?.split_once('/')?;
+    let (index, count) = (index.parse::<u8>().ok()?, count.parse::<u8>().ok()?);
+    (index < count && (2..=MAX_BOND).contains(&count)).then_some((index, count))
+}
+
+// secret of the tunnel => what it was opened for, and where to send the other websockets of its bond
+static BONDS: Lazy<Mutex<HashMap<String, (TunnelOwner, mpsc::Sender<Websocket>)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Tunnel of the server waiting for the other websockets of its bond, for as long as this is alive
+pub struct Registration {
+    secret: String,
+}
+
+impl Registration {
+    pub fn secret(&self) -> &str {
+        &self.secret
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        BONDS.lock().remove(&self.secret);
+    }
+}
+
+pub fn register(owner: TunnelOwner, count: u8) -> (Registration, mpsc::Receiver<Websocket>) {
+    let secret = resume::new_secret();
+    let (tx, rx) = mpsc::channel(count as usize);
+    BONDS.lock().insert(secret.clone(), (owner, tx));
+    (Registration { secret }, rx)
+}
+
+/// Bond of this secret, if its tunnel was opened for the same as the websocket joining it
+pub fn find(secret: &str, owner: &TunnelOwner) -> Option<mpsc::Sender<Websocket>> {
+    match BONDS.lock().get(secret) {
+        Some((tunnel_owner, tx)) if tunnel_owner == owner => Some(tx.clone()),
+        _ => None,
+    }
+}
+
+/// Relay the tunnel striped across the websockets of its bond. The chunks read from the local side are numbered,
+/// sent by whichever websocket is free first, and put back in order by the peer
+pub async fn relay<R: TunnelRead, W: TunnelWrite>(
+    local_rx: impl AsyncRead + Send + Unpin,
+    local_tx: impl AsyncWrite + Send + Unpin,
+    members: Vec<(R, W)>,
+    keepalive: Option<Duration>,
+    stats: &TunnelStats,
+) -> anyhow::Result<()> {
+    let (mut local_rx, mut local_tx) = count_local(local_rx, local_tx, stats);
+    let (chunk_tx, chunk_rx) = mpsc::channel::<(u64, Bytes)>(members.len() * 2);
+    let chunk_rx = tokio::sync::Mutex::new(chunk_rx);
+    let (received_tx, mut received_rx) = mpsc::channel::<(u64, Bytes)>(members.len() * 16);
+    let (ws_rxs, ws_txs): (Vec<_>, Vec<_>) = members.into_iter().unzip();
+
+    // An empty chunk is the eof of the stream
+    let read_local = async move {
+        let mut seq = 0;
+        loop {
+            let mut buf = BytesMut::with_capacity(CHUNK_LEN);
+            let read_len = local_rx
+                .read_buf(&mut buf)
+                .await
+                .context("cannot read from the local side")?;
+            if chunk_tx.send((seq, buf.freeze())).await.is_err() {
+                return Err(anyhow!("websockets of the bond are closed"));
+            }
+            seq += 1;
+            if read_len == 0 {
+                return anyhow::Ok(());
+            }
+        }
+    };
+    let send = try_join_all(ws_txs.into_iter().map(|ws_tx| send_chunks(ws_tx, &chunk_rx, keepalive)));
+
+    let receive = try_join_all(
+        ws_rxs
+            .into_iter()
+            .map(|ws_rx| receive_chunks(ws_rx, received_tx.clone())),
+    );
+    drop(received_tx);
+    let write_local = async {
+        let mut next = 0;
+        let mut pending = BTreeMap::new();
+        while let Some((seq, data)) = received_rx.recv().await {
+            pending.insert(seq, data);
+            while let Some(data) = pending.remove(&next) {
+                next += 1;
+                if data.is_empty() {
+                    local_tx.shutdown().await.context("cannot close the local side")?;
+                    return anyhow::Ok(());
+                }
+                local_tx
+                    .write_all(&data)
+                    .await
+                    .context("cannot write to the local side")?;
+            }
+            if pending.len() > MAX_REORDER {
+                return Err(anyhow!("a websocket of the bond stalled, {} chunks wait for it", pending.len()));
+            }
+        }
+        // Closed by the peer before the end of the stream
+        Ok(())
+    };
+
+    let ws_txs = select! {
+        ret = async { tokio::try_join!(read_local, send, write_local) } => ret?.1,
+        Err(err) = receive => return Err(err),
+    };
+    for mut ws_tx in ws_txs {
+        let _ = ws_tx.close(CloseReason::Normal).await;
+    }
+    Ok(())
+}
+
+// Send the chunks the other websockets of the bond are not already sending, prefixed by their number
+async fn send_chunks<W: TunnelWrite>(
+    mut ws_tx: W,
+    chunks: &tokio::sync::Mutex<mpsc::Receiver<(u64, Bytes)>>,
+    keepalive: Option<Duration>,
+) -> anyhow::Result<W> {
+    loop {
+        let next = async { chunks.lock().await.recv().await };
+        let chunk = match keepalive {
+            Some(interval) => match tokio::time::timeout(interval, next).await {
+                Ok(chunk) => chunk,
+                Err(_) => {
+                    ws_tx.ping().await.context("cannot ping over a websocket of the bond")?;
+                    continue;
+                }
+            },
+            None => next.await,
+        };
+        let Some((seq, data)) = chunk else {
+            return Ok(ws_tx);
+        };
+
+        let buf = ws_tx.buf_mut();
+        buf.put_u64(seq);
+        buf.extend_from_slice(&data);
+        ws_tx
+            .write()
+            .await
+            .context("cannot send over a websocket of the bond")?;
+    }
+}
+
+async fn receive_chunks(mut ws_rx: impl TunnelRead, received: mpsc::Sender<(u64, Bytes)>) -> anyhow::Result<()> {
+    let mut frame = FrameBuf(BytesMut::new());
+    loop {
+        match ws_rx.copy(&mut frame).await {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotConnected => return Ok(()),
+            Err(err) => return Err(anyhow::Error::new(err).context("websocket of the bond broke")),
+        }
+
+        // Pongs write nothing
+        let mut data = frame.0.split().freeze();
+        if data.is_empty() {
+            continue;
+        }
+        if data.len() < 8 {
+            return Err(anyhow!("invalid chunk of {} bytes received over the bond", data.len()));
+        }
+        let seq = data.get_u64();
+        if received.send((seq, data)).await.is_err() {
+            // The stream from the peer is over
+            return Ok(());
+        }
+    }
+}
+
+// Payload of the last frame received
+struct FrameBuf(BytesMut);
+
+impl AsyncWrite for FrameBuf {
+    fn poll_write(mut self: Pin<&mut Self>, _cx: &mut std::task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.0.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::Span;
+
+    // Websocket of the bond over a channel, closed with the channel
+    struct ChannelWrite(mpsc::Sender<Bytes>, BytesMut);
+    struct ChannelRead(mpsc::Receiver<Bytes>);
+
+    impl TunnelWrite for ChannelWrite {
+        fn buf_mut(&mut self) -> &mut BytesMut {
+            &mut self.1
+        }
+
+        async fn write(&mut self) -> Result<(), io::Error> {
+            let data = self.1.split().freeze();
+            self.0.send(data).await.map_err(|_| io::ErrorKind::BrokenPipe.into())
+        }
+
+        async fn ping(&mut self) -> Result<(), io::Error> {
+            Ok(())
+        }
+
+        async fn close(&mut self, _reason: CloseReason) -> Result<(), io::Error> {
+            Ok(())
+        }
+    }
+
+    impl TunnelRead for ChannelRead {
+        async fn copy(&mut self, mut writer: impl AsyncWrite + Unpin + Send) -> Result<(), io::Error> {
+            match self.0.recv().await {
+                None => Err(io::ErrorKind::NotConnected.into()),
+                Some(data) => writer.write_all(&data).await,
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let parse = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(&BOND_HEADER, HeaderValue::from_static(value));
+            parse_header(&headers)
+        };
+        assert_eq!(parse("0/4"), Some((0, 4)));
+        assert_eq!(header(3, 4), "3/4");
+        assert_eq!(parse("4/4"), None);
+        assert_eq!(parse("0/1"), None);
+        assert_eq!(parse("0/17"), None);
+        assert_eq!(parse("a/4"), None);
+    }
+
+    #[tokio::test]
+    async fn test_relay() {
+        // Both ends of 3 websockets
+        let (mut left, mut right) = (Vec::new(), Vec::new());
+        for _ in 0..3 {
+            let (left_tx, right_rx) = mpsc::channel(16);
+            let (right_tx, left_rx) = mpsc::channel(16);
+            left.push((ChannelRead(left_rx), ChannelWrite(left_tx, BytesMut::new())));
+            right.push((ChannelRead(right_rx), ChannelWrite(right_tx, BytesMut::new())));
+        }
+
+        let (mut app, local) = tokio::io::duplex(1024);
+        let (local_rx, local_tx) = tokio::io::split(local);
+        let (dest, remote) = tokio::io::duplex(1024);
+        let (remote_rx, remote_tx) = tokio::io::split(remote);
+        let client =
+            tokio::spawn(async move { relay(local_rx, local_tx, left, None, &TunnelStats::new(Span::none())).await });
+        let server =
+            tokio::spawn(
+                async move { relay(remote_rx, remote_tx, right, None, &TunnelStats::new(Span::none())).await },
+            );
+
+        // Echo server as destination
+        let (mut dest_rx, mut dest_tx) = tokio::io::split(dest);
+        let echo = tokio::spawn(async move { tokio::io::copy(&mut dest_rx, &mut dest_tx).await });
+
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let (mut app_rx, mut app_tx) = tokio::io::split(&mut app);
+        let (_, echoed) = tokio::join!(
+            async {
+                app_tx.write_all(&data).await.unwrap();
+                app_tx.shutdown().await.unwrap();
+            },
+            async {
+                let mut echoed = Vec::new();
+                app_rx.read_to_end(&mut echoed).await.unwrap();
+                echoed
+            }
+        );
+        assert_eq!(echoed, data);
+
+        client.await.unwrap().unwrap();
+        server.await.unwrap().unwrap();
+        echo.await.unwrap().unwrap();
+    }
+}
@@ -1,86 +1,554 @@
+use super::bond;
+use super::resume::{self, RESUME_HEADER, SESSION_HEADER};
 use super::{JwtTunnelConfig, RemoteAddr, TransportScheme, JWT_DECODE};
-use crate::tunnel::transport::{TunnelReader, TunnelWriter};
-use crate::{tunnel, WsClientConfig};
+use crate::circuit_breaker::CircuitOpen;
+use crate::flight_recorder::FlightRecorder;
+use crate::hooks::TunnelHook;
+use crate::metrics::ListenerMetrics;
+use crate::pcap::TunnelCapture;
+use crate::redact::RedactedHeaders;
+use crate::statsd::Counter;
+use crate::tunnel::transport::close_reason::{CloseReason, RetryAfter};
+use crate::tunnel::transport::http2::Http2Transport;
+use crate::tunnel::transport::io::{ResumableTunnel, TunnelPriority, TunnelStats};
+use crate::tunnel::transport::long_polling::LongPollingTransport;
+use crate::tunnel::transport::websocket::{WebsocketTransport, WebsocketTunnelRead, WebsocketTunnelWrite};
+use crate::tunnel::transport::{TunnelReader, TunnelTransport, TunnelWrite, TunnelWriter};
+use crate::version::{peer_features, FEATURE_BOND, FEATURE_RESUME, FEATURE_RETRANSMIT, FEATURE_REUSE};
+use crate::{admin, statsd, tunnel, WsClientConfig};
+use anyhow::anyhow;
+use futures_util::future::try_join_all;
 use futures_util::pin_mut;
 use hyper::header::COOKIE;
+use hyper::http::response::Parts;
+use hyper::http::HeaderValue;
+use hyper::StatusCode;
 use jsonwebtoken::TokenData;
 use log::debug;
+use std::collections::HashMap;
 use std::future::Future;
 use std::ops::Deref;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::sync::oneshot;
+use tokio::sync::Semaphore;
 use tokio_stream::{Stream, StreamExt};
-use tracing::{error, event, span, Instrument, Level, Span};
+use tracing::{error, event, info, span, warn, Instrument, Level, Span};
 use url::Host;
 use uuid::Uuid;
 
-async fn connect_to_server<R, W>(
+// Longer Retry-After are not waited for, the local connection would be kept hanging for too long
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+// Delay between the attempts to resume a tunnel, while the network of the client is changing
+const RESUME_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Open a tunnel to the server, retrying when it answers to try again later (429, 503) with --http-upgrade-retries
+async fn connect(
+    request_id: Uuid,
+    client_cfg: &WsClientConfig,
+    remote_cfg: &RemoteAddr,
+    reuse: bool,
+) -> anyhow::Result<(TunnelReader, TunnelWriter, Parts)> {
+    let mut retries = 0;
+    loop {
+        let ret = connect_with_circuit_breaker(request_id, client_cfg, remote_cfg, reuse).await;
+        let Some(retry) = ret
+            .as_ref()
+            .err()
+            .and_then(|err| err.downcast_ref::<RetryAfter>())
+            .copied()
+        else {
+            return ret;
+        };
+        // Without Retry-After, back off exponentially: 1s, 2s, 4s...
+        let delay = retry.delay.unwrap_or_else(|| Duration::from_secs(1 << retries.min(5)));
+        if retries >= client_cfg.upgrade_retries || delay > MAX_RETRY_AFTER {
+            return ret;
+        }
+
+        retries += 1;
+        warn!(
+            "Server answered {} to the upgrade request, retrying in {:?} ({}/{})",
+            retry.status, delay, retries, client_cfg.upgrade_retries
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Open a tunnel to the server, unless the circuit breaker considers it down
+async fn connect_with_circuit_breaker(
     request_id: Uuid,
     client_cfg: &WsClientConfig,
     remote_cfg: &RemoteAddr,
+    reuse: bool,
+) -> anyhow::Result<(TunnelReader, TunnelWriter, Parts)> {
+    let Some(circuit_breaker) = &client_cfg.circuit_breaker else {
+        return connect_transport(request_id, client_cfg, remote_cfg, reuse).await;
+    };
+
+    circuit_breaker.check()?;
+    let ret = connect_transport(request_id, client_cfg, remote_cfg, reuse).await;
+    match &ret {
+        // The server refused the tunnel itself, it is up
+        Ok(_) => circuit_breaker.record_success(),
+        Err(err) if err.is::<CloseReason>() => circuit_breaker.record_success(),
+        Err(err) => circuit_breaker.record_failure(err),
+    }
+    ret
+}
+
+/// Log the error of a tunnel. Failing fast is not logged again, the circuit breaker already logged why it is open
+fn log_tunnel_error(err: &anyhow::Error) {
+    if err.is::<CircuitOpen>() {
+        debug!("{:?}", err);
+    } else {
+        error!("{:?}", err);
+    }
+}
+
+/// Open a tunnel to the server with the transport of the config if any, else the one matching the scheme of the server url.
+/// For websocket, fallback to long polling if the upgrade is refused, i.e: by a proxy blocking websockets
+async fn connect_transport(
+    request_id: Uuid,
+    client_cfg: &WsClientConfig,
+    remote_cfg: &RemoteAddr,
+    reuse: bool,
+) -> anyhow::Result<(TunnelReader, TunnelWriter, Parts)> {
+    if let Some(transport) = &client_cfg.transport {
+        return transport.connect(request_id, client_cfg, remote_cfg, reuse).await;
+    }
+
+    match client_cfg.remote_addr.scheme() {
+        TransportScheme::Ws | TransportScheme::Wss => {
+            if !client_cfg.long_polling_fallback.is_active() {
+                match connect_with(WebsocketTransport, request_id, client_cfg, remote_cfg, reuse).await {
+                    Ok(tunnel) => return Ok(tunnel),
+                    // The server is up but busy, another transport would not help
+                    Err(err) if err.is::<RetryAfter>() => return Err(err),
+                    Err(err) => match err.downcast_ref::<fastwebsockets::WebSocketError>() {
+                        None => return Err(err),
+                        // The server accepted the websocket but refused the tunnel itself, no need to try another transport
+                        Some(fastwebsockets::WebSocketError::InvalidStatusCode(status))
+                            if tunnel_refusal(*status) != CloseReason::Error =>
+                        {
+                            let reason = tunnel_refusal(*status);
+                            return Err(anyhow::Error::new(reason).context(err));
+                        }
+                        Some(_) => warn!("Websocket upgrade refused, trying HTTP long polling: {:?}", err),
+                    },
+                }
+            }
+
+            let tunnel = connect_with(LongPollingTransport, request_id, client_cfg, remote_cfg, reuse).await?;
+            if client_cfg.long_polling_fallback.activate() {
+                warn!("Using HTTP long polling transport for next tunnels, performances will be degraded. Websocket will be tried again in a few minutes");
+            }
+            Ok(tunnel)
+        }
+        TransportScheme::Http | TransportScheme::Https => {
+            connect_with(Http2Transport, request_id, client_cfg, remote_cfg, reuse).await
+        }
+    }
+}
+
+async fn connect_with<T: TunnelTransport>(
+    transport: T,
+    request_id: Uuid,
+    client_cfg: &WsClientConfig,
+    remote_cfg: &RemoteAddr,
+    reuse: bool,
+) -> anyhow::Result<(TunnelReader, TunnelWriter, Parts)> {
+    let (reader, writer, response) = transport.connect(request_id, client_cfg, remote_cfg, reuse).await?;
+    Ok((reader.into(), writer.into(), response))
+}
+
+fn tunnel_refusal(status: u16) -> CloseReason {
+    StatusCode::from_u16(status)
+        .map(CloseReason::from_status_code)
+        .unwrap_or(CloseReason::Error)
+}
+
+/// Websockets left by the last connections of a listener, reused by its next ones toward the same destination to avoid
+/// a new handshake. The server keeps the destination of the first connection of a websocket for the ones reusing it.
+/// Idle websockets are not pinged, so they are not reused after the ping frequency, as a proxy may have closed them
+#[derive(Default)]
+pub struct IdleWebsocket {
+    inner: parking_lot::Mutex<HashMap<String, (WebsocketTunnelRead, WebsocketTunnelWrite, Instant)>>,
+}
+
+impl IdleWebsocket {
+    fn put(
+        &self,
+        remote_cfg: &RemoteAddr,
+        ws_rx: WebsocketTunnelRead,
+        ws_tx: WebsocketTunnelWrite,
+        max_idle: Duration,
+    ) {
+        let mut idle = self.inner.lock();
+        idle.retain(|_, (_, _, idle_since)| idle_since.elapsed() < max_idle);
+        idle.insert(idle_key(remote_cfg), (ws_rx, ws_tx, Instant::now()));
+    }
+
+    fn take(&self, remote_cfg: &RemoteAddr, max_idle: Duration) -> Option<(WebsocketTunnelRead, WebsocketTunnelWrite)> {
+        let (ws_rx, ws_tx, idle_since) = self.inner.lock().remove(&idle_key(remote_cfg))?;
+        (idle_since.elapsed() < max_idle).then_some((ws_rx, ws_tx))
+    }
+}
+
+fn idle_key(remote_cfg: &RemoteAddr) -> String {
+    format!("{}://{}:{}", remote_cfg.protocol.name(), remote_cfg.host, remote_cfg.port)
+}
+
+fn tunnel_stats(request_id: Uuid, remote_cfg: &RemoteAddr, metrics: Option<Arc<ListenerMetrics>>) -> TunnelStats {
+    let hook = TunnelHook::new(
+        &request_id.to_string(),
+        remote_cfg.protocol.name(),
+        format!("{}:{}", remote_cfg.host, remote_cfg.port),
+        None,
+    );
+    let capture = TunnelCapture::new(
+        &request_id.to_string(),
+        remote_cfg.port,
+        remote_cfg.protocol.is_datagram(),
+        false,
+    );
+    TunnelStats::new(Span::current())
+        .with_hook(hook)
+        .with_metrics(metrics, &format!("{}:{}", remote_cfg.host, remote_cfg.port))
+        .with_flight_recorder(FlightRecorder::new(&request_id.to_string()))
+        .with_capture(capture)
+}
+
+/// Answer owed to the local client once its tunnel is opened or refused by the server, i.e: the reply of socks5
+pub type TunnelReply = Box<dyn FnOnce(Result<(), CloseReason>) + Send>;
+
+/// Tunnel the connection to the server. With an idle websocket slot, the websocket is kept once the connection is done,
+/// for the next connection of the listener to reuse it
+#[allow(clippy::too_many_arguments)]
+pub async fn connect_to_server<R, W>(
+    request_id: Uuid,
+    client_cfg: &WsClientConfig,
+    remote_cfg: &RemoteAddr,
+    priority: TunnelPriority,
+    metrics: Option<Arc<ListenerMetrics>>,
+    idle_websocket: Option<&IdleWebsocket>,
+    mut reply: Option<TunnelReply>,
     duplex_stream: (R, W),
 ) -> anyhow::Result<()>
 where
     R: AsyncRead + Send + 'static,
     W: AsyncWrite + Send + 'static,
 {
+    let (local_rx, local_tx) = duplex_stream;
+    if let Some(idle_websocket) = idle_websocket {
+        if let Some((ws_rx, mut ws_tx)) = idle_websocket.take(remote_cfg, client_cfg.websocket_ping_frequency) {
+            if ws_tx.next_connection().await.is_ok() {
+                debug!("Reusing idle websocket");
+                if let Some(reply) = reply.take() {
+                    reply(Ok(()));
+                }
+                let stats = tunnel_stats(request_id, remote_cfg, metrics);
+                let (ws_rx, ws_tx) =
+                    super::transport::io::relay_reusable(local_rx, local_tx, ws_rx, ws_tx, stats).await?;
+                idle_websocket.put(remote_cfg, ws_rx, ws_tx, client_cfg.websocket_ping_frequency);
+                return Ok(());
+            }
+        }
+    }
+
     // Connect to server with the correct protocol
-    let (ws_rx, ws_tx, response) = match client_cfg.remote_addr.scheme() {
-        TransportScheme::Ws | TransportScheme::Wss => {
-            tunnel::transport::websocket::connect(request_id, client_cfg, remote_cfg)
-                .await
-                .map(|(r, w, response)| (TunnelReader::Websocket(r), TunnelWriter::Websocket(w), response))?
+    let started_at = Instant::now();
+    let reuse = idle_websocket.is_some();
+    let tunnel = connect(request_id, client_cfg, remote_cfg, reuse).await;
+    if let Some(reply) = reply {
+        reply(match &tunnel {
+            Ok(_) => Ok(()),
+            Err(err) => Err(err.downcast_ref::<CloseReason>().copied().unwrap_or(CloseReason::Error)),
+        });
+    }
+    let (ws_rx, ws_tx, response) = tunnel?;
+    if let Some(metrics) = &metrics {
+        metrics.upgrade_done(started_at.elapsed());
+    }
+
+    debug!(
+        "Server response: {:?} {:?}",
+        response.status,
+        RedactedHeaders(&response.headers)
+    );
+    let stats = tunnel_stats(request_id, remote_cfg, metrics);
+    let idle_websocket = idle_websocket.filter(|_| peer_features(&response.headers).contains(&FEATURE_REUSE));
+    // Secret of the tunnel issued by the server, to resume it or join its bond
+    let secret = response.headers.get(&SESSION_HEADER).cloned();
+    let bonded =
+        !reuse && bond::is_bonded(client_cfg, remote_cfg) && peer_features(&response.headers).contains(&FEATURE_BOND);
+    let resumable = !reuse
+        && !bonded
+        && resume::is_resumable(client_cfg, remote_cfg)
+        && peer_features(&response.headers).contains(&FEATURE_RESUME);
+    let (ws_rx, ws_tx) = match (idle_websocket, ws_rx, ws_tx, secret) {
+        (Some(idle_websocket), TunnelReader::Websocket(ws_rx), TunnelWriter::Websocket(ws_tx), _) => {
+            let (ws_rx, ws_tx) = super::transport::io::relay_reusable(local_rx, local_tx, ws_rx, ws_tx, stats).await?;
+            idle_websocket.put(remote_cfg, ws_rx, ws_tx, client_cfg.websocket_ping_frequency);
+            return Ok(());
         }
-        TransportScheme::Http | TransportScheme::Https => {
-            tunnel::transport::http2::connect(request_id, client_cfg, remote_cfg)
-                .await
-                .map(|(r, w, response)| (TunnelReader::Http2(r), TunnelWriter::Http2(w), response))?
+        (_, TunnelReader::Websocket(ws_rx), TunnelWriter::Websocket(ws_tx), Some(secret)) if bonded => {
+            let (local_rx, local_tx) = (Box::pin(local_rx), Box::pin(local_tx));
+            return relay_bonded(
+                request_id,
+                client_cfg,
+                remote_cfg,
+                &secret,
+                (ws_rx, ws_tx),
+                (local_rx, local_tx),
+                stats,
+            )
+            .await;
         }
+        (_, TunnelReader::Websocket(ws_rx), TunnelWriter::Websocket(ws_tx), Some(secret)) if resumable => {
+            let retransmit_buffer = client_cfg
+                .resume_buffer
+                .filter(|_| peer_features(&response.headers).contains(&FEATURE_RETRANSMIT));
+            let tunnel = ResumableTunnel::new(Box::pin(local_rx), Box::pin(local_tx), stats)
+                .with_retransmit_buffer(retransmit_buffer);
+            return relay_resumable(request_id, client_cfg, remote_cfg, &secret, (ws_rx, ws_tx), tunnel).await;
+        }
+        (_, ws_rx, ws_tx, _) => (ws_rx, ws_tx),
     };
 
-    debug!("Server response: {:?}", response);
-    let (local_rx, local_tx) = duplex_stream;
-    let (close_tx, close_rx) = oneshot::channel::<()>();
+    super::transport::io::relay_tunnel(
+        local_rx,
+        local_tx,
+        ws_rx,
+        ws_tx,
+        Some(client_cfg.keepalive.clone()),
+        stats,
+        Some(client_cfg.scheduler.schedule(priority)),
+        client_cfg.write_batching.filter(|_| !remote_cfg.protocol.is_datagram()),
+    )
+    .await;
 
-    // Forward local tx to websocket tx
-    let ping_frequency = client_cfg.websocket_ping_frequency;
-    tokio::spawn(
-        super::transport::io::propagate_local_to_remote(local_rx, ws_tx, close_tx, Some(ping_frequency))
-            .instrument(Span::current()),
-    );
-
-    // Forward websocket rx to local rx
-    let _ = super::transport::io::propagate_remote_to_local(local_tx, ws_rx, close_rx).await;
+    Ok(())
+}
 
+/// Relay the connection over a tunnel that is resumed over a new websocket each time its websocket breaks,
+/// i.e: when the network of the client changes
+async fn relay_resumable(
+    request_id: Uuid,
+    client_cfg: &WsClientConfig,
+    remote_cfg: &RemoteAddr,
+    secret: &HeaderValue,
+    websocket: (WebsocketTunnelRead, WebsocketTunnelWrite),
+    mut tunnel: ResumableTunnel,
+) -> anyhow::Result<()> {
+    let (mut ws_rx, mut ws_tx) = websocket;
+    while tunnel.relay(ws_rx, ws_tx, Some(client_cfg.keepalive.clone())).await {
+        warn!("Websocket of the tunnel broke, resuming the tunnel");
+        let server_received;
+        (ws_rx, ws_tx, server_received) = resume(request_id, client_cfg, remote_cfg, secret, tunnel.received()).await?;
+        if !server_received.is_some_and(|received| tunnel.resume_from(received)) {
+            let _ = ws_tx.close(CloseReason::Error).await;
+            return Err(anyhow!(
+                "Cannot resume tunnel, {} bytes sent to the server but it received {:?}",
+                tunnel.sent(),
+                server_received
+            ));
+        }
+        info!("Tunnel resumed over a new websocket");
+    }
     Ok(())
 }
 
-pub async fn run_tunnel<T, R, W>(client_config: Arc<WsClientConfig>, incoming_cnx: T) -> anyhow::Result<()>
+/// Open the other websockets of the bond of the tunnel, then relay the connection striped across all of them
+async fn relay_bonded(
+    request_id: Uuid,
+    client_cfg: &WsClientConfig,
+    remote_cfg: &RemoteAddr,
+    secret: &HeaderValue,
+    websocket: bond::Websocket,
+    local: (impl AsyncRead + Send + Unpin, impl AsyncWrite + Send + Unpin),
+    stats: TunnelStats,
+) -> anyhow::Result<()> {
+    let count = client_cfg.bond.unwrap_or_default();
+    let others = try_join_all((1..count).map(|index| {
+        tunnel::transport::websocket::join_bond(request_id, client_cfg, remote_cfg, secret, index, count)
+    }))
+    .await?;
+    let mut members = vec![websocket];
+    members.extend(others.into_iter().map(|(ws_rx, ws_tx, _)| (ws_rx, ws_tx)));
+
+    debug!("Tunnel bonded across {} websockets", count);
+    let keepalive = Some(client_cfg.keepalive.interval()).filter(|interval| !interval.is_zero());
+    bond::relay(local.0, local.1, members, keepalive, &stats).await
+}
+
+/// New websocket for the tunnel, retrying until --resume-timeout-sec while the server cannot be reached.
+/// Returns it with the bytes of the tunnel the server received
+async fn resume(
+    request_id: Uuid,
+    client_cfg: &WsClientConfig,
+    remote_cfg: &RemoteAddr,
+    secret: &HeaderValue,
+    received: u64,
+) -> anyhow::Result<(WebsocketTunnelRead, WebsocketTunnelWrite, Option<u64>)> {
+    let timeout = client_cfg.resume_timeout.unwrap_or_default();
+    let deadline = Instant::now() + timeout;
+    loop {
+        let err = match tunnel::transport::websocket::resume(request_id, client_cfg, remote_cfg, secret, received).await
+        {
+            Ok((ws_rx, ws_tx, response)) => {
+                let server_received = response
+                    .headers
+                    .get(&RESUME_HEADER)
+                    .and_then(|h| h.to_str().ok())
+                    .and_then(|h| h.parse::<u64>().ok());
+                return Ok((ws_rx, ws_tx, server_received));
+            }
+            Err(err) => err,
+        };
+
+        // The server closed the tunnel, or lost data of it
+        if let Some(fastwebsockets::WebSocketError::InvalidStatusCode(status)) = err.downcast_ref() {
+            if matches!(StatusCode::from_u16(*status), Ok(StatusCode::GONE | StatusCode::CONFLICT)) {
+                return Err(err.context("Server refused to resume the tunnel"));
+            }
+        }
+        if Instant::now() + RESUME_RETRY_DELAY > deadline {
+            return Err(err.context(format!("Tunnel not resumed after {:?}", timeout)));
+        }
+        debug!("Cannot resume tunnel yet: {:?}", err);
+        tokio::time::sleep(RESUME_RETRY_DELAY).await;
+    }
+}
+
+/// Options of a local listener (-L), applied to all its connections
+#[derive(Clone, Debug, Default)]
+pub struct ListenerOptions {
+    pub priority: TunnelPriority,
+    pub name: Option<String>,
+    /// Once reached, the listener is closed and the live tunnels with it
+    pub max_duration: Option<Duration>,
+    /// Keep the websocket of a connection once done, for the next one
+    pub reuse: bool,
+    /// Connections above this number of live ones are refused
+    pub max_conn: Option<usize>,
+}
+
+pub async fn run_tunnel<T, R, W>(
+    client_config: Arc<WsClientConfig>,
+    options: ListenerOptions,
+    metrics: Option<Arc<ListenerMetrics>>,
+    incoming_cnx: T,
+) -> anyhow::Result<()>
 where
-    T: Stream<Item = anyhow::Result<((R, W), RemoteAddr)>>,
+    T: Stream<Item = anyhow::Result<((R, W), RemoteAddr, Option<TunnelReply>)>>,
     R: AsyncRead + Send + 'static,
     W: AsyncWrite + Send + 'static,
 {
+    let ListenerOptions {
+        priority,
+        name,
+        max_duration,
+        reuse,
+        max_conn,
+    } = options;
+    let idle_websocket = reuse.then(|| Arc::new(IdleWebsocket::default()));
+    let deadline = max_duration.map(|max_duration| tokio::time::Instant::now() + max_duration);
+    let live_cnx = max_conn.map(|max_conn| Arc::new(Semaphore::new(max_conn)));
+    let mut at_max_conn = false;
+
     pin_mut!(incoming_cnx);
-    while let Some(Ok((cnx_stream, remote_addr))) = incoming_cnx.next().await {
+    loop {
+        let cnx = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, incoming_cnx.next()).await {
+                Ok(cnx) => cnx,
+                Err(_) => {
+                    info!(
+                        "Listener {}reached its max duration of {:?}, closing it",
+                        name.as_ref().map(|name| format!("{} ", name)).unwrap_or_default(),
+                        max_duration.unwrap_or_default()
+                    );
+                    break;
+                }
+            },
+            None => incoming_cnx.next().await,
+        };
+        let Some(Ok((cnx_stream, remote_addr, reply))) = cnx else {
+            break;
+        };
+        let permit = match &live_cnx {
+            None => None,
+            Some(live_cnx) => match live_cnx.clone().try_acquire_owned() {
+                Ok(permit) => {
+                    at_max_conn = false;
+                    Some(permit)
+                }
+                Err(_) => {
+                    if !std::mem::replace(&mut at_max_conn, true) {
+                        warn!(
+                            "Listener {}reached its max of {} connections, refusing new ones",
+                            name.as_ref().map(|name| format!("{} ", name)).unwrap_or_default(),
+                            max_conn.unwrap_or_default()
+                        );
+                    }
+                    if let Some(reply) = reply {
+                        reply(Err(CloseReason::QuotaExceeded));
+                    }
+                    continue;
+                }
+            },
+        };
+
         let request_id = Uuid::now_v7();
         let span = span!(
             Level::INFO,
             "tunnel",
             id = request_id.to_string(),
-            remote = format!("{}:{}", remote_addr.host, remote_addr.port)
+            name = name.as_deref(),
+            remote = format!("{}:{}", remote_addr.host, remote_addr.port),
+            bytes_tx = tracing::field::Empty,
+            bytes_rx = tracing::field::Empty,
+            duration = tracing::field::Empty,
+            rtt = tracing::field::Empty
         );
         let client_config = client_config.clone();
+        let remote = format!("{}:{}", remote_addr.host, remote_addr.port);
+        let metrics = metrics.clone();
+        if let Some(metrics) = &metrics {
+            metrics.connection_accepted();
+        }
+        let idle_websocket = idle_websocket.clone();
 
         let tunnel = async move {
-            let _ = connect_to_server(request_id, &client_config, &remote_addr, cnx_stream)
-                .await
-                .map_err(|err| error!("{:?}", err));
+            let _permit = permit;
+            let tunnel = connect_to_server(
+                request_id,
+                &client_config,
+                &remote_addr,
+                priority,
+                metrics.clone(),
+                idle_websocket.as_deref(),
+                reply,
+                cnx_stream,
+            );
+            let ret = match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, tunnel).await.unwrap_or_else(|_| {
+                    info!("Closing tunnel, its listener reached its max duration");
+                    Ok(())
+                }),
+                None => tunnel.await,
+            };
+            if let Err(err) = ret {
+                statsd::incr(Counter::Errors, 1);
+                if let Some(metrics) = &metrics {
+                    metrics.error();
+                }
+                log_tunnel_error(&err);
+            }
         }
         .instrument(span);
 
-        tokio::spawn(tunnel);
+        tokio::spawn(admin::track_tunnel(request_id, remote, name.clone(), tunnel));
     }
 
     Ok(())
@@ -89,6 +557,8 @@ where
 pub async fn run_reverse_tunnel<F, Fut, T>(
     client_cfg: Arc<WsClientConfig>,
     remote_addr: RemoteAddr,
+    name: Option<String>,
+    metrics: Option<Arc<ListenerMetrics>>,
     connect_to_dest: F,
 ) -> anyhow::Result<()>
 where
@@ -103,39 +573,39 @@ where
             Level::INFO,
             "tunnel",
             id = request_id.to_string(),
-            remote = format!("{}:{}", remote_addr.host, remote_addr.port)
+            name = name.as_deref(),
+            remote = format!("{}:{}", remote_addr.host, remote_addr.port),
+            bytes_tx = tracing::field::Empty,
+            bytes_rx = tracing::field::Empty,
+            duration = tracing::field::Empty,
+            rtt = tracing::field::Empty
         );
         // Correctly configure tunnel cfg
-        let (ws_rx, ws_tx, response) = match client_cfg.remote_addr.scheme() {
-            TransportScheme::Ws | TransportScheme::Wss => {
-                match tunnel::transport::websocket::connect(request_id, &client_cfg, &remote_addr)
-                    .instrument(span.clone())
-                    .await
-                {
-                    Ok((r, w, response)) => (TunnelReader::Websocket(r), TunnelWriter::Websocket(w), response),
-                    Err(err) => {
-                        event!(parent: &span, Level::ERROR, "Retrying in 1sec, cannot connect to remote server: {:?}", err);
-                        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-                        continue;
-                    }
+        let (ws_rx, mut ws_tx, response) = match connect(request_id, &client_cfg, &remote_addr, false)
+            .instrument(span.clone())
+            .await
+        {
+            Ok(tunnel) => tunnel,
+            Err(err) => {
+                statsd::incr(Counter::Errors, 1);
+                if let Some(metrics) = &metrics {
+                    metrics.reconnect();
                 }
-            }
-            TransportScheme::Http | TransportScheme::Https => {
-                match tunnel::transport::http2::connect(request_id, &client_cfg, &remote_addr)
-                    .instrument(span.clone())
-                    .await
-                {
-                    Ok((r, w, response)) => (TunnelReader::Http2(r), TunnelWriter::Http2(w), response),
-                    Err(err) => {
-                        event!(parent: &span, Level::ERROR, "Retrying in 1sec, cannot connect to remote server: {:?}", err);
-                        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-                        continue;
-                    }
+                if err.is::<CircuitOpen>() {
+                    event!(parent: &span, Level::DEBUG, "Retrying in 1sec: {:?}", err);
+                } else {
+                    event!(parent: &span, Level::ERROR, "Retrying in 1sec, cannot connect to remote server: {:?}", err);
                 }
+                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                continue;
             }
         };
 
         // Connect to endpoint
+        // The server only answers the upgrade once a connection arrives, so its latency is not tracked
+        if let Some(metrics) = &metrics {
+            metrics.connection_accepted();
+        }
         event!(parent: &span, Level::DEBUG, "Server response: {:?}", response);
         let remote = response
             .headers
@@ -150,30 +620,86 @@ where
                 protocol: jwt.claims.p,
                 host: Host::parse(&jwt.claims.r).unwrap_or_else(|_| Host::Domain(String::new())),
                 port: jwt.claims.rp,
+                fallbacks: vec![],
             });
 
         let stream = match connect_to_dest(remote).instrument(span.clone()).await {
             Ok(s) => s,
             Err(err) => {
+                statsd::incr(Counter::Errors, 1);
+                if let Some(metrics) = &metrics {
+                    metrics.error();
+                }
                 event!(parent: &span, Level::ERROR, "Cannot connect to xxxx: {err:?}");
+                let _ = ws_tx.close(CloseReason::from_error(&err)).await;
                 continue;
             }
         };
 
         let (local_rx, local_tx) = tokio::io::split(stream);
-        let (close_tx, close_rx) = oneshot::channel::<()>();
 
+        let hook = TunnelHook::new(
+            &request_id.to_string(),
+            remote_addr.protocol.name(),
+            format!("{}:{}", remote_addr.host, remote_addr.port),
+            None,
+        );
+        let capture = TunnelCapture::new(
+            &request_id.to_string(),
+            remote_addr.port,
+            remote_addr.protocol.is_datagram(),
+            true,
+        );
+        let stats = TunnelStats::new(span.clone())
+            .with_hook(hook)
+            .with_metrics(metrics.clone(), &format!("{}:{}", remote_addr.host, remote_addr.port))
+            .with_flight_recorder(FlightRecorder::new(&request_id.to_string()))
+            .with_capture(capture);
+        let write_batching = client_config
+            .write_batching
+            .filter(|_| !remote_addr.protocol.is_datagram());
         let tunnel = async move {
-            let ping_frequency = client_config.websocket_ping_frequency;
-            tokio::spawn(
-                super::transport::io::propagate_local_to_remote(local_rx, ws_tx, close_tx, Some(ping_frequency))
-                    .in_current_span(),
-            );
-
-            // Forward websocket rx to local rx
-            let _ = super::transport::io::propagate_remote_to_local(local_tx, ws_rx, close_rx).await;
+            super::transport::io::relay_tunnel(
+                local_rx,
+                local_tx,
+                ws_rx,
+                ws_tx,
+                Some(client_config.keepalive.clone()),
+                stats,
+                Some(client_config.scheduler.schedule(TunnelPriority::Normal)),
+                write_batching,
+            )
+            .await;
         }
         .instrument(span.clone());
-        tokio::spawn(tunnel);
+        tokio::spawn(admin::track_tunnel(
+            request_id,
+            format!("{}:{}", remote_addr.host, remote_addr.port),
+            name.clone(),
+            tunnel,
+        ));
     }
 }
+
+/// Perform an upgrade request against the server for the given destination, and close the tunnel right away.
+/// Return the time it took to connect to the server and get the upgrade accepted
+pub async fn healthcheck(
+    client_cfg: &WsClientConfig,
+    remote_addr: &RemoteAddr,
+    timeout: Duration,
+) -> anyhow::Result<Duration> {
+    let request_id = Uuid::now_v7();
+    let started_at = Instant::now();
+    let upgrade = async {
+        let (_, w, _) = connect(request_id, client_cfg, remote_addr, false).await?;
+        Ok::<_, anyhow::Error>(w)
+    };
+
+    let mut ws_tx = tokio::time::timeout(timeout, upgrade)
+        .await
+        .map_err(|_| anyhow::anyhow!("timeout after {:?} while doing upgrade request", timeout))??;
+    let elapsed = started_at.elapsed();
+    let _ = ws_tx.close(CloseReason::Normal).await;
+
+    Ok(elapsed)
+}
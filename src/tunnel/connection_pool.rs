@@ -0,0 +1,200 @@
+use crate::dns::DnsResolver;
+use crate::netns::NetNs;
+use crate::tcp;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::io::ErrorKind;
+use std::mem::MaybeUninit;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::select;
+use tokio::sync::Notify;
+use tracing::{debug, info, warn};
+use url::{Host, Url};
+
+/// Idle connections are replaced after this duration, before firewalls and NATs on the way forget about them
+const MAX_IDLE: Duration = Duration::from_secs(60);
+
+/// Delay before the pool is checked again, or retried after the destination refused a connection
+const REFILL_INTERVAL: Duration = Duration::from_secs(5);
+
+struct PoolState {
+    idle: Mutex<VecDeque<(TcpStream, Instant)>>,
+    refill: Notify,
+}
+
+/// Keep a few connections already opened to a destination, to save the TCP handshake when a tunnel toward it is opened
+#[derive(Clone)]
+pub struct ConnectionPool {
+    host: Host<String>,
+    port: u16,
+    size: usize,
+    state: Arc<PoolState>,
+}
+
+impl Debug for ConnectionPool {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}={}", self.host, self.port, self.size)
+    }
+}
+
+pub fn parse_connection_pool(arg: &str) -> Result<ConnectionPool, io::Error> {
+    let Some((dest, size)) = arg.rsplit_once('=') else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse connection pool from {}, expected DEST:PORT=SIZE", arg),
+        ));
+    };
+
+    let (Ok(url), Ok(size)) = (Url::parse(&format!("fake://{}", dest)), size.parse::<usize>()) else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse connection pool from {}, expected DEST:PORT=SIZE", arg),
+        ));
+    };
+    let (Some(host), Some(port)) = (url.host(), url.port()) else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse connection pool destination from {}", dest),
+        ));
+    };
+    if size == 0 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("connection pool of {} must keep at least one connection", dest),
+        ));
+    }
+
+    Ok(ConnectionPool {
+        host: host.to_owned(),
+        port,
+        size,
+        state: Arc::new(PoolState {
+            idle: Mutex::new(VecDeque::with_capacity(size)),
+            refill: Notify::new(),
+        }),
+    })
+}
+
+/// A connection closed by the destination while idle reads as end of stream.
+/// Data already sent, like the banner of an ssh server, is left for the tunnel
+fn is_alive(stream: &TcpStream) -> bool {
+    let mut buf = [MaybeUninit::uninit(); 1];
+    match socket2::SockRef::from(stream).peek(&mut buf) {
+        Ok(0) => false,
+        Ok(_) => true,
+        Err(err) => err.kind() == ErrorKind::WouldBlock,
+    }
+}
+
+impl ConnectionPool {
+    pub fn is_for(&self, host: &Host<String>, port: u16) -> bool {
+        &self.host == host && self.port == port
+    }
+
+    /// Open connections in the background to keep the pool full, for as long as the server runs
+    pub fn start(
+        &self,
+        so_mark: Option<u32>,
+        connect_timeout: Duration,
+        dns_resolver: DnsResolver,
+        netns: Option<NetNs>,
+    ) {
+        let (host, port, size) = (self.host.clone(), self.port, self.size);
+        let state = self.state.clone();
+        info!("Keeping {} connections opened to {}:{}", size, host, port);
+
+        tokio::spawn(async move {
+            loop {
+                state
+                    .idle
+                    .lock()
+                    .retain(|(stream, since)| since.elapsed() < MAX_IDLE && is_alive(stream));
+
+                while state.idle.lock().len() < size {
+                    match tcp::connect_in(&host, port, so_mark, connect_timeout, &dns_resolver, netns.as_ref()).await {
+                        Ok(stream) => state.idle.lock().push_back((stream, Instant::now())),
+                        Err(err) => {
+                            warn!("Cannot fill connection pool of {}:{}: {:#}", host, port, err);
+                            break;
+                        }
+                    }
+                }
+
+                select! {
+                    _ = state.refill.notified() => {},
+                    _ = tokio::time::sleep(REFILL_INTERVAL) => {},
+                }
+            }
+        });
+    }
+
+    /// Take an idle connection of the pool, if any is still open. The pool is refilled behind
+    pub fn take(&self) -> Option<TcpStream> {
+        let stream = pop_alive(&mut self.state.idle.lock());
+        self.state.refill.notify_one();
+        if stream.is_some() {
+            debug!("Using a pooled connection to {}:{}", self.host, self.port);
+        }
+        stream
+    }
+}
+
+fn pop_alive(idle: &mut VecDeque<(TcpStream, Instant)>) -> Option<TcpStream> {
+    while let Some((stream, since)) = idle.pop_front() {
+        if since.elapsed() < MAX_IDLE && is_alive(&stream) {
+            return Some(stream);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_parse_connection_pool() {
+        let pool = parse_connection_pool("bastion.corp:22=4").unwrap();
+        assert!(pool.is_for(&Host::Domain("bastion.corp".to_string()), 22));
+        assert_eq!(pool.size, 4);
+        assert!(parse_connection_pool("[::1]:22=1")
+            .unwrap()
+            .is_for(&Host::Ipv6("::1".parse().unwrap()), 22));
+        assert!(parse_connection_pool("bastion.corp:22").is_err());
+        assert!(parse_connection_pool("bastion.corp=2").is_err());
+        assert!(parse_connection_pool("bastion.corp:22=0").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pooled_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let pool = parse_connection_pool(&format!("127.0.0.1:{}=2", port)).unwrap();
+        pool.start(None, Duration::from_secs(1), DnsResolver::system(), None);
+
+        let (first, _) = listener.accept().await.unwrap();
+        let (mut second, _) = listener.accept().await.unwrap();
+        // Wait for the pool to register the second connection
+        while pool.state.idle.lock().len() < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // Connections closed by the destination are skipped
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut stream = pool.take().unwrap();
+        second.write_all(b"SSH-2.0").await.unwrap();
+        let mut buf = [0u8; 7];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"SSH-2.0");
+
+        // The pool is refilled in the background
+        let _refill = listener.accept().await.unwrap();
+    }
+}
@@ -1,11 +1,30 @@
+mod bond;
 pub mod client;
+pub mod connection_pool;
+pub mod port_knocking;
+mod resume;
 pub mod server;
 mod tls_reloader;
 mod transport;
 
+pub use transport::close_reason::{CloseReason, CLOSE_REASON_HEADER};
+pub use transport::io::{active_tunnels, last_rtt, totals, TunnelPriority, TunnelScheduler, WriteBatching};
+pub use transport::keepalive::{AdaptiveKeepalive, Keepalive};
+pub use transport::long_polling::{LongPollingFallback, TRANSPORT_HEADER};
+
+use crate::totp::TOTP_HEADER;
+use crate::tunnel::bond::BOND_HEADER;
+use crate::tunnel::resume::RESUME_HEADER;
+use crate::version::{CLIENT_FEATURES_HEADER, CLIENT_VERSION_HEADER};
 use crate::{tcp, tls, LocalProtocol, TlsClientConfig, WsClientConfig};
+use anyhow::anyhow;
 use async_trait::async_trait;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
 use bb8::ManageConnection;
+use hyper::header::{Entry, AUTHORIZATION, SEC_WEBSOCKET_PROTOCOL};
+use hyper::http::{HeaderName, HeaderValue};
+use hyper::HeaderMap;
 use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
@@ -30,6 +49,8 @@ struct JwtTunnelConfig {
     pub p: LocalProtocol, // protocol to use
     pub r: String,        // remote host
     pub rp: u16,          // remote port
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fb: Vec<String>, // fallback destinations host:port, tried in order when the remote cannot be reached
 }
 
 impl JwtTunnelConfig {
@@ -48,9 +69,15 @@ impl JwtTunnelConfig {
                 LocalProtocol::TProxyUdp { timeout } => LocalProtocol::Udp { timeout },
                 LocalProtocol::Unix { .. } => LocalProtocol::Tcp { proxy_protocol: false },
                 LocalProtocol::ReverseUnix { .. } => dest.protocol.clone(),
+                LocalProtocol::Bench => LocalProtocol::Bench,
             },
             r: dest.host.to_string(),
             rp: dest.port,
+            fb: dest
+                .fallbacks
+                .iter()
+                .map(|(host, port)| format!("{}:{}", host, port))
+                .collect(),
         }
     }
 }
@@ -62,6 +89,7 @@ fn tunnel_to_jwt_token(request_id: Uuid, tunnel: &RemoteAddr) -> String {
 }
 
 static JWT_HEADER_PREFIX: &str = "authorization.bearer.";
+static PROTOCOL_HEADER_PREFIX: &str = "header.";
 static JWT_SECRET: &[u8; 15] = b"champignonfrais";
 static JWT_KEY: Lazy<(Header, EncodingKey)> =
     Lazy::new(|| (Header::new(Algorithm::HS256), EncodingKey::from_secret(JWT_SECRET)));
@@ -72,11 +100,74 @@ static JWT_DECODE: Lazy<(Validation, DecodingKey)> = Lazy::new(|| {
     (validation, DecodingKey::from_secret(JWT_SECRET))
 });
 
-#[derive(Debug)]
+// Only those can be carried in Sec-WebSocket-Protocol, for a client to not be able to spoof the headers
+// set by a proxy in front of the server, i.e: X-Forwarded-For
+fn is_protocol_header(name: &HeaderName) -> bool {
+    name == AUTHORIZATION
+        || name == TOTP_HEADER
+        || name == CLIENT_VERSION_HEADER
+        || name == CLIENT_FEATURES_HEADER
+        || name == SESSION_HEADER
+        || name == RESUME_HEADER
+        || name == BOND_HEADER
+}
+
+/// Move the headers of wstunnel into tokens of Sec-WebSocket-Protocol, i.e: header.x-wstunnel-totp.MTIzNDU2
+fn headers_to_protocol(headers: &mut HeaderMap) -> anyhow::Result<()> {
+    let mut protocol = headers
+        .get(SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let names: Vec<HeaderName> = headers
+        .keys()
+        .filter(|name| is_protocol_header(name))
+        .cloned()
+        .collect();
+    for name in names {
+        for value in headers.get_all(&name) {
+            let value = URL_SAFE_NO_PAD.encode(value.as_bytes());
+            protocol = format!("{}, {}{}.{}", protocol, PROTOCOL_HEADER_PREFIX, name, value);
+        }
+        headers.remove(&name);
+    }
+
+    headers.insert(
+        SEC_WEBSOCKET_PROTOCOL,
+        HeaderValue::from_str(protocol.trim_start_matches(", "))?,
+    );
+    Ok(())
+}
+
+/// Restore the headers carried in tokens of Sec-WebSocket-Protocol, without overriding the ones sent as is
+fn headers_from_protocol(headers: &mut HeaderMap) {
+    let Some(protocol) = headers.get(SEC_WEBSOCKET_PROTOCOL).and_then(|h| h.to_str().ok()) else {
+        return;
+    };
+
+    let restored: Vec<(HeaderName, HeaderValue)> = protocol
+        .split(',')
+        .filter_map(|token| {
+            let (name, value) = token.trim().strip_prefix(PROTOCOL_HEADER_PREFIX)?.split_once('.')?;
+            let name = HeaderName::from_str(name).ok().filter(is_protocol_header)?;
+            let value = HeaderValue::from_bytes(&URL_SAFE_NO_PAD.decode(value).ok()?).ok()?;
+            Some((name, value))
+        })
+        .collect();
+    for (name, value) in restored {
+        if let Entry::Vacant(entry) = headers.entry(name) {
+            entry.insert(value);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct RemoteAddr {
     pub protocol: LocalProtocol,
     pub host: Host,
     pub port: u16,
+    /// Tried in order by the server when it cannot connect to host:port
+    pub fallbacks: Vec<(Host, u16)>,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -201,6 +292,15 @@ impl TransportAddr {
         }
     }
 
+    pub fn tls_mut(&mut self) -> Option<&mut TlsClientConfig> {
+        match self {
+            TransportAddr::Wss { tls, .. } => Some(tls),
+            TransportAddr::Https { tls, .. } => Some(tls),
+            TransportAddr::Ws { .. } => None,
+            TransportAddr::Http { .. } => None,
+        }
+    }
+
     pub fn host(&self) -> &Host {
         match self {
             TransportAddr::Wss { host, .. } => host,
@@ -236,6 +336,16 @@ impl TryFrom<JwtTunnelConfig> for RemoteAddr {
             protocol: jwt.p,
             host: Host::parse(&jwt.r)?,
             port: jwt.rp,
+            fallbacks: jwt
+                .fb
+                .iter()
+                .map(|dest| {
+                    let (host, port) = dest
+                        .rsplit_once(':')
+                        .ok_or_else(|| anyhow!("invalid fallback destination {}", dest))?;
+                    Ok((Host::parse(host)?, port.parse()?))
+                })
+                .collect::<anyhow::Result<_>>()?,
         })
     }
 }
@@ -313,8 +423,9 @@ impl ManageConnection for WsClientConfig {
                 so_mark,
                 timeout,
                 &self.dns_resolver,
+                self.http_proxy_negotiate,
             )
-            .await?
+            .await
         } else {
             tcp::connect(
                 self.remote_addr.host(),
@@ -323,8 +434,10 @@ impl ManageConnection for WsClientConfig {
                 timeout,
                 &self.dns_resolver,
             )
-            .await?
+            .await
         };
+        // The server may have moved to another ip, resolve its address again on the next reconnect
+        let tcp_stream = tcp_stream.inspect_err(|_| self.dns_resolver.clear_cache())?;
 
         if self.remote_addr.tls().is_some() {
             let tls_stream = tls::connect(self, tcp_stream).await?;
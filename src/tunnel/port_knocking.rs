@@ -0,0 +1,254 @@
+use ahash::{HashMap, HashMapExt};
+use anyhow::Context;
+use parking_lot::Mutex;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, UdpSocket};
+use tracing::{debug, error, info, Instrument, Span};
+
+/// Once an ip has completed the knock sequence, it stays allowed for this duration since its last connection
+const KNOCK_GRANT_DURATION: Duration = Duration::from_secs(3600);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum KnockProtocol {
+    Tcp,
+    Udp,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct KnockStep {
+    pub protocol: KnockProtocol,
+    pub port: u16,
+}
+
+impl Display for KnockStep {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.protocol {
+            KnockProtocol::Tcp => write!(f, "tcp:{}", self.port),
+            KnockProtocol::Udp => write!(f, "udp:{}", self.port),
+        }
+    }
+}
+
+pub fn parse_knock_step(arg: &str) -> Result<KnockStep, io::Error> {
+    let Some((protocol, port)) = arg.trim().split_once(':') else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse knock step from {}, expected PROTOCOL:PORT", arg),
+        ));
+    };
+
+    let protocol = match protocol {
+        "tcp" => KnockProtocol::Tcp,
+        "udp" => KnockProtocol::Udp,
+        _ => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid knock protocol {}, expected tcp or udp", protocol),
+            ))
+        }
+    };
+
+    let Ok(port) = port.parse::<u16>() else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse knock port from {}", arg),
+        ));
+    };
+
+    Ok(KnockStep { protocol, port })
+}
+
+struct PortKnockingState {
+    sequence: Vec<KnockStep>,
+    timeout: Duration,
+    // ip => (index of the next expected step, time of the first knock)
+    in_progress: Mutex<HashMap<IpAddr, (usize, Instant)>>,
+    // ip => last time the ip was seen allowed
+    granted: Mutex<HashMap<IpAddr, Instant>>,
+}
+
+#[derive(Clone)]
+pub struct PortKnocking {
+    state: Arc<PortKnockingState>,
+}
+
+impl PortKnocking {
+    pub fn new(sequence: Vec<KnockStep>, timeout: Duration) -> Self {
+        Self {
+            state: Arc::new(PortKnockingState {
+                sequence,
+                timeout,
+                in_progress: Mutex::new(HashMap::with_capacity(0)),
+                granted: Mutex::new(HashMap::with_capacity(0)),
+            }),
+        }
+    }
+
+    /// Bind every port of the knock sequence and start recording knocks
+    pub async fn run_listeners(&self, bind: IpAddr) -> anyhow::Result<()> {
+        info!(
+            "Server is hidden behind port knocking sequence {}",
+            self.state
+                .sequence
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        );
+
+        for step in self.state.sequence.iter().copied() {
+            let bind = SocketAddr::new(bind, step.port);
+            let this = self.clone();
+            match step.protocol {
+                KnockProtocol::Tcp => {
+                    let listener = TcpListener::bind(bind)
+                        .await
+                        .with_context(|| format!("Cannot bind knock port {}", step))?;
+                    let fut = async move {
+                        loop {
+                            match listener.accept().await {
+                                // Drop the connection right away, we only care about the peer ip
+                                Ok((_stream, peer)) => this.knock(peer.ip(), step),
+                                Err(err) => {
+                                    error!("Error while accepting knock on {}: {:?}", step, err);
+                                    tokio::time::sleep(Duration::from_millis(100)).await;
+                                }
+                            }
+                        }
+                    };
+                    tokio::spawn(fut.instrument(Span::current()));
+                }
+                KnockProtocol::Udp => {
+                    let socket = UdpSocket::bind(bind)
+                        .await
+                        .with_context(|| format!("Cannot bind knock port {}", step))?;
+                    let fut = async move {
+                        let mut buf = [0u8; 64];
+                        loop {
+                            match socket.recv_from(&mut buf).await {
+                                Ok((_, peer)) => this.knock(peer.ip(), step),
+                                Err(err) => {
+                                    error!("Error while receiving knock on {}: {:?}", step, err);
+                                    tokio::time::sleep(Duration::from_millis(100)).await;
+                                }
+                            }
+                        }
+                    };
+                    tokio::spawn(fut.instrument(Span::current()));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn knock(&self, ip: IpAddr, step: KnockStep) {
+        let state = &self.state;
+        let now = Instant::now();
+        let mut in_progress = state.in_progress.lock();
+        in_progress.retain(|_, (_, started_at)| now.duration_since(*started_at) <= state.timeout);
+
+        let next_step = match in_progress.get(&ip) {
+            Some((idx, _)) if state.sequence[*idx] == step => *idx + 1,
+            // Wrong knock, the sequence must be restarted from the beginning
+            _ if state.sequence[0] == step => {
+                in_progress.insert(ip, (0, now));
+                1
+            }
+            _ => {
+                in_progress.remove(&ip);
+                return;
+            }
+        };
+
+        if next_step < state.sequence.len() {
+            if let Some((idx, _)) = in_progress.get_mut(&ip) {
+                *idx = next_step;
+            }
+            return;
+        }
+
+        in_progress.remove(&ip);
+        drop(in_progress);
+        debug!("Knock sequence completed by {}", ip);
+        let mut granted = state.granted.lock();
+        granted.retain(|_, last_seen| now.duration_since(*last_seen) <= KNOCK_GRANT_DURATION);
+        granted.insert(ip, now);
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut granted = self.state.granted.lock();
+        match granted.get_mut(&ip) {
+            Some(last_seen) if now.duration_since(*last_seen) <= KNOCK_GRANT_DURATION => {
+                *last_seen = now;
+                true
+            }
+            Some(_) => {
+                granted.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn parse_knock_sequence(arg: &str) -> Vec<KnockStep> {
+        arg.split(',').map(|s| parse_knock_step(s).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_parse_knock_step() {
+        assert_eq!(
+            parse_knock_step("udp:7000").unwrap(),
+            KnockStep {
+                protocol: KnockProtocol::Udp,
+                port: 7000
+            }
+        );
+        assert_eq!(
+            parse_knock_step("tcp:8000").unwrap(),
+            KnockStep {
+                protocol: KnockProtocol::Tcp,
+                port: 8000
+            }
+        );
+        assert!(parse_knock_step("").is_err());
+        assert!(parse_knock_step("icmp:80").is_err());
+        assert!(parse_knock_step("tcp:99999").is_err());
+    }
+
+    #[test]
+    fn test_knock_sequence() {
+        let seq = parse_knock_sequence("udp:7000,tcp:8000,udp:9000");
+        let knocking = PortKnocking::new(seq.clone(), Duration::from_secs(10));
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let other_ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        knocking.knock(ip, seq[0]);
+        knocking.knock(ip, seq[1]);
+        assert!(!knocking.is_allowed(ip));
+
+        // A wrong knock reset the sequence
+        knocking.knock(ip, seq[0]);
+        knocking.knock(ip, seq[2]);
+        assert!(!knocking.is_allowed(ip));
+
+        knocking.knock(ip, seq[0]);
+        knocking.knock(other_ip, seq[0]);
+        knocking.knock(ip, seq[1]);
+        knocking.knock(ip, seq[2]);
+        assert!(knocking.is_allowed(ip));
+        assert!(!knocking.is_allowed(other_ip));
+    }
+}
@@ -0,0 +1,128 @@
+use crate::tunnel::transport::websocket::{WebsocketTunnelRead, WebsocketTunnelWrite};
+use crate::tunnel::RemoteAddr;
+use crate::version::{FEATURE_RESUME, FEATURE_RETRANSMIT};
+use crate::{LocalProtocol, WsClientConfig};
+use ahash::{HashMap, HashMapExt};
+use hyper::http::HeaderName;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use tokio::sync::{mpsc, oneshot};
+use uuid::Uuid;
+
+/// Secret of a tunnel, issued by the server in its answer to the upgrade, for only its client to resume it or
+/// to join its bond
+pub static SESSION_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-session");
+/// Bytes of the tunnel received by the peer, in the request to resume a tunnel and in its answer
+pub static RESUME_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-resume");
+
+/// What a tunnel was opened for. A request to resume it or to join its bond must be for the same,
+/// so the secret of a tunnel cannot be used to reach another destination or by another user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunnelOwner {
+    pub tunnel_id: String,
+    pub protocol: LocalProtocol,
+    pub host: String,
+    pub port: u16,
+    pub user: Option<String>,
+}
+
+pub fn new_secret() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Tunnels the client asks the server to keep when their websocket breaks. Only tcp streams can be resumed,
+/// the datagrams of udp tunnels are lost with the websocket anyway
+pub fn is_resumable(client_cfg: &WsClientConfig, dest_addr: &RemoteAddr) -> bool {
+    client_cfg.resume_timeout.is_some() && matches!(dest_addr.protocol, LocalProtocol::Tcp { .. })
+}
+
+/// Features advertised by the client for its resumable tunnels
+pub fn features(client_cfg: &WsClientConfig, features: &str) -> String {
+    match client_cfg.resume_buffer {
+        Some(_) => format!("{},{},{}", features, FEATURE_RESUME, FEATURE_RETRANSMIT),
+        None => format!("{},{}", features, FEATURE_RESUME),
+    }
+}
+
+pub type Websocket = (WebsocketTunnelRead, WebsocketTunnelWrite);
+
+/// Ask a tunnel of the server to continue over the new websocket of its client
+pub struct ResumeRequest {
+    /// Bytes of the tunnel received by the client
+    pub client_received: u64,
+    /// Bytes of the tunnel received by the server, or why the tunnel cannot be resumed
+    pub reply: oneshot::Sender<Result<u64, String>>,
+    /// Websocket to continue over, once the upgrade is done
+    pub websocket: oneshot::Receiver<Websocket>,
+}
+
+// secret of the tunnel => what it was opened for, and how to resume it
+static TUNNELS: Lazy<Mutex<HashMap<String, (TunnelOwner, mpsc::Sender<ResumeRequest>)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Tunnel of the server that can be resumed, for as long as this is alive
+pub struct Registration {
+    secret: String,
+}
+
+impl Registration {
+    pub fn secret(&self) -> &str {
+        &self.secret
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        TUNNELS.lock().remove(&self.secret);
+    }
+}
+
+pub fn register(owner: TunnelOwner) -> (Registration, mpsc::Receiver<ResumeRequest>) {
+    let secret = new_secret();
+    let (tx, rx) = mpsc::channel(1);
+    TUNNELS.lock().insert(secret.clone(), (owner, tx));
+    (Registration { secret }, rx)
+}
+
+/// Tunnel of this secret, if it was opened for the same as the request to resume it
+pub fn find(secret: &str, owner: &TunnelOwner) -> Option<mpsc::Sender<ResumeRequest>> {
+    match TUNNELS.lock().get(secret) {
+        Some((tunnel_owner, tx)) if tunnel_owner == owner => Some(tx.clone()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_tunnel_of_same_owner() {
+        let owner = TunnelOwner {
+            tunnel_id: "tunnel".to_string(),
+            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            host: "localhost".to_string(),
+            port: 22,
+            user: Some("alice".to_string()),
+        };
+        let (registration, _rx) = register(owner.clone());
+        let secret = registration.secret().to_string();
+        assert!(find(&secret, &owner).is_some());
+
+        // Another destination, user or secret cannot resume it
+        let other_port = TunnelOwner {
+            port: 23,
+            ..owner.clone()
+        };
+        let other_user = TunnelOwner {
+            user: None,
+            ..owner.clone()
+        };
+        assert!(find(&secret, &other_port).is_none());
+        assert!(find(&secret, &other_user).is_none());
+        assert!(find(&new_secret(), &owner).is_none());
+
+        drop(registration);
+        assert!(find(&secret, &owner).is_none());
+    }
+}
@@ -3,44 +3,136 @@ use anyhow::anyhow;
 use bytes::Bytes;
 use futures_util::{pin_mut, FutureExt, Stream, StreamExt};
 use http_body_util::combinators::BoxBody;
-use http_body_util::{BodyStream, Either, StreamBody};
+use http_body_util::{BodyExt, BodyStream, Either, Limited, StreamBody};
 use std::cmp::min;
 use std::fmt::Debug;
 use std::future::Future;
-use std::net::{IpAddr, SocketAddr};
+use std::iter;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::ops::{Deref, Not};
 use std::pin::Pin;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use super::{tunnel_to_jwt_token, JwtTunnelConfig, RemoteAddr, JWT_DECODE, JWT_HEADER_PREFIX};
-use crate::{socks5, tcp, tls, udp, LocalProtocol, TlsServerConfig, WsServerConfig};
+use super::{headers_from_protocol, tunnel_to_jwt_token, JwtTunnelConfig, RemoteAddr, JWT_DECODE, JWT_HEADER_PREFIX};
+use crate::{
+    handover, ktls, natpmp, socks5, speed_test, tcp, tls, udp, Destination, DestinationRewrite, LocalProtocol,
+    TlsServerConfig, WsServerConfig,
+};
 use hyper::body::{Frame, Incoming};
-use hyper::header::{CONTENT_TYPE, COOKIE, SEC_WEBSOCKET_PROTOCOL};
-use hyper::http::HeaderValue;
+use hyper::header::{AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, COOKIE, SEC_WEBSOCKET_PROTOCOL};
+use hyper::http::{HeaderName, HeaderValue};
 use hyper::server::conn::{http1, http2};
-use hyper::service::service_fn;
+use hyper::HeaderMap;
 use hyper::{http, Request, Response, StatusCode, Version};
 use hyper_util::rt::TokioExecutor;
 use jsonwebtoken::TokenData;
 use once_cell::sync::Lazy;
 use parking_lot::Mutex;
 
-use crate::socks5::Socks5Stream;
+use crate::access_log::AccessLogEntry;
+use crate::auth_hook::{AuthDecision, AuthHook, AuthRequest};
+use crate::flight_recorder::FlightRecorder;
+use crate::hooks::TunnelHook;
+use crate::htpasswd::Htpasswd;
+use crate::jwks::JwksValidator;
+use crate::pcap::TunnelCapture;
+use crate::redact::RedactedUri;
+use crate::restrictions::TunnelRequest;
+use crate::socks5::{Socks5Limits, Socks5Reply, Socks5Stream};
+use crate::statsd;
+use crate::statsd::Counter;
+use crate::totp::{Totp, TOTP_HEADER};
+use crate::tunnel::bond;
+use crate::tunnel::port_knocking::PortKnocking;
+use crate::tunnel::resume::{self, ResumeRequest, TunnelOwner, RESUME_HEADER, SESSION_HEADER};
 use crate::tunnel::tls_reloader::TlsReloader;
+use crate::tunnel::transport::close_reason::CloseReason;
 use crate::tunnel::transport::http2::{Http2TunnelRead, Http2TunnelWrite};
+use crate::tunnel::transport::io::{ResumableTunnel, TunnelStats};
+use crate::tunnel::transport::long_polling;
+use crate::tunnel::transport::memory::MemoryConnection;
 use crate::tunnel::transport::websocket::{WebsocketTunnelRead, WebsocketTunnelWrite};
+use crate::tunnel::transport::{TunnelRead, TunnelReader, TunnelWrite};
 use crate::udp::UdpStream;
+use crate::version::{
+    parse_version, peer_features, CLIENT_FEATURES_HEADER, CLIENT_VERSION_HEADER, FEATURE_BOND, FEATURE_HALF_CLOSE,
+    FEATURE_RESUME, FEATURE_RETRANSMIT, FEATURE_REUSE, SERVER_FEATURES,
+};
+use crate::webhook;
+use crate::webhook::WebhookTunnel;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::select;
 use tokio::sync::{mpsc, oneshot};
 use tokio_rustls::TlsAcceptor;
 use tokio_stream::wrappers::ReceiverStream;
-use tracing::{error, info, span, warn, Instrument, Level, Span};
+use tracing::{debug, error, info, span, warn, Instrument, Level, Span};
 use url::Host;
 use uuid::Uuid;
 
+/// Sub-path under which a reverse proxy mounts the server, and that it stripped from the forwarded request
+const X_FORWARDED_PREFIX: &str = "x-forwarded-prefix";
+
+/// Connect to the destination of the tunnel, or else to its fallbacks in order
+async fn connect_with_fallbacks<'a, T, F, Fut>(remote: &'a RemoteAddr, connect: F) -> anyhow::Result<T>
+where
+    F: Fn(&'a Host, u16) -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let mut cnx = connect(&remote.host, remote.port).await;
+    let mut dest = (&remote.host, remote.port);
+    for (host, port) in &remote.fallbacks {
+        let Err(err) = &cnx else {
+            break;
+        };
+        warn!(
+            "Cannot connect to {}:{}, trying fallback {}:{}: {:#}",
+            dest.0, dest.1, host, port, err
+        );
+        cnx = connect(host, *port).await;
+        dest = (host, *port);
+    }
+    cnx
+}
+
+/// Destination to connect to in place of the one requested by the client, per --rewrite
+fn rewrite_destination(rewrites: &[DestinationRewrite], host: Host, port: u16) -> (Host, u16) {
+    match rewrites
+        .iter()
+        .find(|rewrite| rewrite.requested.0 == host && rewrite.requested.1 == port)
+    {
+        Some(rewrite) => {
+            debug!(
+                "Rewriting destination {}:{} to {}:{}",
+                host, port, rewrite.actual.0, rewrite.actual.1
+            );
+            rewrite.actual.clone()
+        }
+        None => (host, port),
+    }
+}
+
+/// Destination of tcp and udp tunnels, once rewritten
+fn remote_addr(server_config: &WsServerConfig, jwt: JwtTunnelConfig) -> anyhow::Result<RemoteAddr> {
+    let remote = RemoteAddr::try_from(jwt)?;
+    if server_config.rewrites.is_empty() {
+        return Ok(remote);
+    }
+
+    let (host, port) = rewrite_destination(&server_config.rewrites, remote.host, remote.port);
+    Ok(RemoteAddr {
+        protocol: remote.protocol,
+        host,
+        port,
+        fallbacks: remote
+            .fallbacks
+            .into_iter()
+            .map(|(host, port)| rewrite_destination(&server_config.rewrites, host, port))
+            .collect(),
+    })
+}
+
 async fn run_tunnel(
     server_config: &WsServerConfig,
     jwt: TokenData<JwtTunnelConfig>,
@@ -48,27 +140,67 @@ async fn run_tunnel(
 ) -> anyhow::Result<(RemoteAddr, Pin<Box<dyn AsyncRead + Send>>, Pin<Box<dyn AsyncWrite + Send>>)> {
     match jwt.claims.p {
         LocalProtocol::Udp { timeout, .. } => {
-            let remote = RemoteAddr::try_from(jwt.claims)?;
-            let cnx = udp::connect(
-                &remote.host,
-                remote.port,
-                timeout.unwrap_or(Duration::from_secs(10)),
-                &server_config.dns_resolver,
-            )
+            let remote = remote_addr(server_config, jwt.claims)?;
+            let flow_permit = match &server_config.udp_flows {
+                Some(udp_flows) => Some(udp_flows.clone().try_acquire_owned().map_err(|_| {
+                    anyhow::Error::new(CloseReason::QuotaExceeded).context("too many UDP flows opened on the server")
+                })?),
+                None => None,
+            };
+            let cnx = connect_with_fallbacks(&remote, |host, port| {
+                udp::connect_in(
+                    host,
+                    port,
+                    timeout.unwrap_or(Duration::from_secs(10)),
+                    &server_config.dns_resolver,
+                    server_config.egress_netns.as_ref(),
+                )
+            })
             .await?;
+            let cnx = if server_config.udp_full_cone {
+                cnx.into_full_cone().await?
+            } else {
+                cnx
+            };
+            let cnx = if server_config.udp_pmtu_discovery {
+                cnx.enable_pmtu_discovery()?
+            } else {
+                cnx
+            };
+            let cnx = match server_config.udp_egress_timeout {
+                Some(timeout) => cnx.with_idle_timeout(timeout),
+                None => cnx,
+            };
+            let cnx = match flow_permit {
+                Some(permit) => cnx.with_flow_permit(permit),
+                None => cnx,
+            };
 
             Ok((remote, Box::pin(cnx.clone()), Box::pin(cnx)))
         }
         LocalProtocol::Tcp { proxy_protocol } => {
-            let remote = RemoteAddr::try_from(jwt.claims)?;
-            let mut socket = tcp::connect(
-                &remote.host,
-                remote.port,
-                server_config.socket_so_mark,
-                Duration::from_secs(10),
-                &server_config.dns_resolver,
-            )
-            .await?;
+            let remote = remote_addr(server_config, jwt.claims)?;
+            let pooled = server_config
+                .connection_pools
+                .iter()
+                .find(|pool| pool.is_for(&remote.host, remote.port))
+                .and_then(|pool| pool.take());
+            let mut socket = match pooled {
+                Some(socket) => socket,
+                None => {
+                    connect_with_fallbacks(&remote, |host, port| {
+                        tcp::connect_in(
+                            host,
+                            port,
+                            server_config.socket_so_mark,
+                            Duration::from_secs(10),
+                            &server_config.dns_resolver,
+                            server_config.egress_netns.as_ref(),
+                        )
+                    })
+                    .await?
+                }
+            };
 
             if proxy_protocol {
                 let header = ppp::v2::Builder::with_addresses(
@@ -91,14 +223,16 @@ async fn run_tunnel(
 
             let local_srv = (Host::parse(&jwt.claims.r)?, jwt.claims.rp);
             let bind = format!("{}:{}", local_srv.0, local_srv.1);
-            let listening_server = tcp::run_server(bind.parse()?, false);
-            let tcp = run_listening_server(&local_srv, SERVERS.deref(), listening_server).await?;
+            let listening_server = tcp::run_server(bind.parse()?, false, false);
+            let port_mapping = server_config.nat_pmp_gateway.map(|gw| (gw, natpmp::Protocol::Tcp));
+            let tcp = run_listening_server(&local_srv, SERVERS.deref(), listening_server, port_mapping).await?;
             let (local_rx, local_tx) = tcp.into_split();
 
             let remote = RemoteAddr {
                 protocol: jwt.claims.p,
                 host: local_srv.0,
                 port: local_srv.1,
+                fallbacks: vec![],
             };
             Ok((remote, Box::pin(local_rx), Box::pin(local_tx)))
         }
@@ -111,25 +245,34 @@ async fn run_tunnel(
             let bind = format!("{}:{}", local_srv.0, local_srv.1);
             let listening_server =
                 udp::run_server(bind.parse()?, timeout, |_| Ok(()), |send_socket| Ok(send_socket.clone()));
-            let udp = run_listening_server(&local_srv, SERVERS.deref(), listening_server).await?;
+            let port_mapping = server_config.nat_pmp_gateway.map(|gw| (gw, natpmp::Protocol::Udp));
+            let udp = run_listening_server(&local_srv, SERVERS.deref(), listening_server, port_mapping).await?;
             let (local_rx, local_tx) = tokio::io::split(udp);
 
             let remote = RemoteAddr {
                 protocol: jwt.claims.p,
                 host: local_srv.0,
                 port: local_srv.1,
+                fallbacks: vec![],
             };
             Ok((remote, Box::pin(local_rx), Box::pin(local_tx)))
         }
         LocalProtocol::ReverseSocks5 => {
             #[allow(clippy::type_complexity)]
-            static SERVERS: Lazy<Mutex<HashMap<(Host<String>, u16), mpsc::Receiver<(Socks5Stream, (Host, u16))>>>> =
-                Lazy::new(|| Mutex::new(HashMap::with_capacity(0)));
+            static SERVERS: Lazy<
+                Mutex<HashMap<(Host<String>, u16), mpsc::Receiver<(Socks5Stream, (Host, u16), Option<Socks5Reply>)>>>,
+            > = Lazy::new(|| Mutex::new(HashMap::with_capacity(0)));
 
             let local_srv = (Host::parse(&jwt.claims.r)?, jwt.claims.rp);
             let bind = format!("{}:{}", local_srv.0, local_srv.1);
-            let listening_server = socks5::run_server(bind.parse()?, None);
-            let (stream, local_srv) = run_listening_server(&local_srv, SERVERS.deref(), listening_server).await?;
+            let listening_server = socks5::run_server(bind.parse()?, None, Socks5Limits::default());
+            let port_mapping = server_config.nat_pmp_gateway.map(|gw| (gw, natpmp::Protocol::Tcp));
+            let (stream, local_srv, reply) =
+                run_listening_server(&local_srv, SERVERS.deref(), listening_server, port_mapping).await?;
+            // The destination is only known by the client, which closes the websocket if it cannot connect to it
+            if let Some(reply) = reply {
+                reply.send(Ok(()));
+            }
             let protocol = stream.local_protocol();
             let (local_rx, local_tx) = tokio::io::split(stream);
 
@@ -137,6 +280,7 @@ async fn run_tunnel(
                 protocol,
                 host: local_srv.0,
                 port: local_srv.1,
+                fallbacks: vec![],
             };
             Ok((remote, Box::pin(local_rx), Box::pin(local_tx)))
         }
@@ -151,13 +295,14 @@ async fn run_tunnel(
 
             let local_srv = (Host::parse(&jwt.claims.r)?, jwt.claims.rp);
             let listening_server = unix_socket::run_server(path);
-            let stream = run_listening_server(&local_srv, SERVERS.deref(), listening_server).await?;
+            let stream = run_listening_server(&local_srv, SERVERS.deref(), listening_server, None).await?;
             let (local_rx, local_tx) = stream.into_split();
 
             let remote = RemoteAddr {
                 protocol: jwt.claims.p.clone(),
                 host: local_srv.0,
                 port: local_srv.1,
+                fallbacks: vec![],
             };
             Ok((remote, Box::pin(local_rx), Box::pin(local_tx)))
         }
@@ -166,6 +311,20 @@ async fn run_tunnel(
             error!("Received an unsupported target protocol {:?}", jwt.claims);
             Err(anyhow::anyhow!("Invalid upgrade request"))
         }
+        LocalProtocol::Bench => {
+            if !server_config.bench_endpoint {
+                warn!("Rejecting speed test, the bench endpoint is not enabled with --enable-bench-endpoint");
+                return Err(anyhow!("bench endpoint is not enabled"));
+            }
+            let (local_rx, local_tx) = speed_test::bench_endpoint(&jwt.claims.r)?;
+            let remote = RemoteAddr {
+                protocol: jwt.claims.p,
+                host: Host::Domain(jwt.claims.r),
+                port: jwt.claims.rp,
+                fallbacks: vec![],
+            };
+            Ok((remote, local_rx, local_tx))
+        }
         LocalProtocol::Stdio
         | LocalProtocol::Socks5 { .. }
         | LocalProtocol::TProxyTcp
@@ -182,6 +341,7 @@ async fn run_listening_server<T, Fut, FutOut, E>(
     local_srv: &(Host, u16),
     servers: &Mutex<HashMap<(Host<String>, u16), mpsc::Receiver<T>>>,
     gen_listening_server: Fut,
+    port_mapping: Option<(Ipv4Addr, natpmp::Protocol)>,
 ) -> anyhow::Result<T>
 where
     Fut: Future<Output = anyhow::Result<FutOut>>,
@@ -195,7 +355,11 @@ where
     } else {
         let listening_server = gen_listening_server.await?;
         let (tx, rx) = mpsc::channel::<T>(1);
+        let port = local_srv.1;
         let fut = async move {
+            let port_mapping = port_mapping.map(|(gateway, protocol)| {
+                tokio::spawn(natpmp::keep_mapped(gateway, protocol, port).in_current_span())
+            });
             pin_mut!(listening_server);
             loop {
                 select! {
@@ -221,6 +385,9 @@ where
                 }
             }
             info!("Stopping listening server");
+            if let Some(port_mapping) = port_mapping {
+                port_mapping.abort();
+            }
         };
 
         tokio::spawn(fut.instrument(Span::current()));
@@ -236,7 +403,7 @@ where
 }
 
 #[inline]
-fn extract_x_forwarded_for(req: &Request<Incoming>) -> Result<Option<(IpAddr, &str)>, Response<String>> {
+fn extract_x_forwarded_for<B>(req: &Request<B>) -> Result<Option<(IpAddr, &str)>, Response<String>> {
     let Some(x_forward_for) = req.headers().get("X-Forwarded-For") else {
         return Ok(None);
     };
@@ -249,12 +416,9 @@ fn extract_x_forwarded_for(req: &Request<Incoming>) -> Result<Option<(IpAddr, &s
 }
 
 #[inline]
-fn validate_url(
-    req: &Request<Incoming>,
-    path_restriction_prefix: &Option<Vec<String>>,
-) -> Result<(), Response<String>> {
+fn validate_url<B>(req: &Request<B>, path_restriction_prefix: &Option<Vec<String>>) -> Result<(), Response<String>> {
     if !req.uri().path().ends_with("/events") {
-        warn!("Rejecting connection with bad upgrade request: {}", req.uri());
+        warn!("Rejecting connection with bad upgrade request: {}", RedactedUri(req.uri()));
         return Err(http::Response::builder()
             .status(StatusCode::BAD_REQUEST)
             .body("Invalid upgrade request".into())
@@ -263,16 +427,15 @@ fn validate_url(
 
     if let Some(paths_prefix) = &path_restriction_prefix {
         let path = req.uri().path();
-        let min_len = min(path.len(), 1);
-        let mut max_len = 0;
-        if &path[0..min_len] != "/"
-            || !paths_prefix.iter().any(|p| {
-                max_len = min(path.len(), p.len() + 1);
-                p == &path[min_len..max_len]
-            })
-            || !path[max_len..].starts_with('/')
+        // A reverse proxy mounting us under a sub-path may have stripped it, i.e: the prefix itself
+        let forwarded_path = forwarded_path(req.headers(), path);
+        if !has_path_prefix(path, paths_prefix)
+            && !forwarded_path.is_some_and(|path| has_path_prefix(&path, paths_prefix))
         {
-            warn!("Rejecting connection with bad path prefix in upgrade request: {}", req.uri());
+            warn!(
+                "Rejecting connection with bad path prefix in upgrade request: {}",
+                RedactedUri(req.uri())
+            );
             return Err(http::Response::builder()
                 .status(StatusCode::BAD_REQUEST)
                 .body("Invalid upgrade request".to_string())
@@ -283,26 +446,74 @@ fn validate_url(
     Ok(())
 }
 
+fn has_path_prefix(path: &str, paths_prefix: &[String]) -> bool {
+    let min_len = min(path.len(), 1);
+    let mut max_len = 0;
+    &path[0..min_len] == "/"
+        && paths_prefix.iter().any(|p| {
+            max_len = min(path.len(), p.len() + 1);
+            p == &path[min_len..max_len]
+        })
+        && path[max_len..].starts_with('/')
+}
+
+/// Path requested by the client, before a reverse proxy stripped the prefix given in its X-Forwarded-Prefix header
+fn forwarded_path(headers: &HeaderMap, path: &str) -> Option<String> {
+    let prefix = headers
+        .get(X_FORWARDED_PREFIX)
+        .and_then(|h| h.to_str().ok())?
+        .trim()
+        .trim_matches('/');
+    (!prefix.is_empty()).then(|| format!("/{}{}", prefix, path))
+}
+
 #[inline]
-fn extract_tunnel_info(req: &Request<Incoming>) -> Result<TokenData<JwtTunnelConfig>, Response<String>> {
+fn extract_tunnel_info<B>(
+    req: &Request<B>,
+    default_destination: Option<&Destination>,
+) -> Result<TokenData<JwtTunnelConfig>, Response<String>> {
     let jwt = req
         .headers()
         .get(SEC_WEBSOCKET_PROTOCOL)
         .and_then(|header| header.to_str().ok())
-        .and_then(|header| header.split_once(JWT_HEADER_PREFIX))
-        .map(|(_prefix, jwt)| jwt)
-        .or_else(|| req.headers().get(COOKIE).and_then(|header| header.to_str().ok()))
+        .and_then(|header| {
+            header
+                .split(',')
+                .find_map(|token| token.trim().strip_prefix(JWT_HEADER_PREFIX))
+        })
+        // The jwt is the only cookie without a name, others can be set by a CDN or load balancer in front of the server
+        .or_else(|| {
+            req.headers()
+                .get_all(COOKIE)
+                .iter()
+                .filter_map(|header| header.to_str().ok())
+                .flat_map(|header| header.split(';'))
+                .map(str::trim)
+                .find(|cookie| !cookie.contains('='))
+        })
         .unwrap_or_default();
 
+    // A bare upgrade, without tunnel info, is a tcp tunnel toward the default destination
+    if let (true, Some((host, port))) = (jwt.is_empty(), default_destination) {
+        debug!("No tunnel info in upgrade request, using default destination {}:{}", host, port);
+        return Ok(TokenData {
+            header: jsonwebtoken::Header::default(),
+            claims: JwtTunnelConfig {
+                id: Uuid::now_v7().to_string(),
+                p: LocalProtocol::Tcp { proxy_protocol: false },
+                r: host.to_string(),
+                rp: *port,
+                fb: vec![],
+            },
+        });
+    }
+
     let (validation, decode_key) = JWT_DECODE.deref();
     let jwt = match jsonwebtoken::decode(jwt, decode_key, validation) {
         Ok(jwt) => jwt,
         err => {
-            warn!(
-                "error while decoding jwt for tunnel info {:?} header {:?}",
-                err,
-                req.headers().get(SEC_WEBSOCKET_PROTOCOL)
-            );
+            // Not the header, it can carry the credentials of the client as protocol tokens
+            warn!("error while decoding jwt for tunnel info {:?}", err);
             return Err(http::Response::builder()
                 .status(StatusCode::BAD_REQUEST)
                 .body("Invalid upgrade request".to_string())
@@ -313,85 +524,401 @@ fn extract_tunnel_info(req: &Request<Incoming>) -> Result<TokenData<JwtTunnelCon
     Ok(jwt)
 }
 
+/// Replace the destination requested by the client with the one of --force-destination.
+/// Only tcp and udp tunnels are allowed, others would not connect to it
+fn force_destination(
+    jwt: &mut TokenData<JwtTunnelConfig>,
+    forced_destination: &Option<Destination>,
+) -> Result<(), Response<String>> {
+    let Some((host, port)) = forced_destination else {
+        return Ok(());
+    };
+
+    if !matches!(jwt.claims.p, LocalProtocol::Tcp { .. } | LocalProtocol::Udp { .. }) {
+        warn!(
+            "Rejecting {} tunnel, only tcp and udp tunnels are allowed with --force-destination",
+            jwt.claims.p.name()
+        );
+        return Err(CloseReason::Restricted.rejection(format!("{} tunnels are not allowed", jwt.claims.p.name())));
+    }
+
+    let host = host.to_string();
+    if jwt.claims.r != host || jwt.claims.rp != *port {
+        debug!(
+            "Ignoring requested destination {}:{}, forced to {}:{}",
+            jwt.claims.r, jwt.claims.rp, host, port
+        );
+    }
+    jwt.claims.r = host;
+    jwt.claims.rp = *port;
+    jwt.claims.fb.clear();
+    Ok(())
+}
+
+/// Restriction is DEST:PORT, or prefixed by tcp: or udp: to only allow this protocol toward the destination
+fn is_allowed_destination(allowed_dest: &str, protocol: &LocalProtocol, requested_dest: &str) -> bool {
+    let qualified = |prefix: &str| allowed_dest.strip_prefix(prefix).filter(|dest| dest.contains(':'));
+    match (qualified("tcp:"), qualified("udp:")) {
+        (Some(dest), _) => !protocol.is_datagram() && dest == requested_dest,
+        (_, Some(dest)) => protocol.is_datagram() && dest == requested_dest,
+        (None, None) => allowed_dest == requested_dest,
+    }
+}
+
 #[inline]
-fn validate_destination(
-    _req: &Request<Incoming>,
+fn validate_destination<B>(
+    req: &Request<B>,
     jwt: &TokenData<JwtTunnelConfig>,
-    destination_restriction: &Option<Vec<String>>,
+    server_config: &WsServerConfig,
+    user: Option<&str>,
 ) -> Result<(), Response<String>> {
-    let Some(allowed_dests) = &destination_restriction else {
+    // Fallbacks must be allowed too, as the client can make them the destination by making the first one fail
+    let requested_dests: Vec<String> = iter::once(format!("{}:{}", jwt.claims.r, jwt.claims.rp))
+        .chain(jwt.claims.fb.iter().cloned())
+        .collect();
+
+    if let Some(restrictions) = &server_config.restrictions {
+        let forwarded_path = forwarded_path(req.headers(), req.uri().path());
+        let tunnel = TunnelRequest {
+            protocol: &jwt.claims.p,
+            destinations: &requested_dests,
+            user,
+            path: req.uri().path(),
+            forwarded_path: forwarded_path.as_deref(),
+        };
+        let Some(rule) = restrictions.find(&tunnel) else {
+            warn!(
+                "Rejecting connection not allowed by any restriction rule: {} {}",
+                jwt.claims.p.name(),
+                requested_dests.join(",")
+            );
+            return Err(CloseReason::Restricted.rejection(requested_dests.join(",")));
+        };
+        Span::current().record("rule", &rule.name);
+        rule.log_admitted(&tunnel);
+        return Ok(());
+    }
+
+    let Some(allowed_dests) = &server_config.restrict_to else {
         return Ok(());
     };
+    for requested_dest in requested_dests {
+        if allowed_dests
+            .iter()
+            .any(|dest| is_allowed_destination(dest, &jwt.claims.p, &requested_dest))
+            .not()
+        {
+            warn!(
+                "Rejecting connection with not allowed destination: {} {}",
+                if jwt.claims.p.is_datagram() { "udp" } else { "tcp" },
+                requested_dest
+            );
+            return Err(CloseReason::Restricted.rejection(requested_dest));
+        }
+    }
 
-    let requested_dest = format!("{}:{}", jwt.claims.r, jwt.claims.rp);
-    if allowed_dests.iter().any(|dest| dest == &requested_dest).not() {
-        warn!("Rejecting connection with not allowed destination: {}", requested_dest);
-        return Err(http::Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body("Invalid upgrade request".to_string())
-            .unwrap());
+    Ok(())
+}
+
+#[inline]
+fn validate_totp<B>(
+    req: &Request<B>,
+    jwt: &TokenData<JwtTunnelConfig>,
+    auth_totp: &Option<Totp>,
+) -> Result<(), Response<String>> {
+    let Some(totp) = auth_totp else {
+        return Ok(());
+    };
+
+    let proof = req
+        .headers()
+        .get(&TOTP_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or_default();
+    if let Err(err) = totp.verify_once_now(proof, &jwt.claims.id) {
+        warn!("Rejecting connection with {}", err);
+        return Err(CloseReason::Unauthorized.rejection(err));
+    }
+
+    Ok(())
+}
+
+fn validate_client_version<B>(req: &Request<B>, server_config: &WsServerConfig) -> Result<(), Response<String>> {
+    if let Some(min_version) = server_config.min_client_version {
+        let version = req
+            .headers()
+            .get(&CLIENT_VERSION_HEADER)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| parse_version(h).ok());
+
+        if !matches!(version, Some(v) if v >= min_version) {
+            let version = version.map_or_else(|| "unknown".to_string(), |v| v.to_string());
+            warn!(
+                "Rejecting connection from client with version {} below {}",
+                version, min_version
+            );
+            return Err(http::Response::builder()
+                .status(StatusCode::UPGRADE_REQUIRED)
+                .body(format!(
+                    "Client version {} is too old, please upgrade wstunnel to at least {}",
+                    version, min_version
+                ))
+                .unwrap());
+        }
+    }
+
+    if !server_config.required_client_features.is_empty() {
+        let features = peer_features(req.headers());
+
+        if let Some(missing) = server_config
+            .required_client_features
+            .iter()
+            .find(|f| !features.contains(&f.as_str()))
+        {
+            warn!("Rejecting connection from client not supporting feature {}", missing);
+            return Err(http::Response::builder()
+                .status(StatusCode::UPGRADE_REQUIRED)
+                .body(format!(
+                    "Client does not support required feature {}, please upgrade wstunnel",
+                    missing
+                ))
+                .unwrap());
+        }
     }
 
     Ok(())
 }
 
+async fn validate_jwks<B>(req: &Request<B>, auth_jwks: &Option<JwksValidator>) -> Result<(), Response<String>> {
+    let Some(jwks) = auth_jwks else {
+        return Ok(());
+    };
+
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(|token| token.trim())
+        .unwrap_or_default();
+
+    match jwks.validate(token).await {
+        Ok(token) => {
+            info!("Bearer token accepted for subject {:?}", token.claims.sub);
+            Ok(())
+        }
+        Err(err) => {
+            warn!("Rejecting connection with invalid bearer token: {:?}", err);
+            Err(CloseReason::Unauthorized.rejection("invalid bearer token"))
+        }
+    }
+}
+
+/// Return the authenticated user, if basic auth is required
+async fn validate_htpasswd<B>(
+    req: &Request<B>,
+    auth_htpasswd: &Option<Htpasswd>,
+) -> Result<Option<String>, Response<String>> {
+    let Some(htpasswd) = auth_htpasswd else {
+        return Ok(None);
+    };
+
+    let authorization = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    // Password hashes are slow to compute by design, so do not block the runtime with it
+    let htpasswd = htpasswd.clone();
+    match tokio::task::spawn_blocking(move || htpasswd.verify(&authorization)).await {
+        Ok(Some(user)) => {
+            info!("Basic auth accepted for user {}", user);
+            Ok(Some(user))
+        }
+        _ => {
+            warn!("Rejecting connection with invalid basic auth credentials");
+            Err(CloseReason::Unauthorized.rejection("invalid credentials"))
+        }
+    }
+}
+
+/// Let the hook of --auth-hook-url allow, deny or route the tunnel
+async fn validate_auth_hook<B>(
+    req: &Request<B>,
+    jwt: &mut TokenData<JwtTunnelConfig>,
+    client_addr: SocketAddr,
+    user: Option<&str>,
+    server_config: &WsServerConfig,
+) -> Result<(), Response<String>> {
+    let Some(auth_hook) = &server_config.auth_hook else {
+        return Ok(());
+    };
+
+    let request = AuthRequest {
+        headers: req.headers(),
+        path: req.uri().path(),
+        peer_addr: client_addr,
+        protocol: &jwt.claims.p,
+        host: &jwt.claims.r,
+        port: jwt.claims.rp,
+        user,
+    };
+    match auth_hook.authorize(request).await {
+        AuthDecision::Allow => Ok(()),
+        AuthDecision::Deny(message) => {
+            warn!("Rejecting connection denied by auth hook: {}", message);
+            Err(CloseReason::Unauthorized.rejection(message))
+        }
+        AuthDecision::Route((host, port)) => {
+            debug!(
+                "Auth hook routed requested destination {}:{} to {}:{}",
+                jwt.claims.r, jwt.claims.rp, host, port
+            );
+            jwt.claims.r = host.to_string();
+            jwt.claims.rp = port;
+            jwt.claims.fb.clear();
+            // The hook cannot route the tunnel around --force-destination
+            force_destination(jwt, &server_config.force_destination)?;
+            Span::current().record("remote", format!("{}:{}", jwt.claims.r, jwt.claims.rp));
+            Ok(())
+        }
+    }
+}
+
+/// Checks of the request of a tunnel shared by all the transports: forwarded ip, path, destination and authentication.
+/// Returns the tunnel asked by the client, with its user when authenticated with --auth-htpasswd
+async fn validate_tunnel_request<B>(
+    server_config: &WsServerConfig,
+    client_addr: &mut SocketAddr,
+    req: &Request<B>,
+) -> Result<(TokenData<JwtTunnelConfig>, Option<String>), Response<String>> {
+    if let Some((x_forward_for, x_forward_for_str)) = extract_x_forwarded_for(req)? {
+        info!("Request X-Forwarded-For: {:?}", x_forward_for);
+        Span::current().record("forwarded_for", x_forward_for_str);
+        client_addr.set_ip(x_forward_for);
+    }
+
+    validate_url(req, &server_config.restrict_http_upgrade_path_prefix)?;
+
+    let default_destination = server_config
+        .force_destination
+        .as_ref()
+        .or(server_config.default_destination.as_ref());
+    let mut jwt = extract_tunnel_info(req, default_destination)?;
+    force_destination(&mut jwt, &server_config.force_destination)?;
+
+    Span::current().record("id", &jwt.claims.id);
+    Span::current().record("remote", format!("{}:{}", jwt.claims.r, jwt.claims.rp));
+
+    validate_totp(req, &jwt, &server_config.auth_totp)?;
+    validate_jwks(req, &server_config.auth_jwks).await?;
+    validate_client_version(req, server_config)?;
+    let user = validate_htpasswd(req, &server_config.auth_htpasswd).await?;
+    validate_auth_hook(req, &mut jwt, *client_addr, user.as_deref(), server_config).await?;
+    validate_destination(req, &jwt, server_config, user.as_deref())?;
+
+    Ok((jwt, user))
+}
+
 async fn ws_server_upgrade(
     server_config: Arc<WsServerConfig>,
     mut client_addr: SocketAddr,
     mut req: Request<Incoming>,
 ) -> Response<String> {
     if !fastwebsockets::upgrade::is_upgrade_request(&req) {
-        warn!("Rejecting connection with bad upgrade request: {}", req.uri());
+        warn!("Rejecting connection with bad upgrade request: {}", RedactedUri(req.uri()));
         return http::Response::builder()
             .status(StatusCode::BAD_REQUEST)
             .body("Invalid upgrade request".to_string())
             .unwrap();
     }
+    headers_from_protocol(req.headers_mut());
 
-    match extract_x_forwarded_for(&req) {
-        Ok(Some((x_forward_for, x_forward_for_str))) => {
-            info!("Request X-Forwarded-For: {:?}", x_forward_for);
-            Span::current().record("forwarded_for", x_forward_for_str);
-            client_addr.set_ip(x_forward_for);
-        }
-        Ok(_) => {}
+    let (jwt, user) = match validate_tunnel_request(&server_config, &mut client_addr, &req).await {
+        Ok(ret) => ret,
         Err(err) => return err,
     };
 
-    if let Err(err) = validate_url(&req, &server_config.restrict_http_upgrade_path_prefix) {
-        return err;
+    let owner = tunnel_owner(&jwt, user.as_deref());
+    if req.headers().contains_key(&RESUME_HEADER) {
+        return resume_tunnel(&server_config, &owner, req).await;
     }
-
-    let jwt = match extract_tunnel_info(&req) {
-        Ok(jwt) => jwt,
-        Err(err) => return err,
-    };
-
-    Span::current().record("id", &jwt.claims.id);
-    Span::current().record("remote", format!("{}:{}", jwt.claims.r, jwt.claims.rp));
-
-    if let Err(err) = validate_destination(&req, &jwt, &server_config.restrict_to) {
-        return err;
+    if bond::parse_header(req.headers()).is_some_and(|(index, _)| index > 0) {
+        return join_bond(&server_config, &owner, req).await;
     }
 
+    let access_log = AccessLogEntry::new(
+        client_addr.ip(),
+        &req,
+        format_args!("{}:{}", jwt.claims.r, jwt.claims.rp),
+        StatusCode::SWITCHING_PROTOCOLS.as_u16(),
+    );
     let req_protocol = jwt.claims.p.clone();
+    // Only fixed tcp destinations can be connected to again, for the next connections of the client
+    let features = peer_features(req.headers());
+    let reuse_jwt = (features.contains(&FEATURE_REUSE)
+        && features.contains(&FEATURE_HALF_CLOSE)
+        && matches!(req_protocol, LocalProtocol::Tcp { .. }))
+    .then(|| jwt.clone());
+    // Each connection over a reused websocket has its own line in the access log
+    let reuse_access_log = access_log.clone().filter(|_| reuse_jwt.is_some());
+    let reports = tunnel_reports(&jwt.claims.id, &jwt.claims, client_addr.ip(), access_log);
+    // The first websocket of a bonded tunnel, the others join it with the secret of the tunnel in the response
+    let bond = bond::parse_header(req.headers())
+        .filter(|_| {
+            reuse_jwt.is_none() && features.contains(&FEATURE_BOND) && matches!(req_protocol, LocalProtocol::Tcp { .. })
+        })
+        .map(|(_, count)| count);
+    // The connection to the destination outlives the websocket, for the client to resume the tunnel after a network change
+    let resumable = server_config.resume_timeout.is_some()
+        && reuse_jwt.is_none()
+        && bond.is_none()
+        && features.contains(&FEATURE_RESUME)
+        && features.contains(&FEATURE_HALF_CLOSE)
+        && matches!(req_protocol, LocalProtocol::Tcp { .. });
+    let retransmit_buffer = server_config
+        .resume_buffer
+        .filter(|_| features.contains(&FEATURE_RETRANSMIT));
     let tunnel = match run_tunnel(&server_config, jwt, client_addr).await {
         Ok(ret) => ret,
         Err(err) => {
-            warn!("Rejecting connection with bad upgrade request: {} {}", err, req.uri());
-            return http::Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .body("Invalid upgrade request".to_string())
-                .unwrap();
+            warn!(
+                "Rejecting connection with bad upgrade request: {} {}",
+                err,
+                RedactedUri(req.uri())
+            );
+            return CloseReason::from_error(&err).rejection(format!("{:#}", err));
         }
     };
 
     let (remote_addr, local_rx, local_tx) = tunnel;
     info!("connected to {:?} {}:{}", req_protocol, remote_addr.host, remote_addr.port);
+    let write_batching = server_config
+        .write_batching
+        .filter(|_| !remote_addr.protocol.is_datagram());
+    let half_close = peer_features(req.headers()).contains(&FEATURE_HALF_CLOSE);
+    // Registered before answering with their secret, for the other websockets of the bond to find the tunnel
+    let bond_members = bond.map(|count| (count, bond::register(owner.clone(), count)));
+    let resume_registration = resumable.then(|| resume::register(owner));
+    let secret = bond_members
+        .as_ref()
+        .map(|(_, (registration, _))| registration.secret())
+        .or_else(|| {
+            resume_registration
+                .as_ref()
+                .map(|(registration, _)| registration.secret())
+        })
+        .map(|secret| HeaderValue::from_str(secret).expect("tunnel secret is a valid header value"));
     let (mut response, fut) = match fastwebsockets::upgrade::upgrade(&mut req) {
         Ok(ret) => ret,
         Err(err) => {
-            warn!("Rejecting connection with bad upgrade request: {} {}", err, req.uri());
+            warn!(
+                "Rejecting connection with bad upgrade request: {} {}",
+                err,
+                RedactedUri(req.uri())
+            );
             return http::Response::builder()
                 .status(StatusCode::BAD_REQUEST)
                 .body(format!("Invalid upgrade request: {:?}", err))
@@ -399,6 +926,7 @@ async fn ws_server_upgrade(
         }
     };
 
+    let response_features = server_features(&server_config);
     tokio::spawn(
         async move {
             let (ws_rx, mut ws_tx) = match fut.await {
@@ -408,21 +936,33 @@ async fn ws_server_upgrade(
                     return;
                 }
             };
-            let (close_tx, close_rx) = oneshot::channel::<()>();
             ws_tx.set_auto_apply_mask(server_config.websocket_mask_frame);
 
-            tokio::task::spawn(
-                super::transport::io::propagate_remote_to_local(local_tx, WebsocketTunnelRead::new(ws_rx), close_rx)
-                    .instrument(Span::current()),
-            );
+            let stats = reports();
 
-            let _ = super::transport::io::propagate_local_to_remote(
-                local_rx,
-                WebsocketTunnelWrite::new(ws_tx),
-                close_tx,
-                None,
-            )
-            .await;
+            let ws_tx = WebsocketTunnelWrite::new(ws_tx, half_close);
+            let ws_rx = WebsocketTunnelRead::new(ws_rx, &ws_tx);
+            if let Some(jwt) = reuse_jwt {
+                let local = (local_rx, local_tx);
+                serve_reused_websocket(server_config, jwt, client_addr, (ws_rx, ws_tx), local, stats, reuse_access_log)
+                    .await;
+                return;
+            }
+            if let (Some((_registration, resume_rx)), Some(timeout)) =
+                (resume_registration, server_config.resume_timeout)
+            {
+                let tunnel = ResumableTunnel::new(local_rx, local_tx, stats).with_retransmit_buffer(retransmit_buffer);
+                serve_resumable_tunnel(timeout, (ws_rx, ws_tx), resume_rx, tunnel).await;
+                return;
+            }
+            if let Some((count, (_registration, members_rx))) = bond_members {
+                let timeout = server_config.timeout_connect;
+                serve_bonded_tunnel(timeout, count, (ws_rx, ws_tx), members_rx, local_rx, local_tx, stats).await;
+                return;
+            }
+
+            super::transport::io::relay_tunnel(local_rx, local_tx, ws_rx, ws_tx, None, stats, None, write_batching)
+                .await;
         }
         .instrument(Span::current()),
     );
@@ -437,82 +977,429 @@ async fn ws_server_upgrade(
         };
         response.headers_mut().insert(COOKIE, header_val);
     }
-    response
-        .headers_mut()
-        .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("v1"));
+    // Plain websocket clients of --default-destination fail the handshake on a sub protocol they did not ask for
+    if req.headers().contains_key(SEC_WEBSOCKET_PROTOCOL) {
+        response
+            .headers_mut()
+            .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("v1"));
+    }
+    if let Ok(features) = HeaderValue::from_str(&response_features) {
+        response.headers_mut().insert(&CLIENT_FEATURES_HEADER, features);
+    }
+    if let Some(secret) = secret {
+        response.headers_mut().insert(&SESSION_HEADER, secret);
+    }
 
     Response::from_parts(response.into_parts().0, "".to_string())
 }
 
-async fn http_server_upgrade(
-    server_config: Arc<WsServerConfig>,
-    mut client_addr: SocketAddr,
-    mut req: Request<Incoming>,
-) -> Response<Either<String, BoxBody<Bytes, anyhow::Error>>> {
-    match extract_x_forwarded_for(&req) {
-        Ok(Some((x_forward_for, x_forward_for_str))) => {
-            info!("Request X-Forwarded-For: {:?}", x_forward_for);
-            Span::current().record("forwarded_for", x_forward_for_str);
-            client_addr.set_ip(x_forward_for);
-        }
-        Ok(_) => {}
-        Err(err) => return err.map(Either::Left),
-    };
+fn tunnel_owner(jwt: &TokenData<JwtTunnelConfig>, user: Option<&str>) -> TunnelOwner {
+    TunnelOwner {
+        tunnel_id: jwt.claims.id.clone(),
+        protocol: jwt.claims.p.clone(),
+        host: jwt.claims.r.clone(),
+        port: jwt.claims.rp,
+        user: user.map(str::to_string),
+    }
+}
 
-    if let Err(err) = validate_url(&req, &server_config.restrict_http_upgrade_path_prefix) {
-        return err.map(Either::Left);
+fn server_features(server_config: &WsServerConfig) -> String {
+    match (server_config.resume_timeout, server_config.resume_buffer) {
+        (Some(_), Some(_)) => format!("{},{},{}", SERVER_FEATURES.join(","), FEATURE_RESUME, FEATURE_RETRANSMIT),
+        (Some(_), None) => format!("{},{}", SERVER_FEATURES.join(","), FEATURE_RESUME),
+        (None, _) => SERVER_FEATURES.join(","),
     }
+}
 
-    let jwt = match extract_tunnel_info(&req) {
-        Ok(jwt) => jwt,
-        Err(err) => return err.map(Either::Left),
-    };
+/// Relay a tunnel whose connection to the destination outlives its websocket. When the websocket breaks,
+/// wait up to `timeout` for the client to resume the tunnel over a new one
+async fn serve_resumable_tunnel(
+    timeout: Duration,
+    websocket: resume::Websocket,
+    mut resume_rx: mpsc::Receiver<ResumeRequest>,
+    mut tunnel: ResumableTunnel,
+) {
+    let mut websocket = websocket;
+    loop {
+        let (ws_rx, ws_tx) = websocket;
+        let mut request = select! {
+            broken = tunnel.relay(ws_rx, ws_tx, None) => {
+                if !broken {
+                    return;
+                }
+                info!("Websocket of the tunnel broke, waiting up to {:?} for the client to resume it", timeout);
+                None
+            }
+            // The client noticed first that the websocket is broken
+            request = resume_rx.recv() => request,
+        };
 
-    Span::current().record("id", &jwt.claims.id);
-    Span::current().record("remote", format!("{}:{}", jwt.claims.r, jwt.claims.rp));
+        let deadline = Instant::now() + timeout;
+        websocket = loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let request = match request.take() {
+                Some(request) => request,
+                None => match tokio::time::timeout(remaining, resume_rx.recv()).await {
+                    Ok(Some(request)) => request,
+                    _ => {
+                        info!("Tunnel not resumed after {:?}, closing it", timeout);
+                        return;
+                    }
+                },
+            };
 
-    if let Err(err) = validate_destination(&req, &jwt, &server_config.restrict_to) {
-        return err.map(Either::Left);
+            // Without retransmission, data lost with the broken websocket cannot be sent again
+            if !tunnel.resume_from(request.client_received) {
+                let err = format!(
+                    "{} bytes sent to the client, but it received {}",
+                    tunnel.sent(),
+                    request.client_received
+                );
+                warn!("Closing tunnel that cannot be resumed: {}", err);
+                let _ = request.reply.send(Err(err));
+                return;
+            }
+            let _ = request.reply.send(Ok(tunnel.received()));
+            match tokio::time::timeout(remaining, request.websocket).await {
+                Ok(Ok(websocket)) => break websocket,
+                _ => debug!("Upgrade of the websocket to resume the tunnel failed"),
+            }
+        };
+        info!("Tunnel resumed over a new websocket");
     }
+}
 
-    let req_protocol = jwt.claims.p.clone();
-    let tunnel = match run_tunnel(&server_config, jwt, client_addr).await {
-        Ok(ret) => ret,
-        Err(err) => {
-            warn!("Rejecting connection with bad upgrade request: {} {}", err, req.uri());
-            return http::Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .body(Either::Left("Invalid upgrade request".to_string()))
-                .unwrap();
+/// Wait up to `timeout` for the other websockets of a bonded tunnel, then relay it striped across all of them
+async fn serve_bonded_tunnel(
+    timeout: Duration,
+    count: u8,
+    websocket: bond::Websocket,
+    mut members_rx: mpsc::Receiver<bond::Websocket>,
+    local_rx: impl AsyncRead + Send + Unpin,
+    local_tx: impl AsyncWrite + Send + Unpin,
+    stats: TunnelStats,
+) {
+    let mut members = vec![websocket];
+    let deadline = Instant::now() + timeout;
+    while members.len() < count as usize {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match tokio::time::timeout(remaining, members_rx.recv()).await {
+            Ok(Some(websocket)) => members.push(websocket),
+            _ => {
+                warn!(
+                    "Only {} of the {} websockets of the bond joined after {:?}, closing tunnel",
+                    members.len(),
+                    count,
+                    timeout
+                );
+                return;
+            }
         }
-    };
-
-    let (remote_addr, local_rx, local_tx) = tunnel;
-    info!("connected to {:?} {}:{}", req_protocol, remote_addr.host, remote_addr.port);
+    }
+    drop(members_rx);
 
-    let req_content_type = req.headers_mut().remove(CONTENT_TYPE);
-    let ws_rx = BodyStream::new(req.into_body());
-    let (ws_tx, rx) = mpsc::channel::<Bytes>(1024);
-    let body = BoxBody::new(StreamBody::new(
-        ReceiverStream::new(rx).map(|s| -> anyhow::Result<Frame<Bytes>> { Ok(Frame::data(s)) }),
-    ));
+    debug!("Relaying tunnel bonded across {} websockets", count);
+    if let Err(err) = bond::relay(local_rx, local_tx, members, None, &stats).await {
+        warn!("Bonded tunnel closed: {:#}", err);
+    }
+}
 
-    let mut response = Response::builder()
-        .status(StatusCode::OK)
-        .body(Either::Right(body))
-        .expect("bug: failed to build response");
+/// Add a websocket to the bond of a tunnel the client opened with its first one
+async fn join_bond(
+    server_config: &WsServerConfig,
+    owner: &TunnelOwner,
+    mut req: Request<Incoming>,
+) -> Response<String> {
+    let refuse = |status: StatusCode, err: &str| {
+        warn!("Rejecting websocket joining a bond: {}", err);
+        http::Response::builder()
+            .status(status)
+            .body(format!("Cannot join bond: {}", err))
+            .unwrap()
+    };
+    let Some(session) = req.headers().get(&SESSION_HEADER).and_then(|h| h.to_str().ok()) else {
+        return refuse(StatusCode::BAD_REQUEST, "no session");
+    };
+    let Some(bond) = bond::find(session, owner) else {
+        return refuse(StatusCode::GONE, "no such tunnel");
+    };
 
+    let (mut response, fut) = match fastwebsockets::upgrade::upgrade(&mut req) {
+        Ok(ret) => ret,
+        Err(err) => return refuse(StatusCode::BAD_REQUEST, &format!("invalid upgrade request: {:?}", err)),
+    };
+    let mask_frame = server_config.websocket_mask_frame;
     tokio::spawn(
         async move {
-            let (close_tx, close_rx) = oneshot::channel::<()>();
-            tokio::task::spawn(
-                super::transport::io::propagate_remote_to_local(local_tx, Http2TunnelRead::new(ws_rx), close_rx)
-                    .instrument(Span::current()),
-            );
+            let (ws_rx, mut ws_tx) = match fut.await {
+                Ok(ws) => ws.split(tokio::io::split),
+                Err(err) => {
+                    error!("Error during http upgrade request: {:?}", err);
+                    return;
+                }
+            };
+            ws_tx.set_auto_apply_mask(mask_frame);
+            let ws_tx = WebsocketTunnelWrite::new(ws_tx, false);
+            let ws_rx = WebsocketTunnelRead::new(ws_rx, &ws_tx);
+            let _ = bond.send((ws_rx, ws_tx)).await;
+        }
+        .instrument(Span::current()),
+    );
 
-            let _ =
-                super::transport::io::propagate_local_to_remote(local_rx, Http2TunnelWrite::new(ws_tx), close_tx, None)
-                    .await;
+    let headers = response.headers_mut();
+    headers.insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("v1"));
+    if let Ok(features) = HeaderValue::from_str(&server_features(server_config)) {
+        headers.insert(&CLIENT_FEATURES_HEADER, features);
+    }
+
+    Response::from_parts(response.into_parts().0, "".to_string())
+}
+
+/// Continue a tunnel over a new websocket of its client, i.e: after a network change of the client
+async fn resume_tunnel(
+    server_config: &WsServerConfig,
+    owner: &TunnelOwner,
+    mut req: Request<Incoming>,
+) -> Response<String> {
+    let refuse = |status: StatusCode, err: &str| {
+        warn!("Rejecting request to resume tunnel: {}", err);
+        http::Response::builder()
+            .status(status)
+            .body(format!("Cannot resume tunnel: {}", err))
+            .unwrap()
+    };
+    let client_received = req
+        .headers()
+        .get(&RESUME_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.parse::<u64>().ok());
+    let session = req.headers().get(&SESSION_HEADER).and_then(|h| h.to_str().ok());
+    let (Some(client_received), Some(session)) = (client_received, session) else {
+        return refuse(StatusCode::BAD_REQUEST, "invalid resume request");
+    };
+    let Some(tunnel) = resume::find(session, owner) else {
+        return refuse(StatusCode::GONE, "no such tunnel");
+    };
+
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let (websocket_tx, websocket_rx) = oneshot::channel();
+    let request = ResumeRequest {
+        client_received,
+        reply: reply_tx,
+        websocket: websocket_rx,
+    };
+    if tunnel.send(request).await.is_err() {
+        return refuse(StatusCode::GONE, "no such tunnel");
+    }
+    let server_received = match reply_rx.await {
+        Ok(Ok(received)) => received,
+        Ok(Err(err)) => return refuse(StatusCode::CONFLICT, &err),
+        Err(_) => return refuse(StatusCode::GONE, "no such tunnel"),
+    };
+
+    let (mut response, fut) = match fastwebsockets::upgrade::upgrade(&mut req) {
+        Ok(ret) => ret,
+        Err(err) => return refuse(StatusCode::BAD_REQUEST, &format!("invalid upgrade request: {:?}", err)),
+    };
+    let mask_frame = server_config.websocket_mask_frame;
+    tokio::spawn(
+        async move {
+            let (ws_rx, mut ws_tx) = match fut.await {
+                Ok(ws) => ws.split(tokio::io::split),
+                Err(err) => {
+                    error!("Error during http upgrade request: {:?}", err);
+                    return;
+                }
+            };
+            ws_tx.set_auto_apply_mask(mask_frame);
+            let ws_tx = WebsocketTunnelWrite::new(ws_tx, true);
+            let ws_rx = WebsocketTunnelRead::new(ws_rx, &ws_tx);
+            let _ = websocket_tx.send((ws_rx, ws_tx));
+        }
+        .instrument(Span::current()),
+    );
+
+    let headers = response.headers_mut();
+    headers.insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("v1"));
+    if let Ok(features) = HeaderValue::from_str(&server_features(server_config)) {
+        headers.insert(&CLIENT_FEATURES_HEADER, features);
+    }
+    headers.insert(&RESUME_HEADER, HeaderValue::from(server_received));
+
+    Response::from_parts(response.into_parts().0, "".to_string())
+}
+
+/// Everything reported about a tunnel of the client, i.e: access log, webhook, hooks, flight recorder and capture.
+/// The stats report on drop, so they are only created once the tunnel is relayed
+fn tunnel_reports(
+    id: &str,
+    claims: &JwtTunnelConfig,
+    client_ip: IpAddr,
+    access_log: Option<AccessLogEntry>,
+) -> impl FnOnce() -> TunnelStats + Send + 'static {
+    let remote = format!("{}:{}", claims.r, claims.rp);
+    let webhook = WebhookTunnel::new(
+        id,
+        client_ip,
+        serde_json::to_value(&claims.p).unwrap_or_default(),
+        remote.clone(),
+    );
+    let recorder = FlightRecorder::new(id);
+    let hook = TunnelHook::new(id, claims.p.name(), remote, Some(client_ip));
+    let capture = TunnelCapture::new(id, claims.rp, claims.p.is_datagram(), !claims.p.is_reverse_tunnel());
+    move || {
+        TunnelStats::new(Span::current())
+            .with_access_log(access_log)
+            .with_webhook(webhook)
+            .with_hook(hook)
+            .with_flight_recorder(recorder)
+            .with_capture(capture)
+    }
+}
+
+/// Relay the connections of the client one after the other over the same websocket,
+/// connecting again to the destination each time the client starts a new one.
+/// Each connection is reported as its own tunnel, with a new id
+async fn serve_reused_websocket(
+    server_config: Arc<WsServerConfig>,
+    jwt: TokenData<JwtTunnelConfig>,
+    client_addr: SocketAddr,
+    websocket: (WebsocketTunnelRead, WebsocketTunnelWrite),
+    local: (Pin<Box<dyn AsyncRead + Send>>, Pin<Box<dyn AsyncWrite + Send>>),
+    mut stats: TunnelStats,
+    access_log: Option<AccessLogEntry>,
+) {
+    let (mut ws_rx, mut ws_tx) = websocket;
+    let (mut local_rx, mut local_tx) = local;
+    loop {
+        (ws_rx, ws_tx) = match super::transport::io::relay_reusable(local_rx, local_tx, ws_rx, ws_tx, stats).await {
+            Ok(websocket) => websocket,
+            Err(err) => {
+                debug!("Closing reused websocket: {:?}", err);
+                return;
+            }
+        };
+
+        if let Err(err) = ws_rx.wait_next_connection().await {
+            debug!("Closing reused websocket: {:?}", err);
+            return;
+        }
+
+        (_, local_rx, local_tx) = match run_tunnel(&server_config, jwt.clone(), client_addr).await {
+            Ok(tunnel) => tunnel,
+            Err(err) => {
+                warn!(
+                    "Cannot connect to {}:{} for the next connection: {:?}",
+                    jwt.claims.r, jwt.claims.rp, err
+                );
+                let _ = ws_tx.close(CloseReason::from_error(&err)).await;
+                return;
+            }
+        };
+        let id = Uuid::now_v7().to_string();
+        info!(
+            "Reusing websocket for a new connection {} to {}:{}",
+            id, jwt.claims.r, jwt.claims.rp
+        );
+        let access_log = access_log.as_ref().map(AccessLogEntry::restarted);
+        stats = tunnel_reports(&id, &jwt.claims, client_addr.ip(), access_log)();
+    }
+}
+
+async fn http_server_upgrade(
+    server_config: Arc<WsServerConfig>,
+    mut client_addr: SocketAddr,
+    mut req: Request<Incoming>,
+) -> Response<Either<String, BoxBody<Bytes, anyhow::Error>>> {
+    let (jwt, _user) = match validate_tunnel_request(&server_config, &mut client_addr, &req).await {
+        Ok(ret) => ret,
+        Err(err) => return err.map(Either::Left),
+    };
+
+    let tunnel_id = jwt.claims.id.clone();
+    // Registered before opening the tunnel, to refuse a tunnel id already in use without side effects
+    let long_polling_session = if long_polling::is_long_polling_request(&req) {
+        match long_polling::new_session(&tunnel_id) {
+            Some(session) => Some(session),
+            None => {
+                warn!("Rejecting long polling connection, tunnel id {} is already in use", tunnel_id);
+                return http::Response::builder()
+                    .status(StatusCode::CONFLICT)
+                    .body(Either::Left("Tunnel id already in use".to_string()))
+                    .unwrap();
+            }
+        }
+    } else {
+        None
+    };
+    let access_log = AccessLogEntry::new(
+        client_addr.ip(),
+        &req,
+        format_args!("{}:{}", jwt.claims.r, jwt.claims.rp),
+        StatusCode::OK.as_u16(),
+    );
+    let reports = tunnel_reports(&jwt.claims.id, &jwt.claims, client_addr.ip(), access_log);
+    let req_protocol = jwt.claims.p.clone();
+    let tunnel = match run_tunnel(&server_config, jwt, client_addr).await {
+        Ok(ret) => ret,
+        Err(err) => {
+            warn!(
+                "Rejecting connection with bad upgrade request: {} {}",
+                err,
+                RedactedUri(req.uri())
+            );
+            if let Some((push_token, _)) = &long_polling_session {
+                long_polling::close_session(&tunnel_id, push_token);
+            }
+            return CloseReason::from_error(&err)
+                .rejection(format!("{:#}", err))
+                .map(Either::Left);
+        }
+    };
+
+    let (remote_addr, local_rx, local_tx) = tunnel;
+    info!("connected to {:?} {}:{}", req_protocol, remote_addr.host, remote_addr.port);
+    let write_batching = server_config
+        .write_batching
+        .filter(|_| !remote_addr.protocol.is_datagram());
+
+    let req_content_type = req.headers_mut().remove(CONTENT_TYPE);
+    let (push_token, ws_rx) = match long_polling_session {
+        Some((push_token, session)) => (Some(push_token), TunnelReader::LongPolling(session)),
+        None => (
+            None,
+            TunnelReader::Http2(Http2TunnelRead::new(BodyStream::new(req.into_body()))),
+        ),
+    };
+    let (ws_tx, rx) = mpsc::channel::<Bytes>(1024);
+    let body = BoxBody::new(StreamBody::new(
+        ReceiverStream::new(rx).map(|s| -> anyhow::Result<Frame<Bytes>> { Ok(Frame::data(s)) }),
+    ));
+
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .body(Either::Right(body))
+        .expect("bug: failed to build response");
+
+    let session_push_token = push_token.clone();
+    tokio::spawn(
+        async move {
+            let stats = reports();
+            super::transport::io::relay_tunnel(
+                local_rx,
+                local_tx,
+                ws_rx,
+                Http2TunnelWrite::new(ws_tx),
+                None,
+                stats,
+                None,
+                write_batching,
+            )
+            .await;
+
+            if let Some(push_token) = session_push_token {
+                long_polling::close_session(&tunnel_id, &push_token);
+            }
         }
         .instrument(Span::current()),
     );
@@ -528,13 +1415,177 @@ async fn http_server_upgrade(
         response.headers_mut().insert(COOKIE, header_val);
     }
 
-    if let Some(content_type) = req_content_type {
+    if let Some(push_token) = push_token {
+        response.headers_mut().insert(
+            &long_polling::PUSH_TOKEN_HEADER,
+            HeaderValue::from_str(&push_token).expect("push token is a valid header value"),
+        );
+        // Prevent proxies from buffering the response, as it carries the data of the tunnel
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+        response
+            .headers_mut()
+            .insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        response
+            .headers_mut()
+            .insert("x-accel-buffering", HeaderValue::from_static("no"));
+    } else if let Some(content_type) = req_content_type {
         response.headers_mut().insert(CONTENT_TYPE, content_type);
     }
 
     response
 }
 
+/// Serve a tunnel carried by another transport than the http ones of the listener, i.e: the in-memory one of tests.
+/// `req` has the headers of the http2 request of a tunnel, and the response is to be sent back before the tunnel is used.
+/// Reusing, resuming and bonding tunnels are specific to websockets, they are not available with other transports
+pub async fn serve_tunnel<B, R: TunnelRead, W: TunnelWrite>(
+    server_config: Arc<WsServerConfig>,
+    mut client_addr: SocketAddr,
+    req: Request<B>,
+    tunnel: (R, W),
+) -> Response<String> {
+    let (jwt, _user) = match validate_tunnel_request(&server_config, &mut client_addr, &req).await {
+        Ok(ret) => ret,
+        Err(err) => return err,
+    };
+
+    let access_log = AccessLogEntry::new(
+        client_addr.ip(),
+        &req,
+        format_args!("{}:{}", jwt.claims.r, jwt.claims.rp),
+        StatusCode::OK.as_u16(),
+    );
+    let reports = tunnel_reports(&jwt.claims.id, &jwt.claims, client_addr.ip(), access_log);
+    let req_protocol = jwt.claims.p.clone();
+    let (remote_addr, local_rx, local_tx) = match run_tunnel(&server_config, jwt, client_addr).await {
+        Ok(ret) => ret,
+        Err(err) => {
+            warn!(
+                "Rejecting connection with bad upgrade request: {} {}",
+                err,
+                RedactedUri(req.uri())
+            );
+            return CloseReason::from_error(&err).rejection(format!("{:#}", err));
+        }
+    };
+
+    info!("connected to {:?} {}:{}", req_protocol, remote_addr.host, remote_addr.port);
+    let write_batching = server_config
+        .write_batching
+        .filter(|_| !remote_addr.protocol.is_datagram());
+    let (tunnel_rx, tunnel_tx) = tunnel;
+    tokio::spawn(
+        async move {
+            let stats = reports();
+            super::transport::io::relay_tunnel(
+                local_rx,
+                local_tx,
+                tunnel_rx,
+                tunnel_tx,
+                None,
+                stats,
+                None,
+                write_batching,
+            )
+            .await;
+        }
+        .instrument(Span::current()),
+    );
+
+    let mut response = Response::new(String::new());
+    if req_protocol == LocalProtocol::ReverseSocks5 {
+        let Ok(header_val) = HeaderValue::from_str(&tunnel_to_jwt_token(Uuid::from_u128(0), &remote_addr)) else {
+            error!("Bad header value for reverse socks5: {} {}", remote_addr.host, remote_addr.port);
+            return http::Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body("Invalid upgrade request".to_string())
+                .unwrap();
+        };
+        response.headers_mut().insert(COOKIE, header_val);
+    }
+
+    response
+}
+
+/// Serve the tunnels of the in-memory transport, until all its client halves are dropped
+pub async fn serve_memory(server_config: Arc<WsServerConfig>, mut connections: mpsc::Receiver<MemoryConnection>) {
+    // In-memory tunnels have no peer, they are reported as coming from the loopback
+    let client_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+    while let Some(cnx) = connections.recv().await {
+        let span = span!(
+            Level::INFO,
+            "tunnel",
+            id = tracing::field::Empty,
+            remote = tracing::field::Empty,
+            peer = "memory",
+            forwarded_for = tracing::field::Empty,
+            rule = tracing::field::Empty,
+            bytes_tx = tracing::field::Empty,
+            bytes_rx = tracing::field::Empty,
+            duration = tracing::field::Empty
+        );
+        let server_config = server_config.clone();
+        tokio::spawn(
+            async move {
+                let rejection = track_rejection(client_addr, &cnx.request);
+                let response = serve_tunnel(server_config, client_addr, cnx.request, cnx.tunnel).await;
+                let _ = cnx.response.send(count_rejection(response, rejection));
+            }
+            .instrument(span),
+        );
+    }
+}
+
+/// Receive the data sent by a long polling client, and forward it to its tunnel
+/// The client and the destination were validated with the GET request that opened the tunnel, the push token
+/// issued then is the proof of it. It is not authenticated again, as a bcrypt hash for each chunk would cost too much
+async fn long_polling_server_push(server_config: Arc<WsServerConfig>, req: Request<Incoming>) -> Response<String> {
+    if let Err(err) = validate_url(&req, &server_config.restrict_http_upgrade_path_prefix) {
+        return err;
+    }
+
+    let header = |name: &HeaderName| {
+        req.headers()
+            .get(name)
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or_default()
+            .to_string()
+    };
+    let session = header(&long_polling::SESSION_HEADER);
+    let push_token = header(&long_polling::PUSH_TOKEN_HEADER);
+    Span::current().record("id", &session);
+
+    let data = match Limited::new(req.into_body(), long_polling::MAX_PUSH_BODY_SIZE)
+        .collect()
+        .await
+    {
+        Ok(body) => body.to_bytes(),
+        Err(err) => {
+            warn!("Rejecting long polling data, cannot read body: {:?}", err);
+            return http::Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body("Invalid upgrade request".to_string())
+                .unwrap();
+        }
+    };
+
+    if let Err(err) = long_polling::push(&session, &push_token, data).await {
+        warn!("Rejecting long polling data: {}", err);
+        return http::Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body("Invalid upgrade request".to_string())
+            .unwrap();
+    }
+
+    http::Response::builder()
+        .status(StatusCode::OK)
+        .header(CACHE_CONTROL, "no-cache")
+        .body(String::new())
+        .unwrap()
+}
+
 struct TlsContext<'a> {
     tls_acceptor: Arc<TlsAcceptor>,
     tls_reloader: TlsReloader,
@@ -554,44 +1605,125 @@ impl TlsContext<'_> {
     }
 }
 
-pub async fn run_server(server_config: Arc<WsServerConfig>) -> anyhow::Result<()> {
-    info!("Starting wstunnel server listening on {}", server_config.bind);
+// Clients connecting in ipv4 to a server listening on [::] appear as ipv4 mapped addresses
+fn unmapped_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ipv6) => ipv6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        IpAddr::V4(_) => ip,
+    }
+}
 
-    // setup upgrade request handler
-    let mk_websocket_upgrade_fn = |server_config: Arc<WsServerConfig>, client_addr: SocketAddr| {
-        move |req: Request<Incoming>| {
-            ws_server_upgrade(server_config.clone(), client_addr, req).map::<anyhow::Result<_>, _>(Ok)
-        }
+fn is_source_allowed(server_config: &WsServerConfig, ip: IpAddr) -> bool {
+    if server_config.deny_from.iter().any(|net| net.contains(&ip)) {
+        return false;
+    }
+
+    server_config.allow_from.is_empty() || server_config.allow_from.iter().any(|net| net.contains(&ip))
+}
+
+/// What is needed to report a request, if it ends up being rejected
+struct Rejection {
+    client_ip: IpAddr,
+    request: Option<String>,
+    access_log: Option<AccessLogEntry>,
+}
+
+fn track_rejection<B>(client_addr: SocketAddr, req: &Request<B>) -> Rejection {
+    let client_ip = match req.headers().get("X-Forwarded-For").and_then(|h| h.to_str().ok()) {
+        Some(x_forward_for) => x_forward_for
+            .split(',')
+            .next()
+            .and_then(|ip| ip.trim().parse().ok())
+            .unwrap_or(client_addr.ip()),
+        None => client_addr.ip(),
     };
+    Rejection {
+        client_ip,
+        request: webhook::is_enabled().then(|| format!("{} {}", req.method(), RedactedUri(req.uri()))),
+        access_log: AccessLogEntry::new(client_ip, req, RedactedUri(req.uri()), 0),
+    }
+}
 
-    let mk_http_upgrade_fn = |server_config: Arc<WsServerConfig>, client_addr: SocketAddr| {
-        move |req: Request<Incoming>| {
-            http_server_upgrade(server_config.clone(), client_addr, req).map::<anyhow::Result<_>, _>(Ok)
+fn count_rejection<B>(response: Response<B>, rejection: Rejection) -> Response<B> {
+    if response.status().is_client_error() || response.status().is_server_error() {
+        statsd::incr(Counter::Errors, 1);
+        if let Some(mut access_log) = rejection.access_log {
+            access_log.set_status(response.status().as_u16());
+            access_log.write(0);
         }
-    };
+        if let Some(request) = &rejection.request {
+            webhook::tunnel_rejected(rejection.client_ip, request, response.status().as_u16());
+        }
+    }
+    response
+}
 
-    let mk_auto_upgrade_fn = |server_config: Arc<WsServerConfig>, client_addr: SocketAddr| {
-        move |req: Request<Incoming>| {
-            let server_config = server_config.clone();
-            async move {
-                if fastwebsockets::upgrade::is_upgrade_request(&req) {
-                    ws_server_upgrade(server_config.clone(), client_addr, req)
-                        .map(|response| Ok::<_, anyhow::Error>(response.map(Either::Left)))
-                        .await
-                } else if req.version() == Version::HTTP_2 {
-                    http_server_upgrade(server_config.clone(), client_addr, req)
-                        .map::<anyhow::Result<_>, _>(Ok)
-                        .await
-                } else {
-                    error!("Invalid protocol version request, got {:?} while expecting either websocket http1 upgrade or http2", req.version());
-                    Ok(http::Response::builder()
-                        .status(StatusCode::BAD_REQUEST)
-                        .body(Either::Left("Invalid protocol request".to_string()))
-                        .unwrap())
-                }
-            }
+type ServerResponse = Response<Either<String, BoxBody<Bytes, anyhow::Error>>>;
+
+/// Hyper service handling the tunnel upgrade requests of one client connection (websocket, http2 and long polling).
+/// Port knocking, --allow-from/--deny-from, GeoIP and SNI checks are not done by it, but by the accept loop of run_server.
+/// The path of the request only needs to end with /events, so it can be served under any prefix i.e: /wstunnel/events
+/// The connection must be served with upgrades enabled, for websocket to work
+#[derive(Clone)]
+pub struct WsServerService {
+    server_config: Arc<WsServerConfig>,
+    client_addr: SocketAddr,
+}
+
+impl WsServerService {
+    pub fn new(server_config: Arc<WsServerConfig>, client_addr: SocketAddr) -> Self {
+        Self {
+            server_config,
+            client_addr,
         }
+    }
+
+    async fn handle(self, req: Request<Incoming>) -> ServerResponse {
+        let rejection = track_rejection(self.client_addr, &req);
+        let response = if fastwebsockets::upgrade::is_upgrade_request(&req) {
+            ws_server_upgrade(self.server_config, self.client_addr, req)
+                .await
+                .map(Either::Left)
+        } else if long_polling::is_push_request(&req) {
+            long_polling_server_push(self.server_config, req)
+                .await
+                .map(Either::Left)
+        } else if req.version() == Version::HTTP_2 || long_polling::is_long_polling_request(&req) {
+            http_server_upgrade(self.server_config, self.client_addr, req).await
+        } else {
+            error!("Invalid protocol version request, got {:?} while expecting either websocket http1 upgrade, http2 or long polling", req.version());
+            http::Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Either::Left("Invalid protocol request".to_string()))
+                .unwrap()
+        };
+        count_rejection(response, rejection)
+    }
+}
+
+impl hyper::service::Service<Request<Incoming>> for WsServerService {
+    type Response = ServerResponse;
+    type Error = anyhow::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn call(&self, req: Request<Incoming>) -> Self::Future {
+        Box::pin(self.clone().handle(req).map(Ok))
+    }
+}
+
+pub async fn run_server(server_config: Arc<WsServerConfig>) -> anyhow::Result<()> {
+    // Take over the listener of the previous server process, if it handed it over
+    let listener = match handover::inherited_listener(server_config.bind) {
+        Some(listener) => listener,
+        None => tcp::bind_listener(server_config.bind, false)?,
     };
+    serve(server_config, listener).await
+}
+
+/// Serve the connections of an already bound listener, and run forever or until it is handed over to a new process.
+/// `bind` of the config must be the address of the listener, as port knocking listens on the same ip
+pub async fn serve(server_config: Arc<WsServerConfig>, listener: TcpListener) -> anyhow::Result<()> {
+    info!("Starting wstunnel server listening on {}", server_config.bind);
 
     // Init TLS if needed
     let mut tls_context = if let Some(tls_config) = &server_config.tls {
@@ -605,16 +1737,79 @@ pub async fn run_server(server_config: Arc<WsServerConfig>) -> anyhow::Result<()
         None
     };
 
-    // Bind server and run forever to serve incoming connections.
-    let listener = TcpListener::bind(&server_config.bind).await?;
+    // Start port knocking listeners if the server must stay hidden
+    let port_knocking = if server_config.knock_sequence.is_empty() {
+        None
+    } else {
+        let port_knocking = PortKnocking::new(server_config.knock_sequence.clone(), server_config.knock_timeout);
+        port_knocking.run_listeners(server_config.bind.ip()).await?;
+        Some(port_knocking)
+    };
+
+    for pool in &server_config.connection_pools {
+        pool.start(
+            server_config.socket_so_mark,
+            server_config.timeout_connect,
+            server_config.dns_resolver.clone(),
+            server_config.egress_netns.clone(),
+        );
+    }
+
+    handover::notify_ready();
+    let mut handover_signal = handover::HandoverSignal::new()?;
     loop {
-        let (stream, peer_addr) = match listener.accept().await {
+        let accepted = select! {
+            accepted = tcp::accept(&listener) => accepted,
+            _ = handover_signal.recv() => {
+                // The new process could not bind the knocking ports, still held by this one
+                if port_knocking.is_some() {
+                    warn!("Ignoring SIGUSR2, the listener cannot be handed over with port knocking");
+                    continue;
+                }
+                match handover::hand_over(&listener).await {
+                    Ok(()) => {
+                        info!("Listener handed over to the new server process, not accepting new connections");
+                        return Ok(());
+                    }
+                    Err(err) => {
+                        error!("Cannot hand over the listener, still serving: {:?}", err);
+                        continue;
+                    }
+                }
+            }
+        };
+        let (stream, peer_addr) = match accepted {
             Ok(ret) => ret,
             Err(err) => {
                 warn!("Error while accepting connection {:?}", err);
                 continue;
             }
         };
+
+        let peer_ip = unmapped_ip(peer_addr.ip());
+        if !is_source_allowed(&server_config, peer_ip) {
+            debug!(
+                "Dropping connection from {}, not allowed by --allow-from/--deny-from",
+                peer_addr
+            );
+            continue;
+        }
+        if let Some(geoip) = &server_config.geoip {
+            if !geoip.is_allowed(peer_ip) {
+                debug!(
+                    "Dropping connection from {}, country not allowed by --allow-countries/--deny-countries",
+                    peer_addr
+                );
+                continue;
+            }
+        }
+
+        if let Some(port_knocking) = &port_knocking {
+            if !port_knocking.is_allowed(peer_ip) {
+                debug!("Dropping connection from {}, knock sequence not completed", peer_addr);
+                continue;
+            }
+        }
         let _ = stream.set_nodelay(true);
 
         let span = span!(
@@ -623,7 +1818,11 @@ pub async fn run_server(server_config: Arc<WsServerConfig>) -> anyhow::Result<()
             id = tracing::field::Empty,
             remote = tracing::field::Empty,
             peer = peer_addr.to_string(),
-            forwarded_for = tracing::field::Empty
+            forwarded_for = tracing::field::Empty,
+            rule = tracing::field::Empty,
+            bytes_tx = tracing::field::Empty,
+            bytes_rx = tracing::field::Empty,
+            duration = tracing::field::Empty
         );
 
         info!("Accepting connection");
@@ -634,9 +1833,10 @@ pub async fn run_server(server_config: Arc<WsServerConfig>) -> anyhow::Result<()
             Some(tls) => {
                 // Reload TLS certificate if needed
                 let tls_acceptor = tls.tls_acceptor().clone();
+                let ktls = tls.tls_config.ktls;
                 let fut = async move {
                     info!("Doing TLS handshake");
-                    let tls_stream = match tls_acceptor.accept(stream).await {
+                    let tls_stream = match ktls::accept(&tls_acceptor, stream, ktls).await {
                         Ok(tls_stream) => hyper_util::rt::TokioIo::new(tls_stream),
                         Err(err) => {
                             error!("error while accepting TLS connection {}", err);
@@ -644,7 +1844,7 @@ pub async fn run_server(server_config: Arc<WsServerConfig>) -> anyhow::Result<()
                         }
                     };
 
-                    match tls_stream.inner().get_ref().1.alpn_protocol() {
+                    match tls_stream.inner().alpn_protocol() {
                         // http2
                         Some(b"h2") => {
                             let mut conn_builder = http2::Builder::new(TokioExecutor::new());
@@ -652,17 +1852,16 @@ pub async fn run_server(server_config: Arc<WsServerConfig>) -> anyhow::Result<()
                                 conn_builder.keep_alive_interval(ping);
                             }
 
-                            let http_upgrade_fn = mk_http_upgrade_fn(server_config, peer_addr);
-                            let con_fut = conn_builder.serve_connection(tls_stream, service_fn(http_upgrade_fn));
+                            let con_fut = conn_builder
+                                .serve_connection(tls_stream, WsServerService::new(server_config, peer_addr));
                             if let Err(e) = con_fut.await {
                                 error!("Error while upgrading cnx to http: {:?}", e);
                             }
                         }
                         // websocket
                         _ => {
-                            let websocket_upgrade_fn = mk_websocket_upgrade_fn(server_config, peer_addr);
                             let conn_fut = http1::Builder::new()
-                                .serve_connection(tls_stream, service_fn(websocket_upgrade_fn))
+                                .serve_connection(tls_stream, WsServerService::new(server_config, peer_addr))
                                 .with_upgrades();
 
                             if let Err(e) = conn_fut.await {
@@ -685,8 +1884,8 @@ pub async fn run_server(server_config: Arc<WsServerConfig>) -> anyhow::Result<()
                         conn_fut.http2().keep_alive_interval(ping);
                     }
 
-                    let websocket_upgrade_fn = mk_auto_upgrade_fn(server_config, peer_addr);
-                    let upgradable = conn_fut.serve_connection_with_upgrades(stream, service_fn(websocket_upgrade_fn));
+                    let upgradable =
+                        conn_fut.serve_connection_with_upgrades(stream, WsServerService::new(server_config, peer_addr));
 
                     if let Err(e) = upgradable.await {
                         error!("Error while upgrading cnx to websocket: {:?}", e);
@@ -699,3 +1898,230 @@ pub async fn run_server(server_config: Arc<WsServerConfig>) -> anyhow::Result<()
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_allowed_destination() {
+        let tcp = LocalProtocol::Tcp { proxy_protocol: false };
+        let udp = LocalProtocol::Udp { timeout: None };
+
+        assert!(is_allowed_destination("1.1.1.1:53", &tcp, "1.1.1.1:53"));
+        assert!(is_allowed_destination("1.1.1.1:53", &udp, "1.1.1.1:53"));
+        assert!(is_allowed_destination("udp:1.1.1.1:53", &udp, "1.1.1.1:53"));
+        assert!(!is_allowed_destination("udp:1.1.1.1:53", &tcp, "1.1.1.1:53"));
+        assert!(is_allowed_destination("tcp:localhost:22", &tcp, "localhost:22"));
+        assert!(!is_allowed_destination("tcp:localhost:22", &udp, "localhost:22"));
+        assert!(!is_allowed_destination("tcp:localhost:22", &tcp, "localhost:2222"));
+        // A host named tcp, without protocol
+        assert!(is_allowed_destination("tcp:22", &udp, "tcp:22"));
+    }
+
+    #[test]
+    fn test_rewrite_destination() {
+        let rewrites = [DestinationRewrite {
+            requested: (Host::Domain("db.example.com".to_string()), 5432),
+            actual: (Host::Ipv4("10.0.12.4".parse().unwrap()), 6432),
+        }];
+
+        assert_eq!(
+            rewrite_destination(&rewrites, Host::Domain("db.example.com".to_string()), 5432),
+            (Host::Ipv4("10.0.12.4".parse().unwrap()), 6432)
+        );
+        assert_eq!(
+            rewrite_destination(&rewrites, Host::Domain("db.example.com".to_string()), 443),
+            (Host::Domain("db.example.com".to_string()), 443)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connect_with_fallbacks() {
+        let remote = RemoteAddr {
+            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            host: Host::Domain("primary".to_string()),
+            port: 443,
+            fallbacks: vec![
+                (Host::Domain("backup".to_string()), 443),
+                (Host::Domain("last".to_string()), 443),
+            ],
+        };
+        let connect = |up: &'static [&'static str]| {
+            move |host: &Host, port: u16| {
+                let host = host.to_string();
+                async move {
+                    if up.contains(&host.as_str()) {
+                        Ok(format!("{}:{}", host, port))
+                    } else {
+                        Err(anyhow!("{} is down", host))
+                    }
+                }
+            }
+        };
+
+        assert_eq!(
+            connect_with_fallbacks(&remote, connect(&["primary", "backup"]))
+                .await
+                .unwrap(),
+            "primary:443"
+        );
+        assert_eq!(
+            connect_with_fallbacks(&remote, connect(&["backup", "last"]))
+                .await
+                .unwrap(),
+            "backup:443"
+        );
+        let err = connect_with_fallbacks(&remote, connect(&[])).await.unwrap_err();
+        assert_eq!(err.to_string(), "last is down");
+    }
+
+    #[test]
+    fn test_forwarded_path_prefix() {
+        let prefixes = vec!["secret".to_string()];
+        assert!(has_path_prefix("/secret/events", &prefixes));
+        assert!(!has_path_prefix("/events", &prefixes));
+        assert!(!has_path_prefix("/secretx/events", &prefixes));
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(forwarded_path(&headers, "/events"), None);
+        headers.insert(X_FORWARDED_PREFIX, HeaderValue::from_static("/secret/"));
+        assert_eq!(forwarded_path(&headers, "/events").as_deref(), Some("/secret/events"));
+        headers.insert(X_FORWARDED_PREFIX, HeaderValue::from_static("/ext"));
+        assert_eq!(
+            forwarded_path(&headers, "/secret/events").as_deref(),
+            Some("/ext/secret/events")
+        );
+    }
+
+    #[test]
+    fn test_headers_in_protocol() {
+        let mut headers = HeaderMap::new();
+        headers.insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("v1, authorization.bearer.jwt"));
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Basic dXNlcjpwYXNz"));
+        headers.insert(&TOTP_HEADER, HeaderValue::from_static("123456"));
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        crate::tunnel::headers_to_protocol(&mut headers).unwrap();
+
+        assert!(headers.get(AUTHORIZATION).is_none());
+        assert!(headers.get(&TOTP_HEADER).is_none());
+        assert!(headers.get(CACHE_CONTROL).is_some());
+        let protocol = headers.get(SEC_WEBSOCKET_PROTOCOL).unwrap().to_str().unwrap();
+        assert!(protocol.starts_with("v1, authorization.bearer.jwt, header."));
+
+        // Headers set by a proxy cannot be spoofed
+        let protocol = format!("{}, header.x-forwarded-for.MTI3LjAuMC4x", protocol);
+        headers.insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_str(&protocol).unwrap());
+        headers_from_protocol(&mut headers);
+        assert_eq!(headers.get(AUTHORIZATION).unwrap(), "Basic dXNlcjpwYXNz");
+        assert_eq!(headers.get(&TOTP_HEADER).unwrap(), "123456");
+        assert!(headers.get("x-forwarded-for").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_geoip_refused_at_accept() {
+        use crate::geoip::GeoIp;
+        use crate::harness::LocalServer;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Whether the server answers a request, or drops the connection as soon as accepted
+        async fn answers(countries: (Vec<String>, Vec<String>)) -> bool {
+            let server = LocalServer::start_with("ws", [], |config| {
+                let localhost = (IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), "FR");
+                config.geoip = Some(GeoIp::from_countries([localhost], countries.0, countries.1));
+            })
+            .await
+            .unwrap();
+
+            let mut stream = tokio::net::TcpStream::connect(server.bind).await.unwrap();
+            let _ = stream
+                .write_all(b"GET /v1/events HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await;
+            let mut buf = [0u8; 64];
+            let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+                .await
+                .unwrap();
+            matches!(read, Ok(len) if len > 0)
+        }
+
+        assert!(answers((vec!["FR".to_string()], vec![])).await);
+        assert!(!answers((vec![], vec!["FR".to_string()])).await);
+        assert!(!answers((vec!["US".to_string()], vec![])).await);
+    }
+
+    #[tokio::test]
+    async fn test_reused_websocket_access_log() {
+        use crate::harness::LocalServer;
+        use crate::tunnel::client::{self, ListenerOptions};
+        use tokio::io::AsyncReadExt;
+        use tokio_stream::wrappers::UnboundedReceiverStream;
+        use url::Host;
+
+        // The access log is global, this is the only test to enable it
+        let log_path = std::env::temp_dir().join(format!("wstunnel-access-{}.log", Uuid::now_v7()));
+        crate::access_log::init(&log_path).unwrap();
+
+        let echo = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = echo.accept().await {
+                tokio::spawn(async move {
+                    let (mut rx, mut tx) = stream.split();
+                    let _ = tokio::io::copy(&mut rx, &mut tx).await;
+                    let _ = tx.shutdown().await;
+                });
+            }
+        });
+
+        let server = LocalServer::start("ws", []).await.unwrap();
+        let client_config = server.client([]).await.unwrap();
+        let (cnx_tx, cnx_rx) = mpsc::unbounded_channel();
+        let options = ListenerOptions {
+            reuse: true,
+            ..Default::default()
+        };
+        tokio::spawn(client::run_tunnel(
+            client_config,
+            options,
+            None,
+            UnboundedReceiverStream::new(cnx_rx),
+        ));
+
+        let remote = RemoteAddr {
+            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            host: Host::Ipv4(Ipv4Addr::LOCALHOST),
+            port: echo_port,
+            fallbacks: vec![],
+        };
+        // One connection after the other, for the second one to reuse the websocket of the first
+        for _ in 0..2 {
+            let (local, tunnel) = tokio::io::duplex(1024);
+            cnx_tx
+                .send(anyhow::Ok((tokio::io::split(tunnel), remote.clone(), None)))
+                .unwrap();
+            let (mut rx, mut tx) = tokio::io::split(local);
+            tx.write_all(b"ping").await.unwrap();
+            tx.shutdown().await.unwrap();
+            let mut echoed = Vec::new();
+            tokio::time::timeout(Duration::from_secs(5), rx.read_to_end(&mut echoed))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(echoed, b"ping");
+        }
+
+        // Lines are written once the server is done with the connection
+        let destination = format!("127.0.0.1:{}", echo_port);
+        let started_at = Instant::now();
+        let lines = loop {
+            let log = std::fs::read_to_string(&log_path).unwrap_or_default();
+            let lines = log.lines().filter(|line| line.contains(&destination)).count();
+            if lines >= 2 || started_at.elapsed() > Duration::from_secs(5) {
+                break lines;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        let _ = std::fs::remove_file(&log_path);
+        assert_eq!(lines, 2);
+    }
+}
@@ -130,9 +130,12 @@ impl TlsReloader {
 
         if let Some(path) = event.paths.iter().find(|p| p.ends_with(&this.key_path)) {
             match event.kind {
-                EventKind::Create(_) | EventKind::Modify(_) => match tls::load_private_key_from_file(&this.key_path) {
+                EventKind::Create(_) | EventKind::Modify(_) => match tls::load_private_key_from_file(
+                    &this.key_path,
+                    tls.tls_key_password.as_ref().map(|p| p.0.as_str()),
+                ) {
                     Ok(tls_key) => {
-                        *tls.tls_key.lock() = tls_key;
+                        *tls.tls_key.lock() = tls::TlsPrivateKey::Der(tls_key);
                         this.tls_reload_certificate.store(true, Ordering::Relaxed);
                     }
                     Err(err) => {
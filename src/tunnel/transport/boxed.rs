@@ -0,0 +1,97 @@
+// Object safe versions of the transport traits, for transports not known to TunnelReader and TunnelWriter.
+// Kept out of the transport module, as their blanket impls would make its method calls ambiguous
+
+use crate::tunnel::transport::close_reason::CloseReason;
+use crate::tunnel::transport::{TunnelRead, TunnelReader, TunnelTransport, TunnelWrite, TunnelWriter};
+use crate::tunnel::RemoteAddr;
+use crate::WsClientConfig;
+use bytes::BytesMut;
+use futures_util::future::BoxFuture;
+use hyper::http::response::Parts;
+use std::time::Duration;
+use tokio::io::AsyncWrite;
+use uuid::Uuid;
+
+/// Object safe TunnelRead, for the halves of transports without their own variant of TunnelReader
+pub trait DynTunnelRead: Send + 'static {
+    fn copy<'a>(&'a mut self, writer: &'a mut (dyn AsyncWrite + Unpin + Send)) -> BoxFuture<'a, std::io::Result<()>>;
+    fn take_rtt(&mut self) -> Option<Duration>;
+    fn take_ack(&mut self) -> Option<u64>;
+}
+
+impl<T: TunnelRead> DynTunnelRead for T {
+    fn copy<'a>(&'a mut self, writer: &'a mut (dyn AsyncWrite + Unpin + Send)) -> BoxFuture<'a, std::io::Result<()>> {
+        Box::pin(TunnelRead::copy(self, writer))
+    }
+
+    fn take_rtt(&mut self) -> Option<Duration> {
+        TunnelRead::take_rtt(self)
+    }
+
+    fn take_ack(&mut self) -> Option<u64> {
+        TunnelRead::take_ack(self)
+    }
+}
+
+/// Object safe TunnelWrite, for the halves of transports without their own variant of TunnelWriter
+pub trait DynTunnelWrite: Send + 'static {
+    fn buf_mut(&mut self) -> &mut BytesMut;
+    fn write(&mut self) -> BoxFuture<'_, std::io::Result<()>>;
+    fn ping(&mut self) -> BoxFuture<'_, std::io::Result<()>>;
+    fn close(&mut self, reason: CloseReason) -> BoxFuture<'_, std::io::Result<()>>;
+    fn shutdown_write(&mut self) -> BoxFuture<'_, std::io::Result<()>>;
+    fn ack(&mut self, received: u64) -> BoxFuture<'_, std::io::Result<()>>;
+}
+
+impl<T: TunnelWrite> DynTunnelWrite for T {
+    fn buf_mut(&mut self) -> &mut BytesMut {
+        TunnelWrite::buf_mut(self)
+    }
+
+    fn write(&mut self) -> BoxFuture<'_, std::io::Result<()>> {
+        Box::pin(TunnelWrite::write(self))
+    }
+
+    fn ping(&mut self) -> BoxFuture<'_, std::io::Result<()>> {
+        Box::pin(TunnelWrite::ping(self))
+    }
+
+    fn close(&mut self, reason: CloseReason) -> BoxFuture<'_, std::io::Result<()>> {
+        Box::pin(TunnelWrite::close(self, reason))
+    }
+
+    fn shutdown_write(&mut self) -> BoxFuture<'_, std::io::Result<()>> {
+        Box::pin(TunnelWrite::shutdown_write(self))
+    }
+
+    fn ack(&mut self, received: u64) -> BoxFuture<'_, std::io::Result<()>> {
+        Box::pin(TunnelWrite::ack(self, received))
+    }
+}
+
+/// Object safe TunnelTransport, to plug a transport in WsClientConfig
+pub trait DynTunnelTransport: Send + Sync {
+    fn connect<'a>(
+        &'a self,
+        request_id: Uuid,
+        client_cfg: &'a WsClientConfig,
+        remote_cfg: &'a RemoteAddr,
+        reuse: bool,
+    ) -> BoxFuture<'a, anyhow::Result<(TunnelReader, TunnelWriter, Parts)>>;
+}
+
+impl<T: TunnelTransport> DynTunnelTransport for T {
+    fn connect<'a>(
+        &'a self,
+        request_id: Uuid,
+        client_cfg: &'a WsClientConfig,
+        remote_cfg: &'a RemoteAddr,
+        reuse: bool,
+    ) -> BoxFuture<'a, anyhow::Result<(TunnelReader, TunnelWriter, Parts)>> {
+        Box::pin(async move {
+            let (reader, writer, response) =
+                TunnelTransport::connect(self, request_id, client_cfg, remote_cfg, reuse).await?;
+            Ok((reader.into(), writer.into(), response))
+        })
+    }
+}
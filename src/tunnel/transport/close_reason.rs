@@ -0,0 +1,270 @@
+use hyper::header::{HeaderName, RETRY_AFTER};
+use hyper::{HeaderMap, Response, StatusCode};
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::ErrorKind;
+use std::time::{Duration, SystemTime};
+
+/// Code of the reason of a refused tunnel, as the status code alone can be rewritten by a proxy in front of the server
+pub static CLOSE_REASON_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-close-reason");
+
+/// Why a tunnel has been closed, or refused before being opened.
+/// It is sent to the peer, for it to log the real cause and report it to its local application instead of a generic EOF
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CloseReason {
+    Normal,
+    ConnectionRefused,
+    ConnectionReset,
+    Restricted,
+    Unauthorized,
+    Timeout,
+    QuotaExceeded,
+    Error,
+}
+
+impl CloseReason {
+    pub fn from_io_error(err: &io::Error) -> Self {
+        match err.kind() {
+            ErrorKind::ConnectionRefused => CloseReason::ConnectionRefused,
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe => {
+                CloseReason::ConnectionReset
+            }
+            ErrorKind::TimedOut => CloseReason::Timeout,
+            ErrorKind::PermissionDenied => CloseReason::Restricted,
+            // Reasons without an io error kind of their own, i.e: quota exceeded, are carried as the inner error
+            _ => err
+                .get_ref()
+                .and_then(|err| err.downcast_ref::<CloseReason>())
+                .copied()
+                .unwrap_or(CloseReason::Error),
+        }
+    }
+
+    /// Look for the root io error or close reason of the chain, i.e: the connection to the destination has been refused
+    pub fn from_error(err: &anyhow::Error) -> Self {
+        err.chain()
+            .find_map(|err| {
+                err.downcast_ref::<CloseReason>()
+                    .copied()
+                    .or_else(|| err.downcast_ref::<io::Error>().map(Self::from_io_error))
+            })
+            .unwrap_or(CloseReason::Error)
+    }
+
+    /// Code of the websocket close frame. Application specific codes are in the 4000-4999 range
+    pub fn code(self) -> u16 {
+        match self {
+            CloseReason::Normal => 1000,
+            CloseReason::Error => 1011,
+            CloseReason::ConnectionRefused => 4001,
+            CloseReason::ConnectionReset => 4002,
+            CloseReason::Restricted => 4003,
+            CloseReason::Timeout => 4004,
+            CloseReason::Unauthorized => 4005,
+            CloseReason::QuotaExceeded => 4006,
+        }
+    }
+
+    pub fn from_code(code: u16) -> Self {
+        match code {
+            1000 | 1001 => CloseReason::Normal,
+            4001 => CloseReason::ConnectionRefused,
+            4002 => CloseReason::ConnectionReset,
+            4003 => CloseReason::Restricted,
+            4004 => CloseReason::Timeout,
+            4005 => CloseReason::Unauthorized,
+            4006 => CloseReason::QuotaExceeded,
+            _ => CloseReason::Error,
+        }
+    }
+
+    /// Status of the response, when the tunnel is refused during the upgrade request
+    pub fn status_code(self) -> StatusCode {
+        match self {
+            CloseReason::ConnectionRefused | CloseReason::ConnectionReset => StatusCode::BAD_GATEWAY,
+            CloseReason::Restricted => StatusCode::FORBIDDEN,
+            CloseReason::Unauthorized => StatusCode::UNAUTHORIZED,
+            CloseReason::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            CloseReason::QuotaExceeded => StatusCode::TOO_MANY_REQUESTS,
+            CloseReason::Normal | CloseReason::Error => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    pub fn from_status_code(status: StatusCode) -> Self {
+        match status {
+            StatusCode::BAD_GATEWAY => CloseReason::ConnectionRefused,
+            StatusCode::FORBIDDEN => CloseReason::Restricted,
+            StatusCode::UNAUTHORIZED => CloseReason::Unauthorized,
+            StatusCode::GATEWAY_TIMEOUT => CloseReason::Timeout,
+            StatusCode::TOO_MANY_REQUESTS => CloseReason::QuotaExceeded,
+            _ => CloseReason::Error,
+        }
+    }
+
+    /// Reason of a refused upgrade request, from the close reason header of the server or else its status code
+    pub fn from_response(status: StatusCode, headers: &HeaderMap) -> Self {
+        headers
+            .get(&CLOSE_REASON_HEADER)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.parse::<u16>().ok())
+            .map(Self::from_code)
+            .unwrap_or_else(|| Self::from_status_code(status))
+    }
+
+    /// Response refusing the upgrade request, the message is logged as is by the client
+    pub fn rejection(self, message: impl Display) -> Response<String> {
+        Response::builder()
+            .status(self.status_code())
+            .header(&CLOSE_REASON_HEADER, self.code())
+            .body(format!("Invalid upgrade request: {}: {}", self, message))
+            .unwrap()
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CloseReason::Normal => "normal close",
+            CloseReason::ConnectionRefused => "connection refused",
+            CloseReason::ConnectionReset => "connection reset",
+            CloseReason::Restricted => "destination not allowed",
+            CloseReason::Unauthorized => "authentication rejected",
+            CloseReason::Timeout => "timeout",
+            CloseReason::QuotaExceeded => "quota exceeded",
+            CloseReason::Error => "error",
+        }
+    }
+
+    /// Error to report to the local application
+    pub fn to_io_error(self) -> io::Error {
+        let kind = match self {
+            CloseReason::Normal => ErrorKind::NotConnected,
+            CloseReason::ConnectionRefused => ErrorKind::ConnectionRefused,
+            CloseReason::ConnectionReset => ErrorKind::ConnectionReset,
+            CloseReason::Restricted | CloseReason::Unauthorized => ErrorKind::PermissionDenied,
+            CloseReason::Timeout => ErrorKind::TimedOut,
+            // ErrorKind::QuotaExceeded needs a more recent rust than our MSRV
+            CloseReason::QuotaExceeded => return io::Error::other(self),
+            CloseReason::Error => ErrorKind::Other,
+        };
+        io::Error::new(kind, format!("tunnel closed by remote: {}", self))
+    }
+}
+
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::error::Error for CloseReason {}
+
+/// Upgrade request refused for now, i.e: by a rate limiting CDN in front of the server, that the client can retry later
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RetryAfter {
+    pub status: StatusCode,
+    // Delay asked by the Retry-After header of the response, in seconds or as a date
+    pub delay: Option<Duration>,
+}
+
+impl RetryAfter {
+    pub fn from_response(status: StatusCode, headers: &HeaderMap) -> Option<Self> {
+        if status != StatusCode::TOO_MANY_REQUESTS && status != StatusCode::SERVICE_UNAVAILABLE {
+            return None;
+        }
+
+        let delay = headers
+            .get(RETRY_AFTER)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| parse_retry_after(h.trim(), SystemTime::now()));
+        Some(Self { status, delay })
+    }
+}
+
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+impl Display for RetryAfter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.delay {
+            Some(delay) => write!(f, "server answered {}, retry after {:?}", self.status, delay),
+            None => write!(f, "server answered {}", self.status),
+        }
+    }
+}
+
+impl std::error::Error for RetryAfter {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_close_reason_round_trip() {
+        for reason in [
+            CloseReason::Normal,
+            CloseReason::ConnectionRefused,
+            CloseReason::ConnectionReset,
+            CloseReason::Restricted,
+            CloseReason::Unauthorized,
+            CloseReason::Timeout,
+            CloseReason::QuotaExceeded,
+            CloseReason::Error,
+        ] {
+            assert_eq!(CloseReason::from_code(reason.code()), reason);
+        }
+        assert_eq!(CloseReason::from_code(1006), CloseReason::Error);
+
+        let err = anyhow::Error::new(io::Error::from(ErrorKind::ConnectionRefused)).context("Cannot connect");
+        assert_eq!(CloseReason::from_error(&err), CloseReason::ConnectionRefused);
+        assert_eq!(CloseReason::from_error(&anyhow::anyhow!("bad jwt")), CloseReason::Error);
+        let err = anyhow::Error::new(CloseReason::QuotaExceeded).context("too many flows");
+        assert_eq!(CloseReason::from_error(&err), CloseReason::QuotaExceeded);
+        assert_eq!(
+            CloseReason::from_io_error(&CloseReason::QuotaExceeded.to_io_error()),
+            CloseReason::QuotaExceeded
+        );
+        assert_eq!(
+            CloseReason::from_status_code(CloseReason::Timeout.status_code()),
+            CloseReason::Timeout
+        );
+
+        let rejection = CloseReason::QuotaExceeded.rejection("too many tunnels");
+        assert_eq!(rejection.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            CloseReason::from_response(StatusCode::BAD_REQUEST, rejection.headers()),
+            CloseReason::QuotaExceeded
+        );
+        assert_eq!(
+            CloseReason::from_response(StatusCode::FORBIDDEN, &HeaderMap::new()),
+            CloseReason::Restricted
+        );
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(RetryAfter::from_response(StatusCode::FORBIDDEN, &headers), None);
+        assert_eq!(
+            RetryAfter::from_response(StatusCode::SERVICE_UNAVAILABLE, &headers)
+                .unwrap()
+                .delay,
+            None
+        );
+
+        headers.insert(RETRY_AFTER, "120".parse().unwrap());
+        let retry = RetryAfter::from_response(StatusCode::TOO_MANY_REQUESTS, &headers).unwrap();
+        assert_eq!(retry.delay, Some(Duration::from_secs(120)));
+
+        let now = httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").unwrap();
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+}
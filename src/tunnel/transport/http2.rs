@@ -1,5 +1,9 @@
-use crate::tunnel::transport::{headers_from_file, TunnelRead, TunnelWrite, MAX_PACKET_LENGTH};
+use crate::redact::RedactedRequest;
+use crate::totp::TOTP_HEADER;
+use crate::tunnel::transport::close_reason::{CloseReason, RetryAfter};
+use crate::tunnel::transport::{headers_from_file, TunnelRead, TunnelTransport, TunnelWrite, MAX_PACKET_LENGTH};
 use crate::tunnel::{tunnel_to_jwt_token, RemoteAddr, TransportScheme};
+use crate::version::{CLIENT_FEATURES, CLIENT_FEATURES_HEADER, CLIENT_VERSION_HEADER};
 use crate::WsClientConfig;
 use anyhow::{anyhow, Context};
 use bytes::{Bytes, BytesMut};
@@ -7,6 +11,7 @@ use http_body_util::{BodyExt, BodyStream, StreamBody};
 use hyper::body::{Frame, Incoming};
 use hyper::header::{AUTHORIZATION, CONTENT_TYPE, COOKIE};
 use hyper::http::response::Parts;
+use hyper::http::HeaderValue;
 use hyper::Request;
 use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
 use log::{debug, error, warn};
@@ -92,11 +97,29 @@ impl TunnelWrite for Http2TunnelWrite {
         Ok(())
     }
 
-    async fn close(&mut self) -> Result<(), io::Error> {
+    // The reason cannot be carried, the end of the stream is the only close signal
+    async fn close(&mut self, _reason: CloseReason) -> Result<(), io::Error> {
         Ok(())
     }
 }
 
+pub struct Http2Transport;
+
+impl TunnelTransport for Http2Transport {
+    type Reader = Http2TunnelRead;
+    type Writer = Http2TunnelWrite;
+
+    async fn connect(
+        &self,
+        request_id: Uuid,
+        client_cfg: &WsClientConfig,
+        remote_cfg: &RemoteAddr,
+        _reuse: bool,
+    ) -> anyhow::Result<(Http2TunnelRead, Http2TunnelWrite, Parts)> {
+        connect(request_id, client_cfg, remote_cfg).await
+    }
+}
+
 pub async fn connect(
     request_id: Uuid,
     client_cfg: &WsClientConfig,
@@ -138,9 +161,14 @@ pub async fn connect(
         ))
         .header(COOKIE, tunnel_to_jwt_token(request_id, dest_addr))
         .header(CONTENT_TYPE, "application/json")
+        .header(&CLIENT_VERSION_HEADER, env!("CARGO_PKG_VERSION"))
+        .header(&CLIENT_FEATURES_HEADER, CLIENT_FEATURES.join(","))
         .version(hyper::Version::HTTP_2);
 
     let headers = req.headers_mut().unwrap();
+    if let Some(cookie_jar) = &client_cfg.cookie_jar {
+        cookie_jar.add_cookie_header(headers);
+    }
     for (k, v) in &client_cfg.http_headers {
         let _ = headers.remove(k);
         headers.append(k, v.clone());
@@ -151,6 +179,11 @@ pub async fn connect(
         headers.append(AUTHORIZATION, auth.clone());
     }
 
+    if let Some(totp) = &client_cfg.auth_totp {
+        let _ = headers.remove(&TOTP_HEADER);
+        headers.append(&TOTP_HEADER, HeaderValue::from_str(&totp.proof_now(&request_id.to_string()))?);
+    }
+
     if let Some(headers_file) = headers_file {
         for (k, v) in headers_file {
             let _ = headers.remove(&k);
@@ -166,7 +199,7 @@ pub async fn connect(
             client_cfg.remote_addr
         )
     })?;
-    debug!("with HTTP upgrade request {:?}", req);
+    debug!("with HTTP upgrade request {:?}", RedactedRequest(&req));
     let transport = pooled_cnx.deref_mut().take().unwrap();
     let (mut request_sender, cnx) = hyper::client::conn::http2::Builder::new(TokioExecutor::new())
         .timer(TokioTimer::new())
@@ -186,13 +219,21 @@ pub async fn connect(
         .send_request(req)
         .await
         .with_context(|| format!("failed to send http2 request with the server {:?}", client_cfg.remote_addr))?;
+    if let Some(cookie_jar) = &client_cfg.cookie_jar {
+        cookie_jar.store(response.headers());
+    }
 
     if !response.status().is_success() {
-        return Err(anyhow!(
-            "Http2 server rejected the connection: {:?}: {:?}",
-            response.status(),
-            String::from_utf8(response.into_body().collect().await?.to_bytes().to_vec()).unwrap_or_default()
-        ));
+        let status = response.status();
+        let reason = CloseReason::from_response(status, response.headers());
+        let retry = RetryAfter::from_response(status, response.headers());
+        let body = String::from_utf8(response.into_body().collect().await?.to_bytes().to_vec()).unwrap_or_default();
+        let err =
+            anyhow::Error::new(reason).context(format!("Http2 server rejected the connection: {:?}: {}", status, body));
+        return Err(match retry {
+            Some(retry) => err.context(retry),
+            None => err,
+        });
     }
 
     let (parts, body) = response.into_parts();
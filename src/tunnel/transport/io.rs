@@ -1,20 +1,634 @@
-use crate::tunnel::transport::{TunnelRead, TunnelWrite};
-use bytes::BufMut;
+use crate::access_log::AccessLogEntry;
+use crate::flight_recorder::FlightRecorder;
+use crate::hooks::TunnelHook;
+use crate::metrics::{DestinationMetrics, ListenerMetrics};
+use crate::pcap::TunnelCapture;
+use crate::statsd;
+use crate::statsd::Counter;
+use crate::tunnel::transport::close_reason::CloseReason;
+use crate::tunnel::transport::keepalive::Keepalive;
+use crate::tunnel::transport::{TunnelRead, TunnelWrite, MAX_PACKET_LENGTH};
+use crate::webhook::WebhookTunnel;
+use bytes::{Buf, BufMut, BytesMut};
 use futures_util::{pin_mut, FutureExt};
+use parking_lot::Mutex;
+use pin_project::pin_project;
+use std::fmt::Display;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::Duration;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
 use tokio::select;
-use tokio::sync::oneshot;
+use tokio::sync::{oneshot, watch};
 use tokio::time::Instant;
 use tracing::log::debug;
-use tracing::{error, info, warn};
+use tracing::{error, info, warn, Span};
+
+// A priority class competes for the link while one of its tunnels has transferred data during this window
+const ACTIVE_WINDOW: Duration = Duration::from_millis(50);
+// How far ahead of the other active classes, in weighted bytes, a class can get before waiting for them
+const SCHEDULER_QUANTUM: u64 = 256 * 1024;
+
+static ACTIVE_TUNNELS: AtomicU64 = AtomicU64::new(0);
+static TOTAL_TUNNELS: AtomicU64 = AtomicU64::new(0);
+static TOTAL_BYTES_TX: AtomicU64 = AtomicU64::new(0);
+static TOTAL_BYTES_RX: AtomicU64 = AtomicU64::new(0);
+// Last round trip time measured on any tunnel, in microseconds. 0 if none yet
+static LAST_RTT_US: AtomicU64 = AtomicU64::new(0);
+
+// Round trip time is degraded once it is this many times its best value of the tunnel, and at least this much higher
+const RTT_DEGRADED_FACTOR: u64 = 2;
+const RTT_DEGRADED_MIN_INCREASE: Duration = Duration::from_millis(50);
+
+/// Number of tunnels currently open, in this process
+pub fn active_tunnels() -> u64 {
+    ACTIVE_TUNNELS.load(Ordering::Relaxed)
+}
+
+/// Totals since the start of the process: (tunnels opened, bytes sent, bytes received)
+pub fn totals() -> (u64, u64, u64) {
+    (
+        TOTAL_TUNNELS.load(Ordering::Relaxed),
+        TOTAL_BYTES_TX.load(Ordering::Relaxed),
+        TOTAL_BYTES_RX.load(Ordering::Relaxed),
+    )
+}
+
+/// Last round trip time measured with a ping of a tunnel, in this process
+pub fn last_rtt() -> Option<Duration> {
+    match LAST_RTT_US.load(Ordering::Relaxed) {
+        0 => None,
+        rtt => Some(Duration::from_micros(rtt)),
+    }
+}
+
+/// Scheduling preference of a tunnel, when several tunnels of the client compete for bandwidth
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum TunnelPriority {
+    Interactive,
+    #[default]
+    Normal,
+    Bulk,
+}
+
+impl TunnelPriority {
+    // Share of the bandwidth of the class, relative to the others: 16 / 4 / 1
+    fn cost(self, len: usize) -> u64 {
+        let len = len as u64;
+        match self {
+            TunnelPriority::Interactive => len,
+            TunnelPriority::Normal => len * 4,
+            TunnelPriority::Bulk => len * 16,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Default)]
+struct ClassState {
+    // Weighted bytes transferred by the class
+    virtual_bytes: u64,
+    last_active: Option<Instant>,
+}
+
+impl ClassState {
+    fn is_active(&self, now: Instant) -> bool {
+        self.last_active
+            .is_some_and(|at| now.duration_since(at) < ACTIVE_WINDOW)
+    }
+}
+
+// One direction of the link, shared by the tunnels of the client
+#[derive(Default)]
+struct SchedulerLane {
+    classes: Mutex<[ClassState; 3]>,
+    progress: tokio::sync::Notify,
+}
+
+impl SchedulerLane {
+    // Weighted fair queuing between the priority classes. A class only waits when it is ahead of another class that
+    // is transferring data at the same time, so a lone tunnel, whatever its priority, gets the whole link
+    async fn schedule(&self, priority: TunnelPriority, len: usize) {
+        loop {
+            let progress = self.progress.notified();
+            {
+                let mut classes = self.classes.lock();
+                let now = Instant::now();
+                let me = priority.index();
+                let competing = classes
+                    .iter()
+                    .enumerate()
+                    .filter(|(ix, class)| *ix != me && class.is_active(now))
+                    .map(|(_, class)| class.virtual_bytes)
+                    .min();
+
+                let class = &mut classes[me];
+                // Do not let a class bank credit while it was idle, it would starve the others once back
+                if !class.is_active(now) {
+                    class.virtual_bytes = class.virtual_bytes.max(competing.unwrap_or(0));
+                }
+                class.last_active = Some(now);
+
+                if competing.map_or(true, |min| class.virtual_bytes <= min + SCHEDULER_QUANTUM) {
+                    class.virtual_bytes += priority.cost(len);
+                    drop(classes);
+                    self.progress.notify_waiters();
+                    return;
+                }
+            }
+
+            // Wait for the other classes to catch up, or to stop competing
+            let _ = tokio::time::timeout(ACTIVE_WINDOW, progress).await;
+        }
+    }
+}
+
+/// Shares the bandwidth between the tunnels of a client according to their priority
+#[derive(Default)]
+pub struct TunnelScheduler {
+    local_to_remote: SchedulerLane,
+    remote_to_local: SchedulerLane,
+}
+
+impl TunnelScheduler {
+    pub fn schedule(self: &Arc<Self>, priority: TunnelPriority) -> TunnelSchedule {
+        TunnelSchedule {
+            scheduler: self.clone(),
+            priority,
+        }
+    }
+}
+
+/// Priority of a tunnel within the scheduler of its client
+#[derive(Clone)]
+pub struct TunnelSchedule {
+    scheduler: Arc<TunnelScheduler>,
+    priority: TunnelPriority,
+}
+
+impl TunnelSchedule {
+    // Called each time the tunnel has a chunk of data to send to the remote
+    async fn local_to_remote(&self, len: usize) {
+        self.scheduler.local_to_remote.schedule(self.priority, len).await
+    }
+
+    // Called each time the tunnel has received a chunk of data from the remote
+    async fn remote_to_local(&self, len: usize) {
+        self.scheduler.remote_to_local.schedule(self.priority, len).await
+    }
+}
+
+struct TunnelStatsInner {
+    span: Span,
+    started_at: Instant,
+    bytes_tx: AtomicU64,
+    bytes_rx: AtomicU64,
+    access_log: Option<AccessLogEntry>,
+    webhook: Option<WebhookTunnel>,
+    hook: Option<TunnelHook>,
+    metrics: Option<Arc<ListenerMetrics>>,
+    destination_metrics: Option<Arc<DestinationMetrics>>,
+    capture: Option<TunnelCapture>,
+    recorder: Option<Arc<FlightRecorder>>,
+    failed: AtomicBool,
+    half_closed: AtomicU8,
+    rtt: Mutex<Rtt>,
+}
+
+// Round trip times of the pings of a tunnel
+#[derive(Default)]
+struct Rtt {
+    min: Option<Duration>,
+    // Smoothed like TCP does, 7/8 of the previous value and 1/8 of the new sample
+    smoothed: Option<Duration>,
+    degraded: bool,
+    samples: u64,
+}
+
+// Directions of the tunnel that have been half closed
+const LOCAL_HALF_CLOSED: u8 = 1;
+const REMOTE_HALF_CLOSED: u8 = 2;
+
+impl Drop for TunnelStatsInner {
+    fn drop(&mut self) {
+        statsd::tunnel_closed();
+        ACTIVE_TUNNELS.fetch_sub(1, Ordering::Relaxed);
+        self.span.record("bytes_tx", self.bytes_tx.load(Ordering::Relaxed));
+        self.span.record("bytes_rx", self.bytes_rx.load(Ordering::Relaxed));
+        self.span
+            .record("duration", tracing::field::debug(self.started_at.elapsed()));
+        if let Some(rtt) = self.rtt.lock().smoothed {
+            self.span.record("rtt", tracing::field::debug(rtt));
+        }
+        self.span.in_scope(|| info!("Tunnel closed"));
+        if let Some(access_log) = &self.access_log {
+            access_log.write(self.bytes_tx.load(Ordering::Relaxed));
+        }
+        if let Some(webhook) = &self.webhook {
+            webhook.closed(
+                self.bytes_tx.load(Ordering::Relaxed),
+                self.bytes_rx.load(Ordering::Relaxed),
+                self.started_at.elapsed(),
+            );
+        }
+        if let Some(hook) = &self.hook {
+            hook.disconnected(
+                self.bytes_tx.load(Ordering::Relaxed),
+                self.bytes_rx.load(Ordering::Relaxed),
+                self.started_at.elapsed(),
+            );
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.tunnel_closed();
+        }
+        if let Some(metrics) = &self.destination_metrics {
+            metrics.tunnel_done(
+                self.started_at.elapsed(),
+                self.bytes_tx.load(Ordering::Relaxed) + self.bytes_rx.load(Ordering::Relaxed),
+            );
+        }
+        if let Some(recorder) = &self.recorder {
+            recorder.record(format_args!(
+                "closed, {} bytes sent and {} received",
+                self.bytes_tx.load(Ordering::Relaxed),
+                self.bytes_rx.load(Ordering::Relaxed)
+            ));
+            if self.failed.load(Ordering::Relaxed) {
+                recorder.dump("that failed");
+            }
+        }
+    }
+}
+
+/// Count bytes going through both halves of a tunnel. Once both halves are closed,
+/// the totals and duration are recorded on the tunnel span.
+#[derive(Clone)]
+pub struct TunnelStats {
+    inner: Arc<TunnelStatsInner>,
+}
+
+impl TunnelStats {
+    pub fn new(span: Span) -> Self {
+        statsd::tunnel_opened();
+        ACTIVE_TUNNELS.fetch_add(1, Ordering::Relaxed);
+        TOTAL_TUNNELS.fetch_add(1, Ordering::Relaxed);
+        Self {
+            inner: Arc::new(TunnelStatsInner {
+                span,
+                started_at: Instant::now(),
+                bytes_tx: AtomicU64::new(0),
+                bytes_rx: AtomicU64::new(0),
+                access_log: None,
+                webhook: None,
+                hook: None,
+                metrics: None,
+                destination_metrics: None,
+                capture: None,
+                recorder: None,
+                failed: AtomicBool::new(false),
+                half_closed: AtomicU8::new(0),
+                rtt: Mutex::new(Rtt::default()),
+            }),
+        }
+    }
+
+    fn event(&self, event: impl Display) {
+        if let Some(recorder) = &self.inner.recorder {
+            recorder.record(event);
+        }
+    }
+
+    // The events of the flight recorder are logged once the tunnel is closed
+    fn failed(&self, event: impl Display) {
+        self.inner.failed.store(true, Ordering::Relaxed);
+        self.event(event);
+    }
+
+    // Return true if both directions of the tunnel are now half closed
+    fn half_close(&self, direction: u8) -> bool {
+        self.event(if direction == LOCAL_HALF_CLOSED {
+            "local => remote half closed"
+        } else {
+            "local <= remote half closed"
+        });
+        let half_closed = self.inner.half_closed.fetch_or(direction, Ordering::Relaxed) | direction;
+        half_closed == LOCAL_HALF_CLOSED | REMOTE_HALF_CLOSED
+    }
+
+    fn record_rtt(&self, sample: Duration) {
+        LAST_RTT_US.store(sample.as_micros().max(1) as u64, Ordering::Relaxed);
+        statsd::rtt(sample);
+        if let Some(metrics) = &self.inner.metrics {
+            metrics.rtt(sample);
+        }
+        self.event(format_args!("ping round trip time {:?}", sample));
+
+        let mut rtt = self.inner.rtt.lock();
+        rtt.samples += 1;
+        let min = rtt.min.map_or(sample, |min| min.min(sample));
+        rtt.min = Some(min);
+        let smoothed = rtt.smoothed.map_or(sample, |smoothed| (smoothed * 7 + sample) / 8);
+        rtt.smoothed = Some(smoothed);
+        debug!("ping round trip time {:?}, smoothed {:?}", sample, smoothed);
+
+        let degraded = smoothed.as_micros() as u64 > min.as_micros() as u64 * RTT_DEGRADED_FACTOR
+            && smoothed > min + RTT_DEGRADED_MIN_INCREASE;
+        if degraded != rtt.degraded {
+            rtt.degraded = degraded;
+            if degraded {
+                warn!(
+                    "Round trip time to the peer degraded to {:?}, it was {:?} at best",
+                    smoothed, min
+                );
+            } else {
+                info!("Round trip time to the peer is back to {:?}", smoothed);
+            }
+        }
+    }
+
+    fn rtt_samples(&self) -> u64 {
+        self.inner.rtt.lock().samples
+    }
+
+    fn bytes(&self) -> u64 {
+        self.inner.bytes_tx.load(Ordering::Relaxed) + self.inner.bytes_rx.load(Ordering::Relaxed)
+    }
+
+    fn is_fully_half_closed(&self) -> bool {
+        self.inner.half_closed.load(Ordering::Relaxed) == LOCAL_HALF_CLOSED | REMOTE_HALF_CLOSED
+    }
+
+    /// Write a line to the access log once the tunnel is closed
+    pub fn with_access_log(mut self, access_log: Option<AccessLogEntry>) -> Self {
+        if let Some(inner) = Arc::get_mut(&mut self.inner) {
+            inner.access_log = access_log;
+        }
+        self
+    }
+
+    /// Notify the webhook that the tunnel is opened, and once it is closed
+    pub fn with_webhook(mut self, webhook: Option<WebhookTunnel>) -> Self {
+        if let Some(webhook) = &webhook {
+            webhook.opened();
+        }
+        if let Some(inner) = Arc::get_mut(&mut self.inner) {
+            inner.webhook = webhook;
+        }
+        self
+    }
+
+    /// Run the hook commands now that the tunnel is opened, and once it is closed
+    pub fn with_hook(mut self, hook: Option<TunnelHook>) -> Self {
+        if let Some(hook) = &hook {
+            hook.connected();
+        }
+        if let Some(inner) = Arc::get_mut(&mut self.inner) {
+            inner.hook = hook;
+        }
+        self
+    }
+
+    /// Account the tunnel and its bytes in the metrics of the listener it comes from, and of its destination
+    pub fn with_metrics(mut self, metrics: Option<Arc<ListenerMetrics>>, destination: &str) -> Self {
+        if let Some(metrics) = &metrics {
+            metrics.tunnel_opened();
+        }
+        if let Some(inner) = Arc::get_mut(&mut self.inner) {
+            inner.destination_metrics = metrics.as_ref().map(|m| m.destination(destination));
+            inner.metrics = metrics;
+        }
+        self
+    }
+
+    /// Keep the last events of the tunnel, to log them if it fails
+    pub fn with_flight_recorder(mut self, recorder: Option<Arc<FlightRecorder>>) -> Self {
+        if let Some(recorder) = &recorder {
+            recorder.record("opened");
+        }
+        if let Some(inner) = Arc::get_mut(&mut self.inner) {
+            inner.recorder = recorder;
+        }
+        self
+    }
+
+    /// Record the payload of the tunnel in the pcap file
+    pub fn with_capture(mut self, capture: Option<TunnelCapture>) -> Self {
+        if let Some(inner) = Arc::get_mut(&mut self.inner) {
+            inner.capture = capture;
+        }
+        self
+    }
+}
+
+/// Count bytes read from the local side, that are going to be sent to the remote
+#[pin_project]
+struct CountingReader<R> {
+    #[pin]
+    inner: R,
+    stats: TunnelStats,
+}
+
+impl<R: AsyncRead> AsyncRead for CountingReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let filled_before = buf.filled().len();
+        let ret = this.inner.poll_read(cx, buf);
+        let read_len = buf.filled().len() - filled_before;
+        this.stats.inner.bytes_tx.fetch_add(read_len as u64, Ordering::Relaxed);
+        TOTAL_BYTES_TX.fetch_add(read_len as u64, Ordering::Relaxed);
+        statsd::incr(Counter::BytesTx, read_len as u64);
+        if let Some(metrics) = &this.stats.inner.metrics {
+            metrics.add_bytes_tx(read_len as u64);
+        }
+        if let Some(capture) = &this.stats.inner.capture {
+            if read_len > 0 {
+                capture.local_to_remote(&buf.filled()[filled_before..]);
+            }
+        }
+        ret
+    }
+}
+
+/// Count bytes written to the local side, that have been received from the remote
+#[pin_project]
+struct CountingWriter<W> {
+    #[pin]
+    inner: W,
+    stats: TunnelStats,
+}
+
+impl<W: AsyncWrite> AsyncWrite for CountingWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let ret = this.inner.poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = &ret {
+            let bytes_rx = this.stats.inner.bytes_rx.fetch_add(*written as u64, Ordering::Relaxed);
+            if let (0, Some(metrics)) = (bytes_rx, &this.stats.inner.destination_metrics) {
+                if *written > 0 {
+                    metrics.first_byte(this.stats.inner.started_at.elapsed());
+                }
+            }
+            TOTAL_BYTES_RX.fetch_add(*written as u64, Ordering::Relaxed);
+            statsd::incr(Counter::BytesRx, *written as u64);
+            if let Some(metrics) = &this.stats.inner.metrics {
+                metrics.add_bytes_rx(*written as u64);
+            }
+            if let Some(capture) = &this.stats.inner.capture {
+                capture.remote_to_local(&buf[..*written]);
+            }
+        }
+        ret
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    // Only called when the remote has half closed the tunnel
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let ret = this.inner.poll_shutdown(cx);
+        if ret.is_ready() {
+            this.stats.half_close(REMOTE_HALF_CLOSED);
+        }
+        ret
+    }
+}
+
+/// Count the bytes going through the local side of a tunnel relayed without the functions of this module
+pub fn count_local<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    local_rx: R,
+    local_tx: W,
+    stats: &TunnelStats,
+) -> (impl AsyncRead + Unpin, impl AsyncWrite + Unpin) {
+    (
+        CountingReader {
+            inner: local_rx,
+            stats: stats.clone(),
+        },
+        CountingWriter {
+            inner: local_tx,
+            stats: stats.clone(),
+        },
+    )
+}
+
+/// Wait a bit for more bytes after a small read, before sending them in a frame.
+/// Protocols doing many tiny writes then need fewer frames and TLS records, at the cost of latency
+#[derive(Debug, Clone, Copy)]
+pub struct WriteBatching {
+    pub delay: Duration,
+    pub max_bytes: usize,
+}
+
+// Pings of a tunnel. With an adaptive keepalive, a ping sent after a whole interval without data is a probe:
+// the pong of the peer tells the path still knows the connection after being idle that long
+struct Pinger {
+    keepalive: Option<Keepalive>,
+    // Consider the peer gone as soon as it does not answer a ping, not only an idle probe
+    require_pongs: bool,
+    interval: Duration,
+    bytes: u64,
+    // Pongs received before the last ping was sent
+    pongs: Option<u64>,
+    // Interval of the probe, and pongs received before it was sent
+    probe: Option<(Duration, u64)>,
+}
+
+impl Pinger {
+    fn new(keepalive: Option<Keepalive>, stats: &TunnelStats) -> Self {
+        Self {
+            interval: keepalive
+                .as_ref()
+                .map_or(Duration::from_secs(3600 * 24), Keepalive::interval),
+            keepalive,
+            require_pongs: false,
+            bytes: stats.bytes(),
+            pongs: None,
+            probe: None,
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.keepalive.is_some()
+    }
+
+    // Time to wait for the next ping, or an error if the peer did not answer the last one
+    fn tick(&mut self, stats: &TunnelStats) -> io::Result<Duration> {
+        let Some(keepalive) = &self.keepalive else {
+            return Ok(self.interval);
+        };
+
+        let pongs = stats.rtt_samples();
+        // Peers that never answered pings are too old to, not dead
+        let unanswered = |pongs_before: u64| pongs_before > 0 && pongs == pongs_before;
+        let dead = || io::Error::new(io::ErrorKind::TimedOut, "peer stopped answering pings");
+        if let (Keepalive::Adaptive(adaptive), Some((interval, pongs_before))) = (keepalive, self.probe.take()) {
+            if unanswered(pongs_before) {
+                adaptive.died(interval);
+                return Err(dead());
+            }
+            if pongs > pongs_before {
+                adaptive.survived(interval);
+            }
+        }
+        if self.require_pongs && self.pongs.is_some_and(unanswered) {
+            return Err(dead());
+        }
+        self.pongs = Some(pongs);
+
+        let Keepalive::Adaptive(adaptive) = keepalive else {
+            return Ok(self.interval);
+        };
+        let bytes = stats.bytes();
+        if bytes == self.bytes {
+            self.probe = Some((self.interval, pongs));
+        }
+        self.bytes = bytes;
+        self.interval = adaptive.interval();
+        Ok(self.interval)
+    }
+}
+
+/// Relay both directions of a tunnel from the current task, instead of spawning a task for one of them.
+/// It halves the tasks and their wake ups on a busy server. Each direction still stops the other once it is done
+#[allow(clippy::too_many_arguments)]
+pub async fn relay_tunnel(
+    local_rx: impl AsyncRead,
+    local_tx: impl AsyncWrite + Send,
+    ws_rx: impl TunnelRead,
+    ws_tx: impl TunnelWrite,
+    keepalive: Option<Keepalive>,
+    stats: TunnelStats,
+    schedule: Option<TunnelSchedule>,
+    batching: Option<WriteBatching>,
+) {
+    let (close_tx, close_rx) = oneshot::channel::<()>();
+    let local_to_remote =
+        propagate_local_to_remote(local_rx, ws_tx, close_tx, keepalive, stats.clone(), schedule.clone(), batching);
+    let remote_to_local = propagate_remote_to_local(local_tx, ws_rx, close_rx, stats, schedule);
+    let _ = tokio::join!(local_to_remote, remote_to_local);
+}
 
 pub async fn propagate_local_to_remote(
     local_rx: impl AsyncRead,
     mut ws_tx: impl TunnelWrite,
     mut close_tx: oneshot::Sender<()>,
-    ping_frequency: Option<Duration>,
+    keepalive: Option<Keepalive>,
+    stats: TunnelStats,
+    schedule: Option<TunnelSchedule>,
+    batching: Option<WriteBatching>,
 ) -> anyhow::Result<()> {
+    let local_rx = CountingReader {
+        inner: local_rx,
+        stats: stats.clone(),
+    };
     let _guard = scopeguard::guard((), |_| {
         info!("Closing local => remote tunnel");
     });
@@ -23,67 +637,199 @@ pub async fn propagate_local_to_remote(
 
     // We do our own pin_mut! to avoid shadowing timeout and be able to reset it, on next loop iteration
     // We reuse the future to avoid creating a timer in the tight loop
-    let frequency = ping_frequency.unwrap_or(Duration::from_secs(3600 * 24));
-    let start_at = Instant::now().checked_add(frequency).unwrap_or(Instant::now());
-    let timeout = tokio::time::interval_at(start_at, frequency);
+    let mut pinger = Pinger::new(keepalive, &stats);
+    let timeout = tokio::time::sleep(pinger.interval);
     let should_close = close_tx.closed().fuse();
 
     pin_mut!(timeout);
     pin_mut!(should_close);
     pin_mut!(local_rx);
+    let mut half_closed = false;
+    let mut close_reason = CloseReason::Normal;
+    // Eof or error met while batching, handled once the batched bytes are sent
+    let mut pending_read: Option<io::Result<usize>> = None;
     loop {
         debug_assert!(
             ws_tx.buf_mut().chunk_mut().len() >= MAX_PACKET_LENGTH,
             "buffer must be large enough to receive a whole packet length"
         );
 
-        let read_len = select! {
-            biased;
+        let read_len = if let Some(read_len) = pending_read.take() {
+            read_len
+        } else {
+            select! {
+                biased;
 
-            read_len = local_rx.read_buf(ws_tx.buf_mut()) => read_len,
+                read_len = local_rx.read_buf(ws_tx.buf_mut()) => read_len,
 
-            _ = &mut should_close => break,
+                _ = &mut should_close => break,
 
-            _ = timeout.tick(), if ping_frequency.is_some() => {
-                debug!("sending ping to keep connection alive");
-                ws_tx.ping().await?;
-                continue;
+                _ = &mut timeout, if pinger.is_enabled() => {
+                    match pinger.tick(&stats) {
+                        Ok(interval) => timeout.as_mut().reset(Instant::now() + interval),
+                        Err(err) => {
+                            warn!("closing tunnel: {}", err);
+                            stats.failed(format_args!("closing tunnel: {}", err));
+                            close_reason = CloseReason::Timeout;
+                            break;
+                        }
+                    }
+                    debug!("sending ping to keep connection alive");
+                    ws_tx.ping().await?;
+                    continue;
+                }
             }
         };
 
         let _read_len = match read_len {
-            Ok(0) => break,
+            Ok(0) => {
+                // Let the remote know there is nothing more to send, but keep receiving data from it.
+                // If it does not support half-close, fallback to closing the whole tunnel
+                if ws_tx.shutdown_write().await.is_ok() {
+                    debug!("local => remote tunnel half closed");
+                    half_closed = !stats.half_close(LOCAL_HALF_CLOSED);
+                }
+                break;
+            }
             Ok(read_len) => read_len,
             Err(err) => {
                 warn!("error while reading incoming bytes from local tx tunnel: {}", err);
+                stats.failed(format_args!("cannot read from local: {}", err));
+                close_reason = CloseReason::from_io_error(&err);
                 break;
             }
         };
 
+        if let Some(batching) = &batching {
+            let deadline = Instant::now() + batching.delay;
+            while ws_tx.buf_mut().len() < batching.max_bytes {
+                match tokio::time::timeout_at(deadline, local_rx.read_buf(ws_tx.buf_mut())).await {
+                    Ok(Ok(0)) => {
+                        pending_read = Some(Ok(0));
+                        break;
+                    }
+                    Ok(Err(err)) => {
+                        pending_read = Some(Err(err));
+                        break;
+                    }
+                    Ok(Ok(_)) => {}
+                    Err(_) => break,
+                }
+            }
+        }
+
+        if let Some(schedule) = &schedule {
+            schedule.local_to_remote(ws_tx.buf_mut().len()).await;
+        }
+
         //debug!("read {} wasted {}% usable {} capa {}", read_len, 100 - (read_len * 100 / buffer.capacity()), buffer.as_slice().len(), buffer.capacity());
         if let Err(err) = ws_tx.write().await {
             warn!("error while writing to tx tunnel {}", err);
+            stats.failed(format_args!("cannot write to tunnel: {}", err));
             break;
         }
     }
 
-    // Send normal close
-    let _ = ws_tx.close().await;
+    // Wait for the remote to be done too before closing the tunnel
+    if half_closed {
+        loop {
+            select! {
+                _ = &mut should_close => break,
+
+                _ = &mut timeout, if pinger.is_enabled() => {
+                    match pinger.tick(&stats) {
+                        Ok(interval) => timeout.as_mut().reset(Instant::now() + interval),
+                        Err(err) => {
+                            warn!("closing tunnel: {}", err);
+                            stats.failed(format_args!("closing tunnel: {}", err));
+                            close_reason = CloseReason::Timeout;
+                            break;
+                        }
+                    }
+                    debug!("sending ping to keep connection alive");
+                    if ws_tx.ping().await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    // Let the remote know why the tunnel is closed, i.e: the connection has been reset
+    let _ = ws_tx.close(close_reason).await;
 
     Ok(())
 }
 
+/// Relay one connection over a tunnel that outlives it. Each side signals the end of its stream with a half-close,
+/// once both are done the tunnel is handed back to carry the next connection, instead of being closed
+pub async fn relay_reusable<RX: TunnelRead, TX: TunnelWrite>(
+    local_rx: impl AsyncRead,
+    local_tx: impl AsyncWrite + Send,
+    mut ws_rx: RX,
+    mut ws_tx: TX,
+    stats: TunnelStats,
+) -> anyhow::Result<(RX, TX)> {
+    let local_to_remote = async {
+        let local_rx = CountingReader {
+            inner: local_rx,
+            stats: stats.clone(),
+        };
+        pin_mut!(local_rx);
+        loop {
+            match local_rx.read_buf(ws_tx.buf_mut()).await {
+                Ok(0) => {
+                    ws_tx.shutdown_write().await?;
+                    stats.half_close(LOCAL_HALF_CLOSED);
+                    return anyhow::Ok(ws_tx);
+                }
+                Ok(_) => ws_tx.write().await?,
+                Err(err) => {
+                    stats.failed(format_args!("cannot read from local: {}", err));
+                    let _ = ws_tx.close(CloseReason::from_io_error(&err)).await;
+                    return Err(err.into());
+                }
+            }
+        }
+    };
+
+    let remote_to_local = async {
+        let local_tx = CountingWriter {
+            inner: local_tx,
+            stats: stats.clone(),
+        };
+        pin_mut!(local_tx);
+        while stats.inner.half_closed.load(Ordering::Relaxed) & REMOTE_HALF_CLOSED == 0 {
+            ws_rx.copy(&mut local_tx).await?;
+            if let Some(rtt) = ws_rx.take_rtt() {
+                stats.record_rtt(rtt);
+            }
+        }
+        anyhow::Ok(ws_rx)
+    };
+
+    let (ws_tx, ws_rx) = tokio::try_join!(local_to_remote, remote_to_local)?;
+    Ok((ws_rx, ws_tx))
+}
+
 pub async fn propagate_remote_to_local(
     local_tx: impl AsyncWrite + Send,
     mut ws_rx: impl TunnelRead,
     mut close_rx: oneshot::Receiver<()>,
+    stats: TunnelStats,
+    schedule: Option<TunnelSchedule>,
 ) -> anyhow::Result<()> {
+    let local_tx = CountingWriter {
+        inner: local_tx,
+        stats: stats.clone(),
+    };
     let _guard = scopeguard::guard((), |_| {
         info!("Closing local <= remote tunnel");
     });
 
     pin_mut!(local_tx);
     loop {
+        let bytes_rx = stats.inner.bytes_rx.load(Ordering::Relaxed);
         let msg = select! {
             biased;
             msg = ws_rx.copy(&mut local_tx) => msg,
@@ -92,9 +838,642 @@ pub async fn propagate_remote_to_local(
 
         if let Err(err) = msg {
             error!("error while reading from tunnel rx {}", err);
+            stats.failed(format_args!("cannot read from tunnel: {}", err));
             break;
         }
+        if let Some(rtt) = ws_rx.take_rtt() {
+            stats.record_rtt(rtt);
+        }
+
+        // Both sides have finished sending data
+        if stats.is_fully_half_closed() {
+            break;
+        }
+
+        if let Some(schedule) = &schedule {
+            let len = stats.inner.bytes_rx.load(Ordering::Relaxed) - bytes_rx;
+            schedule.remote_to_local(len as usize).await;
+        }
     }
 
     Ok(())
 }
+
+// How a relay over a websocket ended
+enum RelayEnd {
+    // The tunnel is over, the websocket is closed with this reason
+    Done(CloseReason),
+    // The websocket broke before the end of the tunnel
+    Broken,
+}
+
+// Remember if writing to the local side failed, for its errors not to be taken for the websocket breaking.
+// Once the remote has sent its eof, what it sends is only read for its acknowledgements and dropped
+struct LocalWriter<'a, W> {
+    inner: &'a mut Option<W>,
+    failed: bool,
+}
+
+impl<W: AsyncWrite + Unpin> LocalWriter<'_, W> {
+    fn check<T>(&mut self, ret: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        if let Poll::Ready(Err(_)) = &ret {
+            self.failed = true;
+        }
+        ret
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for LocalWriter<'_, W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let Some(inner) = self.inner.as_mut() else {
+            return Poll::Ready(Ok(buf.len()));
+        };
+        let ret = Pin::new(inner).poll_write(cx, buf);
+        self.check(ret)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Some(inner) = self.inner.as_mut() else {
+            return Poll::Ready(Ok(()));
+        };
+        let ret = Pin::new(inner).poll_flush(cx);
+        self.check(ret)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Some(inner) = self.inner.as_mut() else {
+            return Poll::Ready(Ok(()));
+        };
+        let ret = Pin::new(inner).poll_shutdown(cx);
+        self.check(ret)
+    }
+}
+
+// Bytes received by a resumable tunnel after which it acknowledges them, for the remote to free its buffer
+const ACK_EVERY: u64 = 16 * 1024;
+
+// Bytes sent to the remote that it has not acknowledged yet, to send them again over the next websocket
+struct RetransmitBuffer {
+    // Offset in the stream of the first byte of data
+    start: u64,
+    data: BytesMut,
+    capacity: usize,
+}
+
+impl RetransmitBuffer {
+    fn is_full(&self) -> bool {
+        self.data.len() >= self.capacity
+    }
+
+    // Drop the bytes the remote received
+    fn ack(&mut self, received: u64) {
+        let acked = received.saturating_sub(self.start).min(self.data.len() as u64);
+        self.data.advance(acked as usize);
+        self.start += acked;
+    }
+}
+
+/// Local side of a tunnel that outlives its websocket. When the websocket breaks, i.e: on a network change,
+/// the tunnel is resumed over a new websocket instead of closing the local connection
+pub struct ResumableTunnel {
+    // None once the local side has sent its eof
+    local_rx: Option<CountingReader<Pin<Box<dyn AsyncRead + Send>>>>,
+    // None once the remote side has sent its eof
+    local_tx: Option<CountingWriter<Pin<Box<dyn AsyncWrite + Send>>>>,
+    retransmit: Option<RetransmitBuffer>,
+    stats: TunnelStats,
+}
+
+impl ResumableTunnel {
+    pub fn new(
+        local_rx: Pin<Box<dyn AsyncRead + Send>>,
+        local_tx: Pin<Box<dyn AsyncWrite + Send>>,
+        stats: TunnelStats,
+    ) -> Self {
+        Self {
+            local_rx: Some(CountingReader {
+                inner: local_rx,
+                stats: stats.clone(),
+            }),
+            local_tx: Some(CountingWriter {
+                inner: local_tx,
+                stats: stats.clone(),
+            }),
+            retransmit: None,
+            stats,
+        }
+    }
+
+    /// Keep up to `capacity` bytes sent until the remote acknowledges them, to send them again if the websocket
+    /// breaks before they are received. Reading the local side waits while the buffer is full.
+    /// The remote must acknowledge what it receives, both sides agree on it with the retransmit feature
+    pub fn with_retransmit_buffer(mut self, capacity: Option<usize>) -> Self {
+        self.retransmit = capacity.map(|capacity| RetransmitBuffer {
+            start: self.sent(),
+            data: BytesMut::new(),
+            // For the acknowledgements sent every ACK_EVERY bytes to free it before it is full
+            capacity: capacity.max(ACK_EVERY as usize * 4),
+        });
+        self
+    }
+
+    /// Bytes received from the remote, and written to the local side
+    pub fn received(&self) -> u64 {
+        self.stats.inner.bytes_rx.load(Ordering::Relaxed)
+    }
+
+    /// Bytes read from the local side for the remote. Without a retransmission buffer, the ones in flight when
+    /// the websocket broke are lost
+    pub fn sent(&self) -> u64 {
+        self.stats.inner.bytes_tx.load(Ordering::Relaxed)
+    }
+
+    /// Prepare to resume the tunnel from the bytes the remote received. Returns false if some of the bytes
+    /// it missed cannot be sent again, the tunnel cannot be resumed then
+    pub fn resume_from(&mut self, remote_received: u64) -> bool {
+        let sent = self.sent();
+        match &mut self.retransmit {
+            Some(buffer) if (buffer.start..=sent).contains(&remote_received) => {
+                buffer.ack(remote_received);
+                true
+            }
+            _ => remote_received == sent,
+        }
+    }
+
+    /// Relay the tunnel over the websocket, until the end of the tunnel or until the websocket breaks.
+    /// Returns true if it broke, for the tunnel to be resumed over a new websocket
+    pub async fn relay(
+        &mut self,
+        mut ws_rx: impl TunnelRead,
+        mut ws_tx: impl TunnelWrite,
+        keepalive: Option<Keepalive>,
+    ) -> bool {
+        let Self {
+            local_rx,
+            local_tx,
+            retransmit,
+            stats,
+        } = self;
+        let mut pinger = Pinger::new(keepalive, stats);
+        pinger.require_pongs = true;
+        let acking = retransmit.is_some();
+        let acked_before = retransmit.as_ref().map_or(0, |buffer| buffer.start);
+        let (remote_done_tx, mut remote_done_rx) = oneshot::channel::<()>();
+        // Acknowledgements received by the read half, and to send by the write half
+        let (remote_acked_tx, mut remote_acked_rx) = watch::channel(0);
+        let (ack_tx, mut ack_rx) = watch::channel(0);
+
+        let local_to_remote = async {
+            // What the remote missed of the previous websocket, and our eof if it was sent over it
+            if let Some(buffer) = retransmit.as_ref() {
+                for chunk in buffer.data.chunks(MAX_PACKET_LENGTH) {
+                    ws_tx.buf_mut().extend_from_slice(chunk);
+                    ws_tx.write().await.map_err(|_| RelayEnd::Broken)?;
+                }
+            }
+            if local_rx.is_none() {
+                ws_tx.shutdown_write().await.map_err(|_| RelayEnd::Broken)?;
+            }
+
+            let timeout = tokio::time::sleep(pinger.interval);
+            pin_mut!(timeout);
+            while let Some(rx) = local_rx {
+                let window_full = retransmit.as_ref().is_some_and(RetransmitBuffer::is_full);
+                select! {
+                    biased;
+
+                    Ok(()) = remote_acked_rx.changed() => {
+                        if let Some(buffer) = retransmit.as_mut() {
+                            buffer.ack(*remote_acked_rx.borrow_and_update());
+                        }
+                    },
+
+                    Ok(()) = ack_rx.changed() => {
+                        let received = *ack_rx.borrow_and_update();
+                        ws_tx.ack(received).await.map_err(|_| RelayEnd::Broken)?
+                    },
+
+                    read_len = rx.read_buf(ws_tx.buf_mut()), if !window_full => match read_len {
+                        Ok(0) => {
+                            // The eof is sent again over the next websocket if this one breaks
+                            *local_rx = None;
+                            stats.half_close(LOCAL_HALF_CLOSED);
+                            ws_tx.shutdown_write().await.map_err(|_| RelayEnd::Broken)?;
+                        }
+                        Ok(_) => {
+                            if let Some(buffer) = retransmit.as_mut() {
+                                buffer.data.extend_from_slice(ws_tx.buf_mut());
+                            }
+                            ws_tx.write().await.map_err(|_| RelayEnd::Broken)?
+                        }
+                        Err(err) => return Err(RelayEnd::Done(CloseReason::from_io_error(&err))),
+                    },
+
+                    _ = &mut timeout, if pinger.is_enabled() => {
+                        resumable_ping(&mut pinger, stats, &mut ws_tx, timeout.as_mut()).await?
+                    }
+                }
+            }
+
+            // Keep pinging and acknowledging until the remote is done too
+            loop {
+                select! {
+                    biased;
+
+                    Ok(()) = ack_rx.changed() => {
+                        let received = *ack_rx.borrow_and_update();
+                        ws_tx.ack(received).await.map_err(|_| RelayEnd::Broken)?
+                    },
+                    _ = &mut remote_done_rx => return Ok(()),
+                    _ = &mut timeout, if pinger.is_enabled() => {
+                        resumable_ping(&mut pinger, stats, &mut ws_tx, timeout.as_mut()).await?
+                    }
+                }
+            }
+        };
+
+        let remote_to_local = async {
+            let _remote_done = remote_done_tx;
+            let mut last_ack = stats.inner.bytes_rx.load(Ordering::Relaxed);
+            let mut remote_acked = acked_before;
+            let mut writer = LocalWriter {
+                inner: local_tx,
+                failed: false,
+            };
+            loop {
+                let remote_eof = stats.inner.half_closed.load(Ordering::Relaxed) & REMOTE_HALF_CLOSED != 0;
+                if remote_eof {
+                    *writer.inner = None;
+                }
+                // With retransmission, the tunnel is over once the remote also received all we sent
+                let local_done = stats.inner.half_closed.load(Ordering::Relaxed) & LOCAL_HALF_CLOSED != 0
+                    && remote_acked == stats.inner.bytes_tx.load(Ordering::Relaxed);
+                if writer.inner.is_none() && (!acking || local_done) {
+                    return Ok(());
+                }
+
+                match ws_rx.copy(&mut writer).await {
+                    Ok(()) => {
+                        if let Some(rtt) = ws_rx.take_rtt() {
+                            stats.record_rtt(rtt);
+                        }
+                        if let Some(acked) = ws_rx.take_ack() {
+                            remote_acked = acked;
+                            remote_acked_tx.send_replace(acked);
+                        }
+                    }
+                    Err(err) if writer.failed => return Err(RelayEnd::Done(CloseReason::from_io_error(&err))),
+                    Err(err) if err.kind() == io::ErrorKind::ConnectionAborted => return Err(RelayEnd::Broken),
+                    // Closed by the remote
+                    Err(_) => return Err(RelayEnd::Done(CloseReason::Normal)),
+                }
+
+                let received = stats.inner.bytes_rx.load(Ordering::Relaxed);
+                let remote_eof = stats.inner.half_closed.load(Ordering::Relaxed) & REMOTE_HALF_CLOSED != 0;
+                if acking && received > last_ack && (received - last_ack >= ACK_EVERY || remote_eof) {
+                    last_ack = received;
+                    ack_tx.send_replace(received);
+                }
+            }
+        };
+
+        match tokio::try_join!(local_to_remote, remote_to_local) {
+            Ok(_) => {
+                let _ = ws_tx.close(CloseReason::Normal).await;
+                false
+            }
+            Err(RelayEnd::Done(reason)) => {
+                let _ = ws_tx.close(reason).await;
+                false
+            }
+            Err(RelayEnd::Broken) => true,
+        }
+    }
+}
+
+// Ping the remote, the websocket is broken once a ping is not answered
+async fn resumable_ping(
+    pinger: &mut Pinger,
+    stats: &TunnelStats,
+    ws_tx: &mut impl TunnelWrite,
+    timeout: Pin<&mut tokio::time::Sleep>,
+) -> Result<(), RelayEnd> {
+    match pinger.tick(stats) {
+        Ok(interval) => timeout.reset(Instant::now() + interval),
+        Err(err) => {
+            warn!("websocket of the tunnel is broken: {}", err);
+            return Err(RelayEnd::Broken);
+        }
+    }
+    ws_tx.ping().await.map_err(|_| RelayEnd::Broken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::transport::http2::Http2TunnelWrite;
+    use bytes::{Bytes, BytesMut};
+    use tokio::io::AsyncWriteExt;
+    use tokio::sync::mpsc;
+
+    // Tunnel over a channel, an empty message is a half-close and "ack N" an acknowledgement
+    struct ChannelWrite(mpsc::Sender<Bytes>, BytesMut);
+    struct ChannelRead(mpsc::Receiver<Bytes>, Option<u64>);
+
+    impl TunnelWrite for ChannelWrite {
+        fn buf_mut(&mut self) -> &mut BytesMut {
+            &mut self.1
+        }
+
+        async fn write(&mut self) -> Result<(), io::Error> {
+            let data = self.1.split().freeze();
+            self.1.reserve(1024);
+            self.0.send(data).await.map_err(|_| io::ErrorKind::BrokenPipe.into())
+        }
+
+        async fn ping(&mut self) -> Result<(), io::Error> {
+            Ok(())
+        }
+
+        async fn close(&mut self, _reason: CloseReason) -> Result<(), io::Error> {
+            Ok(())
+        }
+
+        async fn shutdown_write(&mut self) -> Result<(), io::Error> {
+            self.0
+                .send(Bytes::new())
+                .await
+                .map_err(|_| io::ErrorKind::BrokenPipe.into())
+        }
+
+        async fn ack(&mut self, received: u64) -> Result<(), io::Error> {
+            self.0
+                .send(Bytes::from(format!("ack {}", received)))
+                .await
+                .map_err(|_| io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    impl TunnelRead for ChannelRead {
+        async fn copy(&mut self, mut writer: impl AsyncWrite + Unpin + Send) -> Result<(), io::Error> {
+            match self.0.recv().await {
+                None => Err(io::ErrorKind::NotConnected.into()),
+                Some(data) if data.is_empty() => writer.shutdown().await,
+                Some(data) if data.starts_with(b"ack ") => {
+                    self.1 = std::str::from_utf8(&data[4..]).ok().and_then(|ack| ack.parse().ok());
+                    Ok(())
+                }
+                Some(data) => writer.write_all(&data).await,
+            }
+        }
+
+        fn take_ack(&mut self) -> Option<u64> {
+            self.1.take()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_reusable() {
+        let (to_local, ws_rx) = mpsc::channel(16);
+        let (ws_tx, mut from_local) = mpsc::channel(16);
+        let mut tunnel = (ChannelRead(ws_rx, None), ChannelWrite(ws_tx, BytesMut::with_capacity(1024)));
+
+        // Several connections one after the other, over the same tunnel
+        for _ in 0..2 {
+            let (mut app, local) = tokio::io::duplex(1024);
+            let (local_rx, local_tx) = tokio::io::split(local);
+            let relay = tokio::spawn(relay_reusable(
+                local_rx,
+                local_tx,
+                tunnel.0,
+                tunnel.1,
+                TunnelStats::new(Span::none()),
+            ));
+
+            app.write_all(b"ping").await.unwrap();
+            app.shutdown().await.unwrap();
+            assert_eq!(from_local.recv().await.unwrap(), "ping");
+            assert_eq!(from_local.recv().await.unwrap(), "");
+
+            to_local.send(Bytes::from_static(b"pong")).await.unwrap();
+            to_local.send(Bytes::new()).await.unwrap();
+            let mut response = Vec::new();
+            app.read_to_end(&mut response).await.unwrap();
+            assert_eq!(response, b"pong");
+
+            tunnel = relay.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resumable_tunnel() {
+        let (mut app, local) = tokio::io::duplex(1024);
+        let (local_rx, local_tx) = tokio::io::split(local);
+        let stats = TunnelStats::new(Span::none());
+        let mut tunnel = ResumableTunnel::new(Box::pin(local_rx), Box::pin(local_tx), stats);
+
+        // The websocket breaks while sending, the local connection stays open
+        let (to_local, ws_rx) = mpsc::channel(16);
+        let (ws_tx, from_local) = mpsc::channel::<Bytes>(16);
+        drop(from_local);
+        let (broken, ()) = tokio::join!(
+            tunnel.relay(
+                ChannelRead(ws_rx, None),
+                ChannelWrite(ws_tx, BytesMut::with_capacity(1024)),
+                None
+            ),
+            async {
+                to_local.send(Bytes::from_static(b"ping")).await.unwrap();
+                let mut ping = [0; 4];
+                app.read_exact(&mut ping).await.unwrap();
+                assert_eq!(&ping, b"ping");
+                app.write_all(b"lost").await.unwrap();
+            }
+        );
+        assert!(broken);
+        assert_eq!((tunnel.received(), tunnel.sent()), (4, 4));
+        // Without retransmission, only a remote that lost nothing can resume
+        assert!(!tunnel.resume_from(0));
+        assert!(tunnel.resume_from(4));
+
+        // And the tunnel goes on over the next one
+        let (to_local, ws_rx) = mpsc::channel(16);
+        let (ws_tx, mut from_local) = mpsc::channel(16);
+        let (broken, ()) = tokio::join!(
+            tunnel.relay(
+                ChannelRead(ws_rx, None),
+                ChannelWrite(ws_tx, BytesMut::with_capacity(1024)),
+                None
+            ),
+            async {
+                app.write_all(b"pong").await.unwrap();
+                app.shutdown().await.unwrap();
+                assert_eq!(from_local.recv().await.unwrap(), "pong");
+                assert_eq!(from_local.recv().await.unwrap(), "");
+
+                to_local.send(Bytes::new()).await.unwrap();
+                let mut rest = Vec::new();
+                app.read_to_end(&mut rest).await.unwrap();
+                assert!(rest.is_empty());
+            }
+        );
+        assert!(!broken);
+        assert_eq!((tunnel.received(), tunnel.sent()), (4, 8));
+    }
+
+    #[tokio::test]
+    async fn test_resumable_tunnel_retransmit() {
+        let (mut app, local) = tokio::io::duplex(1024);
+        let (local_rx, local_tx) = tokio::io::split(local);
+        let stats = TunnelStats::new(Span::none());
+        let mut tunnel =
+            ResumableTunnel::new(Box::pin(local_rx), Box::pin(local_tx), stats).with_retransmit_buffer(Some(1024));
+
+        // The first bytes are lost in flight, and the websocket breaks while sending the next ones
+        let (_to_local, ws_rx) = mpsc::channel(16);
+        let (ws_tx, mut from_local) = mpsc::channel::<Bytes>(16);
+        let (broken, ()) = tokio::join!(
+            tunnel.relay(
+                ChannelRead(ws_rx, None),
+                ChannelWrite(ws_tx, BytesMut::with_capacity(1024)),
+                None
+            ),
+            async {
+                app.write_all(b"lost").await.unwrap();
+                assert_eq!(from_local.recv().await.unwrap(), "lost");
+                from_local.close();
+                app.write_all(b"more").await.unwrap();
+            }
+        );
+        assert!(broken);
+        assert_eq!(tunnel.sent(), 8);
+        assert!(!tunnel.resume_from(9));
+        assert!(tunnel.resume_from(0));
+
+        // They are sent again over the next websocket, and the tunnel ends once the remote acknowledged them
+        let (to_local, ws_rx) = mpsc::channel(16);
+        let (ws_tx, mut from_local) = mpsc::channel(16);
+        let (broken, ()) = tokio::join!(
+            tunnel.relay(
+                ChannelRead(ws_rx, None),
+                ChannelWrite(ws_tx, BytesMut::with_capacity(1024)),
+                None
+            ),
+            async {
+                assert_eq!(from_local.recv().await.unwrap(), "lostmore");
+                app.shutdown().await.unwrap();
+                assert_eq!(from_local.recv().await.unwrap(), "");
+
+                to_local.send(Bytes::from_static(b"ack 8")).await.unwrap();
+                to_local.send(Bytes::from_static(b"pong")).await.unwrap();
+                to_local.send(Bytes::new()).await.unwrap();
+                let mut response = Vec::new();
+                app.read_to_end(&mut response).await.unwrap();
+                assert_eq!(response, b"pong");
+                assert_eq!(from_local.recv().await.unwrap(), "ack 4");
+            }
+        );
+        assert!(!broken);
+        assert_eq!((tunnel.received(), tunnel.sent()), (4, 8));
+    }
+
+    #[tokio::test]
+    async fn test_relay_tunnel() {
+        let (to_local, ws_rx) = mpsc::channel(16);
+        let (ws_tx, mut from_local) = mpsc::channel(16);
+        let (mut app, local) = tokio::io::duplex(1024);
+        let (local_rx, local_tx) = tokio::io::split(local);
+        let relay = tokio::spawn(relay_tunnel(
+            local_rx,
+            local_tx,
+            ChannelRead(ws_rx, None),
+            ChannelWrite(ws_tx, BytesMut::with_capacity(MAX_PACKET_LENGTH)),
+            None,
+            TunnelStats::new(Span::none()),
+            None,
+            None,
+        ));
+
+        app.write_all(b"hello").await.unwrap();
+        assert_eq!(from_local.recv().await.unwrap(), "hello");
+        to_local.send(Bytes::from_static(b"world")).await.unwrap();
+        let mut buf = [0u8; 5];
+        app.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+
+        // Both directions are done once each side has half closed
+        app.shutdown().await.unwrap();
+        assert_eq!(from_local.recv().await.unwrap(), "");
+        to_local.send(Bytes::new()).await.unwrap();
+        relay.await.unwrap();
+        assert_eq!(app.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_scheduler_only_throttles_competing_tunnels() {
+        let scheduler = Arc::new(TunnelScheduler::default());
+        let bulk = scheduler.schedule(TunnelPriority::Bulk);
+        let interactive = scheduler.schedule(TunnelPriority::Interactive);
+
+        // Alone on the link, a bulk tunnel is never delayed
+        tokio::time::timeout(Duration::from_millis(10), async {
+            for _ in 0..1000 {
+                bulk.local_to_remote(MAX_PACKET_LENGTH).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // Once an interactive tunnel competes, bulk waits for it to get its share
+        interactive.local_to_remote(MAX_PACKET_LENGTH).await;
+        bulk.local_to_remote(MAX_PACKET_LENGTH).await;
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), bulk.local_to_remote(MAX_PACKET_LENGTH))
+                .await
+                .is_err()
+        );
+        for _ in 0..12 {
+            interactive.local_to_remote(MAX_PACKET_LENGTH).await;
+        }
+        tokio::time::timeout(Duration::from_millis(10), bulk.local_to_remote(MAX_PACKET_LENGTH))
+            .await
+            .unwrap();
+
+        // The other direction is not affected
+        tokio::time::timeout(Duration::from_millis(10), bulk.remote_to_local(MAX_PACKET_LENGTH))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_write_batching() {
+        let (mut local, local_rx) = tokio::io::duplex(1024);
+        let (ws_tx, mut frames) = mpsc::channel(16);
+        let (close_tx, _close_rx) = oneshot::channel();
+        let batching = WriteBatching {
+            delay: Duration::from_millis(500),
+            max_bytes: 1024,
+        };
+        let tunnel = tokio::spawn(propagate_local_to_remote(
+            local_rx,
+            Http2TunnelWrite::new(ws_tx),
+            close_tx,
+            None,
+            TunnelStats::new(Span::none()),
+            None,
+            Some(batching),
+        ));
+
+        for _ in 0..3 {
+            local.write_all(b"ab").await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        drop(local);
+
+        // Tiny writes are sent in a single frame, and the eof met while batching still closes the tunnel
+        assert_eq!(frames.recv().await.unwrap(), "ababab");
+        tunnel.await.unwrap().unwrap();
+        assert!(frames.recv().await.is_none());
+    }
+}
@@ -0,0 +1,118 @@
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info};
+
+/// How often tunnels ping their peer to keep the connection alive
+#[derive(Clone, Debug)]
+pub enum Keepalive {
+    Fixed(Duration),
+    Adaptive(Arc<AdaptiveKeepalive>),
+}
+
+impl Keepalive {
+    pub fn interval(&self) -> Duration {
+        match self {
+            Keepalive::Fixed(interval) => *interval,
+            Keepalive::Adaptive(adaptive) => adaptive.interval(),
+        }
+    }
+}
+
+/// Ping interval learning the idle timeout of the NATs and proxies on the path to the server, shared by the tunnels.
+/// It starts at the minimum and widens each time an idle connection is still answering after a whole interval.
+/// Once a connection dies at an interval, it shrinks back and never widens up to that interval again
+#[derive(Debug)]
+pub struct AdaptiveKeepalive {
+    min: Duration,
+    max: Duration,
+    state: Mutex<AdaptiveState>,
+}
+
+#[derive(Debug)]
+struct AdaptiveState {
+    interval: Duration,
+    // Shortest interval a connection died at
+    ceiling: Option<Duration>,
+}
+
+impl AdaptiveKeepalive {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max: max.max(min),
+            state: Mutex::new(AdaptiveState {
+                interval: min,
+                ceiling: None,
+            }),
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.state.lock().interval
+    }
+
+    /// An idle connection answered a ping sent after `interval` of silence
+    pub fn survived(&self, interval: Duration) {
+        let mut state = self.state.lock();
+        if interval < state.interval {
+            return;
+        }
+
+        let mut widened = (state.interval * 3 / 2).min(self.max);
+        if let Some(ceiling) = state.ceiling {
+            widened = widened.min(ceiling * 9 / 10).max(state.interval);
+        }
+        if widened != state.interval {
+            debug!("Connection survived {:?} idle, pinging every {:?}", interval, widened);
+            state.interval = widened;
+        }
+    }
+
+    /// An idle connection stopped answering after `interval` of silence, the path forgot it in between
+    pub fn died(&self, interval: Duration) {
+        let mut state = self.state.lock();
+        state.ceiling = Some(state.ceiling.map_or(interval, |ceiling| ceiling.min(interval)));
+        let narrowed = (interval * 2 / 3).max(self.min).min(state.interval);
+        info!(
+            "Connection died after {:?} idle, pinging every {:?} instead of {:?}",
+            interval, narrowed, state.interval
+        );
+        state.interval = narrowed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adaptive_keepalive() {
+        let secs = Duration::from_secs;
+        let keepalive = AdaptiveKeepalive::new(secs(10), secs(60));
+        assert_eq!(keepalive.interval(), secs(10));
+
+        keepalive.survived(secs(10));
+        assert_eq!(keepalive.interval(), secs(15));
+        // Pings sent before the interval widened say nothing about the new one
+        keepalive.survived(secs(10));
+        assert_eq!(keepalive.interval(), secs(15));
+        for _ in 0..10 {
+            keepalive.survived(keepalive.interval());
+        }
+        assert_eq!(keepalive.interval(), secs(60));
+
+        // i.e: a NAT forgetting connections after 40s
+        keepalive.died(secs(60));
+        assert_eq!(keepalive.interval(), secs(40));
+        keepalive.died(secs(40));
+        assert_eq!(keepalive.interval(), secs(40) * 2 / 3);
+        for _ in 0..10 {
+            keepalive.survived(keepalive.interval());
+        }
+        assert_eq!(keepalive.interval(), secs(36));
+
+        keepalive.died(secs(12));
+        assert_eq!(keepalive.interval(), secs(10));
+    }
+}
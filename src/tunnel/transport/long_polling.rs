@@ -0,0 +1,480 @@
+use crate::htpasswd::constant_time_eq;
+use crate::redact::RedactedRequest;
+use crate::totp::TOTP_HEADER;
+use crate::tunnel::transport::close_reason::{CloseReason, RetryAfter};
+use crate::tunnel::transport::http2::Http2TunnelRead;
+use crate::tunnel::transport::{headers_from_file, TunnelRead, TunnelTransport, TunnelWrite, MAX_PACKET_LENGTH};
+use crate::tunnel::{tunnel_to_jwt_token, RemoteAddr};
+use crate::version::{CLIENT_FEATURES, CLIENT_FEATURES_HEADER, CLIENT_VERSION_HEADER};
+use crate::WsClientConfig;
+use ahash::{HashMap, HashMapExt};
+use anyhow::{anyhow, Context};
+use bytes::{Bytes, BytesMut};
+use http_body_util::{BodyExt, BodyStream, Empty, Full};
+use hyper::client::conn::http1::SendRequest;
+use hyper::header::{AUTHORIZATION, CACHE_CONTROL, COOKIE, HOST};
+use hyper::http::response::Parts;
+use hyper::http::{HeaderName, HeaderValue};
+use hyper::{HeaderMap, Method, Request};
+use hyper_util::rt::TokioIo;
+use log::{debug, error};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::io;
+use std::io::ErrorKind;
+use std::ops::DerefMut;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+// Degraded transport for networks where websocket upgrades are blocked.
+// Downstream data is sent in the streamed response of a GET request, upstream data in successive POST requests
+pub static TRANSPORT_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-transport");
+pub static SESSION_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-session");
+// Random token given by the server in the response of the GET request, that the POST requests must carry.
+// The tunnel id is chosen by the client, so it alone does not prove that a POST comes from the client of the tunnel
+pub static PUSH_TOKEN_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-push-token");
+pub static LONG_POLLING: HeaderValue = HeaderValue::from_static("long-polling");
+
+// Upper bound of the body of a POST request, to not let a client buffer too much data on the server
+pub const MAX_PUSH_BODY_SIZE: usize = MAX_PACKET_LENGTH * 64;
+
+// Websocket is tried again after this delay, as the proxy that blocked it may be gone, i.e: after a network change
+const FALLBACK_DURATION: Duration = Duration::from_secs(300);
+
+struct Session {
+    push_token: String,
+    tx: mpsc::Sender<Bytes>,
+}
+
+// tunnel id => upstream data of the tunnel
+static SESSIONS: Lazy<Mutex<HashMap<String, Session>>> = Lazy::new(|| Mutex::new(HashMap::with_capacity(0)));
+
+/// Whether the client uses long polling instead of websocket for its next tunnels, since an upgrade was refused
+#[derive(Default)]
+pub struct LongPollingFallback {
+    since: Mutex<Option<Instant>>,
+}
+
+impl LongPollingFallback {
+    pub fn is_active(&self) -> bool {
+        matches!(*self.since.lock(), Some(since) if since.elapsed() < FALLBACK_DURATION)
+    }
+
+    /// Use long polling for the next tunnels. Returns false if it was already used
+    pub fn activate(&self) -> bool {
+        let mut since = self.since.lock();
+        if matches!(*since, Some(since) if since.elapsed() < FALLBACK_DURATION) {
+            return false;
+        }
+
+        *since = Some(Instant::now());
+        true
+    }
+
+    pub fn reset(&self) {
+        *self.since.lock() = None;
+    }
+}
+
+pub fn is_long_polling_request<B>(req: &Request<B>) -> bool {
+    req.method() == Method::GET && req.headers().get(&TRANSPORT_HEADER) == Some(&LONG_POLLING)
+}
+
+pub fn is_push_request<B>(req: &Request<B>) -> bool {
+    req.method() == Method::POST && req.headers().contains_key(&SESSION_HEADER)
+}
+
+/// Register a new long polling tunnel, and return its push token with the reader of the data pushed by the client.
+/// None if a tunnel with this id already exists, as it would take over the data of the existing tunnel
+pub fn new_session(tunnel_id: &str) -> Option<(String, LongPollingTunnelRead)> {
+    let mut sessions = SESSIONS.lock();
+    if sessions.contains_key(tunnel_id) {
+        return None;
+    }
+
+    let push_token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    let (tx, rx) = mpsc::channel::<Bytes>(64);
+    sessions.insert(
+        tunnel_id.to_string(),
+        Session {
+            push_token: push_token.clone(),
+            tx,
+        },
+    );
+    Some((push_token, LongPollingTunnelRead { inner: rx }))
+}
+
+/// Remove the session, only if it is still the one of this push token and not a newer one with the same id
+pub fn close_session(tunnel_id: &str, push_token: &str) {
+    let mut sessions = SESSIONS.lock();
+    if matches!(sessions.get(tunnel_id), Some(session) if constant_time_eq(session.push_token.as_bytes(), push_token.as_bytes()))
+    {
+        sessions.remove(tunnel_id);
+    }
+}
+
+/// Forward the data of a POST request to its tunnel. An empty body means the client closed its side of the tunnel
+pub async fn push(tunnel_id: &str, push_token: &str, data: Bytes) -> anyhow::Result<()> {
+    let session = match SESSIONS.lock().get(tunnel_id) {
+        Some(session) if constant_time_eq(session.push_token.as_bytes(), push_token.as_bytes()) => session.tx.clone(),
+        Some(_) => return Err(anyhow!("invalid push token for long polling session {}", tunnel_id)),
+        None => return Err(anyhow!("unknown long polling session {}", tunnel_id)),
+    };
+
+    if data.is_empty() {
+        close_session(tunnel_id, push_token);
+        return Ok(());
+    }
+
+    session
+        .send(data)
+        .await
+        .map_err(|_| anyhow!("long polling session {} is closed", tunnel_id))
+}
+
+pub struct LongPollingTunnelRead {
+    inner: mpsc::Receiver<Bytes>,
+}
+
+impl TunnelRead for LongPollingTunnelRead {
+    async fn copy(&mut self, mut writer: impl AsyncWrite + Unpin + Send) -> Result<(), io::Error> {
+        match self.inner.recv().await {
+            Some(data) => match writer.write_all(data.as_ref()).await {
+                Ok(_) => Ok(()),
+                Err(err) => Err(io::Error::new(ErrorKind::ConnectionAborted, err)),
+            },
+            None => Err(io::Error::new(ErrorKind::BrokenPipe, "closed")),
+        }
+    }
+}
+
+pub struct LongPollingTunnelWrite {
+    buf: BytesMut,
+    pool: bb8::Pool<WsClientConfig>,
+    sender: Option<SendRequest<Full<Bytes>>>,
+    path: String,
+    headers: HeaderMap,
+}
+
+impl LongPollingTunnelWrite {
+    async fn sender(&mut self) -> Result<&mut SendRequest<Full<Bytes>>, io::Error> {
+        // Proxies are free to close idle connections, so open a new one if needed.
+        // It is safe as long as no data has been sent on the previous one
+        let is_ready = match &mut self.sender {
+            Some(sender) => sender.ready().await.is_ok(),
+            None => false,
+        };
+
+        if !is_ready {
+            let mut pooled_cnx = self
+                .pool
+                .get()
+                .await
+                .map_err(|err| io::Error::new(ErrorKind::ConnectionAborted, format!("{:?}", err)))?;
+            let transport = pooled_cnx.deref_mut().take().unwrap();
+            let (sender, cnx) = hyper::client::conn::http1::handshake(TokioIo::new(transport))
+                .await
+                .map_err(|err| io::Error::new(ErrorKind::ConnectionAborted, err))?;
+            tokio::spawn(async move {
+                if let Err(err) = cnx.await {
+                    error!("{:?}", err)
+                }
+            });
+            self.sender = Some(sender);
+        }
+
+        Ok(self.sender.as_mut().unwrap())
+    }
+
+    async fn push(&mut self, data: Bytes) -> Result<(), io::Error> {
+        let mut req = Request::builder()
+            .method(Method::POST)
+            .uri(&self.path)
+            .body(Full::new(data))
+            .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+        *req.headers_mut() = self.headers.clone();
+
+        let response = self
+            .sender()
+            .await?
+            .send_request(req)
+            .await
+            .map_err(|err| io::Error::new(ErrorKind::ConnectionAborted, err))?;
+        let status = response.status();
+        // Consume the body, for the connection to be re-usable
+        let _ = response.into_body().collect().await;
+        if !status.is_success() {
+            return Err(io::Error::new(
+                ErrorKind::ConnectionAborted,
+                format!("server refused long polling data with status {}", status),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl TunnelWrite for LongPollingTunnelWrite {
+    fn buf_mut(&mut self) -> &mut BytesMut {
+        &mut self.buf
+    }
+
+    async fn write(&mut self) -> Result<(), io::Error> {
+        let data = self.buf.split().freeze();
+        let ret = self.push(data).await;
+
+        if self.buf.capacity() < MAX_PACKET_LENGTH {
+            self.buf.reserve(MAX_PACKET_LENGTH)
+        }
+
+        ret
+    }
+
+    async fn ping(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    async fn close(&mut self, _reason: CloseReason) -> Result<(), io::Error> {
+        self.push(Bytes::new()).await
+    }
+}
+
+fn add_client_headers(headers: &mut HeaderMap, client_cfg: &WsClientConfig) {
+    if let Some(cookie_jar) = &client_cfg.cookie_jar {
+        cookie_jar.add_cookie_header(headers);
+    }
+    for (k, v) in &client_cfg.http_headers {
+        let _ = headers.remove(k);
+        headers.append(k, v.clone());
+    }
+
+    if let Some(auth) = &client_cfg.http_upgrade_credentials {
+        let _ = headers.remove(AUTHORIZATION);
+        headers.append(AUTHORIZATION, auth.clone());
+    }
+}
+
+fn add_headers_from_file(headers: &mut HeaderMap, client_cfg: &WsClientConfig) {
+    if let Some(headers_file_path) = &client_cfg.http_headers_file {
+        let (host, headers_file) = headers_from_file(headers_file_path);
+        for (k, v) in headers_file {
+            let _ = headers.remove(&k);
+            headers.append(k, v);
+        }
+        if let Some((host, val)) = host {
+            let _ = headers.remove(&host);
+            headers.append(host, val);
+        }
+    }
+}
+
+pub struct LongPollingTransport;
+
+impl TunnelTransport for LongPollingTransport {
+    type Reader = Http2TunnelRead;
+    type Writer = LongPollingTunnelWrite;
+
+    async fn connect(
+        &self,
+        request_id: Uuid,
+        client_cfg: &WsClientConfig,
+        remote_cfg: &RemoteAddr,
+        _reuse: bool,
+    ) -> anyhow::Result<(Http2TunnelRead, LongPollingTunnelWrite, Parts)> {
+        connect(request_id, client_cfg, remote_cfg).await
+    }
+}
+
+pub async fn connect(
+    request_id: Uuid,
+    client_cfg: &WsClientConfig,
+    dest_addr: &RemoteAddr,
+) -> anyhow::Result<(Http2TunnelRead, LongPollingTunnelWrite, Parts)> {
+    let mut pooled_cnx = match client_cfg.cnx_pool().get().await {
+        Ok(cnx) => Ok(cnx),
+        Err(err) => Err(anyhow!("failed to get a connection to the server from the pool: {err:?}")),
+    }?;
+
+    let path = format!("/{}/events", &client_cfg.http_upgrade_path_prefix);
+    let mut req = Request::builder()
+        .method(Method::GET)
+        .uri(&path)
+        .header(HOST, &client_cfg.http_header_host)
+        .header(COOKIE, tunnel_to_jwt_token(request_id, dest_addr))
+        .header(&TRANSPORT_HEADER, LONG_POLLING.clone())
+        .header(CACHE_CONTROL, "no-cache")
+        .header(&CLIENT_VERSION_HEADER, env!("CARGO_PKG_VERSION"))
+        .header(&CLIENT_FEATURES_HEADER, CLIENT_FEATURES.join(","))
+        .version(hyper::Version::HTTP_11);
+
+    let headers = req.headers_mut().unwrap();
+    add_client_headers(headers, client_cfg);
+    if let Some(totp) = &client_cfg.auth_totp {
+        let _ = headers.remove(&TOTP_HEADER);
+        headers.append(&TOTP_HEADER, HeaderValue::from_str(&totp.proof_now(&request_id.to_string()))?);
+    }
+    add_headers_from_file(headers, client_cfg);
+
+    // Upstream requests are identified by the session and its push token, the server authenticates them only with it.
+    // Custom headers and credentials are still sent, for a proxy or CDN in front of the server
+    let mut push_headers = HeaderMap::new();
+    push_headers.insert(HOST, client_cfg.http_header_host.clone());
+    push_headers.insert(&SESSION_HEADER, HeaderValue::from_str(&request_id.to_string())?);
+    push_headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    push_headers.insert(&CLIENT_VERSION_HEADER, HeaderValue::from_static(env!("CARGO_PKG_VERSION")));
+    push_headers.insert(&CLIENT_FEATURES_HEADER, HeaderValue::from_str(&CLIENT_FEATURES.join(","))?);
+    add_client_headers(&mut push_headers, client_cfg);
+    add_headers_from_file(&mut push_headers, client_cfg);
+
+    let req = req.body(Empty::<Bytes>::new()).with_context(|| {
+        format!(
+            "failed to build HTTP request to contact the server {:?}",
+            client_cfg.remote_addr
+        )
+    })?;
+    debug!("with HTTP long polling request {:?}", RedactedRequest(&req));
+    let transport = pooled_cnx.deref_mut().take().unwrap();
+    let (mut request_sender, cnx) = hyper::client::conn::http1::handshake(TokioIo::new(transport))
+        .await
+        .with_context(|| format!("failed to do http handshake with the server {:?}", client_cfg.remote_addr))?;
+    tokio::spawn(async move {
+        if let Err(err) = cnx.await {
+            error!("{:?}", err)
+        }
+    });
+
+    let response = request_sender
+        .send_request(req)
+        .await
+        .with_context(|| format!("failed to send long polling request to the server {:?}", client_cfg.remote_addr))?;
+    if let Some(cookie_jar) = &client_cfg.cookie_jar {
+        cookie_jar.store(response.headers());
+    }
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let reason = CloseReason::from_response(status, response.headers());
+        let retry = RetryAfter::from_response(status, response.headers());
+        let body = String::from_utf8(response.into_body().collect().await?.to_bytes().to_vec()).unwrap_or_default();
+        let err = anyhow::Error::new(reason)
+            .context(format!("Server rejected the long polling connection: {:?}: {}", status, body));
+        return Err(match retry {
+            Some(retry) => err.context(retry),
+            None => err,
+        });
+    }
+
+    let (parts, body) = response.into_parts();
+    let Some(push_token) = parts.headers.get(&PUSH_TOKEN_HEADER) else {
+        return Err(anyhow!("server did not give a push token for the long polling connection"));
+    };
+    push_headers.insert(&PUSH_TOKEN_HEADER, push_token.clone());
+    let tunnel_write = LongPollingTunnelWrite {
+        buf: BytesMut::with_capacity(MAX_PACKET_LENGTH * 16),
+        pool: client_cfg.cnx_pool().clone(),
+        sender: None,
+        path,
+        headers: push_headers,
+    };
+    Ok((Http2TunnelRead::new(BodyStream::new(body)), tunnel_write, parts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::LocalServer;
+    use crate::tunnel::{self, TunnelPriority};
+    use crate::LocalProtocol;
+    use std::net::Ipv4Addr;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+    use url::Host;
+
+    #[tokio::test]
+    async fn test_push_to_session() {
+        let (token, mut session) = new_session("test-session").unwrap();
+        assert!(new_session("test-session").is_none());
+        push("test-session", &token, Bytes::from_static(b"hello"))
+            .await
+            .unwrap();
+
+        let mut buf = Vec::new();
+        session.copy(&mut buf).await.unwrap();
+        assert_eq!(buf, b"hello");
+
+        // Without the token of the session, data cannot be pushed and the session cannot be closed
+        assert!(push("test-session", "guessed", Bytes::from_static(b"hello"))
+            .await
+            .is_err());
+        close_session("test-session", "guessed");
+        assert!(push("test-session", "guessed", Bytes::new()).await.is_err());
+
+        // An empty push closes the session
+        push("test-session", &token, Bytes::new()).await.unwrap();
+        assert!(session.copy(&mut buf).await.is_err());
+        assert!(push("test-session", &token, Bytes::from_static(b"hello"))
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_fallback_expires() {
+        let fallback = LongPollingFallback::default();
+        assert!(!fallback.is_active());
+        assert!(fallback.activate());
+        assert!(fallback.is_active());
+        assert!(!fallback.activate());
+
+        *fallback.since.lock() = Some(Instant::now() - FALLBACK_DURATION);
+        assert!(!fallback.is_active());
+        assert!(fallback.activate());
+        fallback.reset();
+        assert!(!fallback.is_active());
+    }
+
+    #[tokio::test]
+    async fn test_long_polling_tunnel() {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let (mut rx, mut tx) = stream.split();
+            let _ = tokio::io::copy(&mut rx, &mut tx).await;
+        });
+
+        let server = LocalServer::start("ws", []).await.unwrap();
+        let client = server.client([]).await.unwrap();
+        client.long_polling_fallback.activate();
+        let remote = RemoteAddr {
+            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            host: Host::Ipv4(Ipv4Addr::LOCALHOST),
+            port: echo_port,
+            fallbacks: vec![],
+        };
+        let (mut local, tunnel) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            tunnel::client::connect_to_server(
+                Uuid::now_v7(),
+                &client,
+                &remote,
+                TunnelPriority::Normal,
+                None,
+                None,
+                None,
+                tokio::io::split(tunnel),
+            )
+            .await
+        });
+
+        // Data sent by the client goes through POST requests carrying the push token
+        local.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        tokio::time::timeout(Duration::from_secs(5), local.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+}
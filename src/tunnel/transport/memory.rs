@@ -0,0 +1,228 @@
+use crate::totp::TOTP_HEADER;
+use crate::tunnel::transport::close_reason::{CloseReason, RetryAfter};
+use crate::tunnel::transport::{
+    TunnelRead, TunnelReader, TunnelTransport, TunnelWrite, TunnelWriter, MAX_PACKET_LENGTH,
+};
+use crate::tunnel::{tunnel_to_jwt_token, RemoteAddr};
+use crate::version::CLIENT_VERSION_HEADER;
+use crate::WsClientConfig;
+use anyhow::{anyhow, Context};
+use bytes::{Bytes, BytesMut};
+use hyper::header::{AUTHORIZATION, COOKIE};
+use hyper::http::response::Parts;
+use hyper::http::HeaderValue;
+use hyper::{Request, Response};
+use std::io;
+use std::io::ErrorKind;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+use uuid::Uuid;
+
+// Each message is one write of the peer, there is no framing to do as they are never split
+enum Message {
+    Data(Bytes),
+    HalfClose,
+    Ack(u64),
+    Close(CloseReason),
+}
+
+pub struct MemoryTunnelRead {
+    inner: mpsc::Receiver<Message>,
+    ack: Option<u64>,
+}
+
+impl TunnelRead for MemoryTunnelRead {
+    async fn copy(&mut self, mut writer: impl AsyncWrite + Unpin + Send) -> Result<(), io::Error> {
+        let ret = match self.inner.recv().await {
+            Some(Message::Data(data)) => writer.write_all(&data).await,
+            Some(Message::HalfClose) => writer.shutdown().await,
+            Some(Message::Ack(received)) => {
+                self.ack = Some(received);
+                return Ok(());
+            }
+            Some(Message::Close(CloseReason::Normal)) | None => {
+                return Err(io::Error::new(ErrorKind::NotConnected, "in-memory tunnel closed"))
+            }
+            Some(Message::Close(reason)) => return Err(reason.to_io_error()),
+        };
+
+        ret.map_err(|err| io::Error::new(ErrorKind::ConnectionAborted, err))
+    }
+
+    fn take_ack(&mut self) -> Option<u64> {
+        self.ack.take()
+    }
+}
+
+pub struct MemoryTunnelWrite {
+    inner: mpsc::Sender<Message>,
+    buf: BytesMut,
+}
+
+impl MemoryTunnelWrite {
+    async fn send(&self, msg: Message) -> Result<(), io::Error> {
+        self.inner
+            .send(msg)
+            .await
+            .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "in-memory tunnel closed"))
+    }
+}
+
+impl TunnelWrite for MemoryTunnelWrite {
+    fn buf_mut(&mut self) -> &mut BytesMut {
+        &mut self.buf
+    }
+
+    async fn write(&mut self) -> Result<(), io::Error> {
+        let data = self.buf.split().freeze();
+        if self.buf.capacity() < MAX_PACKET_LENGTH {
+            self.buf.reserve(MAX_PACKET_LENGTH)
+        }
+
+        self.send(Message::Data(data)).await
+    }
+
+    // Both ends are in the same process, there is no connection to keep alive
+    async fn ping(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    async fn close(&mut self, reason: CloseReason) -> Result<(), io::Error> {
+        self.send(Message::Close(reason)).await
+    }
+
+    async fn shutdown_write(&mut self) -> Result<(), io::Error> {
+        self.send(Message::HalfClose).await
+    }
+
+    async fn ack(&mut self, received: u64) -> Result<(), io::Error> {
+        self.send(Message::Ack(received)).await
+    }
+}
+
+impl From<MemoryTunnelRead> for TunnelReader {
+    fn from(reader: MemoryTunnelRead) -> Self {
+        TunnelReader::Boxed(Box::new(reader))
+    }
+}
+
+impl From<MemoryTunnelWrite> for TunnelWriter {
+    fn from(writer: MemoryTunnelWrite) -> Self {
+        TunnelWriter::Boxed(Box::new(writer))
+    }
+}
+
+/// Both ends of a tunnel, what is written to one is read from the other
+pub fn tunnel_pair() -> ((MemoryTunnelRead, MemoryTunnelWrite), (MemoryTunnelRead, MemoryTunnelWrite)) {
+    let half = |(tx, rx): (mpsc::Sender<Message>, mpsc::Receiver<Message>)| {
+        let reader = MemoryTunnelRead { inner: rx, ack: None };
+        let writer = MemoryTunnelWrite {
+            inner: tx,
+            buf: BytesMut::with_capacity(MAX_PACKET_LENGTH),
+        };
+        (reader, writer)
+    };
+    let (a_rx, b_tx) = half(mpsc::channel(64));
+    let (b_rx, a_tx) = half(mpsc::channel(64));
+    ((a_rx, a_tx), (b_rx, b_tx))
+}
+
+/// Tunnel opened by a client of the in-memory transport, for the server to serve it with server::serve_tunnel.
+/// The request has the same headers as the http2 one, the response to it is sent back with `response`
+pub struct MemoryConnection {
+    pub request: Request<()>,
+    pub tunnel: (MemoryTunnelRead, MemoryTunnelWrite),
+    pub response: oneshot::Sender<Response<String>>,
+}
+
+/// Transport of the tunnels over channels, to a server in the same process, i.e: for tests.
+/// The server receives them from the receiver returned along with it, with server::serve_memory
+#[derive(Clone)]
+pub struct MemoryTransport {
+    server: mpsc::Sender<MemoryConnection>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> (Self, mpsc::Receiver<MemoryConnection>) {
+        let (server, connections) = mpsc::channel(64);
+        (Self { server }, connections)
+    }
+}
+
+fn tunnel_request(
+    request_id: Uuid,
+    client_cfg: &WsClientConfig,
+    dest_addr: &RemoteAddr,
+) -> anyhow::Result<Request<()>> {
+    let mut req = Request::builder()
+        .method("POST")
+        .uri(format!("/{}/events", &client_cfg.http_upgrade_path_prefix))
+        .header(COOKIE, tunnel_to_jwt_token(request_id, dest_addr))
+        .header(&CLIENT_VERSION_HEADER, env!("CARGO_PKG_VERSION"));
+
+    let headers = req.headers_mut().unwrap();
+    for (k, v) in &client_cfg.http_headers {
+        let _ = headers.remove(k);
+        headers.append(k, v.clone());
+    }
+
+    if let Some(auth) = &client_cfg.http_upgrade_credentials {
+        let _ = headers.remove(AUTHORIZATION);
+        headers.append(AUTHORIZATION, auth.clone());
+    }
+
+    if let Some(totp) = &client_cfg.auth_totp {
+        let _ = headers.remove(&TOTP_HEADER);
+        headers.append(&TOTP_HEADER, HeaderValue::from_str(&totp.proof_now(&request_id.to_string()))?);
+    }
+
+    req.body(())
+        .context("failed to build the request of an in-memory tunnel")
+}
+
+impl TunnelTransport for MemoryTransport {
+    type Reader = MemoryTunnelRead;
+    type Writer = MemoryTunnelWrite;
+
+    async fn connect(
+        &self,
+        request_id: Uuid,
+        client_cfg: &WsClientConfig,
+        remote_cfg: &RemoteAddr,
+        _reuse: bool,
+    ) -> anyhow::Result<(MemoryTunnelRead, MemoryTunnelWrite, Parts)> {
+        let request = tunnel_request(request_id, client_cfg, remote_cfg)?;
+        let (tunnel, server_tunnel) = tunnel_pair();
+        let (response_tx, response_rx) = oneshot::channel();
+        let cnx = MemoryConnection {
+            request,
+            tunnel: server_tunnel,
+            response: response_tx,
+        };
+        self.server
+            .send(cnx)
+            .await
+            .map_err(|_| anyhow!("in-memory server is stopped"))?;
+        let response = response_rx
+            .await
+            .context("in-memory server dropped the tunnel without answering")?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let reason = CloseReason::from_response(status, response.headers());
+            let retry = RetryAfter::from_response(status, response.headers());
+            let err = anyhow::Error::new(reason).context(format!(
+                "In-memory server rejected the tunnel: {:?}: {}",
+                status,
+                response.body()
+            ));
+            return Err(match retry {
+                Some(retry) => err.context(retry),
+                None => err,
+            });
+        }
+
+        let (reader, writer) = tunnel;
+        Ok((reader, writer, response.into_parts().0))
+    }
+}
@@ -1,17 +1,29 @@
+use crate::tunnel::transport::close_reason::CloseReason;
 use crate::tunnel::transport::http2::{Http2TunnelRead, Http2TunnelWrite};
+use crate::tunnel::transport::long_polling::{LongPollingTunnelRead, LongPollingTunnelWrite};
 use crate::tunnel::transport::websocket::{WebsocketTunnelRead, WebsocketTunnelWrite};
+use crate::tunnel::RemoteAddr;
+use crate::WsClientConfig;
 use bytes::BytesMut;
+use hyper::http::response::Parts;
 use hyper::http::{HeaderName, HeaderValue};
 use std::future::Future;
 use std::io::{BufRead, BufReader};
 use std::path::Path;
 use std::str::FromStr;
+use std::time::Duration;
 
 use tokio::io::AsyncWrite;
 use tracing::error;
+use uuid::Uuid;
 
+pub mod boxed;
+pub mod close_reason;
 pub mod http2;
 pub mod io;
+pub mod keepalive;
+pub mod long_polling;
+pub mod memory;
 pub mod websocket;
 
 static MAX_PACKET_LENGTH: usize = 64 * 1024;
@@ -20,7 +32,27 @@ pub trait TunnelWrite: Send + 'static {
     fn buf_mut(&mut self) -> &mut BytesMut;
     fn write(&mut self) -> impl Future<Output = Result<(), std::io::Error>> + Send;
     fn ping(&mut self) -> impl Future<Output = Result<(), std::io::Error>> + Send;
-    fn close(&mut self) -> impl Future<Output = Result<(), std::io::Error>> + Send;
+    fn close(&mut self, reason: CloseReason) -> impl Future<Output = Result<(), std::io::Error>> + Send;
+
+    /// Signal the end of the stream to the peer, while still being able to receive data from it
+    fn shutdown_write(&mut self) -> impl Future<Output = Result<(), std::io::Error>> + Send {
+        async {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "half-close is not supported by this transport",
+            ))
+        }
+    }
+
+    /// Acknowledge the bytes received from the peer, for it to drop them from its retransmission buffer
+    fn ack(&mut self, _received: u64) -> impl Future<Output = Result<(), std::io::Error>> + Send {
+        async {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "acknowledgements are not supported by this transport",
+            ))
+        }
+    }
 }
 
 pub trait TunnelRead: Send + 'static {
@@ -28,25 +60,96 @@ pub trait TunnelRead: Send + 'static {
         &mut self,
         writer: impl AsyncWrite + Unpin + Send,
     ) -> impl Future<Output = Result<(), std::io::Error>> + Send;
+
+    /// Round trip time of the last ping answered by the peer, if not already taken
+    fn take_rtt(&mut self) -> Option<Duration> {
+        None
+    }
+
+    /// Bytes the peer acknowledged receiving in its last acknowledgement, if not already taken
+    fn take_ack(&mut self) -> Option<u64> {
+        None
+    }
+}
+
+/// Way of carrying the tunnels of the client to the server. The tunnel logic of the client only deals with the
+/// TunnelRead and TunnelWrite halves it returns. Built-in transports are picked from the scheme in
+/// client::connect_transport, any other one is used by setting WsClientConfig::transport, its halves being boxed.
+/// On the server, server::serve_tunnel serves the halves of any transport once the request is accepted
+pub trait TunnelTransport: Send + Sync {
+    type Reader: TunnelRead + Into<TunnelReader>;
+    type Writer: TunnelWrite + Into<TunnelWriter>;
+
+    /// Open a tunnel toward `remote_cfg`, along with the response of the server to the upgrade request.
+    /// With `reuse`, the server is asked to keep the transport open for the next connections, if it supports it
+    fn connect(
+        &self,
+        request_id: Uuid,
+        client_cfg: &WsClientConfig,
+        remote_cfg: &RemoteAddr,
+        reuse: bool,
+    ) -> impl Future<Output = anyhow::Result<(Self::Reader, Self::Writer, Parts)>> + Send;
 }
 
 pub enum TunnelReader {
     Websocket(WebsocketTunnelRead),
     Http2(Http2TunnelRead),
+    LongPolling(LongPollingTunnelRead),
+    Boxed(Box<dyn boxed::DynTunnelRead>),
 }
 
 impl TunnelRead for TunnelReader {
-    async fn copy(&mut self, writer: impl AsyncWrite + Unpin + Send) -> Result<(), std::io::Error> {
+    async fn copy(&mut self, mut writer: impl AsyncWrite + Unpin + Send) -> Result<(), std::io::Error> {
         match self {
             TunnelReader::Websocket(s) => s.copy(writer).await,
             TunnelReader::Http2(s) => s.copy(writer).await,
+            TunnelReader::LongPolling(s) => s.copy(writer).await,
+            TunnelReader::Boxed(s) => (**s).copy(&mut writer).await,
+        }
+    }
+
+    fn take_rtt(&mut self) -> Option<Duration> {
+        match self {
+            TunnelReader::Websocket(s) => s.take_rtt(),
+            TunnelReader::Http2(s) => s.take_rtt(),
+            TunnelReader::LongPolling(s) => s.take_rtt(),
+            TunnelReader::Boxed(s) => (**s).take_rtt(),
+        }
+    }
+
+    fn take_ack(&mut self) -> Option<u64> {
+        match self {
+            TunnelReader::Websocket(s) => s.take_ack(),
+            TunnelReader::Http2(s) => s.take_ack(),
+            TunnelReader::LongPolling(s) => s.take_ack(),
+            TunnelReader::Boxed(s) => (**s).take_ack(),
         }
     }
 }
 
+impl From<WebsocketTunnelRead> for TunnelReader {
+    fn from(reader: WebsocketTunnelRead) -> Self {
+        TunnelReader::Websocket(reader)
+    }
+}
+
+impl From<Http2TunnelRead> for TunnelReader {
+    fn from(reader: Http2TunnelRead) -> Self {
+        TunnelReader::Http2(reader)
+    }
+}
+
+impl From<LongPollingTunnelRead> for TunnelReader {
+    fn from(reader: LongPollingTunnelRead) -> Self {
+        TunnelReader::LongPolling(reader)
+    }
+}
+
 pub enum TunnelWriter {
     Websocket(WebsocketTunnelWrite),
     Http2(Http2TunnelWrite),
+    LongPolling(LongPollingTunnelWrite),
+    Boxed(Box<dyn boxed::DynTunnelWrite>),
 }
 
 impl TunnelWrite for TunnelWriter {
@@ -54,6 +157,8 @@ impl TunnelWrite for TunnelWriter {
         match self {
             TunnelWriter::Websocket(s) => s.buf_mut(),
             TunnelWriter::Http2(s) => s.buf_mut(),
+            TunnelWriter::LongPolling(s) => s.buf_mut(),
+            TunnelWriter::Boxed(s) => (**s).buf_mut(),
         }
     }
 
@@ -61,6 +166,8 @@ impl TunnelWrite for TunnelWriter {
         match self {
             TunnelWriter::Websocket(s) => s.write().await,
             TunnelWriter::Http2(s) => s.write().await,
+            TunnelWriter::LongPolling(s) => s.write().await,
+            TunnelWriter::Boxed(s) => (**s).write().await,
         }
     }
 
@@ -68,15 +175,55 @@ impl TunnelWrite for TunnelWriter {
         match self {
             TunnelWriter::Websocket(s) => s.ping().await,
             TunnelWriter::Http2(s) => s.ping().await,
+            TunnelWriter::LongPolling(s) => s.ping().await,
+            TunnelWriter::Boxed(s) => (**s).ping().await,
         }
     }
 
-    async fn close(&mut self) -> Result<(), std::io::Error> {
+    async fn close(&mut self, reason: CloseReason) -> Result<(), std::io::Error> {
         match self {
-            TunnelWriter::Websocket(s) => s.close().await,
-            TunnelWriter::Http2(s) => s.close().await,
+            TunnelWriter::Websocket(s) => s.close(reason).await,
+            TunnelWriter::Http2(s) => s.close(reason).await,
+            TunnelWriter::LongPolling(s) => s.close(reason).await,
+            TunnelWriter::Boxed(s) => (**s).close(reason).await,
         }
     }
+
+    async fn shutdown_write(&mut self) -> Result<(), std::io::Error> {
+        match self {
+            TunnelWriter::Websocket(s) => s.shutdown_write().await,
+            TunnelWriter::Http2(s) => s.shutdown_write().await,
+            TunnelWriter::LongPolling(s) => s.shutdown_write().await,
+            TunnelWriter::Boxed(s) => (**s).shutdown_write().await,
+        }
+    }
+
+    async fn ack(&mut self, received: u64) -> Result<(), std::io::Error> {
+        match self {
+            TunnelWriter::Websocket(s) => s.ack(received).await,
+            TunnelWriter::Http2(s) => s.ack(received).await,
+            TunnelWriter::LongPolling(s) => s.ack(received).await,
+            TunnelWriter::Boxed(s) => (**s).ack(received).await,
+        }
+    }
+}
+
+impl From<WebsocketTunnelWrite> for TunnelWriter {
+    fn from(writer: WebsocketTunnelWrite) -> Self {
+        TunnelWriter::Websocket(writer)
+    }
+}
+
+impl From<Http2TunnelWrite> for TunnelWriter {
+    fn from(writer: Http2TunnelWrite) -> Self {
+        TunnelWriter::Http2(writer)
+    }
+}
+
+impl From<LongPollingTunnelWrite> for TunnelWriter {
+    fn from(writer: LongPollingTunnelWrite) -> Self {
+        TunnelWriter::LongPolling(writer)
+    }
 }
 
 #[allow(clippy::type_complexity)]
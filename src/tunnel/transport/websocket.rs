@@ -1,39 +1,113 @@
-use crate::tunnel::transport::{headers_from_file, TunnelRead, TunnelWrite, MAX_PACKET_LENGTH};
-use crate::tunnel::{tunnel_to_jwt_token, RemoteAddr, JWT_HEADER_PREFIX};
-use crate::WsClientConfig;
+use crate::redact::RedactedRequest;
+use crate::tls::{self, TlsClientOptions};
+use crate::totp::TOTP_HEADER;
+use crate::tunnel::bond::{self, BOND_HEADER};
+use crate::tunnel::resume::{self, RESUME_HEADER, SESSION_HEADER};
+use crate::tunnel::transport::close_reason::{CloseReason, RetryAfter};
+use crate::tunnel::transport::{headers_from_file, TunnelRead, TunnelTransport, TunnelWrite, MAX_PACKET_LENGTH};
+use crate::tunnel::{
+    headers_to_protocol, tunnel_to_jwt_token, RemoteAddr, TransportAddr, TransportScheme, TransportStream,
+    JWT_HEADER_PREFIX,
+};
+use crate::version::{
+    peer_features, CLIENT_FEATURES, CLIENT_FEATURES_HEADER, CLIENT_VERSION_HEADER, FEATURE_BOND, FEATURE_HALF_CLOSE,
+    FEATURE_REUSE,
+};
+use crate::{TlsClientConfig, WsClientConfig};
 use anyhow::{anyhow, Context};
+use bb8::ManageConnection;
 use bytes::{Bytes, BytesMut};
-use fastwebsockets::{Frame, OpCode, Payload, WebSocketRead, WebSocketWrite};
-use http_body_util::Empty;
+use fastwebsockets::{Frame, OpCode, Payload, Role, WebSocket, WebSocketError, WebSocketRead, WebSocketWrite};
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Incoming;
+use hyper::header::{
+    ACCEPT, ACCEPT_ENCODING, ACCEPT_LANGUAGE, CACHE_CONTROL, ORIGIN, PRAGMA, SEC_WEBSOCKET_EXTENSIONS, USER_AGENT,
+};
 use hyper::header::{AUTHORIZATION, SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION, UPGRADE};
-use hyper::header::{CONNECTION, HOST, SEC_WEBSOCKET_KEY};
+use hyper::header::{CONNECTION, HOST, LOCATION, SEC_WEBSOCKET_KEY};
+use hyper::http::request;
 use hyper::http::response::Parts;
+use hyper::http::{HeaderMap, HeaderValue};
 use hyper::upgrade::Upgraded;
-use hyper::Request;
-use hyper_util::rt::TokioExecutor;
+use hyper::{Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
-use log::debug;
+use log::{debug, info, warn};
+use once_cell::sync::Lazy;
 use std::io;
 use std::io::ErrorKind;
 use std::ops::DerefMut;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::Mutex;
 use tracing::trace;
+use url::Url;
 use uuid::Uuid;
 
+const BROWSER_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:131.0) Gecko/20100101 Firefox/131.0";
+
+// Shared with the read half, for it to answer the pings of the peer
+type SharedWebSocketWrite = Arc<Mutex<WebSocketWrite<WriteHalf<TokioIo<Upgraded>>>>>;
+
 pub struct WebsocketTunnelWrite {
-    inner: WebSocketWrite<WriteHalf<TokioIo<Upgraded>>>,
+    inner: SharedWebSocketWrite,
     buf: BytesMut,
+    // Whether the peer understands half-close, older versions only expect a full close
+    half_close: bool,
 }
 
 impl WebsocketTunnelWrite {
-    pub fn new(ws: WebSocketWrite<WriteHalf<TokioIo<Upgraded>>>) -> Self {
+    pub fn new(ws: WebSocketWrite<WriteHalf<TokioIo<Upgraded>>>, half_close: bool) -> Self {
         Self {
-            inner: ws,
+            inner: Arc::new(Mutex::new(ws)),
             buf: BytesMut::with_capacity(MAX_PACKET_LENGTH),
+            half_close,
         }
     }
 }
 
+static STARTED_AT: Lazy<Instant> = Lazy::new(Instant::now);
+
+// Pings carry the time they are sent at, that the peer echoes back in its pong
+fn ping_payload() -> [u8; 8] {
+    (STARTED_AT.elapsed().as_micros() as u64).to_be_bytes()
+}
+
+fn rtt_from_pong(payload: &[u8]) -> Option<Duration> {
+    let sent_at = u64::from_be_bytes(payload.try_into().ok()?);
+    let now = STARTED_AT.elapsed().as_micros() as u64;
+    now.checked_sub(sent_at).map(Duration::from_micros)
+}
+
+// Sent by the client on a reused websocket, for the server to connect again to the destination
+const NEXT_CONNECTION: &[u8] = b"next";
+// Sent by resumable tunnels with the bytes they received, i.e: "ack 65536". Data is never sent in text frames
+const ACK_PREFIX: &[u8] = b"ack ";
+
+fn parse_ack(payload: &[u8]) -> Option<u64> {
+    std::str::from_utf8(payload.strip_prefix(ACK_PREFIX)?)
+        .ok()?
+        .parse()
+        .ok()
+}
+
+impl WebsocketTunnelWrite {
+    /// Start a new connection on a websocket kept after the previous one
+    pub async fn next_connection(&mut self) -> Result<(), io::Error> {
+        if let Err(err) = self
+            .inner
+            .lock()
+            .await
+            .write_frame(Frame::text(Payload::Borrowed(NEXT_CONNECTION)))
+            .await
+        {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, err));
+        }
+
+        Ok(())
+    }
+}
+
 impl TunnelWrite for WebsocketTunnelWrite {
     fn buf_mut(&mut self) -> &mut BytesMut {
         &mut self.buf
@@ -45,6 +119,8 @@ impl TunnelWrite for WebsocketTunnelWrite {
 
         let ret = self
             .inner
+            .lock()
+            .await
             .write_frame(Frame::binary(Payload::BorrowedMut(&mut buf[..read_len])))
             .await;
 
@@ -74,7 +150,23 @@ impl TunnelWrite for WebsocketTunnelWrite {
     async fn ping(&mut self) -> Result<(), io::Error> {
         if let Err(err) = self
             .inner
-            .write_frame(Frame::new(true, OpCode::Ping, None, Payload::BorrowedMut(&mut [])))
+            .lock()
+            .await
+            .write_frame(Frame::new(true, OpCode::Ping, None, Payload::BorrowedMut(&mut ping_payload())))
+            .await
+        {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, err));
+        }
+
+        Ok(())
+    }
+
+    async fn close(&mut self, reason: CloseReason) -> Result<(), io::Error> {
+        if let Err(err) = self
+            .inner
+            .lock()
+            .await
+            .write_frame(Frame::close(reason.code(), reason.as_str().as_bytes()))
             .await
         {
             return Err(io::Error::new(ErrorKind::BrokenPipe, err));
@@ -83,8 +175,34 @@ impl TunnelWrite for WebsocketTunnelWrite {
         Ok(())
     }
 
-    async fn close(&mut self) -> Result<(), io::Error> {
-        if let Err(err) = self.inner.write_frame(Frame::close(1000, &[])).await {
+    // Half-close is signaled with an empty binary frame, data frames are never empty
+    async fn shutdown_write(&mut self) -> Result<(), io::Error> {
+        if !self.half_close {
+            return Err(io::Error::new(ErrorKind::Unsupported, "peer does not support half-close"));
+        }
+
+        if let Err(err) = self
+            .inner
+            .lock()
+            .await
+            .write_frame(Frame::binary(Payload::BorrowedMut(&mut [])))
+            .await
+        {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, err));
+        }
+
+        Ok(())
+    }
+
+    async fn ack(&mut self, received: u64) -> Result<(), io::Error> {
+        let payload = [ACK_PREFIX, received.to_string().as_bytes()].concat();
+        if let Err(err) = self
+            .inner
+            .lock()
+            .await
+            .write_frame(Frame::text(Payload::Owned(payload)))
+            .await
+        {
             return Err(io::Error::new(ErrorKind::BrokenPipe, err));
         }
 
@@ -94,68 +212,175 @@ impl TunnelWrite for WebsocketTunnelWrite {
 
 pub struct WebsocketTunnelRead {
     inner: WebSocketRead<ReadHalf<TokioIo<Upgraded>>>,
+    pong_tx: SharedWebSocketWrite,
+    rtt: Option<Duration>,
+    ack: Option<u64>,
 }
 
 impl WebsocketTunnelRead {
-    pub fn new(ws: WebSocketRead<ReadHalf<TokioIo<Upgraded>>>) -> Self {
-        Self { inner: ws }
+    pub fn new(ws: WebSocketRead<ReadHalf<TokioIo<Upgraded>>>, ws_tx: &WebsocketTunnelWrite) -> Self {
+        Self {
+            inner: ws,
+            pong_tx: ws_tx.inner.clone(),
+            rtt: None,
+            ack: None,
+        }
+    }
+
+    // Pongs are sent from their own task, for reading to never wait on a write half blocked by the peer
+    fn frame_writer(&self) -> impl FnMut(Frame<'_>) -> futures_util::future::Ready<anyhow::Result<()>> {
+        let pong_tx = self.pong_tx.clone();
+        move |frame: Frame<'_>| {
+            if frame.opcode == OpCode::Pong {
+                let payload = frame.payload.to_vec();
+                let pong_tx = pong_tx.clone();
+                tokio::spawn(async move {
+                    let _ = pong_tx
+                        .lock()
+                        .await
+                        .write_frame(Frame::pong(Payload::Owned(payload)))
+                        .await;
+                });
+            }
+            futures_util::future::ready(anyhow::Ok(()))
+        }
     }
 }
 
-fn frame_reader(_: Frame<'_>) -> futures_util::future::Ready<anyhow::Result<()>> {
-    //error!("frame {:?} {:?}", x.opcode, x.payload);
-    futures_util::future::ready(anyhow::Ok(()))
+impl WebsocketTunnelRead {
+    /// Wait for the client to start a new connection on this websocket, or to close it
+    pub async fn wait_next_connection(&mut self) -> Result<(), io::Error> {
+        let mut frame_writer = self.frame_writer();
+        loop {
+            let msg = match self.inner.read_frame(&mut frame_writer).await {
+                Ok(msg) => msg,
+                Err(err) => return Err(io::Error::new(ErrorKind::ConnectionAborted, err)),
+            };
+
+            match msg.opcode {
+                OpCode::Text if msg.payload.as_ref() == NEXT_CONNECTION => return Ok(()),
+                OpCode::Ping | OpCode::Pong => continue,
+                OpCode::Close => return Err(io::Error::new(ErrorKind::NotConnected, "websocket close")),
+                opcode => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("unexpected {:?} frame while waiting for the next connection", opcode),
+                    ))
+                }
+            }
+        }
+    }
 }
 
 impl TunnelRead for WebsocketTunnelRead {
     async fn copy(&mut self, mut writer: impl AsyncWrite + Unpin + Send) -> Result<(), io::Error> {
+        let mut frame_writer = self.frame_writer();
         loop {
-            let msg = match self.inner.read_frame(&mut frame_reader).await {
+            let msg = match self.inner.read_frame(&mut frame_writer).await {
                 Ok(msg) => msg,
                 Err(err) => return Err(io::Error::new(ErrorKind::ConnectionAborted, err)),
             };
 
             trace!("receive ws frame {:?} {:?}", msg.opcode, msg.payload);
             match msg.opcode {
+                OpCode::Binary if msg.payload.as_ref().is_empty() => {
+                    return match writer.shutdown().await {
+                        Ok(_) => Ok(()),
+                        Err(err) => Err(io::Error::new(ErrorKind::ConnectionAborted, err)),
+                    }
+                }
+                OpCode::Text if msg.payload.starts_with(ACK_PREFIX) => {
+                    self.ack = parse_ack(msg.payload.as_ref());
+                    return Ok(());
+                }
                 OpCode::Continuation | OpCode::Text | OpCode::Binary => {
                     return match writer.write_all(msg.payload.as_ref()).await {
                         Ok(_) => Ok(()),
                         Err(err) => Err(io::Error::new(ErrorKind::ConnectionAborted, err)),
                     }
                 }
-                OpCode::Close => return Err(io::Error::new(ErrorKind::NotConnected, "websocket close")),
+                OpCode::Close => {
+                    // Payload of a close frame is the close code, followed by an optional utf-8 reason
+                    let reason = match msg.payload.as_ref() {
+                        [hi, lo, ..] => CloseReason::from_code(u16::from_be_bytes([*hi, *lo])),
+                        _ => CloseReason::Normal,
+                    };
+                    return match reason {
+                        CloseReason::Normal => Err(io::Error::new(ErrorKind::NotConnected, "websocket close")),
+                        reason => Err(reason.to_io_error()),
+                    };
+                }
                 OpCode::Ping => continue,
-                OpCode::Pong => continue,
+                OpCode::Pong => {
+                    self.rtt = rtt_from_pong(msg.payload.as_ref());
+                    return Ok(());
+                }
             };
         }
     }
+
+    fn take_rtt(&mut self) -> Option<Duration> {
+        self.rtt.take()
+    }
+
+    fn take_ack(&mut self) -> Option<u64> {
+        self.ack.take()
+    }
 }
 
-pub async fn connect(
+/// Headers of Firefox opening a websocket from a page of the server, in the order it sends them
+fn browser_upgrade_request(req: request::Builder, client_cfg: &WsClientConfig, protocol: String) -> request::Builder {
+    let scheme = if client_cfg.remote_addr.tls().is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    let origin = format!("{}://{}", scheme, client_cfg.http_header_host.to_str().unwrap_or_default());
+
+    req.header(HOST, &client_cfg.http_header_host)
+        .header(USER_AGENT, BROWSER_USER_AGENT)
+        .header(ACCEPT, "*/*")
+        .header(ACCEPT_LANGUAGE, "en-US,en;q=0.5")
+        .header(ACCEPT_ENCODING, "gzip, deflate, br, zstd")
+        .header(SEC_WEBSOCKET_VERSION, "13")
+        .header(ORIGIN, origin)
+        .header(SEC_WEBSOCKET_PROTOCOL, protocol)
+        .header(SEC_WEBSOCKET_EXTENSIONS, "permessage-deflate")
+        .header(SEC_WEBSOCKET_KEY, fastwebsockets::handshake::generate_key())
+        .header(CONNECTION, "keep-alive, Upgrade")
+        .header(PRAGMA, "no-cache")
+        .header(CACHE_CONTROL, "no-cache")
+        .header(UPGRADE, "websocket")
+}
+
+fn upgrade_request(
     request_id: Uuid,
     client_cfg: &WsClientConfig,
     dest_addr: &RemoteAddr,
-) -> anyhow::Result<(WebsocketTunnelRead, WebsocketTunnelWrite, Parts)> {
-    let mut pooled_cnx = match client_cfg.cnx_pool().get().await {
-        Ok(cnx) => Ok(cnx),
-        Err(err) => Err(anyhow!("failed to get a connection to the server from the pool: {err:?}")),
-    }?;
-
-    let mut req = Request::builder()
-        .method("GET")
-        .uri(format!("/{}/events", &client_cfg.http_upgrade_path_prefix))
-        .header(HOST, &client_cfg.http_header_host)
-        .header(UPGRADE, "websocket")
-        .header(CONNECTION, "upgrade")
-        .header(SEC_WEBSOCKET_KEY, fastwebsockets::handshake::generate_key())
-        .header(SEC_WEBSOCKET_VERSION, "13")
-        .header(
-            SEC_WEBSOCKET_PROTOCOL,
-            format!("v1, {}{}", JWT_HEADER_PREFIX, tunnel_to_jwt_token(request_id, dest_addr)),
-        )
+    tunnel_headers: &HeaderMap,
+    path: &str,
+) -> anyhow::Result<Request<Empty<Bytes>>> {
+    let req = Request::builder().method("GET").uri(path);
+    let protocol = format!("v1, {}{}", JWT_HEADER_PREFIX, tunnel_to_jwt_token(request_id, dest_addr));
+    let req = if client_cfg.mimic_browser {
+        browser_upgrade_request(req, client_cfg, protocol)
+    } else {
+        req.header(HOST, &client_cfg.http_header_host)
+            .header(UPGRADE, "websocket")
+            .header(CONNECTION, "upgrade")
+            .header(SEC_WEBSOCKET_KEY, fastwebsockets::handshake::generate_key())
+            .header(SEC_WEBSOCKET_VERSION, "13")
+            .header(SEC_WEBSOCKET_PROTOCOL, protocol)
+    };
+    let mut req = req
+        .header(&CLIENT_VERSION_HEADER, env!("CARGO_PKG_VERSION"))
         .version(hyper::Version::HTTP_11);
 
     let headers = req.headers_mut().unwrap();
+    headers.extend(tunnel_headers.clone());
+    if let Some(cookie_jar) = &client_cfg.cookie_jar {
+        cookie_jar.add_cookie_header(headers);
+    }
     for (k, v) in &client_cfg.http_headers {
         let _ = headers.remove(k);
         headers.append(k, v.clone());
@@ -166,6 +391,11 @@ pub async fn connect(
         headers.append(AUTHORIZATION, auth.clone());
     }
 
+    if let Some(totp) = &client_cfg.auth_totp {
+        let _ = headers.remove(&TOTP_HEADER);
+        headers.append(&TOTP_HEADER, HeaderValue::from_str(&totp.proof_now(&request_id.to_string()))?);
+    }
+
     if let Some(headers_file_path) = &client_cfg.http_headers_file {
         let (host, headers_file) = headers_from_file(headers_file_path);
         for (k, v) in headers_file {
@@ -178,25 +408,371 @@ pub async fn connect(
         }
     }
 
-    let req = req.body(Empty::<Bytes>::new()).with_context(|| {
+    if client_cfg.headers_in_protocol {
+        headers_to_protocol(headers)?;
+    }
+
+    req.body(Empty::<Bytes>::new()).with_context(|| {
         format!(
             "failed to build HTTP request to contact the server {:?}",
             client_cfg.remote_addr
         )
-    })?;
-    debug!("with HTTP upgrade request {:?}", req);
-    let transport = pooled_cnx.deref_mut().take().unwrap();
-    let (mut ws, response) = fastwebsockets::handshake::client(&TokioExecutor::new(), req, transport)
-        .await
-        .with_context(|| format!("failed to do websocket handshake with the server {:?}", client_cfg.remote_addr))?;
+    })
+}
 
-    ws.set_auto_apply_mask(client_cfg.websocket_mask_frame);
+enum Handshake {
+    Upgraded(WebSocket<TokioIo<Upgraded>>, Response<Incoming>),
+    Redirected(StatusCode, String),
+}
 
-    let (ws_rx, ws_tx) = ws.split(tokio::io::split);
+// Same as fastwebsockets::handshake::client, but giving access to the response when the upgrade is refused
+async fn handshake(
+    client_cfg: &WsClientConfig,
+    req: Request<Empty<Bytes>>,
+    transport: TransportStream,
+) -> anyhow::Result<Handshake> {
+    let (mut sender, cnx) = hyper::client::conn::http1::handshake(TokioIo::new(transport)).await?;
+    tokio::spawn(async move {
+        if let Err(err) = cnx.with_upgrades().await {
+            debug!("Error polling connection: {:?}", err);
+        }
+    });
+
+    let mut response = sender.send_request(req).await?;
+    if let Some(cookie_jar) = &client_cfg.cookie_jar {
+        cookie_jar.store(response.headers());
+    }
+
+    let status = response.status();
+    if status.is_redirection() {
+        if let Some(location) = response.headers().get(LOCATION).and_then(|h| h.to_str().ok()) {
+            return Ok(Handshake::Redirected(status, location.to_string()));
+        }
+    }
+    let header_is = |name, expected: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|h| h.to_str().ok())
+            .is_some_and(|h| h.split(',').any(|token| token.trim().eq_ignore_ascii_case(expected)))
+    };
+    if status != StatusCode::SWITCHING_PROTOCOLS
+        || !header_is(UPGRADE, "websocket")
+        || !header_is(CONNECTION, "upgrade")
+    {
+        // Same error as fastwebsockets, for the caller to know if it can fallback to long polling
+        let retry = RetryAfter::from_response(status, response.headers());
+        let body = String::from_utf8(response.into_body().collect().await?.to_bytes().to_vec()).unwrap_or_default();
+        let err = anyhow::Error::new(WebSocketError::InvalidStatusCode(status.as_u16()))
+            .context(format!("Server rejected the upgrade request: {:?}: {}", status, body));
+        return Err(match retry {
+            Some(retry) => err.context(retry),
+            None => err,
+        });
+    }
+
+    let upgraded = hyper::upgrade::on(&mut response).await?;
+    Ok(Handshake::Upgraded(
+        WebSocket::after_handshake(TokioIo::new(upgraded), Role::Client),
+        response,
+    ))
+}
+
+/// Location of a redirect, resolved against the url of the upgrade request. Only websocket schemes are allowed,
+/// and a redirect from tls to plaintext is refused, as it would send the upgrade request in clear
+fn redirect_url(request_url: &Url, location: &str) -> anyhow::Result<Url> {
+    let mut url = request_url
+        .join(location)
+        .with_context(|| format!("invalid redirect location {}", location))?;
+    let scheme = match url.scheme() {
+        "ws" | "http" => "ws",
+        "wss" | "https" => "wss",
+        scheme => return Err(anyhow!("cannot follow redirect to {} scheme: {}", scheme, location)),
+    };
+    if request_url.scheme() == "wss" && scheme == "ws" {
+        return Err(anyhow!("cannot follow redirect from tls to plaintext: {}", location));
+    }
+    url.set_scheme(scheme)
+        .map_err(|_| anyhow!("cannot follow redirect to {}", location))?;
+    Ok(url)
+}
+
+// Config to reach the server at the redirect url, and the path to send the upgrade request to
+fn redirect_target(
+    client_cfg: &WsClientConfig,
+    path: &str,
+    location: &str,
+) -> anyhow::Result<(WsClientConfig, String)> {
+    let request_url = Url::parse(&format!(
+        "{}://{}:{}{}",
+        client_cfg.websocket_scheme(),
+        client_cfg.remote_addr.host(),
+        client_cfg.remote_addr.port(),
+        path
+    ))?;
+    let url = redirect_url(&request_url, location)?;
+    let (Some(host), Some(port)) = (url.host(), url.port_or_known_default()) else {
+        return Err(anyhow!("invalid redirect location {}", location));
+    };
+
+    let mut redirected_cfg = client_cfg.clone();
+    if url.scheme() != request_url.scheme()
+        || url.host() != request_url.host()
+        || Some(port) != request_url.port_or_known_default()
+    {
+        let scheme = if url.scheme() == "wss" {
+            TransportScheme::Wss
+        } else {
+            TransportScheme::Ws
+        };
+        let tls = match scheme {
+            TransportScheme::Wss => Some(redirect_tls_config(&client_cfg.redirect_tls)?),
+            _ => None,
+        };
+        redirected_cfg.remote_addr = TransportAddr::new(scheme, host.to_owned(), port, tls)
+            .ok_or_else(|| anyhow!("cannot follow redirect to {}", location))?;
+        redirected_cfg.http_header_host =
+            HeaderValue::from_str(&url[url::Position::BeforeHost..url::Position::AfterPort])?;
+    }
+
+    // Another host must not receive the credentials meant for the server, only the tunnel itself is sent to it
+    if url.host() != request_url.host() || Some(port) != request_url.port_or_known_default() {
+        warn!(
+            "Following redirect to {} without the credentials, totp, custom headers and cookies of the upgrade request",
+            location
+        );
+        redirected_cfg.http_upgrade_credentials = None;
+        redirected_cfg.auth_totp = None;
+        redirected_cfg.http_headers.clear();
+        redirected_cfg.http_headers_file = None;
+        redirected_cfg.cookie_jar = None;
+    }
 
     Ok((
-        WebsocketTunnelRead::new(ws_rx),
-        WebsocketTunnelWrite::new(ws_tx),
-        response.into_parts().0,
+        redirected_cfg,
+        url[url::Position::BeforePath..url::Position::AfterQuery].to_string(),
     ))
 }
+
+// Tls config for the host of a redirect. The sni override and ECH config of the client are only meant for its server
+fn redirect_tls_config(options: &TlsClientOptions) -> anyhow::Result<TlsClientConfig> {
+    let tls_connector = tls::tls_connector(
+        options.verify_certificate,
+        Some(vec![b"http/1.1".to_vec()]),
+        !options.sni_disabled,
+        options.fingerprint,
+        None,
+    )?;
+    Ok(TlsClientConfig {
+        tls_connector,
+        tls_sni_override: None,
+        tls_verify_certificate: options.verify_certificate,
+        tls_sni_disabled: options.sni_disabled,
+    })
+}
+
+pub struct WebsocketTransport;
+
+impl TunnelTransport for WebsocketTransport {
+    type Reader = WebsocketTunnelRead;
+    type Writer = WebsocketTunnelWrite;
+
+    async fn connect(
+        &self,
+        request_id: Uuid,
+        client_cfg: &WsClientConfig,
+        remote_cfg: &RemoteAddr,
+        reuse: bool,
+    ) -> anyhow::Result<(WebsocketTunnelRead, WebsocketTunnelWrite, Parts)> {
+        connect(request_id, client_cfg, remote_cfg, reuse).await
+    }
+}
+
+pub async fn connect(
+    request_id: Uuid,
+    client_cfg: &WsClientConfig,
+    dest_addr: &RemoteAddr,
+    reuse: bool,
+) -> anyhow::Result<(WebsocketTunnelRead, WebsocketTunnelWrite, Parts)> {
+    let mut features = CLIENT_FEATURES.join(",");
+    let mut tunnel_headers = HeaderMap::new();
+    if reuse {
+        features = format!("{},{}", features, FEATURE_REUSE);
+    } else if let Some(count) = client_cfg.bond.filter(|_| bond::is_bonded(client_cfg, dest_addr)) {
+        features = format!("{},{}", features, FEATURE_BOND);
+        tunnel_headers.insert(&BOND_HEADER, bond::header(0, count));
+    } else if resume::is_resumable(client_cfg, dest_addr) {
+        features = resume::features(client_cfg, &features);
+    }
+    tunnel_headers.insert(&CLIENT_FEATURES_HEADER, HeaderValue::from_str(&features)?);
+
+    upgrade(request_id, client_cfg, dest_addr, &tunnel_headers).await
+}
+
+/// Continue a tunnel of this client over a new websocket, i.e: after a network change.
+/// The secret is the one the server answered when the tunnel was opened.
+/// The server answers the bytes of the tunnel it received in the resume header of the response
+pub async fn resume(
+    request_id: Uuid,
+    client_cfg: &WsClientConfig,
+    dest_addr: &RemoteAddr,
+    secret: &HeaderValue,
+    received: u64,
+) -> anyhow::Result<(WebsocketTunnelRead, WebsocketTunnelWrite, Parts)> {
+    let mut tunnel_headers = HeaderMap::new();
+    let features = resume::features(client_cfg, &CLIENT_FEATURES.join(","));
+    tunnel_headers.insert(&CLIENT_FEATURES_HEADER, HeaderValue::from_str(&features)?);
+    tunnel_headers.insert(&SESSION_HEADER, secret.clone());
+    tunnel_headers.insert(&RESUME_HEADER, HeaderValue::from(received));
+
+    upgrade(request_id, client_cfg, dest_addr, &tunnel_headers).await
+}
+
+/// Open one more websocket for a bonded tunnel, joined by the server to the first one with the same secret and id
+pub async fn join_bond(
+    request_id: Uuid,
+    client_cfg: &WsClientConfig,
+    dest_addr: &RemoteAddr,
+    secret: &HeaderValue,
+    index: u8,
+    count: u8,
+) -> anyhow::Result<(WebsocketTunnelRead, WebsocketTunnelWrite, Parts)> {
+    let mut tunnel_headers = HeaderMap::new();
+    let features = format!("{},{}", CLIENT_FEATURES.join(","), FEATURE_BOND);
+    tunnel_headers.insert(&CLIENT_FEATURES_HEADER, HeaderValue::from_str(&features)?);
+    tunnel_headers.insert(&SESSION_HEADER, secret.clone());
+    tunnel_headers.insert(&BOND_HEADER, bond::header(index, count));
+
+    upgrade(request_id, client_cfg, dest_addr, &tunnel_headers).await
+}
+
+async fn upgrade(
+    request_id: Uuid,
+    client_cfg: &WsClientConfig,
+    dest_addr: &RemoteAddr,
+    tunnel_headers: &HeaderMap,
+) -> anyhow::Result<(WebsocketTunnelRead, WebsocketTunnelWrite, Parts)> {
+    let mut pooled_cnx = match client_cfg.cnx_pool().get().await {
+        Ok(cnx) => Ok(cnx),
+        Err(err) => Err(anyhow!("failed to get a connection to the server from the pool: {err:?}")),
+    }?;
+
+    let mut transport = pooled_cnx.deref_mut().take().unwrap();
+    let mut path = format!("/{}/events", &client_cfg.http_upgrade_path_prefix);
+    let mut redirected_cfg: Option<WsClientConfig> = None;
+    let mut redirects = 0;
+    let (mut ws, response) = loop {
+        let cfg = redirected_cfg.as_ref().unwrap_or(client_cfg);
+        let req = upgrade_request(request_id, cfg, dest_addr, tunnel_headers, &path)?;
+        debug!("with HTTP upgrade request {:?}", RedactedRequest(&req));
+        let handshake = handshake(cfg, req, transport)
+            .await
+            .with_context(|| format!("failed to do websocket handshake with the server {:?}", cfg.remote_addr))?;
+
+        match handshake {
+            Handshake::Upgraded(ws, response) => break (ws, response),
+            Handshake::Redirected(_, location) if redirects < client_cfg.max_redirects => {
+                redirects += 1;
+                info!("Following redirect of the upgrade request to {}", location);
+                let (next_cfg, next_path) = redirect_target(cfg, &path, &location)?;
+                transport = next_cfg
+                    .connect()
+                    .await?
+                    .ok_or_else(|| anyhow!("cannot connect to {:?}", next_cfg.remote_addr))?;
+                redirected_cfg = Some(next_cfg);
+                path = next_path;
+            }
+            Handshake::Redirected(status, location) => {
+                return Err(
+                    anyhow::Error::new(WebSocketError::InvalidStatusCode(status.as_u16())).context(format!(
+                        "server redirected the upgrade request to {}, use --http-upgrade-max-redirects to follow it",
+                        location
+                    )),
+                );
+            }
+        }
+    };
+
+    ws.set_auto_apply_mask(client_cfg.websocket_mask_frame);
+
+    let (ws_rx, ws_tx) = ws.split(tokio::io::split);
+    let half_close = peer_features(response.headers()).contains(&FEATURE_HALF_CLOSE);
+    let ws_tx = WebsocketTunnelWrite::new(ws_tx, half_close);
+
+    Ok((WebsocketTunnelRead::new(ws_rx, &ws_tx), ws_tx, response.into_parts().0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redirect_url() {
+        let request_url = Url::parse("wss://example.com:443/v1/events").unwrap();
+        let redirect = |location| redirect_url(&request_url, location).map(|url| url.to_string());
+
+        assert_eq!(redirect("/v2/events").unwrap(), "wss://example.com/v2/events");
+        assert_eq!(
+            redirect("https://other.example.com:8443/v1/events?x=1").unwrap(),
+            "wss://other.example.com:8443/v1/events?x=1"
+        );
+        assert!(redirect("http://other.example.com/").is_err());
+        assert!(redirect("ws://example.com/v1/events").is_err());
+        assert!(redirect("ftp://other.example.com/").is_err());
+
+        let request_url = Url::parse("ws://example.com:80/v1/events").unwrap();
+        assert_eq!(
+            redirect_url(&request_url, "http://other.example.com/")
+                .unwrap()
+                .to_string(),
+            "ws://other.example.com/"
+        );
+        assert_eq!(
+            redirect_url(&request_url, "https://example.com/").unwrap().to_string(),
+            "wss://example.com/"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_redirect_target_tls() {
+        use crate::harness::LocalServer;
+        use tokio_rustls::rustls::pki_types::ServerName;
+
+        // The sni override is meant for the server of the command line, not for the one redirected to
+        let server = LocalServer::start("wss", []).await.unwrap();
+        let client_cfg = server
+            .client(["--tls-sni-override=front.example.com".to_string()])
+            .await
+            .unwrap();
+        let (redirected_cfg, path) =
+            redirect_target(&client_cfg, "/v1/events", "wss://other.example.com/v2/events").unwrap();
+        assert_eq!(path, "/v2/events");
+        assert_eq!(
+            redirected_cfg.tls_server_name(),
+            ServerName::try_from("other.example.com").unwrap()
+        );
+
+        // A plaintext server can redirect to a tls one
+        let server = LocalServer::start("ws", []).await.unwrap();
+        let client_cfg = server.client([]).await.unwrap();
+        let (redirected_cfg, _) = redirect_target(&client_cfg, "/v1/events", "wss://other.example.com/").unwrap();
+        assert_eq!(redirected_cfg.websocket_scheme(), "wss");
+        assert!(redirected_cfg
+            .remote_addr
+            .tls()
+            .is_some_and(|tls| tls.tls_sni_override.is_none()));
+    }
+
+    #[test]
+    fn test_rtt_from_pong() {
+        let payload = ping_payload();
+        std::thread::sleep(Duration::from_millis(5));
+        let rtt = rtt_from_pong(&payload).unwrap();
+        assert!(rtt >= Duration::from_millis(5) && rtt < Duration::from_secs(5));
+
+        // Pings of older peers carry no timestamp
+        assert_eq!(rtt_from_pong(&[]), None);
+        assert_eq!(rtt_from_pong(b"keepalive"), None);
+        assert_eq!(rtt_from_pong(&u64::MAX.to_be_bytes()), None);
+    }
+}
@@ -19,8 +19,10 @@ use tokio::net::UdpSocket;
 use tokio::sync::futures::Notified;
 
 use crate::dns::DnsResolver;
-use tokio::sync::Notify;
-use tokio::time::{timeout, Interval};
+use crate::netns::NetNs;
+use socket2::{Domain, Type};
+use tokio::sync::{Notify, OwnedSemaphorePermit};
+use tokio::time::{timeout, Instant, Interval, Sleep};
 use tracing::{debug, error, info};
 use url::Host;
 
@@ -161,6 +163,10 @@ impl UdpStream {
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         self.send_socket.local_addr()
     }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
 }
 
 impl AsyncRead for UdpStream {
@@ -220,6 +226,19 @@ impl AsyncWrite for UdpStream {
     }
 }
 
+/// Socket of a udp server. On [::] it receives from ipv4 clients too, whatever the default of the OS
+pub fn bind_listener(bind: SocketAddr) -> io::Result<UdpSocket> {
+    use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(bind), Type::DGRAM, Some(Protocol::UDP))?;
+    if bind.is_ipv6() && bind.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    socket.bind(&SockAddr::from(bind))?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(std::net::UdpSocket::from(socket))
+}
+
 pub async fn run_server(
     bind: SocketAddr,
     timeout: Option<Duration>,
@@ -232,9 +251,7 @@ pub async fn run_server(
         timeout.unwrap_or(Duration::from_secs(0)).as_secs()
     );
 
-    let listener = UdpSocket::bind(bind)
-        .await
-        .with_context(|| format!("Cannot create UDP server {:?}", bind))?;
+    let listener = bind_listener(bind).with_context(|| format!("Cannot create UDP server {:?}", bind))?;
     configure_listener(&listener)?;
 
     let udp_server = UdpServer::new(listener, timeout);
@@ -284,28 +301,256 @@ pub async fn run_server(
     Ok(stream)
 }
 
+/// Close a flow or a session once nothing has been sent nor received during the timeout
+pub struct IdleWatchdog {
+    timeout: Duration,
+    // Shared by the read and write halves of the socket
+    last_activity: Arc<parking_lot::Mutex<Instant>>,
+    deadline: Pin<Box<Sleep>>,
+}
+
+impl IdleWatchdog {
+    pub fn new(timeout: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            timeout,
+            last_activity: Arc::new(parking_lot::Mutex::new(now)),
+            deadline: Box::pin(tokio::time::sleep_until(now + timeout)),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn touch(&self) {
+        *self.last_activity.lock() = Instant::now();
+    }
+
+    pub fn poll_expired(&mut self, cx: &mut std::task::Context<'_>) -> bool {
+        while self.deadline.as_mut().poll(cx).is_ready() {
+            let deadline = *self.last_activity.lock() + self.timeout;
+            if deadline <= Instant::now() {
+                return true;
+            }
+            self.deadline.as_mut().reset(deadline);
+        }
+        false
+    }
+}
+
+impl Clone for IdleWatchdog {
+    fn clone(&self) -> Self {
+        Self {
+            timeout: self.timeout,
+            last_activity: self.last_activity.clone(),
+            deadline: Box::pin(tokio::time::sleep_until(*self.last_activity.lock() + self.timeout)),
+        }
+    }
+}
+
+const PMTU_WARN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Datagrams dropped for being bigger than the path MTU, to log them at most once per PMTU_WARN_INTERVAL
+/// instead of once per datagram
+#[derive(Default)]
+struct OversizedDrops {
+    // Dropped since the last warning, and when it was logged
+    count: u64,
+    last_warn: Option<Instant>,
+}
+
 #[derive(Clone)]
 pub struct MyUdpSocket {
     socket: Arc<UdpSocket>,
+    // Set once path MTU discovery is enabled
+    pmtu_drops: Option<Arc<parking_lot::Mutex<OversizedDrops>>>,
+    // Destination of an unconnected socket, which accepts datagrams from any peer
+    full_cone_peer: Option<SocketAddr>,
+    idle_watchdog: Option<IdleWatchdog>,
+    // Released once both halves of the socket are dropped
+    _flow_permit: Option<Arc<OwnedSemaphorePermit>>,
 }
 
 impl MyUdpSocket {
     pub fn new(socket: Arc<UdpSocket>) -> Self {
-        Self { socket }
+        Self {
+            socket,
+            pmtu_drops: None,
+            full_cone_peer: None,
+            idle_watchdog: None,
+            _flow_permit: None,
+        }
+    }
+
+    /// Fail the reads once no datagram has been sent nor received for this duration, to release the socket of the flow
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_watchdog = Some(IdleWatchdog::new(timeout));
+        self
+    }
+
+    /// Hold the permit of a limited number of flows for as long as the socket is alive
+    pub fn with_flow_permit(mut self, permit: OwnedSemaphorePermit) -> Self {
+        self._flow_permit = Some(Arc::new(permit));
+        self
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self.full_cone_peer {
+            Some(peer) => Ok(peer),
+            None => self.socket.peer_addr(),
+        }
+    }
+
+    /// Replace the connected socket by an unconnected one, so datagrams sent by any peer to the allocated port
+    /// are relayed back, not only the ones of the destination (endpoint independent mapping, aka full cone NAT).
+    /// Protocols doing NAT traversal like STUN/WebRTC need it, as replies can come from another address
+    pub async fn into_full_cone(self) -> io::Result<Self> {
+        let peer = self.socket.peer_addr()?;
+        let socket = match peer {
+            SocketAddr::V4(_) => UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await?,
+            SocketAddr::V6(_) => UdpSocket::bind(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)).await?,
+        };
+        debug!("Using full cone UDP socket {} toward {}", socket.local_addr()?, peer);
+
+        Ok(Self {
+            socket: Arc::new(socket),
+            full_cone_peer: Some(peer),
+            ..self
+        })
+    }
+
+    /// Send datagrams with the don't fragment bit and let the kernel track the path MTU toward the peer.
+    /// This trades fragmentation for drops: datagrams bigger than the path MTU are never sent, even if the path would
+    /// have delivered their fragments, and the application has to lower its packet size by itself.
+    /// It only helps when a middlebox drops fragments, as drops are then at least logged instead of silent
+    #[cfg(target_os = "linux")]
+    pub fn enable_pmtu_discovery(mut self) -> io::Result<Self> {
+        use nix::libc;
+        use std::os::fd::AsRawFd;
+
+        let (level, optname, value) = match self.peer_addr()? {
+            SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_DO),
+            SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, libc::IPV6_PMTUDISC_DO),
+        };
+        let ret = unsafe {
+            libc::setsockopt(
+                self.socket.as_raw_fd(),
+                level,
+                optname,
+                &value as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+
+        self.pmtu_drops = Some(Arc::new(parking_lot::Mutex::new(OversizedDrops::default())));
+        if let Some(mtu) = self.path_mtu() {
+            info!("Path MTU toward {} is {}", self.peer_addr()?, mtu);
+        }
+        Ok(self)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn enable_pmtu_discovery(self) -> io::Result<Self> {
+        warn!("UDP path MTU discovery is only supported on linux");
+        Ok(self)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn path_mtu(&self) -> Option<usize> {
+        use nix::libc;
+        use std::os::fd::AsRawFd;
+
+        let (level, optname) = match self.peer_addr().ok()? {
+            SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_MTU),
+            SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_MTU),
+        };
+        let mut mtu: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                self.socket.as_raw_fd(),
+                level,
+                optname,
+                &mut mtu as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        (ret == 0).then_some(mtu as usize)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn path_mtu(&self) -> Option<usize> {
+        None
     }
 }
 
 impl AsyncRead for MyUdpSocket {
     fn poll_read(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
-        unsafe { self.map_unchecked_mut(|x| &mut x.socket) }
-            .poll_recv_from(cx, buf)
-            .map(|x| x.map(|_| ()))
+        let this = self.get_mut();
+        if let Some(watchdog) = &mut this.idle_watchdog {
+            if watchdog.poll_expired(cx) {
+                return Poll::Ready(Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!("UDP flow idle for {:?}", watchdog.timeout),
+                )));
+            }
+        }
+
+        ready!(this.socket.poll_recv_from(cx, buf))?;
+        if let Some(watchdog) = &this.idle_watchdog {
+            watchdog.touch();
+        }
+        Poll::Ready(Ok(()))
     }
 }
 
+#[cfg(target_os = "linux")]
+#[inline]
+fn is_datagram_too_big(err: &Error) -> bool {
+    err.raw_os_error() == Some(nix::libc::EMSGSIZE)
+}
+
+#[cfg(not(target_os = "linux"))]
+#[inline]
+fn is_datagram_too_big(_err: &Error) -> bool {
+    false
+}
+
 impl AsyncWrite for MyUdpSocket {
     fn poll_write(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
-        unsafe { self.map_unchecked_mut(|x| &mut x.socket) }.poll_send(cx, buf)
+        let ret = match self.full_cone_peer {
+            Some(peer) => ready!(self.socket.poll_send_to(cx, buf, peer)),
+            None => ready!(self.socket.poll_send(cx, buf)),
+        };
+        match (ret, &self.pmtu_drops) {
+            // Datagram is bigger than the path MTU, drop it to not abort the whole tunnel
+            (Err(err), Some(pmtu_drops)) if is_datagram_too_big(&err) => {
+                let mut drops = pmtu_drops.lock();
+                drops.count += 1;
+                if !matches!(drops.last_warn, Some(at) if at.elapsed() < PMTU_WARN_INTERVAL) {
+                    warn!(
+                        "Dropped {} UDP datagram(s) toward {:?} bigger than the path MTU {:?}, last one was {} bytes",
+                        drops.count,
+                        self.peer_addr(),
+                        self.path_mtu(),
+                        buf.len()
+                    );
+                    drops.count = 0;
+                    drops.last_warn = Some(Instant::now());
+                }
+                Poll::Ready(Ok(buf.len()))
+            }
+            (ret, _) => {
+                if let (Ok(_), Some(watchdog)) = (&ret, &self.idle_watchdog) {
+                    watchdog.touch();
+                }
+                Poll::Ready(ret)
+            }
+        }
     }
 
     fn poll_flush(self: Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Error>> {
@@ -322,6 +567,31 @@ pub async fn connect(
     port: u16,
     connect_timeout: Duration,
     dns_resolver: &DnsResolver,
+) -> anyhow::Result<MyUdpSocket> {
+    connect_in(host, port, connect_timeout, dns_resolver, None).await
+}
+
+async fn bind_socket(addr: &SocketAddr, netns: Option<&NetNs>) -> io::Result<UdpSocket> {
+    let unspecified: SocketAddr = match addr {
+        SocketAddr::V4(_) => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0).into(),
+    };
+    let Some(netns) = netns else {
+        return UdpSocket::bind(unspecified).await;
+    };
+
+    let socket = netns.socket(Domain::for_address(*addr), Type::DGRAM).await?;
+    socket.bind(&unspecified.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Like connect, with the socket created in the network namespace `netns` if any
+pub async fn connect_in(
+    host: &Host<String>,
+    port: u16,
+    connect_timeout: Duration,
+    dns_resolver: &DnsResolver,
+    netns: Option<&NetNs>,
 ) -> anyhow::Result<MyUdpSocket> {
     info!("Opening UDP connection to {}:{}", host, port);
 
@@ -339,12 +609,7 @@ pub async fn connect(
     for addr in socket_addrs {
         debug!("connecting to {}", addr);
 
-        let socket = match &addr {
-            SocketAddr::V4(_) => UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await,
-            SocketAddr::V6(_) => UdpSocket::bind(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)).await,
-        };
-
-        let socket = match socket {
+        let socket = match bind_socket(&addr, netns).await {
             Ok(socket) => socket,
             Err(err) => {
                 warn!("cannot bind udp socket {:?}", err);
@@ -450,10 +715,26 @@ pub fn mk_send_socket_tproxy(listener: &Arc<UdpSocket>) -> anyhow::Result<Arc<Ud
 mod tests {
     use super::*;
     use futures_util::{pin_mut, StreamExt};
-    use tokio::io::AsyncReadExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use tokio::time::error::Elapsed;
     use tokio::time::timeout;
 
+    #[tokio::test]
+    async fn test_bind_listener_dual_stack() {
+        let listener = bind_listener("[::]:0".parse().unwrap()).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"hello", ("127.0.0.1", port)).await.unwrap();
+
+        let mut buf = [0u8; 16];
+        let (len, _) = timeout(Duration::from_secs(1), listener.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..len], b"hello");
+    }
+
     #[tokio::test]
     async fn test_udp_server() {
         let server_addr: SocketAddr = "[::1]:1234".parse().unwrap();
@@ -502,6 +783,67 @@ mod tests {
         assert_eq!(&buf[..16], b"helloworld test\0");
     }
 
+    #[tokio::test]
+    async fn test_full_cone_accept_any_peer() {
+        let destination = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let other_peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let destination_addr = destination.local_addr().unwrap();
+        let cnx = connect(
+            &Host::Ipv4(Ipv4Addr::LOCALHOST),
+            destination_addr.port(),
+            Duration::from_secs(1),
+            &DnsResolver::system(),
+        )
+        .await
+        .unwrap()
+        .into_full_cone()
+        .await
+        .unwrap();
+        let (mut reader, mut writer) = (cnx.clone(), cnx);
+
+        // Datagrams still go to the destination
+        writer.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 25];
+        let (len, mapped_addr) = destination.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"hello");
+
+        // Replies from another peer to the mapped port are relayed too
+        other_peer
+            .send_to(b"world", (Ipv4Addr::LOCALHOST, mapped_addr.port()))
+            .await
+            .unwrap();
+        let ret = timeout(Duration::from_secs(1), reader.read(&mut buf)).await;
+        assert!(matches!(ret, Ok(Ok(5))));
+        assert_eq!(&buf[..5], b"world");
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let destination = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let destination_addr = destination.local_addr().unwrap();
+        let cnx = connect(
+            &Host::Ipv4(Ipv4Addr::LOCALHOST),
+            destination_addr.port(),
+            Duration::from_secs(1),
+            &DnsResolver::system(),
+        )
+        .await
+        .unwrap()
+        .with_idle_timeout(Duration::from_millis(200));
+        let (mut reader, mut writer) = (cnx.clone(), cnx);
+
+        // Sending keeps the flow alive, even if the destination never answers
+        let mut buf = [0u8; 25];
+        for _ in 0..3 {
+            writer.write_all(b"ping").await.unwrap();
+            let ret = timeout(Duration::from_millis(100), reader.read(&mut buf)).await;
+            assert!(ret.is_err());
+        }
+
+        let ret = timeout(Duration::from_secs(1), reader.read(&mut buf)).await;
+        assert!(matches!(ret, Ok(Err(err)) if err.kind() == ErrorKind::TimedOut));
+    }
+
     #[tokio::test]
     async fn test_multiple_client() {
         let server_addr: SocketAddr = "[::1]:1235".parse().unwrap();
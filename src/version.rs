@@ -0,0 +1,93 @@
+use hyper::http::HeaderName;
+use hyper::HeaderMap;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::ErrorKind;
+use std::str::FromStr;
+
+pub static CLIENT_VERSION_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-version");
+pub static CLIENT_FEATURES_HEADER: HeaderName = HeaderName::from_static("x-wstunnel-features");
+
+pub const FEATURE_HALF_CLOSE: &str = "half-close";
+/// Keep the websocket once both sides of a connection are half closed, to carry the next connection to the destination
+pub const FEATURE_REUSE: &str = "reuse";
+/// Keep the connection to the destination when the websocket breaks, for the client to resume the tunnel on a new one.
+/// Only advertised by servers started with --resume-timeout-sec
+pub const FEATURE_RESUME: &str = "resume";
+/// Acknowledge the bytes received by resumable tunnels, for the data lost with a broken websocket to be sent again
+pub const FEATURE_RETRANSMIT: &str = "retransmit";
+/// Stripe a tcp tunnel across several websockets, joined to the first one by the session of the client
+pub const FEATURE_BOND: &str = "bond";
+
+/// Features supported by this client, advertised to the server during the upgrade request.
+/// The ones added depending on the config are listed too in the help of --require-client-features
+pub const CLIENT_FEATURES: &[&str] = &["totp", "speed-test", FEATURE_HALF_CLOSE];
+
+/// Features supported by this server, advertised to the client in the upgrade response
+pub const SERVER_FEATURES: &[&str] = &[FEATURE_HALF_CLOSE, FEATURE_REUSE, FEATURE_BOND];
+
+/// Features advertised by the peer with the features header
+pub fn peer_features(headers: &HeaderMap) -> Vec<&str> {
+    headers
+        .get(&CLIENT_FEATURES_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or_default()
+        .split(',')
+        .map(|f| f.trim())
+        .filter(|f| !f.is_empty())
+        .collect()
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+pub fn parse_version(arg: &str) -> Result<Version, io::Error> {
+    // Ignore pre-release and build metadata, i.e: 9.2.4-beta+abcdef
+    let arg = arg.trim().trim_start_matches('v');
+    let version = arg.split(['-', '+']).next().unwrap_or_default();
+
+    let mut parts = version.split('.').map(u64::from_str);
+    let (Some(Ok(major)), minor, patch, None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse version from {}, expected MAJOR[.MINOR[.PATCH]]", arg),
+        ));
+    };
+
+    let (Ok(minor), Ok(patch)) = (minor.unwrap_or(Ok(0)), patch.unwrap_or(Ok(0))) else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot parse version from {}, expected MAJOR[.MINOR[.PATCH]]", arg),
+        ));
+    };
+
+    Ok(Version { major, minor, patch })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_version() {
+        let v = |major, minor, patch| Version { major, minor, patch };
+        assert_eq!(parse_version("9.2.4").unwrap(), v(9, 2, 4));
+        assert_eq!(parse_version("v9.2").unwrap(), v(9, 2, 0));
+        assert_eq!(parse_version("10").unwrap(), v(10, 0, 0));
+        assert_eq!(parse_version("9.2.4-beta+abcdef").unwrap(), v(9, 2, 4));
+        assert!(parse_version("").is_err());
+        assert!(parse_version("9.x").is_err());
+        assert!(parse_version("9.2.4.1").is_err());
+        assert!(parse_version("9.2.3").unwrap() < parse_version("9.10.0").unwrap());
+    }
+}
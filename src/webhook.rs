@@ -0,0 +1,114 @@
+use crate::dns::DnsResolver;
+use crate::http_client;
+use anyhow::anyhow;
+use bytes::Bytes;
+use once_cell::sync::OnceCell;
+use serde_json::{json, Value};
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use url::Url;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+// Events are dropped if the endpoint cannot keep up, to never slow down tunnels
+const WEBHOOK_QUEUE_SIZE: usize = 1024;
+
+static WEBHOOK: OnceCell<mpsc::Sender<Value>> = OnceCell::new();
+
+/// Start posting tunnel lifecycle events as JSON documents to `url`, one request per event
+pub fn init(url: Url, dns_resolver: DnsResolver) -> anyhow::Result<()> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(anyhow!("webhook url must be http(s), got {}", url));
+    }
+
+    let (tx, mut rx) = mpsc::channel::<Value>(WEBHOOK_QUEUE_SIZE);
+    if WEBHOOK.set(tx).is_err() {
+        return Err(anyhow!("webhook is already initialized"));
+    }
+
+    info!("Sending tunnel events to webhook {}", url);
+    tokio::spawn(async move {
+        while let Some(event) = rx.recv().await {
+            let body = Bytes::from(event.to_string());
+            if let Err(err) = http_client::post_json(&url, body, &dns_resolver, WEBHOOK_TIMEOUT).await {
+                warn!("Cannot send event to webhook: {:?}", err);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+pub fn is_enabled() -> bool {
+    WEBHOOK.get().is_some()
+}
+
+fn send(event: &str, mut fields: Value) {
+    let Some(webhook) = WEBHOOK.get() else {
+        return;
+    };
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    fields["event"] = json!(event);
+    fields["timestamp"] = json!(timestamp);
+    if webhook.try_send(fields).is_err() {
+        warn!("Webhook queue is full, dropping {} event", event);
+    }
+}
+
+/// Metadata of a tunnel, sent with each of its events
+pub struct WebhookTunnel {
+    id: String,
+    client_ip: IpAddr,
+    protocol: Value,
+    remote: String,
+}
+
+impl WebhookTunnel {
+    /// Return None if the webhook is not enabled, to not pay for it
+    pub fn new(id: &str, client_ip: IpAddr, protocol: Value, remote: String) -> Option<Self> {
+        WEBHOOK.get()?;
+        Some(Self {
+            id: id.to_string(),
+            client_ip,
+            protocol,
+            remote,
+        })
+    }
+
+    fn fields(&self) -> Value {
+        json!({
+            "id": self.id,
+            "client_ip": self.client_ip,
+            "protocol": self.protocol,
+            "remote": self.remote,
+        })
+    }
+
+    pub fn opened(&self) {
+        send("tunnel_opened", self.fields());
+    }
+
+    pub fn closed(&self, bytes_tx: u64, bytes_rx: u64, duration: Duration) {
+        let mut fields = self.fields();
+        fields["bytes_tx"] = json!(bytes_tx);
+        fields["bytes_rx"] = json!(bytes_rx);
+        fields["duration_ms"] = json!(duration.as_millis() as u64);
+        send("tunnel_closed", fields);
+    }
+}
+
+pub fn tunnel_rejected(client_ip: IpAddr, request: &str, status: u16) {
+    send(
+        "tunnel_rejected",
+        json!({
+            "client_ip": client_ip,
+            "request": request,
+            "status": status,
+        }),
+    );
+}